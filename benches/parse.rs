@@ -16,25 +16,125 @@ fn generate_build_ninja(statement_count: usize) -> Vec<u8> {
     buf
 }
 
+/// Like generate_build_ninja, but with long variable values, as generators
+/// produce for compiler flags.
+fn generate_build_ninja_long_values(statement_count: usize) -> Vec<u8> {
+    let mut buf: Vec<u8> = Vec::new();
+    write!(buf, "rule cc\n    command = cc $flags -c $in -o $out\n",).unwrap();
+    let flags: String = (0..40)
+        .map(|i| {
+            format!(
+                " -I../../third_party/library{}/include -DFEATURE_{}=1",
+                i, i
+            )
+        })
+        .collect();
+    for i in 0..statement_count {
+        write!(
+            buf,
+            "build foo/bar{}.o: cc src/long/file/name{}.cc\n  flags ={} $extra\n",
+            i, i, flags
+        )
+        .unwrap();
+    }
+    buf
+}
+
+/// Like generate_build_ninja, but with many long paths.
+fn generate_build_ninja_long_paths(statement_count: usize) -> Vec<u8> {
+    let mut buf: Vec<u8> = Vec::new();
+    write!(buf, "rule cc\n    command = cc -c $in -o $out\n",).unwrap();
+    for i in 0..statement_count {
+        write!(
+            buf,
+            "build obj/third_party/some_library/src/component/file{}.o: cc",
+            i
+        )
+        .unwrap();
+        for j in 0..20 {
+            write!(
+                buf,
+                " ../../third_party/some_library/src/component/subdir{}/file{}.cc",
+                j, i
+            )
+            .unwrap();
+        }
+        buf.push(b'\n');
+    }
+    buf
+}
+
+/// Like generate_build_ninja, but with every build sharing a set of implicit
+/// inputs, as with common headers.
+fn generate_build_ninja_shared_inputs(statement_count: usize) -> Vec<u8> {
+    let mut buf: Vec<u8> = Vec::new();
+    write!(buf, "rule cc\n    command = cc -c $in -o $out\n",).unwrap();
+    for i in 0..statement_count {
+        write!(buf, "build foo/bar{}.o: cc src/name{}.cc |", i, i).unwrap();
+        for j in 0..20 {
+            write!(buf, " include/common/header{}.h", j).unwrap();
+        }
+        buf.push(b'\n');
+    }
+    buf
+}
+
+/// Builds using a rule with many bindings, which all need looking up to
+/// evaluate the command.
+fn generate_build_ninja_many_bindings(statement_count: usize) -> Vec<u8> {
+    let mut buf: Vec<u8> = Vec::new();
+    write!(buf, "rule cc\n    command = cc").unwrap();
+    for i in 0..14 {
+        write!(buf, " $flag{}", i).unwrap();
+    }
+    writeln!(buf, " -c $in -o $out").unwrap();
+    for i in 0..14 {
+        writeln!(buf, "    flag{} = -f{}", i, i).unwrap();
+    }
+    for i in 0..statement_count {
+        writeln!(buf, "build foo/bar{}.o: cc src/name{}.cc", i, i).unwrap();
+    }
+    buf
+}
+
 mod parser {
     use super::*;
     use n2::parse::Parser;
 
     #[divan::bench]
     fn synthetic(bencher: Bencher) {
-        let mut input = generate_build_ninja(1000);
-        input.push(0);
+        let input = generate_build_ninja(1000);
+
+        bencher.bench_local(|| {
+            let mut parser = Parser::new(&input);
+            while parser.read().unwrap().is_some() {}
+        });
+    }
+
+    #[divan::bench]
+    fn synthetic_long_values(bencher: Bencher) {
+        let input = generate_build_ninja_long_values(1000);
 
         bencher.bench_local(|| {
             let mut parser = Parser::new(&input);
-            while let Some(_) = parser.read().unwrap() {}
+            while parser.read().unwrap().is_some() {}
+        });
+    }
+
+    #[divan::bench]
+    fn synthetic_long_paths(bencher: Bencher) {
+        let input = generate_build_ninja_long_paths(1000);
+
+        bencher.bench_local(|| {
+            let mut parser = Parser::new(&input);
+            while parser.read().unwrap().is_some() {}
         });
     }
 
     // This can take a while to run (~100ms per sample), so reduce total count.
     #[divan::bench(sample_size = 3, max_time = 1)]
     fn file(bencher: Bencher) {
-        let input = match n2::scanner::read_file_with_nul("benches/build.ninja".as_ref()) {
+        let input = match std::fs::read("benches/build.ninja") {
             Ok(input) => input,
             Err(err) => {
                 eprintln!("failed to read benches/build.ninja: {}", err);
@@ -44,15 +144,37 @@ mod parser {
         };
         bencher.bench_local(|| {
             let mut parser = n2::parse::Parser::new(&input);
-            while let Some(_) = parser.read().unwrap() {}
+            while parser.read().unwrap().is_some() {}
         });
     }
 }
 
 #[divan::bench]
 fn load_synthetic(bencher: Bencher) {
-    let mut input = generate_build_ninja(1000);
-    input.push(0);
+    let input = generate_build_ninja(1000);
+    bencher.bench_local(|| {
+        let mut loader = n2::load::Loader::new();
+        loader
+            .parse(PathBuf::from_str("build.ninja").unwrap(), &input)
+            .unwrap();
+    });
+}
+
+#[divan::bench]
+fn load_synthetic_shared_inputs(bencher: Bencher) {
+    let input = generate_build_ninja_shared_inputs(1000);
+    bencher.bench_local(|| {
+        let mut loader = n2::load::Loader::new();
+        loader
+            .parse(PathBuf::from_str("build.ninja").unwrap(), &input)
+            .unwrap();
+    });
+}
+
+// 100k builds take a while, so reduce total count.
+#[divan::bench(sample_count = 10, sample_size = 1)]
+fn load_many_bindings(bencher: Bencher) {
+    let input = generate_build_ninja_many_bindings(100_000);
     bencher.bench_local(|| {
         let mut loader = n2::load::Loader::new();
         loader
@@ -41,7 +41,21 @@ impl<T: Copy, const CAPACITY: usize> StackStack<T, CAPACITY> {
 /// Does not access the disk, but only simplifies things like
 /// "foo/./bar" => "foo/bar".
 /// These paths can show up due to variable expansion in particular.
+/// Leading ".." components are kept, except at the root of an absolute path.
 pub fn canonicalize_path(path: &mut String) {
+    canonicalize(path, false);
+}
+
+/// Like canonicalize_path, but for Windows paths: a leading drive `C:` or UNC
+/// `\\server` prefix is kept as the root, which `..` can't go above.  Separators are
+/// left as they were, so that commands see paths as the manifest spelled
+/// them; see GraphFiles for how `src\foo.c` and `src/foo.c` are still the
+/// same file.
+pub fn canonicalize_path_windows(path: &mut String) {
+    canonicalize(path, true);
+}
+
+fn canonicalize(path: &mut String, windows: bool) {
     assert!(!path.is_empty());
     let mut components = StackStack::<usize, 60>::new();
 
@@ -53,10 +67,34 @@ pub fn canonicalize_path(path: &mut String) {
     let mut dst = 0;
     let mut src = 0;
 
-    if let Some(b'/' | b'\\') = data.get(src) {
+    let mut absolute = matches!(data.get(src), Some(b'/' | b'\\'));
+    if absolute {
         src += 1;
         dst += 1;
-    };
+        if windows && matches!(data.get(src), Some(b'/' | b'\\')) {
+            src += 1;
+            dst += 1;
+            // The server name is part of the root.  Nothing has been removed
+            // yet, so dst == src and it stays in place.
+            if !matches!(data.get(src), None | Some(b'/' | b'\\')) {
+                src = match data[src..].iter().position(|c| matches!(c, b'/' | b'\\')) {
+                    Some(pos) => src + pos + 1,
+                    None => data.len(),
+                };
+                dst = src;
+            }
+        }
+    } else if windows && data.len() >= 2 && data[0].is_ascii_alphabetic() && data[1] == b':' {
+        // The drive is kept in place as well; only `C:\` is absolute, while
+        // `C:..` is relative to the drive's current directory.
+        src += 2;
+        dst += 2;
+        if matches!(data.get(src), Some(b'/' | b'\\')) {
+            absolute = true;
+            src += 1;
+            dst += 1;
+        }
+    }
 
     // One iteration per path component.
     while let Some(&current) = data.get(src) {
@@ -82,6 +120,8 @@ pub fn canonicalize_path(path: &mut String) {
                             // ".." component, try to back up.
                             if let Some(ofs) = components.pop() {
                                 dst = ofs;
+                            } else if absolute {
+                                // The parent of the root is the root.
                             } else {
                                 // Safety: our invariant is dst <= src and we are inside a branch,
                                 // where even src + 2 < data.len()
@@ -186,6 +226,9 @@ mod tests {
     fn slash() {
         assert_canon_path_eq("/foo", "/foo");
         assert_canon_path_eq("foo//bar", "foo/bar");
+        assert_canon_path_eq("out//obj///foo.o", "out/obj/foo.o");
+        assert_canon_path_eq("//foo", "/foo");
+        assert_canon_path_eq("/", "/");
     }
 
     #[test]
@@ -201,5 +244,42 @@ mod tests {
         assert_canon_path_eq("foo/../", ".");
         assert_canon_path_eq("foo/../../", "../");
         assert_canon_path_eq("foo/../../bar", "../bar");
+        assert_canon_path_eq("./src/../src/foo.c", "src/foo.c");
+        assert_canon_path_eq("a/b/../../..", "..");
+        assert_canon_path_eq("..//..", "../..");
+        assert_canon_path_eq("..", "..");
+    }
+
+    #[test]
+    fn windows() {
+        let canon = |path: &str| {
+            let mut path = path.to_owned();
+            canonicalize_path_windows(&mut path);
+            path
+        };
+        assert_eq!(canon("src\\foo.c"), "src\\foo.c");
+        assert_eq!(canon("src/foo.c"), "src/foo.c");
+        assert_eq!(canon(".\\src\\..\\src/foo.c"), "src/foo.c");
+        assert_eq!(canon("C:\\x\\y"), "C:\\x\\y");
+        assert_eq!(canon("C:/x\\\\y"), "C:/x\\y");
+        assert_eq!(canon("\\\\server\\share\\a"), "\\\\server\\share\\a");
+        assert_eq!(canon("//server/share/./a"), "//server/share/a");
+        assert_eq!(canon("\\\\server\\..\\..\\a"), "\\\\server\\a");
+        assert_eq!(canon("\\\\server"), "\\\\server");
+        assert_eq!(canon("\\x"), "\\x");
+        assert_eq!(canon("C:/x/../.."), "C:/");
+        assert_eq!(canon("C:\\..\\x"), "C:\\x");
+        assert_eq!(canon("c:/a/../b"), "c:/b");
+        assert_eq!(canon("C:"), "C:");
+        assert_eq!(canon("C:..\\x"), "C:..\\x");
+        assert_eq!(canon("C:x\\..\\.."), "C:..");
+    }
+
+    #[test]
+    fn parent_of_root() {
+        assert_canon_path_eq("/..", "/");
+        assert_canon_path_eq("/../foo", "/foo");
+        assert_canon_path_eq("/a/../../b", "/b");
+        assert_canon_path_eq("//../foo", "/foo");
     }
 }
@@ -0,0 +1,120 @@
+//! Removes built files, as `ninja -t clean` does.
+
+use crate::densemap::Index;
+use crate::graph::{BuildId, FileId, Graph};
+use std::collections::HashSet;
+
+/// Which builds to clean.
+pub enum Filter {
+    /// Every build, skipping generators unless asked.
+    All { generator: bool },
+    /// The builds using these rules, skipping generators unless asked.
+    Rules { rules: Vec<String>, generator: bool },
+    /// The builds needed for these targets.
+    Targets(Vec<FileId>),
+}
+
+/// Builds that run commands, chosen by the filter, in manifest order or for
+/// targets, the order they're reached from the targets.
+pub fn select(graph: &Graph, filter: &Filter) -> Vec<BuildId> {
+    let all = (0..graph.builds.next_id().index()).map(BuildId::from);
+    let runs = |id: &BuildId| graph.builds[*id].cmdline.is_some();
+    match filter {
+        Filter::All { generator } => all
+            .filter(runs)
+            .filter(|&id| *generator || !graph.builds[id].generator)
+            .collect(),
+        Filter::Rules { rules, generator } => all
+            .filter(runs)
+            .filter(|&id| *generator || !graph.builds[id].generator)
+            .filter(|&id| rules.contains(&graph.builds[id].rule))
+            .collect(),
+        Filter::Targets(targets) => {
+            let mut seen = HashSet::new();
+            let mut builds = Vec::new();
+            let mut stack: Vec<FileId> = targets.iter().rev().copied().collect();
+            while let Some(id) = stack.pop() {
+                let Some(bid) = graph.file(id).input else {
+                    continue;
+                };
+                if !seen.insert(bid) {
+                    continue;
+                }
+                if runs(&bid) {
+                    builds.push(bid);
+                }
+                stack.extend(graph.builds[bid].ins.ids.iter().rev());
+            }
+            builds
+        }
+    }
+}
+
+/// The files a build leaves behind: its outputs, depfile and rspfile.
+pub fn files(graph: &Graph, id: BuildId) -> Vec<String> {
+    let build = &graph.builds[id];
+    let mut files: Vec<String> = build
+        .outs()
+        .iter()
+        .map(|&out| graph.file(out).name.clone())
+        .collect();
+    files.extend(build.depfile.clone());
+    if let Some(rspfile) = &build.rspfile {
+        files.push(rspfile.path.to_string_lossy().into_owned());
+    }
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = "
+rule cc
+  command = cc $in
+  depfile = $out.d
+rule regen
+  command = configure
+  generator = 1
+build build.ninja: regen configure.in
+build a.o: cc a.c
+build b.o: cc b.c
+build out: cc a.o
+build all: phony out b.o
+";
+
+    fn selected(filter: Filter) -> anyhow::Result<Vec<String>> {
+        let graph = crate::load::parse("build.ninja", MANIFEST.as_bytes().to_vec())?;
+        Ok(select(&graph, &filter)
+            .into_iter()
+            .flat_map(|id| files(&graph, id))
+            .collect())
+    }
+
+    #[test]
+    fn filters() -> anyhow::Result<()> {
+        assert_eq!(
+            selected(Filter::All { generator: false })?,
+            ["a.o", "a.o.d", "b.o", "b.o.d", "out", "out.d"]
+        );
+        assert_eq!(
+            selected(Filter::All { generator: true })?.first().unwrap(),
+            "build.ninja"
+        );
+        assert_eq!(
+            selected(Filter::Rules {
+                rules: vec!["regen".to_owned()],
+                generator: false
+            })?,
+            Vec::<String>::new()
+        );
+        let graph = crate::load::parse("build.ninja", MANIFEST.as_bytes().to_vec())?;
+        let out = graph.files.lookup("out").unwrap();
+        let names: Vec<&str> = select(&graph, &Filter::Targets(vec![out]))
+            .into_iter()
+            .map(|id| graph.file(graph.builds[id].outs()[0]).name.as_str())
+            .collect();
+        assert_eq!(names, ["out", "a.o"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+//! Lists the commands needed to build targets, as `ninja -t commands` does.
+
+use crate::graph::{BuildId, FileId, Graph};
+use std::collections::HashSet;
+use std::fmt::Write;
+
+/// Print the commands of the builds `id` needs before its own, each once.
+/// Walks with an explicit stack, as chains of builds can be deep; a build is
+/// pushed a second time, marked done, to print it after its inputs.
+fn print_build(out: &mut String, graph: &Graph, id: BuildId, seen: &mut HashSet<BuildId>) {
+    let mut stack = vec![(id, false)];
+    while let Some((id, inputs_done)) = stack.pop() {
+        let build = &graph.builds[id];
+        if inputs_done {
+            if let Some(cmdline) = &build.cmdline {
+                writeln!(out, "{}", cmdline).unwrap();
+            }
+            continue;
+        }
+        if !seen.insert(id) {
+            continue;
+        }
+        stack.push((id, true));
+        for &input in build.ordering_ins().iter().rev() {
+            if let Some(bid) = graph.file(input).input {
+                stack.push((bid, false));
+            }
+        }
+    }
+}
+
+/// Format the command lines, exactly as they would run, of the builds
+/// producing the targets, or with `single`, only those builds and not the
+/// ones they depend on.  Inputs' commands come before those of the builds
+/// using them, and otherwise follow the order of the targets and of each
+/// build's inputs, so the listing is the same from run to run.
+pub fn format(graph: &Graph, targets: &[FileId], single: bool) -> String {
+    let mut out = String::new();
+    let mut seen = HashSet::new();
+    for &target in targets {
+        let Some(id) = graph.file(target).input else {
+            continue;
+        };
+        if !single {
+            print_build(&mut out, graph, id, &mut seen);
+        } else if seen.insert(id) {
+            if let Some(cmdline) = &graph.builds[id].cmdline {
+                writeln!(out, "{}", cmdline).unwrap();
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = "
+rule cc
+  command = cc -c $in -o $out
+rule link
+  command = ld @$out.rsp -o \"$out\"
+  rspfile = $out.rsp
+  rspfile_content = $in
+build gen.h: cc gen.in
+build a.o: cc a.c || gen.h
+build b.o: cc b.c
+build out: link b.o a.o
+build all: phony out
+";
+
+    fn commands(targets: &[&str], single: bool) -> anyhow::Result<String> {
+        let graph = crate::load::parse("build.ninja", MANIFEST.as_bytes().to_vec())?;
+        let targets: Vec<FileId> = targets
+            .iter()
+            .map(|name| graph.files.lookup(name).unwrap())
+            .collect();
+        Ok(format(&graph, &targets, single))
+    }
+
+    #[test]
+    fn transitive() -> anyhow::Result<()> {
+        assert_eq!(
+            commands(&["all"], false)?,
+            "cc -c b.c -o b.o
+cc -c gen.in -o gen.h
+cc -c a.c -o a.o
+ld @out.rsp -o \"out\"
+"
+        );
+        assert_eq!(
+            commands(&["a.o", "b.o", "a.o"], false)?,
+            "cc -c gen.in -o gen.h\ncc -c a.c -o a.o\ncc -c b.c -o b.o\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn single() -> anyhow::Result<()> {
+        assert_eq!(commands(&["out"], true)?, "ld @out.rsp -o \"out\"\n");
+        assert_eq!(commands(&["all", "a.c"], true)?, "");
+        Ok(())
+    }
+
+    #[test]
+    fn deep_chain() -> anyhow::Result<()> {
+        let mut manifest = String::from("rule cp\n  command = cp $in $out\n");
+        for i in 1..100_000 {
+            writeln!(manifest, "build f{}: cp f{}", i, i - 1).unwrap();
+        }
+        let graph = crate::load::parse("build.ninja", manifest.into_bytes())?;
+        let target = graph.files.lookup("f99999").unwrap();
+        let out = format(&graph, &[target], false);
+        assert!(out.starts_with("cp f0 f1\ncp f1 f2\n"));
+        assert_eq!(out.lines().count(), 99_999);
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+//! Writes a compilation database (compile_commands.json) for tools like
+//! clangd, as `ninja -t compdb` does.
+
+use crate::densemap::Index;
+use crate::graph::{Build, BuildId, Graph};
+use crate::json;
+
+/// The command for a build, with an `@rspfile` argument replaced by the
+/// rspfile's content when `expand_rsp` is set, so tools that don't read
+/// rspfiles see every argument.
+fn command(build: &Build, expand_rsp: bool) -> String {
+    let cmdline = build.cmdline.as_deref().unwrap_or_default();
+    match &build.rspfile {
+        Some(rspfile) if expand_rsp => {
+            let arg = format!("@{}", rspfile.path.display());
+            cmdline.replacen(&arg, &rspfile.content.replace('\n', " "), 1)
+        }
+        _ => cmdline.to_owned(),
+    }
+}
+
+/// Format the compilation database for the builds using any of `rules`, or
+/// for every build that runs a command if `rules` is empty.  As with ninja,
+/// each entry's file is the build's first input.
+pub fn format(graph: &Graph, directory: &str, rules: &[String], expand_rsp: bool) -> String {
+    let mut out = String::from("[");
+    let mut first = true;
+    for id in (0..graph.builds.next_id().index()).map(BuildId::from) {
+        let build = &graph.builds[id];
+        if build.cmdline.is_none() || !(rules.is_empty() || rules.contains(&build.rule)) {
+            continue;
+        }
+        let (Some(&file), Some(&output)) = (build.explicit_ins().first(), build.outs().first())
+        else {
+            continue;
+        };
+        out.push_str(if first { "\n  {\n" } else { ",\n  {\n" });
+        first = false;
+        out.push_str("    \"directory\": ");
+        json::write_str(&mut out, directory);
+        out.push_str(",\n    \"command\": ");
+        json::write_str(&mut out, &command(build, expand_rsp));
+        out.push_str(",\n    \"file\": ");
+        json::write_str(&mut out, &graph.file(file).name);
+        out.push_str(",\n    \"output\": ");
+        json::write_str(&mut out, &graph.file(output).name);
+        out.push_str("\n  }");
+    }
+    out.push_str(if first { "]\n" } else { "\n]\n" });
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compdb() -> anyhow::Result<()> {
+        let file = "
+rule cc
+  command = cc -c $in -o $out
+rule link
+  command = ld @$out.rsp -o $out
+  rspfile = $out.rsp
+  rspfile_content = $in
+build a.o: cc a.c | a.h
+build b.o: cc b.c
+build out: link a.o b.o
+build all: phony out
+";
+        let graph = crate::load::parse("build.ninja", file.as_bytes().to_vec())?;
+        assert_eq!(
+            format(&graph, "/src", &["cc".to_owned()], false),
+            r#"[
+  {
+    "directory": "/src",
+    "command": "cc -c a.c -o a.o",
+    "file": "a.c",
+    "output": "a.o"
+  },
+  {
+    "directory": "/src",
+    "command": "cc -c b.c -o b.o",
+    "file": "b.c",
+    "output": "b.o"
+  }
+]
+"#
+        );
+        let all = format(&graph, "/src", &[], false);
+        assert!(all.contains(r#""command": "ld @out.rsp -o out""#));
+        assert!(!all.contains("phony"));
+        let expanded = format(&graph, "/src", &["link".to_owned()], true);
+        assert!(expanded.contains(r#""command": "ld a.o b.o -o out""#));
+        assert_eq!(format(&graph, "/src", &["none".to_owned()], false), "[]\n");
+        Ok(())
+    }
+}
@@ -3,17 +3,35 @@
 
 use crate::{
     densemap, densemap::DenseMap, graph::BuildId, graph::FileId, graph::Graph, graph::Hashes,
-    hash::BuildHash,
+    graph::MTime, hash::BuildHash, hash::Digest, ninja_deps, ninja_log,
 };
 use anyhow::{anyhow, bail};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
+use std::io::BufRead;
 use std::io::BufReader;
 use std::io::Read;
 use std::io::Write;
 use std::path::Path;
-
-const VERSION: u32 = 1;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Version 2 added the extended records; version 1 databases are upgraded
+/// by rewriting their header, as their records are read the same way.
+const VERSION: u32 = 2;
+
+/// A build record with no outputs marks an extended record, whose kind
+/// follows in a byte.
+const EXTENDED_MARK: u16 = 0b1000_0000_0000_0000;
+/// A file's content digest, for the content hash mode.
+const EXTENDED_DIGEST: u8 = 0;
+/// A build's hash over its files' contents, for the content hash mode.
+const EXTENDED_CONTENT_HASH: u8 = 1;
+
+/// A database is compacted when loading it once it holds at least this many
+/// build records...
+const COMPACT_MIN_RECORDS: usize = 1000;
+/// ...and this many times as many build records as builds they apply to.
+const COMPACT_RATIO: usize = 3;
 
 /// Files are identified by integers that are stable across n2 executions.
 #[derive(Debug, Clone, Copy)]
@@ -49,6 +67,10 @@ impl RecordWriter {
         self.0.extend_from_slice(buf);
     }
 
+    fn write_u8(&mut self, n: u8) {
+        self.write(&[n]);
+    }
+
     fn write_u16(&mut self, n: u16) {
         self.write(&n.to_le_bytes());
     }
@@ -81,10 +103,19 @@ impl RecordWriter {
 /// An opened database, ready for writes.
 pub struct Writer {
     ids: IdMap,
-    w: File,
+    /// None for a database opened read-only, which discards writes.
+    w: Option<File>,
 }
 
 impl Writer {
+    /// A database that was never opened, which discards writes.
+    pub fn unopened() -> Self {
+        Writer {
+            ids: IdMap::default(),
+            w: None,
+        }
+    }
+
     fn create(path: &Path) -> std::io::Result<Self> {
         let f = std::fs::File::create(path)?;
         let mut w = Self::from_opened(IdMap::default(), f);
@@ -93,12 +124,21 @@ impl Writer {
     }
 
     fn from_opened(ids: IdMap, w: File) -> Self {
-        Writer { ids, w }
+        Writer { ids, w: Some(w) }
     }
 
     fn write_signature(&mut self) -> std::io::Result<()> {
-        self.w.write_all("n2db".as_bytes())?;
-        self.w.write_all(&u32::to_le_bytes(VERSION))
+        let mut w = RecordWriter::default();
+        w.write("n2db".as_bytes());
+        w.write(&u32::to_le_bytes(VERSION));
+        self.write_record(w)
+    }
+
+    fn write_record(&mut self, w: RecordWriter) -> std::io::Result<()> {
+        match &mut self.w {
+            Some(f) => w.finish(f),
+            None => Ok(()),
+        }
     }
 
     fn write_path(&mut self, name: &str) -> std::io::Result<()> {
@@ -106,8 +146,8 @@ impl Writer {
             panic!("filename too long");
         }
         let mut w = RecordWriter::default();
-        w.write_str(&name);
-        w.finish(&mut self.w)
+        w.write_str(name);
+        self.write_record(w)
     }
 
     fn ensure_id(&mut self, graph: &Graph, fileid: FileId) -> std::io::Result<Id> {
@@ -123,6 +163,19 @@ impl Writer {
         Ok(id)
     }
 
+    fn write_outs(
+        &mut self,
+        w: &mut RecordWriter,
+        graph: &Graph,
+        outs: &[FileId],
+    ) -> std::io::Result<()> {
+        for &out in outs {
+            let id = self.ensure_id(graph, out)?;
+            w.write_id(id);
+        }
+        Ok(())
+    }
+
     pub fn write_build(
         &mut self,
         graph: &Graph,
@@ -134,10 +187,7 @@ impl Writer {
         let outs = build.outs();
         let mark = (outs.len() as u16) | 0b1000_0000_0000_0000;
         w.write_u16(mark);
-        for &out in outs {
-            let id = self.ensure_id(graph, out)?;
-            w.write_id(id);
-        }
+        self.write_outs(&mut w, graph, outs)?;
 
         let deps = build.discovered_ins();
         w.write_u16(deps.len() as u16);
@@ -147,7 +197,42 @@ impl Writer {
         }
 
         w.write_u64(hash.0);
-        w.finish(&mut self.w)
+        self.write_record(w)
+    }
+
+    pub fn write_content_hash(
+        &mut self,
+        graph: &Graph,
+        id: BuildId,
+        hash: BuildHash,
+    ) -> std::io::Result<()> {
+        let outs = graph.builds[id].outs();
+        let mut w = RecordWriter::default();
+        w.write_u16(EXTENDED_MARK);
+        w.write_u8(EXTENDED_CONTENT_HASH);
+        w.write_u16(outs.len() as u16);
+        self.write_outs(&mut w, graph, outs)?;
+        w.write_u64(hash.0);
+        self.write_record(w)
+    }
+
+    pub fn write_digest(
+        &mut self,
+        graph: &Graph,
+        id: FileId,
+        digest: &Digest,
+    ) -> std::io::Result<()> {
+        let id = self.ensure_id(graph, id)?;
+        let mut w = RecordWriter::default();
+        w.write_u16(EXTENDED_MARK);
+        w.write_u8(EXTENDED_DIGEST);
+        w.write_id(id);
+        let nanos = digest.mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+        w.write_u64(nanos.as_nanos() as u64);
+        w.write_u64(digest.len);
+        w.write_u64(digest.ctime);
+        w.write_u64(digest.digest);
+        self.write_record(w)
     }
 }
 
@@ -156,24 +241,36 @@ struct Reader<'a> {
     ids: IdMap,
     graph: &'a mut Graph,
     hashes: &'a mut Hashes,
+    /// Count of build records read, including those later superseded.
+    builds: usize,
+    version: u32,
+    /// The offset in the file of the next byte to read, counted rather than
+    /// asked of the file to avoid a seek per record.
+    ofs: u64,
 }
 
 impl<'a> Reader<'a> {
+    fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        self.r.read_exact(buf)?;
+        self.ofs += buf.len() as u64;
+        Ok(())
+    }
+
     fn read_u16(&mut self) -> std::io::Result<u16> {
         let mut buf: [u8; 2] = [0; 2];
-        self.r.read_exact(&mut buf[..])?;
+        self.read_exact(&mut buf[..])?;
         Ok(u16::from_le_bytes(buf))
     }
 
     fn read_u24(&mut self) -> std::io::Result<u32> {
         let mut buf: [u8; 4] = [0; 4];
-        self.r.read_exact(&mut buf[..3])?;
+        self.read_exact(&mut buf[..3])?;
         Ok(u32::from_le_bytes(buf))
     }
 
     fn read_u64(&mut self) -> std::io::Result<u64> {
         let mut buf: [u8; 8] = [0; 8];
-        self.r.read_exact(&mut buf)?;
+        self.read_exact(&mut buf)?;
         Ok(u64::from_le_bytes(buf))
     }
 
@@ -181,10 +278,22 @@ impl<'a> Reader<'a> {
         self.read_u24().map(Id)
     }
 
+    /// Map a db id to its FileId, failing for ids no path record assigned.
+    fn file_id(&self, id: Id) -> std::io::Result<FileId> {
+        match self.ids.fileids.lookup(id) {
+            Some(&fileid) => Ok(fileid),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unknown file id {}", id.0),
+            )),
+        }
+    }
+
     fn read_str(&mut self, len: usize) -> std::io::Result<String> {
         let mut buf = vec![0; len];
-        self.r.read_exact(buf.as_mut_slice())?;
-        Ok(unsafe { String::from_utf8_unchecked(buf) })
+        self.read_exact(buf.as_mut_slice())?;
+        String::from_utf8(buf)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err.utf8_error()))
     }
 
     fn read_path(&mut self, len: usize) -> std::io::Result<()> {
@@ -196,20 +305,12 @@ impl<'a> Reader<'a> {
         Ok(())
     }
 
-    fn read_build(&mut self, len: usize) -> std::io::Result<()> {
-        // This record logs a build.  We expect all the outputs to be
-        // outputs of the same build id; if not, that means the graph has
-        // changed since this log, in which case we just ignore it.
-        //
-        // It's possible we log a build that generates files A B, then
-        // change the build file such that it only generates file A; this
-        // logic will still attach the old dependencies to A, but it
-        // shouldn't matter because the changed command line will cause us
-        // to rebuild A regardless, and these dependencies are only used
-        // to affect dirty checking, not build order.
-
+    /// Reads the outputs of a build record, returning the build they are the
+    /// outputs of, if they are still the outputs of a single build.
+    fn read_outs(&mut self, len: usize) -> std::io::Result<Option<BuildId>> {
         let mut unique_bid = None;
         let mut obsolete = false;
+        let mut unowned = false;
         for _ in 0..len {
             let fileid = self.read_id()?;
             if obsolete {
@@ -217,9 +318,12 @@ impl<'a> Reader<'a> {
                 // keep reading to parse through it.
                 continue;
             }
-            match self.graph.file(self.ids.fileids[fileid]).input {
+            match self.graph.file(self.file_id(fileid)?).input {
                 None => {
-                    obsolete = true;
+                    // Possibly an implicit output added by a dyndep file,
+                    // which isn't known until the dyndep file is loaded;
+                    // checked below once the build is known.
+                    unowned = true;
                 }
                 Some(bid) => {
                     match unique_bid {
@@ -237,14 +341,37 @@ impl<'a> Reader<'a> {
             }
         }
 
+        // Only a build with a dyndep file may have outputs the manifest
+        // doesn't name.  Outputs are part of the hash, so keeping its record
+        // can't cause a stale hash to match.
+        if unowned && unique_bid.is_some_and(|bid| self.graph.builds[bid].dyndep.is_none()) {
+            return Ok(None);
+        }
+        Ok(unique_bid)
+    }
+
+    fn read_build(&mut self, len: usize) -> std::io::Result<()> {
+        // This record logs a build.  We expect all the outputs to be
+        // outputs of the same build id; if not, that means the graph has
+        // changed since this log, in which case we just ignore it.
+        //
+        // It's possible we log a build that generates files A B, then
+        // change the build file such that it only generates file A; this
+        // logic will still attach the old dependencies to A, but it
+        // shouldn't matter because the changed command line will cause us
+        // to rebuild A regardless, and these dependencies are only used
+        // to affect dirty checking, not build order.
+        let unique_bid = self.read_outs(len)?;
+
         let len = self.read_u16()?;
         let mut deps = Vec::new();
         for _ in 0..len {
             let id = self.read_id()?;
-            deps.push(self.ids.fileids[id]);
+            deps.push(self.file_id(id)?);
         }
 
         let hash = BuildHash(self.read_u64()?);
+        self.builds += 1;
 
         // unique_bid is set here if this record is valid.
         if let Some(id) = unique_bid {
@@ -255,68 +382,641 @@ impl<'a> Reader<'a> {
         Ok(())
     }
 
-    fn read_signature(&mut self) -> anyhow::Result<()> {
+    fn read_extended(&mut self) -> std::io::Result<()> {
+        let mut kind = [0u8];
+        self.read_exact(&mut kind)?;
+        match kind[0] {
+            EXTENDED_DIGEST => {
+                let id = self.read_id()?;
+                let id = self.file_id(id)?;
+                let mtime = UNIX_EPOCH + Duration::from_nanos(self.read_u64()?);
+                let len = self.read_u64()?;
+                let ctime = self.read_u64()?;
+                let digest = self.read_u64()?;
+                let digest = Digest {
+                    mtime,
+                    len,
+                    ctime,
+                    digest,
+                };
+                self.hashes.digests.set(id, digest);
+            }
+            EXTENDED_CONTENT_HASH => {
+                let len = self.read_u16()?;
+                let unique_bid = self.read_outs(len as usize)?;
+                let hash = BuildHash(self.read_u64()?);
+                if let Some(id) = unique_bid {
+                    self.hashes.set_content(id, hash);
+                }
+            }
+            kind => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("unknown record kind {}", kind),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the database's version.
+    fn read_signature(&mut self) -> anyhow::Result<u32> {
         let mut buf: [u8; 4] = [0; 4];
-        self.r.read_exact(&mut buf[..])?;
-        if buf.as_slice() != "n2db".as_bytes() {
+        if self.read_exact(&mut buf[..]).is_err() || buf.as_slice() != "n2db".as_bytes() {
             bail!("invalid db signature");
         }
-        self.r.read_exact(&mut buf[..])?;
+        if self.read_exact(&mut buf[..]).is_err() {
+            bail!("truncated db version");
+        }
         let version = u32::from_le_bytes(buf);
-        if version != VERSION {
-            bail!("db version mismatch: got {version}, expected {VERSION}; TODO: db upgrades etc");
+        if !(1..=VERSION).contains(&version) {
+            bail!("db version mismatch: got {version}, expected {VERSION}");
         }
-        Ok(())
+        Ok(version)
+    }
+
+    /// Reads one record, returning false at the end of the file.
+    fn read_record(&mut self) -> std::io::Result<bool> {
+        if self.r.fill_buf()?.is_empty() {
+            return Ok(false);
+        }
+        let mut len = self.read_u16()?;
+        let mask = 0b1000_0000_0000_0000;
+        if len & mask == 0 {
+            self.read_path(len as usize)?;
+        } else if len == EXTENDED_MARK {
+            self.read_extended()?;
+        } else {
+            len &= !mask;
+            self.read_build(len as usize)?;
+        }
+        Ok(true)
     }
 
-    fn read_file(&mut self) -> anyhow::Result<()> {
-        self.read_signature()?;
+    /// Reads records until the end of the file, or until one that is
+    /// truncated or malformed, as left by a crash mid-write.  Records are
+    /// applied as they are read, so everything before that one is kept.
+    /// Returns the offset and error of such a record.
+    fn read_file(&mut self) -> anyhow::Result<Option<(u64, std::io::Error)>> {
+        self.version = self.read_signature()?;
         loop {
-            let mut len = match self.read_u16() {
-                Ok(r) => r,
-                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            let start = self.ofs;
+            match self.read_record() {
+                Ok(true) => {}
+                Ok(false) => return Ok(None),
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::InvalidData
+                    ) =>
+                {
+                    return Ok(Some((start, err)))
+                }
                 Err(err) => bail!(err),
-            };
-            let mask = 0b1000_0000_0000_0000;
-            if len & mask == 0 {
-                self.read_path(len as usize)?;
-            } else {
-                len &= !mask;
-                self.read_build(len as usize)?;
             }
         }
-        Ok(())
     }
 
     /// Reads an on-disk database, loading its state into the provided Graph/Hashes.
-    fn read(f: &mut File, graph: &mut Graph, hashes: &mut Hashes) -> anyhow::Result<IdMap> {
+    /// A damaged tail is ignored with a warning, and if `repair` is set, cut
+    /// off the file.
+    /// Also returns the number of build records read and the version read.
+    fn read(
+        path: &Path,
+        f: &mut File,
+        graph: &mut Graph,
+        hashes: &mut Hashes,
+        repair: bool,
+    ) -> anyhow::Result<(IdMap, usize, u32)> {
         let mut r = Reader {
             r: std::io::BufReader::new(f),
             ids: IdMap::default(),
             graph,
             hashes,
+            builds: 0,
+            version: 0,
+            ofs: 0,
         };
-        r.read_file()?;
+        if let Some((end, err)) = r.read_file()? {
+            let err = match err.kind() {
+                std::io::ErrorKind::UnexpectedEof => "truncated record".to_owned(),
+                _ => err.to_string(),
+            };
+            println!(
+                "n2: warn: {}: {} at byte {}; {} the rest of the database",
+                path.display(),
+                err,
+                end,
+                if repair { "dropping" } else { "ignoring" }
+            );
+            if repair {
+                r.r.into_inner().set_len(end)?;
+            }
+        }
 
-        Ok(r.ids)
+        Ok((r.ids, r.builds, r.version))
     }
 }
 
 /// Opens or creates an on-disk database, loading its state into the provided Graph.
-pub fn open(path: &Path, graph: &mut Graph, hashes: &mut Hashes) -> anyhow::Result<Writer> {
+/// If `may_compact` is set, the graph holds the whole manifest, and a database
+/// mostly holding superseded records is compacted.
+pub fn open(
+    path: &Path,
+    graph: &mut Graph,
+    hashes: &mut Hashes,
+    may_compact: bool,
+) -> anyhow::Result<Writer> {
     match std::fs::OpenOptions::new()
         .read(true)
         .append(true)
         .open(path)
     {
         Ok(mut f) => {
-            let ids = Reader::read(&mut f, graph, hashes)?;
+            let (ids, records, version) = match Reader::read(path, &mut f, graph, hashes, true) {
+                Ok(read) => read,
+                Err(err) => {
+                    // The state is only an optimization, so rather than
+                    // failing, set the database aside and start over.
+                    drop(f);
+                    let mut aside = path.as_os_str().to_owned();
+                    aside.push(".bad");
+                    let aside = Path::new(&aside);
+                    std::fs::rename(path, aside)?;
+                    println!(
+                        "n2: warn: {}: {}; moved it to {} and starting a new database",
+                        path.display(),
+                        err,
+                        aside.display()
+                    );
+                    *hashes = Hashes::default();
+                    return Writer::create(path).map_err(|err| anyhow!(err));
+                }
+            };
+            let live = hashes.iter().count();
+            if may_compact && records >= COMPACT_MIN_RECORDS && records > live * COMPACT_RATIO {
+                drop(f);
+                return compact(path, graph, hashes);
+            }
+            if version < VERSION {
+                drop(f);
+                upgrade(path)?;
+                f = std::fs::OpenOptions::new().append(true).open(path)?;
+            }
             Ok(Writer::from_opened(ids, f))
         }
         Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
             let w = Writer::create(path)?;
+            import_ninja_state(path, graph, hashes);
             Ok(w)
         }
         Err(err) => Err(anyhow!(err)),
     }
 }
+
+/// Loads a database's state like open, without changing it in any way, for
+/// dry runs.  Writes to the returned Writer are discarded.
+pub fn open_read_only(
+    path: &Path,
+    graph: &mut Graph,
+    hashes: &mut Hashes,
+) -> anyhow::Result<Writer> {
+    match File::open(path) {
+        Ok(mut f) => {
+            if let Err(err) = Reader::read(path, &mut f, graph, hashes, false) {
+                println!("n2: warn: {}: {}; ignoring it", path.display(), err);
+                *hashes = Hashes::default();
+            }
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            import_ninja_state(path, graph, hashes);
+        }
+        Err(err) => bail!(err),
+    }
+    Ok(Writer::unopened())
+}
+
+/// Rewrites the database to hold just the latest state of the builds in the
+/// graph, dropping superseded records and those for builds and files no
+/// longer in the manifest.  The new database is written beside the old one
+/// and renamed over it, so an interrupted compaction leaves the old one intact.
+pub fn compact(path: &Path, graph: &Graph, hashes: &Hashes) -> anyhow::Result<Writer> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = Path::new(&tmp);
+
+    let mut builds: Vec<_> = hashes.iter().collect();
+    builds.sort_by_key(|(id, _)| densemap::Index::index(id));
+    let mut content: Vec<_> = hashes.iter_content().collect();
+    content.sort_by_key(|(id, _)| densemap::Index::index(id));
+    let mut digests: Vec<_> = hashes.digests.iter().collect();
+    digests.sort_by_key(|(id, _)| densemap::Index::index(id));
+    let mut w = Writer::create(tmp)?;
+    for (id, hash) in builds {
+        w.write_build(graph, id, hash)?;
+    }
+    for (id, hash) in content {
+        w.write_content_hash(graph, id, hash)?;
+    }
+    for (id, digest) in digests {
+        w.write_digest(graph, id, digest)?;
+    }
+    let ids = w.ids;
+    let f = w.w.unwrap();
+    f.sync_all()?;
+    drop(f);
+
+    std::fs::rename(tmp, path)?;
+    let f = std::fs::OpenOptions::new().append(true).open(path)?;
+    Ok(Writer::from_opened(ids, f))
+}
+
+/// Rewrites an older database's header with the current version.  Written
+/// beside the database and renamed over it, like compaction.
+fn upgrade(path: &Path) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = Path::new(&tmp);
+    let mut data = std::fs::read(path)?;
+    data[4..8].copy_from_slice(&VERSION.to_le_bytes());
+    let mut f = File::create(tmp)?;
+    f.write_all(&data)?;
+    f.sync_all()?;
+    drop(f);
+    std::fs::rename(tmp, path)
+}
+
+/// Seeds the state of a new database from the logs ninja leaves behind in
+/// the same directory, so that switching a build directory over doesn't
+/// rebuild everything.  Builds are marked as imported, to be adopted if their
+/// outputs are newer than their inputs, when ninja's logs show that their
+/// commands haven't changed and that their discovered deps are known.
+fn import_ninja_state(db_path: &Path, graph: &mut Graph, hashes: &mut Hashes) {
+    let deps_path = db_path.with_file_name(".ninja_deps");
+    let with_deps = match deps_path.exists() {
+        true => import_ninja_deps(&deps_path, graph),
+        false => HashSet::new(),
+    };
+
+    let log_path = db_path.with_file_name(".ninja_log");
+    let unchanged = match log_path.exists() {
+        true => import_ninja_log(&log_path, graph),
+        false => None,
+    };
+    let Some(unchanged) = unchanged else {
+        for bid in with_deps {
+            hashes.set_imported(bid);
+        }
+        return;
+    };
+    for bid in unchanged {
+        let build = &graph.builds[bid];
+        let discovers_deps = build.depfile.is_some() || build.showincludes_prefix.is_some();
+        if !discovers_deps || with_deps.contains(&bid) {
+            hashes.set_imported(bid);
+        }
+    }
+}
+
+/// Attach the deps recorded in ninja's deps log to their builds, returning
+/// the builds that got them.  Records ninja would consider stale, because the
+/// output changed after they were written, are skipped.  A log that can't be
+/// read is only warned about.
+fn import_ninja_deps(path: &Path, graph: &mut Graph) -> HashSet<BuildId> {
+    let mut imported = HashSet::new();
+    let log = match ninja_deps::read(path) {
+        Ok(log) => log,
+        Err(err) => {
+            println!(
+                "n2: warn: {}: {}; not importing its deps",
+                path.display(),
+                err
+            );
+            return imported;
+        }
+    };
+    for record in &log.deps {
+        let mut name = record.output.clone();
+        graph.files.canonicalize(&mut name);
+        let Some(out) = graph.files.lookup(&name) else {
+            continue;
+        };
+        let Some(bid) = graph.file(out).input else {
+            continue;
+        };
+        if graph.builds[bid].cmdline.is_none() {
+            continue;
+        }
+        match crate::graph::stat(graph.file(out).path()) {
+            Ok(MTime::Stamp(mtime)) if log.timestamp(mtime) <= record.mtime => {}
+            _ => continue,
+        }
+        let mut deps = Vec::new();
+        for name in &record.deps {
+            let mut name = name.clone();
+            graph.files.canonicalize(&mut name);
+            let id = graph.files.id_from_canonical(name);
+            let build = &graph.builds[bid];
+            if deps.contains(&id)
+                || build.dirtying_ins().contains(&id)
+                || build.outs().contains(&id)
+            {
+                continue;
+            }
+            deps.push(id);
+        }
+        graph.builds[bid].set_discovered_ins(deps);
+        imported.insert(bid);
+    }
+    imported
+}
+
+/// Find the builds whose commands match those ninja's build log recorded for
+/// all their outputs.  Returns None if the log can't be read.
+fn import_ninja_log(path: &Path, graph: &Graph) -> Option<HashSet<BuildId>> {
+    let log = match ninja_log::read(path) {
+        Ok(log) => log,
+        Err(err) => {
+            println!("n2: warn: {}: {}; not importing it", path.display(), err);
+            return None;
+        }
+    };
+    if log.malformed > 0 {
+        println!(
+            "n2: warn: {}: skipped {} malformed lines",
+            path.display(),
+            log.malformed
+        );
+    }
+
+    let mut logged = HashSet::new();
+    let mut changed = HashSet::new();
+    let mut imported = 0;
+    for entry in &log.entries {
+        let mut name = entry.output.clone();
+        graph.files.canonicalize(&mut name);
+        let Some(out) = graph.files.lookup(&name) else {
+            continue;
+        };
+        let Some(bid) = graph.file(out).input else {
+            continue;
+        };
+        let Some(hash) = ninja_log::build_command_hash(&graph.builds[bid]) else {
+            continue;
+        };
+        if hash == entry.command_hash {
+            logged.insert(out);
+            imported += 1;
+        } else {
+            changed.insert(bid);
+        }
+    }
+    println!(
+        "n2: imported {} of {} entries from {}",
+        imported,
+        log.entries.len(),
+        path.display()
+    );
+
+    let unchanged = logged
+        .iter()
+        .filter_map(|&out| graph.file(out).input)
+        .filter(|bid| !changed.contains(bid))
+        .filter(|&bid| {
+            graph.builds[bid]
+                .outs()
+                .iter()
+                .all(|out| logged.contains(out))
+        })
+        .collect();
+    Some(unchanged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph() -> Graph {
+        let manifest = "rule touch\n  command = touch $out\nbuild a: touch\nbuild b: touch\n";
+        crate::load::parse("build.ninja", manifest.as_bytes().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn compact_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".n2_db");
+        let size = || std::fs::metadata(&path).unwrap().len();
+        let (a, b) = (BuildId::from(0), BuildId::from(1));
+
+        let mut g = graph();
+        let mut w = open(&path, &mut g, &mut Hashes::default(), true).unwrap();
+        for i in 0..COMPACT_MIN_RECORDS as u64 {
+            w.write_build(&g, a, BuildHash(i)).unwrap();
+        }
+        w.write_build(&g, b, BuildHash(7)).unwrap();
+        drop(w);
+        let full = size();
+
+        // Left alone unless allowed.
+        open(&path, &mut graph(), &mut Hashes::default(), false).unwrap();
+        assert_eq!(size(), full);
+
+        for _ in 0..2 {
+            let mut hashes = Hashes::default();
+            open(&path, &mut graph(), &mut hashes, true).unwrap();
+            assert!(size() < full / 100);
+            assert_eq!(
+                hashes.get(a),
+                Some(BuildHash(COMPACT_MIN_RECORDS as u64 - 1))
+            );
+            assert_eq!(hashes.get(b), Some(BuildHash(7)));
+        }
+        assert!(!dir.path().join(".n2_db.tmp").exists());
+    }
+
+    #[test]
+    fn recover_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".n2_db");
+        let aside = dir.path().join(".n2_db.bad");
+        let (a, b) = (BuildId::from(0), BuildId::from(1));
+
+        let mut g = graph();
+        let mut w = open(&path, &mut g, &mut Hashes::default(), false).unwrap();
+        w.write_build(&g, a, BuildHash(1)).unwrap();
+        w.write_build(&g, b, BuildHash(2)).unwrap();
+        drop(w);
+        let full = std::fs::read(&path).unwrap();
+        // The signature, then a path and a build record per build.
+        let boundaries = [8, 11, 26, 29, 44];
+        assert_eq!(full.len(), 44);
+
+        for cut in 0..=full.len() {
+            std::fs::write(&path, &full[..cut]).unwrap();
+            let mut g = graph();
+            let mut hashes = Hashes::default();
+            let mut w = open(&path, &mut g, &mut hashes, false).unwrap();
+            let len = std::fs::metadata(&path).unwrap().len() as usize;
+            if cut < 8 {
+                assert!(aside.exists());
+                std::fs::remove_file(&aside).unwrap();
+                assert_eq!(len, 8);
+            } else {
+                assert!(!aside.exists());
+                assert_eq!(len, *boundaries.iter().rfind(|&&b| b <= cut).unwrap());
+            }
+            assert_eq!(hashes.get(a).is_some(), cut >= 26);
+            assert_eq!(hashes.get(b).is_some(), cut >= 44);
+
+            // Records written after recovery are read back.
+            w.write_build(&g, b, BuildHash(3)).unwrap();
+            drop(w);
+            let mut hashes = Hashes::default();
+            open(&path, &mut graph(), &mut hashes, false).unwrap();
+            assert_eq!(hashes.get(a).is_some(), cut >= 26);
+            assert_eq!(hashes.get(b), Some(BuildHash(3)));
+        }
+    }
+
+    #[test]
+    fn dropped_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".n2_db");
+        let a = BuildId::from(0);
+        let parse = |manifest: &str| {
+            let manifest = format!("rule touch\n  command = touch $out\n{}", manifest);
+            crate::load::parse("build.ninja", manifest.into_bytes()).unwrap()
+        };
+
+        let mut g = parse("build a b: touch\n");
+        let mut w = open(&path, &mut g, &mut Hashes::default(), false).unwrap();
+        w.write_build(&g, a, BuildHash(1)).unwrap();
+        drop(w);
+
+        // b is no longer an output, so the record is for some other build.
+        let mut hashes = Hashes::default();
+        open(&path, &mut parse("build a: touch\n"), &mut hashes, false).unwrap();
+        assert_eq!(hashes.get(a), None);
+
+        // Unless a dyndep file, not loaded yet, may name it.
+        let mut hashes = Hashes::default();
+        let mut g = parse("build a: touch || dd\n  dyndep = dd\n");
+        open(&path, &mut g, &mut hashes, false).unwrap();
+        assert_eq!(hashes.get(a), Some(BuildHash(1)));
+    }
+
+    #[test]
+    fn read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".n2_db");
+        let a = BuildId::from(0);
+
+        let mut g = graph();
+        open_read_only(&path, &mut g, &mut Hashes::default()).unwrap();
+        assert!(!path.exists());
+
+        let mut w = open(&path, &mut g, &mut Hashes::default(), false).unwrap();
+        w.write_build(&g, a, BuildHash(1)).unwrap();
+        drop(w);
+        // A damaged tail is ignored rather than cut off, and writes are
+        // discarded.
+        let mut damaged = std::fs::read(&path).unwrap();
+        damaged.push(1);
+        std::fs::write(&path, &damaged).unwrap();
+        let mut hashes = Hashes::default();
+        let mut w = open_read_only(&path, &mut g, &mut hashes).unwrap();
+        assert_eq!(hashes.get(a), Some(BuildHash(1)));
+        w.write_build(&g, a, BuildHash(2)).unwrap();
+        drop(w);
+        assert_eq!(std::fs::read(&path).unwrap(), damaged);
+    }
+
+    #[test]
+    fn recover_unknown_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".n2_db");
+        let mut g = graph();
+        let mut w = open(&path, &mut g, &mut Hashes::default(), false).unwrap();
+        w.write_build(&g, BuildId::from(0), BuildHash(1)).unwrap();
+        drop(w);
+        let mut bytes = std::fs::read(&path).unwrap();
+        let good = bytes.len();
+        // A build record whose output id has no path record.
+        bytes.extend([1, 0x80, 9, 0, 0, 0, 0]);
+        bytes.extend(5u64.to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+
+        let mut hashes = Hashes::default();
+        open(&path, &mut graph(), &mut hashes, false).unwrap();
+        assert_eq!(hashes.get(BuildId::from(0)), Some(BuildHash(1)));
+        assert_eq!(std::fs::metadata(&path).unwrap().len() as usize, good);
+    }
+
+    #[test]
+    fn recover_bad_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".n2_db");
+        std::fs::write(&path, b"n2db\x63\0\0\0 from the future").unwrap();
+        let mut hashes = Hashes::default();
+        open(&path, &mut graph(), &mut hashes, false).unwrap();
+        assert_eq!(
+            std::fs::read(dir.path().join(".n2_db.bad")).unwrap(),
+            b"n2db\x63\0\0\0 from the future"
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), b"n2db\x02\0\0\0");
+    }
+
+    #[test]
+    fn content_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".n2_db");
+        let a = BuildId::from(0);
+        let digest = Digest {
+            mtime: UNIX_EPOCH + Duration::from_nanos(1_700_000_000_123_456_789),
+            len: 5,
+            ctime: 42,
+            digest: 0x1234,
+        };
+
+        let mut g = graph();
+        let mut w = open(&path, &mut g, &mut Hashes::default(), false).unwrap();
+        let out = g.builds[a].outs()[0];
+        w.write_build(&g, a, BuildHash(1)).unwrap();
+        w.write_content_hash(&g, a, BuildHash(2)).unwrap();
+        w.write_digest(&g, out, &digest).unwrap();
+        drop(w);
+
+        let check = |hashes: &Hashes| {
+            assert_eq!(hashes.get(a), Some(BuildHash(1)));
+            assert_eq!(hashes.get_content(a), Some(BuildHash(2)));
+            assert_eq!(hashes.digests.get(out), Some(&digest));
+        };
+        let mut g = graph();
+        let mut hashes = Hashes::default();
+        open(&path, &mut g, &mut hashes, false).unwrap();
+        check(&hashes);
+
+        compact(&path, &g, &hashes).unwrap();
+        let mut hashes = Hashes::default();
+        open(&path, &mut graph(), &mut hashes, false).unwrap();
+        check(&hashes);
+    }
+
+    #[test]
+    fn upgrade_v1() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".n2_db");
+        let mut g = graph();
+        let mut w = open(&path, &mut g, &mut Hashes::default(), false).unwrap();
+        w.write_build(&g, BuildId::from(0), BuildHash(1)).unwrap();
+        drop(w);
+        let mut data = std::fs::read(&path).unwrap();
+        data[4] = 1;
+        std::fs::write(&path, &data).unwrap();
+
+        let mut hashes = Hashes::default();
+        open(&path, &mut graph(), &mut hashes, false).unwrap();
+        assert_eq!(hashes.get(BuildId::from(0)), Some(BuildHash(1)));
+        assert_eq!(std::fs::read(&path).unwrap()[4], 2);
+    }
+}
@@ -1,46 +1,85 @@
 //! Parsing of Makefile syntax as found in `.d` files emitted by C compilers.
 
 use crate::{
-    scanner::{ParseResult, Scanner},
+    scanner::{stop_table, ParseResult, Scanner, StopTable},
     smallmap::SmallMap,
 };
+use std::borrow::Cow;
+use std::collections::HashSet;
 
 /// Skip spaces and backslashed newlines.
-fn skip_spaces(scanner: &mut Scanner) -> ParseResult<()> {
+fn skip_spaces(scanner: &mut Scanner) {
     loop {
-        match scanner.read() {
-            ' ' => {}
-            '\\' => match scanner.read() {
-                '\n' => {}
-                _ => return scanner.parse_error("invalid backslash escape"),
-            },
-            _ => {
-                scanner.back();
-                break;
+        match scanner.peek2() {
+            (' ', _) => scanner.next(),
+            ('\\', '\n') => {
+                scanner.next();
+                scanner.next();
             }
+            _ => break,
         }
     }
-    Ok(())
 }
 
-/// Read one path from the input scanner.
+/// Bytes that may end a path or start an escape, so that read_path can skip
+/// the rest in bulk.
+const PATH_STOPS: StopTable = stop_table(b"\0 \n\r\\$");
+
+/// Read one path from the input scanner, unescaping as ninja does:
+/// - `$$` is `$`;
+/// - 2N+1 backslashes and a space are N backslashes and a space, while 2N
+///   backslashes and a space are 2N backslashes and the end of the path;
+/// - a backslash before `#`, or before `:` not followed by whitespace, is
+///   dropped;
+/// - a backslash and a newline end the path and continue the line;
+/// - other backslashes are kept, as they appear in Windows paths.
+///
 /// Note: treats colon as a valid character in a path because of Windows-style
 /// paths, but this means that the inital `output: ...` path will include the
 /// trailing colon.
-fn read_path<'a>(scanner: &mut Scanner<'a>) -> ParseResult<Option<&'a str>> {
-    skip_spaces(scanner)?;
+fn read_path<'a>(scanner: &mut Scanner<'a>) -> ParseResult<Option<Cow<'a, str>>> {
+    skip_spaces(scanner);
     let start = scanner.ofs;
+    // Once an escape is seen, the unescaped path up to `copied`.
+    let mut unescaped: Option<String> = None;
+    let mut copied = start;
     loop {
+        scanner.skip_to(&PATH_STOPS);
+        let ofs = scanner.ofs;
         match scanner.read() {
             '\0' | ' ' | '\n' => {
                 scanner.back();
                 break;
             }
             '\\' => {
-                if scanner.peek() == '\n' {
-                    scanner.back();
-                    break;
+                let mut count = 1;
+                while scanner.peek() == '\\' {
+                    scanner.next();
+                    count += 1;
                 }
+                let (backslashes, escaped) = match scanner.peek2() {
+                    ('\n', _) => {
+                        scanner.back();
+                        break;
+                    }
+                    (' ', _) if count % 2 == 0 => break,
+                    (' ', _) => (count / 2, ' '),
+                    ('#', _) => (count - 1, '#'),
+                    (':', next) if !matches!(next, ' ' | '\t' | '\n' | '\0') => (count - 1, ':'),
+                    _ => continue,
+                };
+                let path = unescaped.get_or_insert_with(String::new);
+                path.push_str(scanner.slice(copied, ofs)?);
+                path.push_str(&"\\".repeat(backslashes));
+                path.push(escaped);
+                scanner.next();
+                copied = scanner.ofs;
+            }
+            '$' if scanner.peek() == '$' => {
+                let path = unescaped.get_or_insert_with(String::new);
+                path.push_str(scanner.slice(copied, ofs + 1)?);
+                scanner.next();
+                copied = ofs + 2;
             }
             _ => {}
         }
@@ -49,33 +88,80 @@ fn read_path<'a>(scanner: &mut Scanner<'a>) -> ParseResult<Option<&'a str>> {
     if end == start {
         return Ok(None);
     }
-    Ok(Some(scanner.slice(start, end)))
+    Ok(Some(match unescaped {
+        None => Cow::Borrowed(scanner.slice(start, end)?),
+        Some(mut path) => {
+            path.push_str(scanner.slice(copied, end)?);
+            Cow::Owned(path)
+        }
+    }))
 }
 
-/// Parse a `.d` file into `Deps`.
-pub fn parse<'a>(scanner: &mut Scanner<'a>) -> ParseResult<SmallMap<&'a str, Vec<&'a str>>> {
-    let mut result = SmallMap::default();
+/// Read the targets of one `targets: deps` stanza, through the colon, or
+/// None at the end of the input.
+fn read_targets<'a>(scanner: &mut Scanner<'a>) -> ParseResult<Option<Vec<Cow<'a, str>>>> {
+    let mut targets = Vec::new();
     loop {
-        while matches!(scanner.peek(), ' ' | '\n') {
-            scanner.next();
-        }
-        let target = match read_path(scanner)? {
-            None => break,
-            Some(o) => o,
+        let Some(target) = read_path(scanner)? else {
+            if targets.is_empty() {
+                return Ok(None);
+            }
+            scanner.expect(':')?;
+            break;
         };
         scanner.skip_spaces();
-        let target = match target.strip_suffix(':') {
-            None => {
-                scanner.expect(':')?;
-                target
+        match target {
+            Cow::Borrowed(t) if t.ends_with(':') => {
+                targets.push(Cow::Borrowed(&t[..t.len() - 1]));
+                break;
             }
-            Some(target) => target,
+            Cow::Owned(mut t) if t.ends_with(':') => {
+                t.pop();
+                targets.push(Cow::Owned(t));
+                break;
+            }
+            target => targets.push(target),
+        }
+        if scanner.peek() == ':' {
+            scanner.next();
+            break;
+        }
+    }
+    Ok(Some(targets))
+}
+
+/// Parse a `.d` file into a map of each target to its dependencies.
+/// A stanza may have several targets, each getting all its dependencies,
+/// and the dependencies of a target named in several stanzas are combined.
+/// Like ninja, targets without dependencies that were already named as
+/// dependencies are dropped, as gcc's `-MP` writes such a stanza for each
+/// header.
+pub fn parse<'a>(
+    scanner: &mut Scanner<'a>,
+) -> ParseResult<SmallMap<Cow<'a, str>, Vec<Cow<'a, str>>>> {
+    let mut result: SmallMap<Cow<'a, str>, Vec<Cow<'a, str>>> = SmallMap::default();
+    let mut seen_deps: HashSet<Cow<'a, str>> = HashSet::new();
+    loop {
+        while matches!(scanner.peek(), ' ' | '\n') {
+            scanner.next();
+        }
+        let Some(targets) = read_targets(scanner)? else {
+            break;
         };
         let mut deps = Vec::new();
         while let Some(p) = read_path(scanner)? {
             deps.push(p);
         }
-        result.insert(target, deps);
+        for target in targets {
+            if deps.is_empty() && seen_deps.contains(&target) {
+                continue;
+            }
+            match result.get_mut(&target) {
+                Some(existing) => existing.extend(deps.iter().cloned()),
+                None => result.insert(target, deps.clone()),
+            }
+        }
+        seen_deps.extend(deps);
     }
     scanner.expect('\0')?;
 
@@ -87,13 +173,12 @@ mod tests {
     use super::*;
     use std::path::Path;
 
-    fn try_parse(buf: &mut Vec<u8>) -> Result<SmallMap<&str, Vec<&str>>, String> {
-        buf.push(0);
+    fn try_parse(buf: &[u8]) -> Result<SmallMap<Cow<'_, str>, Vec<Cow<'_, str>>>, String> {
         let mut scanner = Scanner::new(buf);
         parse(&mut scanner).map_err(|err| scanner.format_parse_error(Path::new("test"), err))
     }
 
-    fn must_parse(buf: &mut Vec<u8>) -> SmallMap<&str, Vec<&str>> {
+    fn must_parse(buf: &[u8]) -> SmallMap<Cow<'_, str>, Vec<Cow<'_, str>>> {
         match try_parse(buf) {
             Err(err) => {
                 println!("{}", err);
@@ -105,10 +190,8 @@ mod tests {
 
     fn test_for_crlf(input: &str, test: fn(String)) {
         test(input.to_string());
-        if cfg!(feature = "crlf") {
-            let crlf = input.replace('\n', "\r\n");
-            test(crlf);
-        }
+        let crlf = input.replace('\n', "\r\n");
+        test(crlf);
     }
 
     #[test]
@@ -116,8 +199,8 @@ mod tests {
         test_for_crlf(
             "build/browse.o: src/browse.cc src/browse.h build/browse_py.h\n",
             |text| {
-                let mut file = text.into_bytes();
-                let deps = must_parse(&mut file);
+                let file = text.into_bytes();
+                let deps = must_parse(&file);
                 assert_eq!(
                     deps,
                     SmallMap::from([(
@@ -132,8 +215,8 @@ mod tests {
     #[test]
     fn test_parse_space_suffix() {
         test_for_crlf("build/browse.o: src/browse.cc   \n", |text| {
-            let mut file = text.into_bytes();
-            let deps = must_parse(&mut file);
+            let file = text.into_bytes();
+            let deps = must_parse(&file);
             assert_eq!(
                 deps,
                 SmallMap::from([("build/browse.o", vec!["src/browse.cc",])])
@@ -146,8 +229,8 @@ mod tests {
         test_for_crlf(
             "build/browse.o: src/browse.cc\\\n  build/browse_py.h",
             |text| {
-                let mut file = text.into_bytes();
-                let deps = must_parse(&mut file);
+                let file = text.into_bytes();
+                let deps = must_parse(&file);
                 assert_eq!(
                     deps,
                     SmallMap::from([(
@@ -161,8 +244,8 @@ mod tests {
 
     #[test]
     fn test_parse_without_final_newline() {
-        let mut file = b"build/browse.o: src/browse.cc".to_vec();
-        let deps = must_parse(&mut file);
+        let file = b"build/browse.o: src/browse.cc".to_vec();
+        let deps = must_parse(&file);
         assert_eq!(
             deps,
             SmallMap::from([("build/browse.o", vec!["src/browse.cc",])])
@@ -171,8 +254,8 @@ mod tests {
 
     #[test]
     fn test_parse_spaces_before_colon() {
-        let mut file = b"build/browse.o   : src/browse.cc".to_vec();
-        let deps = must_parse(&mut file);
+        let file = b"build/browse.o   : src/browse.cc".to_vec();
+        let deps = must_parse(&file);
         assert_eq!(
             deps,
             SmallMap::from([("build/browse.o", vec!["src/browse.cc",])])
@@ -181,8 +264,8 @@ mod tests {
 
     #[test]
     fn test_parse_windows_dep_path() {
-        let mut file = b"odd/path.o: C:/odd\\path.c".to_vec();
-        let deps = must_parse(&mut file);
+        let file = b"odd/path.o: C:/odd\\path.c".to_vec();
+        let deps = must_parse(&file);
         assert_eq!(
             deps,
             SmallMap::from([("odd/path.o", vec!["C:/odd\\path.c",])])
@@ -191,14 +274,14 @@ mod tests {
 
     #[test]
     fn test_parse_multiple_targets() {
-        let mut file = b"
+        let file = b"
 out/a.o: src/a.c \\
   src/b.c
 
 out/b.o :
 "
         .to_vec();
-        let deps = must_parse(&mut file);
+        let deps = must_parse(&file);
         assert_eq!(
             deps,
             SmallMap::from([
@@ -208,10 +291,148 @@ out/b.o :
         );
     }
 
+    #[test]
+    fn test_parse_escapes() {
+        let file = b"out/a\\ b.o: src/a\\ b.c src/\\#hash.h src/$$dollar.h C:\\x\\y.h\\
+ src/c.h";
+        let deps = must_parse(file);
+        assert_eq!(
+            deps,
+            SmallMap::from([(
+                "out/a b.o",
+                vec![
+                    "src/a b.c",
+                    "src/#hash.h",
+                    "src/$dollar.h",
+                    "C:\\x\\y.h",
+                    "src/c.h"
+                ]
+            )])
+        );
+    }
+
+    #[test]
+    fn test_parse_gcc_escapes() {
+        // gcc -MD for `#include "a b.h"`, `"#x.h"` and `"$y.h"`.
+        let deps = must_parse(b"a.o: a.c a\\ b.h \\#x.h $$y.h\n");
+        assert_eq!(
+            deps,
+            SmallMap::from([("a.o", vec!["a.c", "a b.h", "#x.h", "$y.h"])])
+        );
+    }
+
+    #[test]
+    fn test_parse_windows_escapes() {
+        // clang -MD targeting Windows: backslash separators are literal, while
+        // spaces are still escaped.
+        let deps = must_parse(
+            b"obj\\a.o: C:\\Program\\ Files\\inc\\a.h \\\\server\\share\\b.h \\\n  ..\\c.h\r\n",
+        );
+        assert_eq!(
+            deps,
+            SmallMap::from([(
+                "obj\\a.o",
+                vec![
+                    "C:\\Program Files\\inc\\a.h",
+                    "\\\\server\\share\\b.h",
+                    "..\\c.h"
+                ]
+            )])
+        );
+    }
+
+    #[test]
+    fn test_parse_escaped_colons() {
+        // As written by mingw gcc.
+        let deps = must_parse(b"c\\:\\gcc\\include\\stddef.o: \\\n c\\:\\gcc\\include\\stddef.h");
+        assert_eq!(
+            deps,
+            SmallMap::from([(
+                "c:\\gcc\\include\\stddef.o",
+                vec!["c:\\gcc\\include\\stddef.h"]
+            )])
+        );
+    }
+
+    #[test]
+    fn test_parse_multiple_backslashes() {
+        // Same input and results as ninja's test of the same name.
+        let deps = must_parse(b"a\\ b\\#c.h: \\\\\\\\\\  \\\\\\\\ \\\\share\\info\\\\#1");
+        assert_eq!(
+            deps,
+            SmallMap::from([("a b#c.h", vec!["\\\\ ", "\\\\\\\\", "\\\\share\\info\\#1"])])
+        );
+    }
+
+    #[test]
+    fn test_parse_literal_escapes() {
+        // Backslashes before other characters are kept.
+        let deps = must_parse(b"\\!\\@\\#$$\\%\\^\\&\\[\\]\\\\:");
+        assert_eq!(
+            deps,
+            SmallMap::from([("\\!\\@#$\\%\\^\\&\\[\\]\\\\", Vec::<&str>::new())])
+        );
+    }
+
+    #[test]
+    fn test_parse_escaped_colon_target() {
+        let deps = must_parse(b"a\\ b.o: c.c\n");
+        assert_eq!(deps, SmallMap::from([("a b.o", vec!["c.c"])]));
+    }
+
+    #[test]
+    fn test_parse_multiple_targets_per_rule() {
+        let deps = must_parse(b"out/a.o out/a.h: src/a.c \\\n src/b.h\nout/a.h: gen.py\n");
+        assert_eq!(
+            deps,
+            SmallMap::from([
+                ("out/a.o", vec!["src/a.c", "src/b.h"]),
+                ("out/a.h", vec!["src/a.c", "src/b.h", "gen.py"]),
+            ])
+        );
+        let deps = must_parse(b"out/a.o out/b.o : x.h");
+        assert_eq!(
+            deps,
+            SmallMap::from([("out/a.o", vec!["x.h"]), ("out/b.o", vec!["x.h"])])
+        );
+    }
+
+    #[test]
+    fn test_parse_phony_headers() {
+        // As written by gcc -MD -MP.
+        let deps = must_parse(b"out/a.o: src/a.c src/a.h\n\nsrc/a.h:\n");
+        assert_eq!(
+            deps,
+            SmallMap::from([("out/a.o", vec!["src/a.c", "src/a.h"])])
+        );
+    }
+
+    #[test]
+    fn test_parse_empty() {
+        assert_eq!(must_parse(b""), SmallMap::<&str, Vec<&str>>::default());
+        assert_eq!(must_parse(b"\n\n"), SmallMap::<&str, Vec<&str>>::default());
+        assert_eq!(
+            must_parse(b"out.o:"),
+            SmallMap::from([("out.o", Vec::<&str>::new())])
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_utf8() {
+        let err = try_parse(b"out.o: caf\xE9.h").unwrap_err();
+        assert!(
+            err.starts_with(
+                "parse error: invalid UTF-8; n2 requires UTF-8 paths and text\ntest:1: "
+            ),
+            "expected parse error, got {:?}",
+            err
+        );
+    }
+
     #[test]
     fn test_parse_missing_colon() {
-        let mut file = b"foo bar".to_vec();
-        let err = try_parse(&mut file).unwrap_err();
+        let file = b"foo bar".to_vec();
+        let err = try_parse(&file).unwrap_err();
         assert!(
             err.starts_with("parse error: expected ':'"),
             "expected parse error, got {:?}",
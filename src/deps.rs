@@ -0,0 +1,133 @@
+//! Prints the dependencies the database recorded for builds, as
+//! `ninja -t deps` does.
+//!
+//! Where ninja stores the mtime its deps were recorded at, n2 stores a hash
+//! over the mtimes of all a build's files, so a record is shown as valid when
+//! hashing the files as they are now still gives the recorded hash.
+
+use crate::graph::{BuildId, FileId, FileState, Graph, Hashes, MTime};
+use crate::hash::{self, BuildHash};
+use std::io::Write;
+
+/// A file's mtime, statting it into `file_state` the first time it's asked
+/// for, or None if it can't be statted.
+fn mtime(graph: &Graph, file_state: &mut FileState, id: FileId) -> Option<MTime> {
+    match file_state.get(id) {
+        Some(mtime) => Some(mtime),
+        None => file_state.stat(id, graph.file(id).path()).ok(),
+    }
+}
+
+/// Whether the build's files are all present and still hash to `recorded`.
+fn is_valid(graph: &Graph, file_state: &mut FileState, id: BuildId, recorded: BuildHash) -> bool {
+    let build = &graph.builds[id];
+    let files = build
+        .dirtying_ins()
+        .iter()
+        .chain(build.discovered_ins())
+        .chain(build.outs());
+    for &file in files {
+        if !matches!(mtime(graph, file_state, file), Some(MTime::Stamp(_))) {
+            return false;
+        }
+    }
+    hash::hash_build(&graph.files, file_state, build) == recorded
+}
+
+/// Write the record for the build producing a file, or if the database has
+/// none, say so.  Each record is written as it's formatted, so dumping a
+/// large database doesn't hold all of it in memory.
+pub fn write(
+    w: &mut impl Write,
+    graph: &Graph,
+    hashes: &Hashes,
+    file_state: &mut FileState,
+    out: FileId,
+) -> std::io::Result<()> {
+    let name = &graph.file(out).name;
+    let Some((id, recorded)) = graph
+        .file(out)
+        .input
+        .and_then(|id| Some((id, hashes.get(id)?)))
+    else {
+        return writeln!(w, "{}: deps not found", name);
+    };
+    let build = &graph.builds[id];
+    let deps = build.discovered_ins();
+    let valid = is_valid(graph, file_state, id, recorded);
+    writeln!(
+        w,
+        "{}: #deps {}, hash {:016x} ({})",
+        name,
+        deps.len(),
+        recorded.0,
+        if valid { "VALID" } else { "STALE" }
+    )?;
+    for &dep in deps {
+        let missing = mtime(graph, file_state, dep) == Some(MTime::Missing);
+        let suffix = if missing { " (missing)" } else { "" };
+        writeln!(w, "    {}{}", graph.file(dep).name, suffix)?;
+    }
+    writeln!(w)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let name = |file: &str| dir.path().join(file).to_string_lossy().replace('\\', "/");
+        for file in ["a.c", "a.h", "a.o"] {
+            std::fs::write(name(file), "")?;
+        }
+        let manifest = format!(
+            "rule cc\n  command = cc\nbuild {}: cc {}\nbuild {}: cc {}\n",
+            name("a.o"),
+            name("a.c"),
+            name("b.o"),
+            name("b.c"),
+        );
+        let mut graph = crate::load::parse("build.ninja", manifest.into_bytes())?;
+        let a = BuildId::from(0);
+        let a_h = graph.files.id_from_canonical(name("a.h"));
+        graph.builds[a].set_discovered_ins(vec![a_h]);
+        let mut file_state = FileState::new(&graph);
+        for id in graph.files.all_ids() {
+            file_state.stat(id, graph.file(id).path())?;
+        }
+        let mut hashes = Hashes::default();
+        hashes.set(
+            a,
+            hash::hash_build(&graph.files, &file_state, &graph.builds[a]),
+        );
+
+        let mut out = Vec::new();
+        for file in ["a.o", "b.o"] {
+            let id = graph.files.lookup(&name(file)).unwrap();
+            write(&mut out, &graph, &hashes, &mut FileState::new(&graph), id)?;
+        }
+        let out = String::from_utf8(out)?;
+        let hash = hashes.get(a).unwrap().0;
+        assert_eq!(
+            out,
+            format!(
+                "{}: #deps 1, hash {:016x} (VALID)\n    {}\n\n{}: deps not found\n",
+                name("a.o"),
+                hash,
+                name("a.h"),
+                name("b.o")
+            )
+        );
+
+        std::fs::remove_file(name("a.h"))?;
+        let mut out = Vec::new();
+        let a_o = graph.files.lookup(&name("a.o")).unwrap();
+        write(&mut out, &graph, &hashes, &mut FileState::new(&graph), a_o)?;
+        let out = String::from_utf8(out)?;
+        assert!(out.contains("(STALE)\n"));
+        assert!(out.contains("a.h (missing)\n"));
+        Ok(())
+    }
+}
@@ -0,0 +1,286 @@
+//! Dumps the parsed manifest as JSON, for tools that want to read manifests
+//! without parsing them; see doc/manifest_json.md for the format.
+//!
+//! Nothing is evaluated beyond what's needed to find included files, so
+//! consumers see the manifest as written.  Each statement is written out as
+//! it's parsed, so the dump of a large manifest isn't held in memory.
+
+use crate::eval::{EvalPart, EvalString, Vars};
+use crate::json;
+use crate::load::{self, IncludeResolution};
+use crate::parse::{self, Statement, VarList};
+use anyhow::{anyhow, bail};
+use std::fmt::Write as _;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Version of the format, bumped on incompatible changes.
+const VERSION: u32 = 1;
+
+/// Write an unevaluated string as its parts.  The parser may split a run of
+/// literal text, e.g. at escapes, so such runs are joined back up.
+fn write_eval(out: &mut String, eval: &EvalString<&str>) {
+    let mut parts: Vec<EvalPart<String>> = Vec::new();
+    for part in eval.parts() {
+        match (part, parts.last_mut()) {
+            (EvalPart::Literal(text), Some(EvalPart::Literal(run))) => run.push_str(text),
+            (EvalPart::Literal(text), _) => parts.push(EvalPart::Literal(text.to_owned())),
+            (EvalPart::VarRef(name), _) => parts.push(EvalPart::VarRef(name.to_owned())),
+        }
+    }
+    out.push('[');
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let (key, text) = match part {
+            EvalPart::Literal(text) => ("literal", text),
+            EvalPart::VarRef(name) => ("var", name),
+        };
+        write!(out, "{{\"{}\":", key).unwrap();
+        json::write_str(out, text);
+        out.push('}');
+    }
+    out.push(']');
+}
+
+fn write_evals(out: &mut String, evals: &[EvalString<&str>]) {
+    out.push('[');
+    for (i, eval) in evals.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_eval(out, eval);
+    }
+    out.push(']');
+}
+
+fn write_bindings(out: &mut String, vars: &VarList) {
+    out.push('[');
+    for (i, (name, value)) in vars.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str("{\"name\":");
+        json::write_str(out, name);
+        out.push_str(",\"value\":");
+        write_eval(out, value);
+        out.push('}');
+    }
+    out.push(']');
+}
+
+fn write_build(out: &mut String, build: &parse::Build) {
+    out.push_str("\"rule\":");
+    json::write_str(out, build.rule);
+    let (explicit, implicit) = build.outs.split_at(build.explicit_outs);
+    out.push_str(",\"outputs\":{\"explicit\":");
+    write_evals(out, explicit);
+    out.push_str(",\"implicit\":");
+    write_evals(out, implicit);
+    let (explicit, rest) = build.ins.split_at(build.explicit_ins);
+    let (implicit, rest) = rest.split_at(build.implicit_ins);
+    let (order_only, validations) = rest.split_at(build.order_only_ins);
+    out.push_str("},\"inputs\":{\"explicit\":");
+    write_evals(out, explicit);
+    out.push_str(",\"implicit\":");
+    write_evals(out, implicit);
+    out.push_str(",\"order_only\":");
+    write_evals(out, order_only);
+    out.push_str(",\"validations\":");
+    write_evals(out, validations);
+    out.push_str("},\"bindings\":");
+    write_bindings(out, &build.vars);
+}
+
+struct Dumper<'a, W: Write> {
+    w: W,
+    options: &'a load::Options,
+    /// The files being dumped, outermost first, to catch include cycles.
+    reading: Vec<PathBuf>,
+}
+
+impl<W: Write> Dumper<'_, W> {
+    /// Dump a file's statements, returning its scope as it stands at the end
+    /// of the file.
+    fn file(&mut self, path: &Path, scope: Vars<'static>) -> anyhow::Result<Vars<'static>> {
+        if self.reading.iter().any(|p| p == path) {
+            bail!("cycle in includes: {} includes itself", path.display());
+        }
+        let bytes = if path == Path::new("-") {
+            let mut bytes = Vec::new();
+            std::io::Read::read_to_end(&mut std::io::stdin(), &mut bytes)
+                .map_err(|err| anyhow!("read stdin: {}", err))?;
+            bytes
+        } else {
+            std::fs::read(path).map_err(|err| anyhow!("read {}: {}", path.display(), err))?
+        };
+        self.reading.push(path.to_owned());
+        let options = parse::ParserOptions {
+            strict: self.options.strict,
+            bindings: true,
+            ..parse::ParserOptions::default()
+        };
+        let mut parser = parse::Parser::with_options(&bytes, options);
+        parser.vars = scope;
+        for (name, value) in &self.options.var_overrides {
+            parser.override_var(name, value);
+        }
+
+        let mut out = String::from("{\"path\":");
+        json::write_str(&mut out, &path.to_string_lossy());
+        out.push_str(",\"statements\":[");
+        let mut first = true;
+        loop {
+            let stmt = match parser.read() {
+                Ok(Some(stmt)) => stmt,
+                Ok(None) => break,
+                Err(err) => bail!(parser.format_parse_error(path, err)),
+            };
+            out.push_str(if first { "\n" } else { ",\n" });
+            first = false;
+            let line = parser.statement_line();
+            match stmt {
+                Statement::Binding(binding) => {
+                    write!(out, "{{\"kind\":\"binding\",\"line\":{},\"name\":", line).unwrap();
+                    json::write_str(&mut out, binding.name);
+                    out.push_str(",\"value\":");
+                    write_eval(&mut out, &binding.value);
+                }
+                Statement::Rule(rule) => {
+                    write!(out, "{{\"kind\":\"rule\",\"line\":{},\"name\":", rule.line).unwrap();
+                    json::write_str(&mut out, rule.name);
+                    out.push_str(",\"bindings\":");
+                    write_bindings(&mut out, &rule.vars);
+                }
+                Statement::Build(build) => {
+                    write!(out, "{{\"kind\":\"build\",\"line\":{},", build.line).unwrap();
+                    write_build(&mut out, &build);
+                }
+                Statement::Pool(pool) => {
+                    write!(out, "{{\"kind\":\"pool\",\"line\":{},\"name\":", line).unwrap();
+                    json::write_str(&mut out, pool.name);
+                    write!(out, ",\"depth\":{}", pool.depth).unwrap();
+                }
+                Statement::Default(targets) => {
+                    write!(out, "{{\"kind\":\"default\",\"line\":{},\"targets\":", line).unwrap();
+                    write_evals(&mut out, &targets);
+                }
+                // An include shares the includer's scope; a subninja's
+                // bindings stay within it.
+                Statement::Include(file) => {
+                    parser.vars =
+                        self.include(&mut out, "include", path, line, &file, &parser.vars)?;
+                }
+                Statement::Subninja(file) => {
+                    self.include(&mut out, "subninja", path, line, &file, &parser.vars)?;
+                }
+                Statement::Comment(_) => unreachable!(),
+            }
+            out.push('}');
+            self.w.write_all(out.as_bytes())?;
+            out.clear();
+        }
+        out.push_str(if first { "]}" } else { "\n]}" });
+        self.w.write_all(out.as_bytes())?;
+        self.reading.pop();
+        Ok(parser.vars.into_owned())
+    }
+
+    /// Dump an include or subninja statement in `includer` along with the
+    /// file it names, returning that file's scope at its end.
+    fn include(
+        &mut self,
+        out: &mut String,
+        kind: &str,
+        includer: &Path,
+        line: usize,
+        file: &EvalString<&str>,
+        vars: &Vars,
+    ) -> anyhow::Result<Vars<'static>> {
+        write!(out, "{{\"kind\":\"{}\",\"line\":{},\"path\":", kind, line).unwrap();
+        write_eval(out, file);
+        out.push_str(",\"file\":");
+        self.w.write_all(out.as_bytes())?;
+        out.clear();
+        let path = self.include_path(includer, file.evaluate(&[vars]));
+        self.file(&path, vars.clone().into_owned())
+    }
+
+    /// Find the file an include or subninja in `includer` names.
+    fn include_path(&self, includer: &Path, literal: String) -> PathBuf {
+        let dir = includer.parent().unwrap_or(Path::new(""));
+        if self.options.include_resolution == IncludeResolution::RelativeToIncluder
+            && !dir.as_os_str().is_empty()
+        {
+            return dir.join(literal);
+        }
+        PathBuf::from(literal)
+    }
+}
+
+/// Write the manifest `filename` and the files it includes as JSON.  A
+/// filename of "-" reads the manifest from stdin.
+pub fn write(w: impl Write, filename: &str, options: &load::Options) -> anyhow::Result<()> {
+    let mut dumper = Dumper {
+        w,
+        options,
+        reading: Vec::new(),
+    };
+    write!(dumper.w, "{{\"version\":{},\"file\":", VERSION)?;
+    dumper.file(Path::new(filename), Vars::default())?;
+    writeln!(dumper.w, "}}")?;
+    dumper.w.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dump() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = |name: &str| dir.path().join(name);
+        std::fs::write(
+            path("build.ninja"),
+            "cflags = -O2
+rule cc
+  command = cc $cflags -c $in -o $out
+pool link_pool
+  depth = 1
+build a.o | a.d: cc a.c | a.h || gen |@ lint
+  cflags = $cflags -g
+default a.o
+subninja $dir/sub.ninja
+",
+        )?;
+        std::fs::write(path("sub.ninja"), "")?;
+        let options = load::Options {
+            var_overrides: vec![("dir".to_owned(), dir.path().to_string_lossy().into_owned())],
+            ..load::Options::default()
+        };
+        let mut out = Vec::new();
+        write(&mut out, &path("build.ninja").to_string_lossy(), &options)?;
+        let out = String::from_utf8(out)?;
+        let mut dir_json = String::new();
+        json::write_str(&mut dir_json, &dir.path().to_string_lossy());
+        let dir_json = &dir_json[1..dir_json.len() - 1];
+        assert_eq!(
+            out,
+            format!(
+                r#"{{"version":1,"file":{{"path":"{dir}/build.ninja","statements":[
+{{"kind":"binding","line":1,"name":"cflags","value":[{{"literal":"-O2"}}]}},
+{{"kind":"rule","line":2,"name":"cc","bindings":[{{"name":"command","value":[{{"literal":"cc "}},{{"var":"cflags"}},{{"literal":" -c "}},{{"var":"in"}},{{"literal":" -o "}},{{"var":"out"}}]}}]}},
+{{"kind":"pool","line":4,"name":"link_pool","depth":1}},
+{{"kind":"build","line":6,"rule":"cc","outputs":{{"explicit":[[{{"literal":"a.o"}}]],"implicit":[[{{"literal":"a.d"}}]]}},"inputs":{{"explicit":[[{{"literal":"a.c"}}]],"implicit":[[{{"literal":"a.h"}}]],"order_only":[[{{"literal":"gen"}}]],"validations":[[{{"literal":"lint"}}]]}},"bindings":[{{"name":"cflags","value":[{{"var":"cflags"}},{{"literal":" -g"}}]}}]}},
+{{"kind":"default","line":8,"targets":[[{{"literal":"a.o"}}]]}},
+{{"kind":"subninja","line":9,"path":[{{"var":"dir"}},{{"literal":"/sub.ninja"}}],"file":{{"path":"{dir}/sub.ninja","statements":[]}}}}
+]}}}}
+"#,
+                dir = dir_json
+            )
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+//! Dyndep files: build-time discovered inputs and outputs of build steps.
+//!
+//! See https://ninja-build.org/manual.html#ref_dyndep for the format.  A
+//! dyndep file is itself generated by the build, and n2 reads it just before
+//! checking whether the build steps that reference it are up to date.
+
+use crate::{
+    eval::{EvalString, Vars},
+    graph::{BuildId, FileId, Graph, GraphFiles},
+    parse::{self, Statement},
+    trace,
+};
+use anyhow::{anyhow, bail};
+use std::path::Path;
+
+/// The information a dyndep file provides about a single build step.
+#[derive(Debug, PartialEq)]
+pub struct Dyndep {
+    /// Line of the `build` statement within the dyndep file.
+    pub line: usize,
+    /// The (single, explicit) output naming the build step to update.
+    pub out: String,
+    pub implicit_outs: Vec<String>,
+    pub implicit_ins: Vec<String>,
+}
+
+fn evaluate_paths(
+    files: &GraphFiles,
+    paths: &[EvalString<&str>],
+    vars: &parse::VarList,
+    env: &Vars,
+) -> Vec<String> {
+    paths
+        .iter()
+        .map(|path| {
+            let mut path = path.evaluate(&[vars, env]);
+            files.canonicalize(&mut path);
+            path
+        })
+        .collect()
+}
+
+/// Parse the contents of a dyndep file, canonicalizing paths as `files` does.
+pub fn parse(files: &GraphFiles, path: &Path, buf: &[u8]) -> anyhow::Result<Vec<Dyndep>> {
+    let mut parser = parse::Parser::new(buf);
+    let mut dyndeps = Vec::new();
+    loop {
+        let stmt = match parser
+            .read()
+            .map_err(|err| anyhow!(parser.format_parse_error(path, err)))?
+        {
+            None => break,
+            Some(s) => s,
+        };
+        let b = match stmt {
+            Statement::Build(b) => b,
+            _ => bail!(
+                "{}: dyndep files may only contain build statements",
+                path.display()
+            ),
+        };
+        match parser.vars.get("ninja_dyndep_version").map(|v| v.as_str()) {
+            Some("1") | Some("1.0") => {}
+            Some(v) => bail!(
+                "{}:{}: unsupported 'ninja_dyndep_version = {}'",
+                path.display(),
+                b.line,
+                v
+            ),
+            None => bail!(
+                "{}:{}: expected 'ninja_dyndep_version = ...' before build statements",
+                path.display(),
+                b.line
+            ),
+        }
+        if b.rule != "dyndep" {
+            bail!(
+                "{}:{}: expected build rule 'dyndep', got {:?}",
+                path.display(),
+                b.line,
+                b.rule
+            );
+        }
+        if b.explicit_outs != 1 {
+            bail!(
+                "{}:{}: expected exactly one explicit output",
+                path.display(),
+                b.line
+            );
+        }
+        if b.explicit_ins != 0 || b.order_only_ins != 0 || b.validation_ins != 0 {
+            bail!(
+                "{}:{}: dyndep build statements only accept implicit inputs",
+                path.display(),
+                b.line
+            );
+        }
+        for (name, _) in b.vars.iter() {
+            match *name {
+                // n2 already treats every build as `restat = 1`: a build
+                // that leaves its outputs untouched doesn't rerun the builds
+                // using them.  So there is nothing more to apply.
+                "restat" => {}
+                _ => bail!(
+                    "{}:{}: unexpected variable {:?} in dyndep file",
+                    path.display(),
+                    b.line,
+                    name
+                ),
+            }
+        }
+        let mut outs = evaluate_paths(files, &b.outs, &b.vars, &parser.vars);
+        let implicit_outs = outs.split_off(1);
+        dyndeps.push(Dyndep {
+            line: b.line,
+            out: outs.pop().unwrap(),
+            implicit_outs,
+            implicit_ins: evaluate_paths(files, &b.ins, &b.vars, &parser.vars),
+        });
+    }
+    Ok(dyndeps)
+}
+
+/// Read a dyndep file and attach the inputs/outputs it names to the builds in
+/// the graph.  Returns the ids of the builds that were updated.
+pub fn load(graph: &mut Graph, file: FileId) -> anyhow::Result<Vec<BuildId>> {
+    let path = graph.file(file).path().to_path_buf();
+    let bytes = match trace::scope("read file", || std::fs::read(&path)) {
+        Ok(b) => b,
+        Err(e) => bail!("read dyndep {}: {}", path.display(), e),
+    };
+    let dyndeps = parse(&graph.files, &path, &bytes)?;
+
+    let mut updated = Vec::new();
+    for dyndep in dyndeps {
+        let bid = match graph
+            .files
+            .lookup(&dyndep.out)
+            .and_then(|id| graph.file(id).input)
+        {
+            Some(bid) => bid,
+            None => bail!(
+                "{}:{}: no build statement exists for {:?}",
+                path.display(),
+                dyndep.line,
+                dyndep.out
+            ),
+        };
+        if graph.builds[bid].dyndep != Some(file) {
+            bail!(
+                "{}:{}: build statement for {:?} has no binding 'dyndep = {}'",
+                path.display(),
+                dyndep.line,
+                dyndep.out,
+                graph.file(file).name
+            );
+        }
+        let ins: Vec<FileId> = dyndep
+            .implicit_ins
+            .into_iter()
+            .map(|name| graph.files.id_from_canonical(name))
+            .collect();
+        let outs: Vec<FileId> = dyndep
+            .implicit_outs
+            .into_iter()
+            .map(|name| graph.files.id_from_canonical(name))
+            .collect();
+        graph.add_dyndep_files(bid, &ins, &outs)?;
+        updated.push(bid);
+    }
+    Ok(updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn try_parse(text: &str) -> anyhow::Result<Vec<Dyndep>> {
+        parse(&GraphFiles::default(), Path::new("dd"), text.as_bytes())
+    }
+
+    #[test]
+    fn parse_dyndep() {
+        let dyndeps = try_parse(
+            "ninja_dyndep_version = 1
+build out | out.imp: dyndep | in.imp ./other
+  restat = 1
+build out2: dyndep
+",
+        )
+        .unwrap();
+        assert_eq!(
+            dyndeps,
+            vec![
+                Dyndep {
+                    line: 2,
+                    out: "out".to_owned(),
+                    implicit_outs: vec!["out.imp".to_owned()],
+                    implicit_ins: vec!["in.imp".to_owned(), "other".to_owned()],
+                },
+                Dyndep {
+                    line: 4,
+                    out: "out2".to_owned(),
+                    implicit_outs: vec![],
+                    implicit_ins: vec![],
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_missing_version() {
+        let err = try_parse("build out: dyndep\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "dd:1: expected 'ninja_dyndep_version = ...' before build statements"
+        );
+    }
+
+    #[test]
+    fn parse_bad_rule() {
+        let err = try_parse("ninja_dyndep_version = 1\nbuild out: cc\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "dd:2: expected build rule 'dyndep', got \"cc\""
+        );
+    }
+}
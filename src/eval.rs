@@ -6,12 +6,15 @@ use rustc_hash::FxHashMap;
 use crate::smallmap::SmallMap;
 use std::borrow::Borrow;
 use std::borrow::Cow;
+use std::cell::RefCell;
+use std::hash::Hash;
+use std::rc::Rc;
 
 /// An environment providing a mapping of variable name to variable value.
 /// This represents one "frame" of evaluation context, a given EvalString may
 /// need multiple environments in order to be fully expanded.
 pub trait Env {
-    fn get_var(&self, var: &str) -> Option<EvalString<Cow<str>>>;
+    fn get_var(&self, var: &str) -> Option<EvalString<Cow<'_, str>>>;
 }
 
 /// One token within an EvalString, either literal text or a variable reference.
@@ -32,37 +35,16 @@ impl<T: AsRef<str>> EvalString<T> {
         EvalString(parts)
     }
 
-    fn evaluate_inner(&self, result: &mut String, envs: &[&dyn Env]) {
-        for part in &self.0 {
-            match part {
-                EvalPart::Literal(s) => result.push_str(s.as_ref()),
-                EvalPart::VarRef(v) => {
-                    for (i, env) in envs.iter().enumerate() {
-                        if let Some(v) = env.get_var(v.as_ref()) {
-                            v.evaluate_inner(result, &envs[i + 1..]);
-                            break;
-                        }
-                    }
-                }
-            }
-        }
+    pub fn parts(&self) -> impl Iterator<Item = EvalPart<&str>> {
+        self.0.iter().map(|part| match part {
+            EvalPart::Literal(s) => EvalPart::Literal(s.as_ref()),
+            EvalPart::VarRef(v) => EvalPart::VarRef(v.as_ref()),
+        })
     }
 
-    fn calc_evaluated_length(&self, envs: &[&dyn Env]) -> usize {
-        self.0
-            .iter()
-            .map(|part| match part {
-                EvalPart::Literal(s) => s.as_ref().len(),
-                EvalPart::VarRef(v) => {
-                    for (i, env) in envs.iter().enumerate() {
-                        if let Some(v) = env.get_var(v.as_ref()) {
-                            return v.calc_evaluated_length(&envs[i + 1..]);
-                        }
-                    }
-                    0
-                }
-            })
-            .sum()
+    /// The names of the variables this string references.
+    pub fn var_refs(&self) -> impl Iterator<Item = &str> {
+        var_refs(self.parts())
     }
 
     /// evalulate turns the EvalString into a regular String, looking up the
@@ -72,34 +54,131 @@ impl<T: AsRef<str>> EvalString<T> {
     /// had the first successful lookup.
     pub fn evaluate(&self, envs: &[&dyn Env]) -> String {
         let mut result = String::new();
-        result.reserve(self.calc_evaluated_length(envs));
-        self.evaluate_inner(&mut result, envs);
+        self.evaluate_into(envs, &mut result);
         result
     }
+
+    /// Like evaluate, but appends to `out`, so that a buffer can be reused
+    /// across evaluations.
+    pub fn evaluate_into(&self, envs: &[&dyn Env], out: &mut String) {
+        out.reserve(calc_evaluated_length(self.parts(), envs));
+        evaluate_inner(self.parts(), out, envs);
+    }
+}
+
+fn evaluate_inner<'a>(
+    parts: impl Iterator<Item = EvalPart<&'a str>>,
+    result: &mut String,
+    envs: &[&dyn Env],
+) {
+    for part in parts {
+        match part {
+            EvalPart::Literal(s) => result.push_str(s),
+            EvalPart::VarRef(v) => {
+                for (i, env) in envs.iter().enumerate() {
+                    if let Some(v) = env.get_var(v) {
+                        evaluate_inner(v.parts(), result, &envs[i + 1..]);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn calc_evaluated_length<'a>(
+    parts: impl Iterator<Item = EvalPart<&'a str>>,
+    envs: &[&dyn Env],
+) -> usize {
+    parts
+        .map(|part| match part {
+            EvalPart::Literal(s) => s.len(),
+            EvalPart::VarRef(v) => {
+                for (i, env) in envs.iter().enumerate() {
+                    if let Some(v) = env.get_var(v) {
+                        return calc_evaluated_length(v.parts(), &envs[i + 1..]);
+                    }
+                }
+                0
+            }
+        })
+        .sum()
+}
+
+fn var_refs<'a>(parts: impl Iterator<Item = EvalPart<&'a str>>) -> impl Iterator<Item = &'a str> {
+    parts.filter_map(|part| match part {
+        EvalPart::Literal(_) => None,
+        EvalPart::VarRef(v) => Some(v),
+    })
 }
 
 impl EvalString<&str> {
-    pub fn into_owned(self) -> EvalString<String> {
-        EvalString(
-            self.0
-                .into_iter()
-                .map(|part| match part {
-                    EvalPart::Literal(s) => EvalPart::Literal(s.to_owned()),
-                    EvalPart::VarRef(s) => EvalPart::VarRef(s.to_owned()),
-                })
-                .collect(),
-        )
+    pub fn into_owned(self) -> OwnedEvalString {
+        let mut owned = OwnedEvalString {
+            text: String::with_capacity(self.0.iter().map(|part| part.as_ref().len()).sum()),
+            parts: Vec::with_capacity(self.0.len()),
+        };
+        for part in self.0 {
+            let (is_var, s) = match part {
+                EvalPart::Literal(s) => (false, s),
+                EvalPart::VarRef(s) => (true, s),
+            };
+            owned.text.push_str(s);
+            owned.parts.push((is_var, owned.text.len()));
+        }
+        owned
     }
 }
 
-impl EvalString<String> {
-    pub fn as_cow(&self) -> EvalString<Cow<str>> {
+impl<T: AsRef<str>> EvalPart<T> {
+    fn as_ref(&self) -> &str {
+        match self {
+            EvalPart::Literal(s) | EvalPart::VarRef(s) => s.as_ref(),
+        }
+    }
+}
+
+/// An owned EvalString, as kept for rule bindings.  The text of all the parts
+/// is kept in one buffer, so it takes two allocations however many parts it
+/// has.
+#[derive(Debug, PartialEq)]
+pub struct OwnedEvalString {
+    text: String,
+    /// For each part, whether it is a variable reference, and the offset in
+    /// text where it ends.
+    parts: Vec<(bool, usize)>,
+}
+
+impl OwnedEvalString {
+    pub fn parts(&self) -> impl Iterator<Item = EvalPart<&str>> {
+        let mut start = 0;
+        self.parts.iter().map(move |&(is_var, end)| {
+            let s = &self.text[start..end];
+            start = end;
+            match is_var {
+                false => EvalPart::Literal(s),
+                true => EvalPart::VarRef(s),
+            }
+        })
+    }
+
+    /// The names of the variables this string references.
+    pub fn var_refs(&self) -> impl Iterator<Item = &str> {
+        var_refs(self.parts())
+    }
+
+    pub fn evaluate(&self, envs: &[&dyn Env]) -> String {
+        let mut result = String::with_capacity(calc_evaluated_length(self.parts(), envs));
+        evaluate_inner(self.parts(), &mut result, envs);
+        result
+    }
+
+    pub fn as_cow(&self) -> EvalString<Cow<'_, str>> {
         EvalString(
-            self.0
-                .iter()
+            self.parts()
                 .map(|part| match part {
-                    EvalPart::Literal(s) => EvalPart::Literal(Cow::Borrowed(s.as_ref())),
-                    EvalPart::VarRef(s) => EvalPart::VarRef(Cow::Borrowed(s.as_ref())),
+                    EvalPart::Literal(s) => EvalPart::Literal(Cow::Borrowed(s)),
+                    EvalPart::VarRef(s) => EvalPart::VarRef(Cow::Borrowed(s)),
                 })
                 .collect(),
         )
@@ -107,7 +186,7 @@ impl EvalString<String> {
 }
 
 impl EvalString<&str> {
-    pub fn as_cow(&self) -> EvalString<Cow<str>> {
+    pub fn as_cow(&self) -> EvalString<Cow<'_, str>> {
         EvalString(
             self.0
                 .iter()
@@ -120,42 +199,136 @@ impl EvalString<&str> {
     }
 }
 
+/// Lookups of variables bound nowhere in the manifest in the process
+/// environment, so that e.g. `$CC` can come from there as it does in make.
+/// Each name is looked up once per run.
+#[derive(Debug)]
+pub struct EnvFallback {
+    cache: RefCell<FxHashMap<String, Option<String>>>,
+    /// Print each environment variable as it is first consulted.
+    log: bool,
+}
+
+impl EnvFallback {
+    pub fn new(log: bool) -> Self {
+        EnvFallback {
+            cache: RefCell::default(),
+            log,
+        }
+    }
+
+    pub fn get(&self, var: &str) -> Option<String> {
+        // The implicit variables are never taken from the environment, even
+        // where they are unbound, e.g. at file scope.
+        if matches!(var, "in" | "out" | "in_newline" | "out_newline") {
+            return None;
+        }
+        if let Some(val) = self.cache.borrow().get(var) {
+            return val.clone();
+        }
+        let val = std::env::var(var).ok();
+        if self.log {
+            match &val {
+                Some(val) => println!("n2: env: {}={}", var, val),
+                None => println!("n2: env: {} unset", var),
+            }
+        }
+        self.cache.borrow_mut().insert(var.to_owned(), val.clone());
+        val
+    }
+}
+
 /// A single scope's worth of variable definitions.
-#[derive(Debug, Default)]
-pub struct Vars<'text>(FxHashMap<&'text str, String>);
+#[derive(Debug, Default, Clone)]
+pub struct Vars<'text> {
+    vars: FxHashMap<Cow<'text, str>, String>,
+    /// Consulted for variables not found here, when enabled.
+    pub fallback: Option<Rc<EnvFallback>>,
+}
 
 impl<'text> Vars<'text> {
     pub fn insert(&mut self, key: &'text str, val: String) {
-        self.0.insert(key, val);
+        self.vars.insert(Cow::Borrowed(key), val);
+    }
+    pub fn insert_owned(&mut self, key: String, val: String) {
+        self.vars.insert(Cow::Owned(key), val);
+    }
+    /// Copy the variable names, so the vars outlive the text they came from.
+    pub fn into_owned(self) -> Vars<'static> {
+        Vars {
+            vars: self
+                .vars
+                .into_iter()
+                .map(|(k, v)| (Cow::Owned(k.into_owned()), v))
+                .collect(),
+            fallback: self.fallback,
+        }
     }
+    /// Look up a variable defined in this scope, ignoring any fallback.
     pub fn get(&self, key: &str) -> Option<&String> {
-        self.0.get(key)
+        self.vars.get(key)
+    }
+    /// The names of the variables defined.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.vars.keys().map(|k| k.as_ref())
     }
 }
 impl<'a> Env for Vars<'a> {
-    fn get_var(&self, var: &str) -> Option<EvalString<Cow<str>>> {
-        Some(EvalString::new(vec![EvalPart::Literal(
-            std::borrow::Cow::Borrowed(self.get(var)?),
-        )]))
+    fn get_var(&self, var: &str) -> Option<EvalString<Cow<'_, str>>> {
+        let val = match self.get(var) {
+            Some(val) => Cow::Borrowed(val.as_str()),
+            None => Cow::Owned(self.fallback.as_ref()?.get(var)?),
+        };
+        Some(EvalString::new(vec![EvalPart::Literal(val)]))
     }
 }
 
-impl<K: Borrow<str> + PartialEq> Env for SmallMap<K, EvalString<String>> {
-    fn get_var(&self, var: &str) -> Option<EvalString<Cow<str>>> {
+impl<K: Borrow<str> + Hash + Eq> Env for SmallMap<K, OwnedEvalString> {
+    fn get_var(&self, var: &str) -> Option<EvalString<Cow<'_, str>>> {
         Some(self.get(var)?.as_cow())
     }
 }
 
-impl<K: Borrow<str> + PartialEq> Env for SmallMap<K, EvalString<&str>> {
-    fn get_var(&self, var: &str) -> Option<EvalString<Cow<str>>> {
+impl<K: Borrow<str> + Hash + Eq> Env for SmallMap<K, EvalString<&str>> {
+    fn get_var(&self, var: &str) -> Option<EvalString<Cow<'_, str>>> {
         Some(self.get(var)?.as_cow())
     }
 }
 
 impl Env for SmallMap<&str, String> {
-    fn get_var(&self, var: &str) -> Option<EvalString<Cow<str>>> {
+    fn get_var(&self, var: &str) -> Option<EvalString<Cow<'_, str>>> {
         Some(EvalString::new(vec![EvalPart::Literal(
             std::borrow::Cow::Borrowed(self.get(var)?),
         )]))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn owned_eval_string() {
+        let borrowed = EvalString::new(vec![
+            EvalPart::Literal("cc "),
+            EvalPart::VarRef("flags"),
+            EvalPart::Literal(""),
+            EvalPart::Literal(" -o "),
+            EvalPart::VarRef("out"),
+        ]);
+        let mut vars = Vars::default();
+        vars.insert("flags", "-O2".to_owned());
+        vars.insert("out", "a.o".to_owned());
+        let want = borrowed.evaluate(&[&vars]);
+        let owned = borrowed.into_owned();
+        assert_eq!(owned.evaluate(&[&vars]), want);
+        assert_eq!(owned.evaluate(&[&vars]), "cc -O2 -o a.o");
+        assert_eq!(owned.var_refs().collect::<Vec<_>>(), ["flags", "out"]);
+        // Part boundaries are kept, including empty parts.
+        assert_eq!(owned.as_cow().0.len(), 5);
+        assert_eq!(
+            owned.as_cow().0[1],
+            EvalPart::VarRef(Cow::Borrowed("flags"))
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! The recap of a build's failures, printed once the build stops so it isn't
+//! lost among the output of everything else that ran, and optionally written
+//! as JSON for tools like CI to pick up.
+
+use crate::graph::{Build, BuildId, Graph};
+use crate::json;
+use crate::progress::build_message;
+use std::path::Path;
+
+/// How many lines of each failed task's output the printed summary repeats.
+const OUTPUT_LINES: usize = 5;
+
+/// How many of the builds that didn't run the printed summary lists.
+const MAX_UNBUILT_LISTED: usize = 8;
+
+/// A task that failed.
+pub struct Failure {
+    pub id: BuildId,
+    /// How it failed, e.g. "exit status 1".
+    pub status: String,
+    pub output: Vec<u8>,
+}
+
+fn plural(n: usize) -> &'static str {
+    if n == 1 {
+        ""
+    } else {
+        "s"
+    }
+}
+
+fn out_names<'a>(graph: &'a Graph, build: &'a Build) -> impl Iterator<Item = &'a str> {
+    build.outs().iter().map(|&id| graph.file(id).name.as_str())
+}
+
+/// Format the summary as lines to print.
+pub fn summarize(graph: &Graph, failed: &[Failure], unbuilt: &[BuildId]) -> Vec<String> {
+    let mut lines = vec![format!(
+        "n2: {} task{} failed:",
+        failed.len(),
+        plural(failed.len())
+    )];
+    for failure in failed {
+        let build = &graph.builds[failure.id];
+        lines.push(format!("  {} ({})", build_message(build), failure.status));
+        lines.push(format!(
+            "    outputs: {}",
+            out_names(graph, build).collect::<Vec<_>>().join(" ")
+        ));
+        let output = String::from_utf8_lossy(&failure.output);
+        let mut output_lines = output.lines().filter(|l| !l.trim().is_empty());
+        for line in output_lines.by_ref().take(OUTPUT_LINES) {
+            lines.push(format!("    | {}", line));
+        }
+        let rest = output_lines.count();
+        if rest > 0 {
+            lines.push(format!("    | ...{} more line{}", rest, plural(rest)));
+        }
+    }
+
+    if !unbuilt.is_empty() {
+        lines.push(format!(
+            "n2: {} task{} not built due to earlier failure:",
+            unbuilt.len(),
+            plural(unbuilt.len())
+        ));
+        for &id in unbuilt.iter().take(MAX_UNBUILT_LISTED) {
+            lines.push(format!("  {}", build_message(&graph.builds[id])));
+        }
+        if unbuilt.len() > MAX_UNBUILT_LISTED {
+            lines.push(format!(
+                "  ...and {} more",
+                unbuilt.len() - MAX_UNBUILT_LISTED
+            ));
+        }
+    }
+    lines
+}
+
+fn json_strs<'a>(out: &mut String, strs: impl Iterator<Item = &'a str>) {
+    out.push('[');
+    for (i, s) in strs.enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        json::write_str(out, s);
+    }
+    out.push(']');
+}
+
+/// Format the summary as a JSON object, holding each failure's full output.
+pub fn to_json(graph: &Graph, failed: &[Failure], unbuilt: &[BuildId]) -> String {
+    let mut out = String::from("{\n  \"failed\": [");
+    for (i, failure) in failed.iter().enumerate() {
+        let build = &graph.builds[failure.id];
+        out.push_str(if i > 0 { ",\n    {" } else { "\n    {" });
+        out.push_str("\"description\": ");
+        json::write_str(&mut out, build_message(build));
+        out.push_str(", \"status\": ");
+        json::write_str(&mut out, &failure.status);
+        out.push_str(", \"outputs\": ");
+        json_strs(&mut out, out_names(graph, build));
+        out.push_str(", \"output\": ");
+        json::write_str(&mut out, &String::from_utf8_lossy(&failure.output));
+        out.push('}');
+    }
+    out.push_str(if failed.is_empty() {
+        "],\n"
+    } else {
+        "\n  ],\n"
+    });
+    out.push_str("  \"not_built\": [");
+    for (i, &id) in unbuilt.iter().enumerate() {
+        let build = &graph.builds[id];
+        out.push_str(if i > 0 { ",\n    {" } else { "\n    {" });
+        out.push_str("\"description\": ");
+        json::write_str(&mut out, build_message(build));
+        out.push_str(", \"outputs\": ");
+        json_strs(&mut out, out_names(graph, build));
+        out.push('}');
+    }
+    out.push_str(if unbuilt.is_empty() {
+        "]\n}\n"
+    } else {
+        "\n  ]\n}\n"
+    });
+    out
+}
+
+/// Write the JSON summary to `path`, replacing any from an earlier build.
+pub fn write_json(
+    path: &Path,
+    graph: &Graph,
+    failed: &[Failure],
+    unbuilt: &[BuildId],
+) -> anyhow::Result<()> {
+    let json = to_json(graph, failed, unbuilt);
+    std::fs::write(path, json).map_err(|err| anyhow::anyhow!("write {}: {}", path.display(), err))
+}
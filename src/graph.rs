@@ -3,10 +3,11 @@
 use rustc_hash::FxHashMap;
 
 use crate::{
+    canon::{canonicalize_path, canonicalize_path_windows},
     densemap::{self, DenseMap},
-    hash::BuildHash,
+    hash::{BuildHash, Digests},
 };
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{hash_map::Entry, HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
@@ -56,7 +57,7 @@ impl File {
 }
 
 /// A textual location within a build.ninja file, used in error messages.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FileLoc {
     pub filename: std::rc::Rc<PathBuf>,
     pub line: usize,
@@ -87,6 +88,25 @@ pub struct BuildIns {
     // pub validation: usize,
 }
 
+impl BuildIns {
+    /// Remove every occurrence of an input, adjusting the counts.
+    pub fn remove(&mut self, id: FileId) {
+        for pos in (0..self.ids.len()).rev() {
+            if self.ids[pos] != id {
+                continue;
+            }
+            self.ids.remove(pos);
+            if pos < self.explicit {
+                self.explicit -= 1;
+            } else if pos < self.explicit + self.implicit {
+                self.implicit -= 1;
+            } else if pos < self.explicit + self.implicit + self.order_only {
+                self.order_only -= 1;
+            }
+        }
+    }
+}
+
 /// Output files from a Build.
 pub struct BuildOuts {
     /// Similar to ins, we keep both explicit and implicit outs in one Vec.
@@ -101,7 +121,7 @@ impl BuildOuts {
     pub fn remove_duplicates(&mut self) {
         let mut ids = Vec::new();
         for (i, &id) in self.ids.iter().enumerate() {
-            if self.ids[0..i].iter().any(|&prev| prev == id) {
+            if self.ids[0..i].contains(&id) {
                 // Skip over duplicate.
                 if i < self.explicit {
                     self.explicit -= 1;
@@ -112,6 +132,16 @@ impl BuildOuts {
         }
         self.ids = ids;
     }
+
+    /// Remove the given outputs, e.g. ones generated by another Build.
+    pub fn remove(&mut self, remove: &[FileId]) {
+        let explicit = self.ids[..self.explicit]
+            .iter()
+            .filter(|id| !remove.contains(id))
+            .count();
+        self.ids.retain(|id| !remove.contains(id));
+        self.explicit = explicit;
+    }
 }
 
 #[cfg(test)]
@@ -142,6 +172,33 @@ mod tests {
         assert_eq!(outs.ids, fileids(vec![1, 2]));
         assert_eq!(outs.explicit, 2);
     }
+
+    #[test]
+    fn fold_case() {
+        let mut files = GraphFiles::default();
+        files.set_fold_case(true);
+        let id = files.id_from_canonical("Foo.h".to_owned());
+        assert_eq!(files.id_from_canonical("foo.h".to_owned()), id);
+        assert_eq!(files.id_from_canonical_ref("FOO.H"), id);
+        assert_eq!(files.lookup("fOO.h"), Some(id));
+        assert_eq!(files.by_id[id].name, "Foo.h");
+
+        let mut files = GraphFiles::default();
+        files.set_fold_case(false);
+        let id = files.id_from_canonical("Foo.h".to_owned());
+        assert_ne!(files.id_from_canonical("foo.h".to_owned()), id);
+    }
+
+    #[test]
+    fn windows_paths() {
+        let mut files = GraphFiles::default();
+        files.set_windows_paths();
+        files.set_fold_case(true);
+        let id = files.id_from_canonical("Src\\foo.c".to_owned());
+        assert_eq!(files.id_from_canonical("src/foo.c".to_owned()), id);
+        assert_eq!(files.lookup("src\\Foo.c"), Some(id));
+        assert_eq!(files.by_id[id].name, "Src\\foo.c");
+    }
 }
 
 /// A single build action, generating File outputs from File inputs with a command.
@@ -149,6 +206,9 @@ pub struct Build {
     /// Source location this Build was declared.
     pub location: FileLoc,
 
+    /// Name of the rule this build uses.
+    pub rule: String,
+
     /// User-provided description of the build step.
     pub desc: Option<String>,
 
@@ -158,8 +218,9 @@ pub struct Build {
     /// Path to generated `.d` file, if any.
     pub depfile: Option<String>,
 
-    /// If true, extract "/showIncludes" lines from output.
-    pub parse_showincludes: bool,
+    /// If set, the prefix of "/showIncludes" lines to extract from output, from
+    /// `deps = msvc` and `msvc_deps_prefix`.
+    pub showincludes_prefix: Option<String>,
 
     // Struct that contains the path to the rsp file and its contents, if any.
     pub rspfile: Option<RspFile>,
@@ -167,6 +228,19 @@ pub struct Build {
     /// Pool to execute this build in, if any.
     pub pool: Option<String>,
 
+    /// Dyndep file that adds further inputs/outputs to this build, if any.
+    /// The file is always also one of the build's inputs.
+    pub dyndep: Option<FileId>,
+
+    /// Whether this build regenerates the build files, from the `generator`
+    /// binding.  Changes to its command line don't make it out of date, so
+    /// that e.g. editing CMake flags doesn't prompt a regeneration loop.
+    pub generator: bool,
+
+    /// Whether this build's outputs are kept when it's interrupted, from the
+    /// `precious` binding, rather than deleted as likely half written.
+    pub precious: bool,
+
     pub ins: BuildIns,
 
     /// Additional inputs discovered from a previous build.
@@ -179,18 +253,28 @@ impl Build {
     pub fn new(loc: FileLoc, ins: BuildIns, outs: BuildOuts) -> Self {
         Build {
             location: loc,
+            rule: String::new(),
             desc: None,
             cmdline: None,
             depfile: None,
-            parse_showincludes: false,
+            showincludes_prefix: None,
             rspfile: None,
             pool: None,
+            dyndep: None,
+            generator: false,
+            precious: false,
             ins,
             discovered_ins: Vec::new(),
             outs,
         }
     }
 
+    /// Whether this build runs in the `console` pool, with direct access to
+    /// the terminal.
+    pub fn is_console(&self) -> bool {
+        self.pool.as_deref() == Some("console")
+    }
+
     /// Input paths that appear in `$in`.
     pub fn explicit_ins(&self) -> &[FileId] {
         &self.ins.ids[0..self.ins.explicit]
@@ -232,6 +316,12 @@ impl Build {
         &self.outs.ids[0..self.outs.explicit]
     }
 
+    /// Output paths listed after `|`, which are updated when the build runs
+    /// but don't appear in `$out`.
+    pub fn implicit_outs(&self) -> &[FileId] {
+        &self.outs.ids[self.outs.explicit..]
+    }
+
     /// Output paths that are updated when the build runs.
     pub fn outs(&self) -> &[FileId] {
         &self.outs.ids
@@ -247,10 +337,33 @@ pub struct Graph {
 
 /// Files identified by FileId, as well as mapping string filenames to them.
 /// Split from Graph for lifetime reasons.
-#[derive(Default)]
 pub struct GraphFiles {
     pub by_id: DenseMap<FileId, File>,
     by_name: FxHashMap<String, FileId>,
+    /// How file names are canonicalized before being looked up.
+    canon: fn(&mut String),
+    /// When true, backslashes are path separators, and by_name is keyed by
+    /// names with forward slashes.
+    windows_paths: bool,
+    /// When true, names that differ only in case are the same file, and
+    /// by_name is keyed by lowercased names.
+    fold_case: bool,
+}
+
+impl Default for GraphFiles {
+    fn default() -> Self {
+        GraphFiles {
+            by_id: DenseMap::default(),
+            by_name: FxHashMap::default(),
+            canon: if cfg!(windows) {
+                canonicalize_path_windows
+            } else {
+                canonicalize_path
+            },
+            windows_paths: cfg!(windows),
+            fold_case: cfg!(windows),
+        }
+    }
 }
 
 impl Graph {
@@ -259,13 +372,22 @@ impl Graph {
         &self.files.by_id[id]
     }
 
-    /// Add a new Build, generating a BuildId for it.
-    pub fn add_build(&mut self, mut build: Build) -> anyhow::Result<()> {
+    /// The outputs that no build uses, in manifest order.
+    pub fn roots(&self) -> Vec<FileId> {
+        (0..densemap::Index::index(&self.builds.next_id()))
+            .flat_map(|i| self.builds[BuildId::from(i)].outs())
+            .copied()
+            .filter(|&id| self.file(id).dependents.is_empty())
+            .collect()
+    }
+
+    /// Add a new Build, generating a BuildId for it.  An output already
+    /// generated by another Build is an error, or if `duplicate_out_warn` is
+    /// set, a warning and the output stays with the earlier Build.
+    pub fn add_build(&mut self, mut build: Build, duplicate_out_warn: bool) -> anyhow::Result<()> {
         let new_id = self.builds.next_id();
-        for &id in &build.ins.ids {
-            self.files.by_id[id].dependents.push(new_id);
-        }
         let mut fixup_dups = false;
+        let mut taken = Vec::new();
         for &id in &build.outs.ids {
             let f = &mut self.files.by_id[id];
             match f.input {
@@ -277,12 +399,15 @@ impl Graph {
                     );
                 }
                 Some(prev) => {
-                    anyhow::bail!(
+                    let msg = format!(
                         "{}: {:?} is already an output at {}",
-                        build.location,
-                        f.name,
-                        self.builds[prev].location
+                        build.location, f.name, self.builds[prev].location
                     );
+                    if !duplicate_out_warn {
+                        anyhow::bail!(msg);
+                    }
+                    println!("n2: warn: {}, ignoring it here", msg);
+                    taken.push(id);
                 }
                 None => f.input = Some(new_id),
             }
@@ -290,15 +415,98 @@ impl Graph {
         if fixup_dups {
             build.outs.remove_duplicates();
         }
+        if !taken.is_empty() {
+            build.outs.remove(&taken);
+            if build.outs.ids.is_empty() {
+                return Ok(());
+            }
+        }
+        for &id in &build.ins.ids {
+            self.files.by_id[id].dependents.push(new_id);
+        }
         self.builds.push(build);
         Ok(())
     }
+
+    /// Add implicit inputs and outputs, as found in a dyndep file, to an
+    /// existing Build.  Paths already attached to the build are ignored.
+    pub fn add_dyndep_files(
+        &mut self,
+        id: BuildId,
+        ins: &[FileId],
+        outs: &[FileId],
+    ) -> anyhow::Result<()> {
+        for &out in outs {
+            let f = &mut self.files.by_id[out];
+            match f.input {
+                Some(prev) if prev == id => continue,
+                Some(prev) => {
+                    anyhow::bail!(
+                        "{}: {:?} is already an output at {}",
+                        self.builds[id].location,
+                        f.name,
+                        self.builds[prev].location
+                    );
+                }
+                None => f.input = Some(id),
+            }
+            self.builds[id].outs.ids.push(out);
+        }
+        for &input in ins {
+            let build = &mut self.builds[id];
+            if build.ins.ids.contains(&input) {
+                continue;
+            }
+            // Implicit ins live between the explicit and order-only ins.
+            let pos = build.ins.explicit + build.ins.implicit;
+            build.ins.ids.insert(pos, input);
+            build.ins.implicit += 1;
+            self.files.by_id[input].dependents.push(id);
+        }
+        Ok(())
+    }
 }
 
 impl GraphFiles {
+    /// Canonicalize a file name for lookup.
+    pub fn canonicalize(&self, path: &mut String) {
+        (self.canon)(path)
+    }
+
+    /// Treat backslashes as path separators, as on Windows.  Files keep the
+    /// separators they were first seen with, like ninja's slash_bits, so
+    /// that commands aren't handed paths with their separators changed.
+    /// Must be called before any files are added.
+    pub fn set_windows_paths(&mut self) {
+        self.canon = canonicalize_path_windows;
+        self.windows_paths = true;
+    }
+
+    /// Set whether names that differ only in case are the same file, as on
+    /// Windows and default macOS filesystems.  Files keep the spelling they
+    /// were first seen with.  Must be called before any files are added.
+    pub fn set_fold_case(&mut self, fold_case: bool) {
+        self.fold_case = fold_case;
+    }
+
+    /// The key in by_name for a canonical name, if it differs from the name.
+    fn key(&self, file: &str) -> Option<String> {
+        let mut key = None;
+        if self.windows_paths && file.contains('\\') {
+            key = Some(file.replace('\\', "/"));
+        }
+        if self.fold_case {
+            key = Some(key.as_deref().unwrap_or(file).to_lowercase());
+        }
+        key
+    }
+
     /// Look up a file by its name.  Name must have been canonicalized already.
     pub fn lookup(&self, file: &str) -> Option<FileId> {
-        self.by_name.get(file).copied()
+        match self.key(file) {
+            Some(key) => self.by_name.get(&key).copied(),
+            None => self.by_name.get(file).copied(),
+        }
     }
 
     /// Look up a file by its name, adding it if not already present.
@@ -310,11 +518,15 @@ impl GraphFiles {
     /// usages of this function have an owned string easily accessible anyways.
     pub fn id_from_canonical(&mut self, file: String) -> FileId {
         // TODO: so many string copies :<
-        match self.by_name.entry(file) {
+        let (key, name) = match self.key(&file) {
+            Some(key) => (key, Some(file)),
+            None => (file, None),
+        };
+        match self.by_name.entry(key) {
             Entry::Occupied(o) => *o.get(),
             Entry::Vacant(v) => {
                 let id = self.by_id.push(File {
-                    name: v.key().clone(),
+                    name: name.unwrap_or_else(|| v.key().clone()),
                     input: None,
                     dependents: Vec::new(),
                 });
@@ -324,8 +536,16 @@ impl GraphFiles {
         }
     }
 
+    /// Like id_from_canonical, but only copies the name for a new file.
+    pub fn id_from_canonical_ref(&mut self, file: &str) -> FileId {
+        match self.lookup(file) {
+            Some(id) => id,
+            None => self.id_from_canonical(file.to_owned()),
+        }
+    }
+
     pub fn all_ids(&self) -> impl Iterator<Item = FileId> {
-        (0..self.by_id.next_id().0).map(|id| FileId(id))
+        (0..self.by_id.next_id().0).map(FileId)
     }
 }
 
@@ -372,18 +592,66 @@ impl FileState {
         self.0.set_grow(id, Some(mtime), None);
         Ok(mtime)
     }
+
+    /// Record an mtime for a file without consulting the disk.
+    pub fn set(&mut self, id: FileId, mtime: MTime) {
+        self.0.set_grow(id, Some(mtime), None);
+    }
 }
 
 #[derive(Default)]
-pub struct Hashes(HashMap<BuildId, BuildHash>);
+pub struct Hashes {
+    hashes: HashMap<BuildId, BuildHash>,
+    /// Hashes over the contents rather than mtimes of files, as recorded in
+    /// the content hash mode.
+    content: HashMap<BuildId, BuildHash>,
+    /// The file content digests the content hashes were computed from.
+    pub digests: Digests,
+    /// Builds with no hash whose discovered deps were imported from ninja's
+    /// deps log, which may be up to date by ninja's mtime rules.
+    imported: HashSet<BuildId>,
+}
 
 impl Hashes {
     pub fn set(&mut self, id: BuildId, hash: BuildHash) {
-        self.0.insert(id, hash);
+        self.hashes.insert(id, hash);
     }
 
     pub fn get(&self, id: BuildId) -> Option<BuildHash> {
-        self.0.get(&id).copied()
+        self.hashes.get(&id).copied()
+    }
+
+    pub fn set_content(&mut self, id: BuildId, hash: BuildHash) {
+        self.content.insert(id, hash);
+    }
+
+    pub fn get_content(&self, id: BuildId) -> Option<BuildHash> {
+        self.content.get(&id).copied()
+    }
+
+    /// The builds with known content hashes, in no particular order.
+    pub fn iter_content(&self) -> impl Iterator<Item = (BuildId, BuildHash)> + '_ {
+        self.content.iter().map(|(&id, &hash)| (id, hash))
+    }
+
+    /// The builds with known hashes, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (BuildId, BuildHash)> + '_ {
+        self.hashes.iter().map(|(&id, &hash)| (id, hash))
+    }
+
+    /// Drop what is known about a build, as if it had never run.
+    pub fn forget(&mut self, id: BuildId) {
+        self.hashes.remove(&id);
+        self.content.remove(&id);
+        self.imported.remove(&id);
+    }
+
+    pub fn set_imported(&mut self, id: BuildId) {
+        self.imported.insert(id);
+    }
+
+    pub fn is_imported(&self, id: BuildId) -> bool {
+        self.imported.contains(&id)
     }
 }
 
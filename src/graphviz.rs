@@ -0,0 +1,219 @@
+//! Prints the build graph in Graphviz's dot language, shaped like the output
+//! of `ninja -t graph`: files are boxes, and builds are ellipses labeled with
+//! their rule, or for one input and one output, just a labeled arrow.
+
+use crate::densemap::Index;
+use crate::graph::{BuildId, FileId, Graph};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Write;
+
+#[derive(Default)]
+pub struct Options {
+    /// How many levels of builds below the targets to show; None for all.
+    pub depth: Option<usize>,
+    /// Draw one node per rule rather than the files and builds, for graphs
+    /// too large for dot.
+    pub collapse: bool,
+}
+
+/// Quote a string for dot.
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The builds producing the targets and what they depend on, breadth first
+/// to the given depth.
+fn reachable(graph: &Graph, targets: &[FileId], depth: Option<usize>) -> Vec<BuildId> {
+    let mut seen = HashSet::new();
+    let mut builds = Vec::new();
+    let mut queue: VecDeque<(FileId, usize)> = targets.iter().map(|&id| (id, 1)).collect();
+    while let Some((id, level)) = queue.pop_front() {
+        let Some(bid) = graph.file(id).input else {
+            continue;
+        };
+        if depth.is_some_and(|depth| level > depth) || !seen.insert(bid) {
+            continue;
+        }
+        builds.push(bid);
+        for &input in graph.builds[bid].ordering_ins() {
+            queue.push_back((input, level + 1));
+        }
+    }
+    builds
+}
+
+fn print_builds(out: &mut String, graph: &Graph, builds: &[BuildId]) {
+    let mut files = HashSet::new();
+    let mut file = |out: &mut String, id: FileId| {
+        if files.insert(id) {
+            let name = &graph.file(id).name;
+            writeln!(out, "\"f{}\" [label={}]", id.index(), quote(name)).unwrap();
+        }
+    };
+    for &bid in builds {
+        let build = &graph.builds[bid];
+        for &id in build.ordering_ins().iter().chain(build.outs()) {
+            file(out, id);
+        }
+        let rule = quote(&build.rule);
+        if let ([input], [output]) = (build.ordering_ins(), build.outs()) {
+            if build.ins.explicit == 1 {
+                let (input, output) = (input.index(), output.index());
+                writeln!(out, "\"f{}\" -> \"f{}\" [label={}]", input, output, rule).unwrap();
+                continue;
+            }
+        }
+        let node = format!("\"b{}\"", bid.index());
+        writeln!(out, "{} [label={}, shape=ellipse]", node, rule).unwrap();
+        for &id in build.outs() {
+            writeln!(out, "{} -> \"f{}\"", node, id.index()).unwrap();
+        }
+        let ins = build.ordering_ins();
+        for (i, &id) in ins.iter().enumerate() {
+            let style = if i < build.ins.explicit {
+                ""
+            } else if i < build.ins.explicit + build.ins.implicit {
+                " style=dashed"
+            } else {
+                " style=dotted"
+            };
+            writeln!(
+                out,
+                "\"f{}\" -> {} [arrowhead=none{}]",
+                id.index(),
+                node,
+                style
+            )
+            .unwrap();
+        }
+    }
+}
+
+/// Draw each rule once, labeled with how many builds use it, with an arrow
+/// wherever some build using one rule produces an input to one using another.
+fn print_rules(out: &mut String, graph: &Graph, builds: &[BuildId]) {
+    let mut rules: Vec<&str> = Vec::new();
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    let mut arrows: Vec<(&str, &str)> = Vec::new();
+    for &bid in builds {
+        let build = &graph.builds[bid];
+        let count = counts.entry(&build.rule).or_default();
+        if *count == 0 {
+            rules.push(&build.rule);
+        }
+        *count += 1;
+        for &id in build.ordering_ins() {
+            if let Some(from) = graph.file(id).input {
+                let arrow = (graph.builds[from].rule.as_str(), build.rule.as_str());
+                if !arrows.contains(&arrow) {
+                    arrows.push(arrow);
+                }
+            }
+        }
+    }
+    for rule in rules {
+        let label = format!("{} ({})", rule, counts[rule]);
+        writeln!(
+            out,
+            "{} [label={}, shape=ellipse]",
+            quote(rule),
+            quote(&label)
+        )
+        .unwrap();
+    }
+    for (from, to) in arrows {
+        writeln!(out, "{} -> {}", quote(from), quote(to)).unwrap();
+    }
+}
+
+/// Format the part of the graph needed for the targets.
+pub fn format(graph: &Graph, targets: &[FileId], options: &Options) -> String {
+    let builds = reachable(graph, targets, options.depth);
+    let mut out = String::from(
+        "digraph ninja {
+rankdir=\"LR\"
+node [fontsize=10, shape=box, height=0.25]
+edge [fontsize=10]
+",
+    );
+    if options.collapse {
+        print_rules(&mut out, graph, &builds);
+    } else {
+        print_builds(&mut out, graph, &builds);
+    }
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = "
+rule cc
+  command = cc $in
+rule link
+  command = ld $in
+build a.o: cc a.c
+build b.o: cc b.c | b.h || gen
+build out: link a.o b.o
+";
+
+    fn dot(options: Options) -> anyhow::Result<String> {
+        let graph = crate::load::parse("build.ninja", MANIFEST.as_bytes().to_vec())?;
+        let out = graph.files.lookup("out").unwrap();
+        let text = format(&graph, &[out], &options);
+        Ok(text.lines().skip(4).collect::<Vec<_>>().join("\n"))
+    }
+
+    #[test]
+    fn builds() -> anyhow::Result<()> {
+        assert_eq!(
+            dot(Options::default())?,
+            r#""f1" [label="a.o"]
+"f5" [label="b.o"]
+"f6" [label="out"]
+"b2" [label="link", shape=ellipse]
+"b2" -> "f6"
+"f1" -> "b2" [arrowhead=none]
+"f5" -> "b2" [arrowhead=none]
+"f0" [label="a.c"]
+"f0" -> "f1" [label="cc"]
+"f2" [label="b.c"]
+"f3" [label="b.h"]
+"f4" [label="gen"]
+"b1" [label="cc", shape=ellipse]
+"b1" -> "f5"
+"f2" -> "b1" [arrowhead=none]
+"f3" -> "b1" [arrowhead=none style=dashed]
+"f4" -> "b1" [arrowhead=none style=dotted]
+}"#
+        );
+        let shallow = dot(Options {
+            depth: Some(1),
+            collapse: false,
+        })?;
+        assert!(!shallow.contains("a.c"));
+        Ok(())
+    }
+
+    #[test]
+    fn collapse() -> anyhow::Result<()> {
+        assert_eq!(
+            dot(Options {
+                depth: None,
+                collapse: true
+            })?,
+            r#""link" [label="link (1)", shape=ellipse]
+"cc" [label="cc (2)", shape=ellipse]
+"cc" -> "link"
+}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote(r#"a "b" \c"#), r#""a \"b\" \\c""#);
+    }
+}
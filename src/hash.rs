@@ -5,10 +5,13 @@
 //!   https://neugierig.org/software/blog/2022/03/n2.html
 
 use crate::graph::{Build, FileId, FileState, GraphFiles, MTime, RspFile};
+use rustc_hash::FxHasher;
 use std::{
-    collections::hash_map::DefaultHasher,
+    collections::{hash_map::DefaultHasher, HashMap},
     fmt::Write,
     hash::{Hash, Hasher},
+    io::Read,
+    path::Path,
     time::SystemTime,
 };
 
@@ -102,9 +105,12 @@ fn build_manifest<M: Manifest>(
 ) {
     manifest.write_files("in", files, file_state, build.dirtying_ins());
     manifest.write_files("discovered", files, file_state, build.discovered_ins());
-    manifest.write_cmdline(build.cmdline.as_deref().unwrap_or(""));
-    if let Some(rspfile) = &build.rspfile {
-        manifest.write_rsp(rspfile);
+    // As in ninja, only the inputs of a generator build make it out of date.
+    if !build.generator {
+        manifest.write_cmdline(build.cmdline.as_deref().unwrap_or(""));
+        if let Some(rspfile) = &build.rspfile {
+            manifest.write_rsp(rspfile);
+        }
     }
     manifest.write_files("out", files, file_state, build.outs());
 }
@@ -119,6 +125,145 @@ pub fn hash_build(files: &GraphFiles, file_state: &FileState, build: &Build) ->
     hasher.finish()
 }
 
+/// Content digests of files, as used by the content hash mode in place of
+/// mtimes.  Each digest is valid for the mtime, size and change time the file
+/// had when it was taken, so that files that look unchanged needn't be read
+/// again.
+#[derive(Default)]
+pub struct Digests(HashMap<FileId, Digest>);
+
+/// A file's content digest, and the attributes it had when it was taken.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Digest {
+    pub mtime: SystemTime,
+    pub len: u64,
+    /// On Unix, the inode change time in nanoseconds, which unlike the mtime
+    /// can't be set back, e.g. by tools that restore timestamps; else 0.
+    pub ctime: u64,
+    pub digest: u64,
+}
+
+impl Digest {
+    /// Whether a file with the given metadata looks unchanged since the
+    /// digest was taken.
+    pub fn matches(&self, meta: &std::fs::Metadata) -> std::io::Result<bool> {
+        Ok(self.mtime == meta.modified()? && self.len == meta.len() && self.ctime == ctime(meta))
+    }
+}
+
+#[cfg(unix)]
+pub fn ctime(meta: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    (meta.ctime() as u64)
+        .wrapping_mul(1_000_000_000)
+        .wrapping_add(meta.ctime_nsec() as u64)
+}
+
+#[cfg(not(unix))]
+pub fn ctime(_meta: &std::fs::Metadata) -> u64 {
+    0
+}
+
+impl Digests {
+    pub fn set(&mut self, id: FileId, digest: Digest) {
+        self.0.insert(id, digest);
+    }
+
+    pub fn get(&self, id: FileId) -> Option<&Digest> {
+        self.0.get(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (FileId, &Digest)> {
+        self.0.iter().map(|(&id, digest)| (id, digest))
+    }
+}
+
+/// A digest standing in for the content of a file that has none, like a
+/// directory or a phony output, from its mtime.
+pub fn digest_mtime(mtime: SystemTime) -> u64 {
+    let mut h = FxHasher::default();
+    mtime.hash(&mut h);
+    h.finish()
+}
+
+/// Hash a file's content, reading it in chunks so large files aren't held in
+/// memory.
+///
+/// This uses SipHash rather than FxHash: FxHash works a word at a time, so it
+/// misses leading zero words and depends on how the reads split the content.
+/// SipHash streams the bytes the same however they're split, and the length
+/// is mixed in at the end.
+pub fn digest_file(path: &Path, meta: &std::fs::Metadata) -> std::io::Result<u64> {
+    if meta.is_dir() {
+        return Ok(digest_mtime(meta.modified()?));
+    }
+    let mut h = DefaultHasher::new();
+    let mut f = std::fs::File::open(path)?;
+    let mut buf = vec![0; 64 << 10];
+    let mut len: u64 = 0;
+    loop {
+        let n = f.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        h.write(&buf[..n]);
+        len += n as u64;
+    }
+    h.write_u64(len);
+    Ok(h.finish())
+}
+
+/// A BuildHasher over file contents rather than mtimes, for the content hash
+/// mode.  Digests must be present for all the files.
+struct ContentHash<'a> {
+    hash: TerseHash,
+    digests: &'a Digests,
+}
+
+impl Manifest for ContentHash<'_> {
+    fn write_files(
+        &mut self,
+        _desc: &str,
+        files: &GraphFiles,
+        _file_state: &FileState,
+        ids: &[FileId],
+    ) {
+        for &id in ids {
+            let name = &files.by_id[id].name;
+            let digest = self
+                .digests
+                .get(id)
+                .unwrap_or_else(|| panic!("no digest for {:?}", name));
+            self.hash.write_string(name);
+            digest.digest.hash(&mut self.hash.0);
+        }
+        self.hash.write_separator();
+    }
+
+    fn write_cmdline(&mut self, cmdline: &str) {
+        self.hash.write_cmdline(cmdline);
+    }
+
+    fn write_rsp(&mut self, rspfile: &RspFile) {
+        self.hash.write_rsp(rspfile);
+    }
+}
+
+/// Like hash_build, but hashing the contents of files rather than mtimes.
+pub fn hash_build_content(
+    files: &GraphFiles,
+    file_state: &FileState,
+    digests: &Digests,
+    build: &Build,
+) -> BuildHash {
+    let mut hasher = ContentHash {
+        hash: TerseHash::default(),
+        digests,
+    };
+    build_manifest(&mut hasher, files, file_state, build);
+    hasher.hash.finish()
+}
+
 /// A BuildHasher that records human-readable text for "-d explain" debugging.
 #[derive(Default)]
 struct ExplainHash {
@@ -0,0 +1,231 @@
+//! Lists the files targets are built from, as `ninja -t inputs` does, and the
+//! other way around, the outputs built from files.
+//!
+//! Phony builds that alias other files are looked through: their outputs are
+//! never listed, but the files they stand for are.
+
+use crate::graph::{BuildId, FileId, Graph};
+use std::collections::HashSet;
+use std::fmt::Write;
+
+#[derive(Default)]
+pub struct Options {
+    /// With inputs, list only the inputs of the builds producing the targets.
+    pub direct: bool,
+    /// With inputs, list only files no build produces.
+    pub sources: bool,
+    /// Don't follow order-only inputs.
+    pub skip_order_only: bool,
+}
+
+/// Whether a build is a phony one standing for its inputs.  A phony build
+/// with no inputs instead marks a file that may be missing, so that file is
+/// treated as a source.
+fn is_alias(graph: &Graph, id: BuildId) -> bool {
+    let build = &graph.builds[id];
+    build.cmdline.is_none() && !build.ins.ids.is_empty()
+}
+
+/// The inputs a build needs, optionally leaving out the order-only ones.
+fn build_ins<'a>(graph: &'a Graph, id: BuildId, options: &Options) -> &'a [FileId] {
+    let build = &graph.builds[id];
+    if options.skip_order_only {
+        build.dirtying_ins()
+    } else {
+        build.ordering_ins()
+    }
+}
+
+struct Inputs<'a> {
+    graph: &'a Graph,
+    options: &'a Options,
+    seen: HashSet<FileId>,
+    out: String,
+}
+
+impl Inputs<'_> {
+    /// Visit a build's inputs depth first, each file before its own inputs.
+    /// Walks with an explicit stack, as chains of builds can be deep.
+    fn visit_build(&mut self, id: BuildId) {
+        let mut stack: Vec<FileId> = Vec::new();
+        stack.extend(build_ins(self.graph, id, self.options).iter().rev());
+        while let Some(id) = stack.pop() {
+            if !self.seen.insert(id) {
+                continue;
+            }
+            let file = self.graph.file(id);
+            match file.input {
+                Some(bid) if is_alias(self.graph, bid) => {
+                    stack.extend(build_ins(self.graph, bid, self.options).iter().rev());
+                    continue;
+                }
+                Some(bid) if self.options.sources && self.graph.builds[bid].cmdline.is_some() => {}
+                _ => writeln!(self.out, "{}", file.name).unwrap(),
+            }
+            if let Some(bid) = file.input.filter(|_| !self.options.direct) {
+                stack.extend(build_ins(self.graph, bid, self.options).iter().rev());
+            }
+        }
+    }
+}
+
+/// Format the files the targets are built from, each once, in the order
+/// they're reached walking each build's inputs in turn.
+pub fn inputs(graph: &Graph, targets: &[FileId], options: &Options) -> String {
+    let mut inputs = Inputs {
+        graph,
+        options,
+        seen: HashSet::new(),
+        out: String::new(),
+    };
+    for &target in targets {
+        // An alias's inputs are the inputs of what it stands for, so look
+        // through it before deciding what the target's own inputs are.
+        let mut pending = vec![target];
+        while let Some(id) = pending.pop() {
+            let Some(bid) = graph.file(id).input else {
+                continue;
+            };
+            if is_alias(graph, bid) {
+                inputs.seen.insert(id);
+                pending.extend(build_ins(graph, bid, options).iter().rev());
+            } else {
+                inputs.visit_build(bid);
+            }
+        }
+    }
+    inputs.out
+}
+
+/// Format the outputs that depend on the files, directly or through other
+/// outputs, each once, in the order they're reached.
+pub fn outputs(graph: &Graph, files: &[FileId], options: &Options) -> String {
+    let mut out = String::new();
+    let mut seen: HashSet<FileId> = files.iter().copied().collect();
+    let mut queue: Vec<FileId> = files.to_vec();
+    let mut next = 0;
+    while let Some(&id) = queue.get(next) {
+        next += 1;
+        for &bid in &graph.file(id).dependents {
+            // Validations don't depend on the file, only run alongside it.
+            if !build_ins(graph, bid, options).contains(&id) {
+                continue;
+            }
+            let alias = is_alias(graph, bid);
+            for &output in graph.builds[bid].outs() {
+                if !seen.insert(output) {
+                    continue;
+                }
+                if !alias {
+                    writeln!(out, "{}", graph.file(output).name).unwrap();
+                }
+                queue.push(output);
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = "
+rule cc
+  command = cc -c $in -o $out
+rule link
+  command = ld $in -o $out
+build gen.h: cc gen.in
+build headers: phony gen.h a.h
+build a.o: cc a.c | headers || stamp
+build b.o: cc b.c | a.h
+build out: link a.o b.o
+build all: phony out
+build stamp: phony
+";
+
+    fn run(
+        f: fn(&Graph, &[FileId], &Options) -> String,
+        names: &[&str],
+        options: Options,
+    ) -> anyhow::Result<Vec<String>> {
+        let graph = crate::load::parse("build.ninja", MANIFEST.as_bytes().to_vec())?;
+        let ids: Vec<FileId> = names
+            .iter()
+            .map(|name| graph.files.lookup(name).unwrap())
+            .collect();
+        Ok(f(&graph, &ids, &options)
+            .lines()
+            .map(str::to_owned)
+            .collect())
+    }
+
+    #[test]
+    fn inputs_of_targets() -> anyhow::Result<()> {
+        assert_eq!(
+            run(inputs, &["all"], Options::default())?,
+            ["a.o", "a.c", "gen.h", "gen.in", "a.h", "stamp", "b.o", "b.c"]
+        );
+        let direct = Options {
+            direct: true,
+            ..Options::default()
+        };
+        assert_eq!(
+            run(inputs, &["a.o"], direct)?,
+            ["a.c", "gen.h", "a.h", "stamp"]
+        );
+        let sources = Options {
+            sources: true,
+            skip_order_only: true,
+            ..Options::default()
+        };
+        assert_eq!(
+            run(inputs, &["out"], sources)?,
+            ["a.c", "gen.in", "a.h", "b.c"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn outputs_of_files() -> anyhow::Result<()> {
+        assert_eq!(
+            run(outputs, &["gen.in"], Options::default())?,
+            ["gen.h", "a.o", "out"]
+        );
+        assert_eq!(
+            run(outputs, &["a.h"], Options::default())?,
+            ["b.o", "a.o", "out"]
+        );
+        assert_eq!(
+            run(outputs, &["stamp"], Options::default())?,
+            ["a.o", "out"]
+        );
+        let skip = Options {
+            skip_order_only: true,
+            ..Options::default()
+        };
+        assert!(run(outputs, &["stamp"], skip)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn deep_chain() -> anyhow::Result<()> {
+        let mut manifest = String::from("rule cp\n  command = cp $in $out\n");
+        for i in 1..100_000 {
+            writeln!(manifest, "build f{}: cp f{}", i, i - 1).unwrap();
+        }
+        let graph = crate::load::parse("build.ninja", manifest.into_bytes())?;
+        let last = graph.files.lookup("f99999").unwrap();
+        let out = inputs(&graph, &[last], &Options::default());
+        assert!(out.starts_with("f99998\nf99997\n"));
+        assert_eq!(out.lines().count(), 99_999);
+        let first = graph.files.lookup("f0").unwrap();
+        assert_eq!(
+            outputs(&graph, &[first], &Options::default())
+                .lines()
+                .count(),
+            99_999
+        );
+        Ok(())
+    }
+}
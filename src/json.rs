@@ -0,0 +1,32 @@
+//! Just enough JSON writing for n2's machine-readable outputs.
+
+use std::fmt::Write as _;
+
+/// Append `s` to `out` as a JSON string literal.
+pub fn write_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape() {
+        let mut out = String::new();
+        write_str(&mut out, "a \"b\"\\\n\x1b[1m");
+        assert_eq!(out, r#""a \"b\"\\\n\u001b[1m""#);
+    }
+}
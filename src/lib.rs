@@ -1,11 +1,24 @@
 pub mod canon;
+mod clean;
+mod commands;
+mod compdb;
 mod db;
 mod densemap;
 mod depfile;
+mod deps;
+mod dump;
+mod dyndep;
 mod eval;
+mod failures;
 mod graph;
+mod graphviz;
 mod hash;
+mod inputs;
+mod json;
 pub mod load;
+mod lock;
+mod ninja_deps;
+mod ninja_log;
 pub mod parse;
 mod process;
 #[cfg(unix)]
@@ -15,13 +28,17 @@ mod process_win;
 mod progress;
 mod progress_dumb;
 mod progress_fancy;
+mod query;
 pub mod run;
 pub mod scanner;
+pub mod shell;
 mod signal;
 mod smallmap;
+mod targets;
 mod task;
 mod terminal;
 mod trace;
+mod version;
 mod work;
 
 #[cfg(not(any(miri, windows, target_arch = "wasm32")))]
@@ -1,17 +1,19 @@
 //! Graph loading: runs .ninja parsing and constructs the build graph from it.
 
 use crate::{
-    canon::{canonicalize_path, to_owned_canon_path},
     db,
     eval::{self, EvalPart, EvalString},
     graph::{self, FileId, RspFile},
     parse::{self, Statement},
-    scanner,
+    scanner::ColorMode,
+    shell,
     smallmap::SmallMap,
     trace,
 };
 use anyhow::{anyhow, bail};
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Read;
 use std::path::PathBuf;
 use std::{borrow::Cow, path::Path};
 
@@ -19,6 +21,8 @@ use std::{borrow::Cow, path::Path};
 struct BuildImplicitVars<'a> {
     graph: &'a graph::Graph,
     build: &'a graph::Build,
+    /// Whether to shell-quote the paths in $in and $out, as for `command`.
+    quote: bool,
 }
 impl<'a> BuildImplicitVars<'a> {
     fn file_list(&self, ids: &[FileId], sep: char) -> String {
@@ -27,13 +31,19 @@ impl<'a> BuildImplicitVars<'a> {
             if !out.is_empty() {
                 out.push(sep);
             }
-            out.push_str(&self.graph.file(id).name);
+            let name = &self.graph.file(id).name;
+            // The _newline forms are for rspfiles, read a line at a time.
+            if self.quote && sep == ' ' {
+                out.push_str(&shell::quote(name));
+            } else {
+                out.push_str(name);
+            }
         }
         out
     }
 }
 impl<'a> eval::Env for BuildImplicitVars<'a> {
-    fn get_var(&self, var: &str) -> Option<EvalString<Cow<str>>> {
+    fn get_var(&self, var: &str) -> Option<EvalString<Cow<'_, str>>> {
         let string_to_evalstring =
             |s: String| Some(EvalString::new(vec![EvalPart::Literal(Cow::Owned(s))]));
         match var {
@@ -46,22 +56,194 @@ impl<'a> eval::Env for BuildImplicitVars<'a> {
     }
 }
 
+/// How the paths in include and subninja statements are resolved.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IncludeResolution {
+    /// Relative to the working directory, as ninja does.
+    #[default]
+    WorkingDir,
+    /// Relative to the directory of the file containing the statement.
+    RelativeToIncluder,
+}
+
+/// Options controlling how manifests are loaded.
+#[derive(Clone, Default)]
+pub struct Options {
+    /// When true, redefining a rule is a warning rather than an error, and the
+    /// later definition wins.
+    pub duplicate_rule_warn: bool,
+    /// When true, an output generated by more than one build is a warning
+    /// rather than an error, and the first build generating it wins.
+    pub duplicate_out_warn: bool,
+    /// When true, a rule binding a variable that is neither one ninja
+    /// consults nor referenced by the rule's other bindings is an error
+    /// rather than a warning.
+    pub unknown_rule_var_err: bool,
+    /// When true, reject manifest constructs that ninja would reject.
+    pub strict: bool,
+    /// Whether to color parse errors.
+    pub color: ColorMode,
+    /// When true, warn about references to variables that are bound nowhere
+    /// in the manifest, which are likely typos.
+    pub undefined_var_warn: bool,
+    /// File-scope variable bindings from the command line, which take
+    /// precedence over file-scope assignments in every manifest file.
+    pub var_overrides: Vec<(String, String)>,
+    /// When true, variables bound nowhere in the manifest are taken from the
+    /// process environment, as make does.  Ninja doesn't do this.
+    pub env_fallback: bool,
+    /// When true, print the environment variables consulted by env_fallback.
+    pub log_env_fallback: bool,
+    /// When true, treat backslashes in paths as separators, as is always
+    /// done on Windows, so that `a\b` and `a/b` name the same file.
+    pub windows_paths: bool,
+    /// Whether names differing only in case are the same file; by default
+    /// only on Windows.
+    pub fold_case: Option<bool>,
+    /// How include and subninja paths are resolved.
+    pub include_resolution: IncludeResolution,
+    /// When set, subninjas are only read as needed to find builds for these
+    /// targets; an empty list means the default targets, which needs them
+    /// all.  Unlike ninja, this assumes that files generated by a subninja
+    /// that already exist on disk aren't also generated by builds needed
+    /// from elsewhere.
+    pub lazy_subninjas: Option<Vec<String>>,
+    /// When true, the database is read but left untouched, for dry runs.
+    pub read_only_db: bool,
+    /// When true, the database isn't read at all, for tools that only need
+    /// the manifest.
+    pub no_db: bool,
+}
+
+/// Variables bound anywhere in the manifest, and the references to check
+/// against them once it is loaded, for Options::undefined_var_warn.
+#[derive(Default)]
+struct UndefinedVarCheck {
+    defined: HashSet<String>,
+    /// Rules used by builds.
+    used_rules: HashSet<String>,
+    /// References from build bindings, once per binding name and variable.
+    build_refs: Vec<(String, String, String)>,
+    seen: HashSet<(String, String)>,
+}
+
+/// A rule as defined in a manifest.
+#[derive(Default)]
+struct Rule {
+    /// list of (key, val)
+    vars: SmallMap<String, eval::OwnedEvalString>,
+    /// The line of each of vars' bindings, in the same order.
+    var_lines: Vec<usize>,
+    /// Where the rule was defined; absent for the built-in phony rule.
+    location: Option<graph::FileLoc>,
+}
+
+/// A variable lookup environment for a rule's own bindings, which are
+/// evaluated in the scope of the build using the rule.
+struct RuleVars<'a> {
+    vars: &'a SmallMap<String, eval::OwnedEvalString>,
+    /// The build's implicit vars, the build's bindings and the file scope.
+    envs: [&'a dyn eval::Env; 3],
+    /// The indexes in vars of the bindings being expanded, innermost last.
+    stack: RefCell<Vec<usize>>,
+    /// The first cycle found among the rule's bindings, as the part of stack
+    /// from the binding expanded again.  The reference that closes the cycle
+    /// expands to nothing.
+    cycle: RefCell<Option<Vec<usize>>>,
+}
+impl<'a> RuleVars<'a> {
+    /// Evaluate the rule binding `key`, if any.  A cycle fails with its
+    /// description, e.g. "a -> b -> a", and the index of the binding closing it.
+    fn evaluate(&self, key: &str) -> Result<Option<String>, (String, usize)> {
+        let Some(index) = self.index(key) else {
+            return Ok(None);
+        };
+        *self.stack.borrow_mut() = vec![index];
+        let val = self.expand(index);
+        let Some(cycle) = self.cycle.take() else {
+            return Ok(Some(val));
+        };
+        let names = self.vars.iter().as_slice();
+        let mut chain: Vec<&str> = cycle.iter().map(|&i| names[i].0.as_str()).collect();
+        chain.push(chain[0]);
+        Err((chain.join(" -> "), *cycle.last().unwrap()))
+    }
+
+    fn index(&self, var: &str) -> Option<usize> {
+        self.vars.iter().position(|(name, _)| name == var)
+    }
+
+    fn expand(&self, index: usize) -> String {
+        let [implicit_vars, build_vars, env] = self.envs;
+        let (_, val) = &self.vars.iter().as_slice()[index];
+        val.evaluate(&[implicit_vars, build_vars, self, env])
+    }
+}
+impl<'a> eval::Env for RuleVars<'a> {
+    fn get_var(&self, var: &str) -> Option<EvalString<Cow<'_, str>>> {
+        let index = self.index(var)?;
+        {
+            let mut stack = self.stack.borrow_mut();
+            if let Some(pos) = stack.iter().position(|&i| i == index) {
+                let mut cycle = self.cycle.borrow_mut();
+                cycle.get_or_insert_with(|| stack[pos..].to_vec());
+                return Some(EvalString::new(Vec::new()));
+            }
+            stack.push(index);
+        }
+        let val = self.expand(index);
+        self.stack.borrow_mut().pop();
+        Some(EvalString::new(vec![EvalPart::Literal(Cow::Owned(val))]))
+    }
+}
+
+/// A subninja not yet read, for Options::lazy_subninjas.
+struct DeferredSubninja {
+    id: FileId,
+    /// The scope at the subninja statement.
+    scope: eval::Vars<'static>,
+    /// Loader::reading at the subninja statement, and the statement's line.
+    reading: Vec<(FileId, Option<(&'static str, usize)>)>,
+    line: usize,
+}
+
 /// Internal state used while loading.
 #[derive(Default)]
 pub struct Loader {
     graph: graph::Graph,
-    default: Vec<FileId>,
-    /// rule name -> list of (key, val)
-    rules: HashMap<String, SmallMap<String, eval::EvalString<String>>>,
+    /// The targets of `default` statements, in the order first seen, each
+    /// with the location of the statement naming it.
+    default: Vec<(FileId, graph::FileLoc)>,
+    default_seen: HashSet<FileId>,
+    rules: HashMap<String, Rule>,
     pools: SmallMap<String, usize>,
+    /// The top-level `builddir` binding, which places n2's own state files.
     builddir: Option<String>,
+    /// Whether a subninja is being loaded.  Like ninja, `builddir` is only
+    /// taken from the top-level file and the files it includes.
+    in_subninja: bool,
+    /// Files being read, outermost first, each with the kind and line of the
+    /// statement in the previous file that reached it, to detect cycles.
+    reading: Vec<(FileId, Option<(&'static str, usize)>)>,
+    /// Subninjas deferred by Options::lazy_subninjas, in manifest order.
+    deferred: VecDeque<DeferredSubninja>,
+    /// Scratch space for evaluate_path.
+    path_buf: String,
+    undefined_var_check: UndefinedVarCheck,
+    /// Shared by all file scopes when Options::env_fallback is set.
+    env_fallback: Option<std::rc::Rc<eval::EnvFallback>>,
+    /// Whether a ninja_required_version has been checked in any file yet.
+    required_version_seen: bool,
+    options: Options,
 }
 
 impl Loader {
     pub fn new() -> Self {
         let mut loader = Loader::default();
 
-        loader.rules.insert("phony".to_owned(), SmallMap::default());
+        loader.rules.insert("phony".to_owned(), Rule::default());
+        // Builds in the console pool also get the terminal; see Build::is_console.
+        loader.pools.insert("console".to_owned(), 1);
 
         loader
     }
@@ -71,12 +253,20 @@ impl Loader {
         // Perf: this is called while parsing build.ninja files.  We go to
         // some effort to avoid allocating in the common case of a path that
         // refers to a file that is already known.
-        canonicalize_path(&mut path);
+        self.graph.files.canonicalize(&mut path);
         self.graph.files.id_from_canonical(path)
     }
 
     fn evaluate_path(&mut self, path: EvalString<&str>, envs: &[&dyn eval::Env]) -> FileId {
-        self.path(path.evaluate(envs))
+        // Evaluate into a reused buffer, so that paths naming known files,
+        // the common case, don't allocate.
+        let mut buf = std::mem::take(&mut self.path_buf);
+        buf.clear();
+        path.evaluate_into(envs, &mut buf);
+        self.graph.files.canonicalize(&mut buf);
+        let id = self.graph.files.id_from_canonical_ref(&buf);
+        self.path_buf = buf;
+        id
     }
 
     fn evaluate_paths(
@@ -115,41 +305,144 @@ impl Loader {
             ins,
             outs,
         );
+        if self.options.strict {
+            // n2 drops repeated outputs with a warning; ninja rejects them.
+            // Compared as FileIds, so `a` and `./a` are the same output.
+            let ids = &build.outs.ids;
+            for (i, &id) in ids.iter().enumerate() {
+                if ids[..i].contains(&id) || self.graph.file(id).input.is_some() {
+                    bail!(
+                        "{}: multiple rules generate {}",
+                        build.location,
+                        self.graph.file(id).name
+                    );
+                }
+            }
+        }
 
-        let rule = match self.rules.get(b.rule) {
-            Some(r) => r,
-            None => bail!("unknown rule {:?}", b.rule),
-        };
+        if !self.rules.contains_key(b.rule) {
+            let err = self.unknown_name("rule", b.rule);
+            bail!("{}: {}", build.location, err);
+        }
+        let rule = &self.rules[b.rule];
 
+        // A build with one of its own outputs as an input would never be
+        // up to date.  Like ninja, a phony build doing so is let off with a
+        // warning, as older CMake generated such aliases.
+        let ordering = build.ins.explicit + build.ins.implicit + build.ins.order_only;
+        let own_outs: Vec<FileId> = build.ins.ids[..ordering]
+            .iter()
+            .copied()
+            .filter(|id| build.outs.ids.contains(id))
+            .collect();
+        for id in own_outs {
+            let name = &self.graph.file(id).name;
+            if b.rule != "phony" {
+                bail!(
+                    "{}: {:?} is both an input and an output of this build",
+                    build.location,
+                    name
+                );
+            }
+            println!(
+                "n2: warn: {}: phony {:?} lists itself as an input, ignoring it",
+                build.location, name
+            );
+            build.ins.remove(id);
+        }
+        if self.options.undefined_var_warn {
+            let check = &mut self.undefined_var_check;
+            if !check.used_rules.contains(b.rule) {
+                check.used_rules.insert(b.rule.to_owned());
+            }
+            for (name, val) in b.vars.iter() {
+                check.defined.insert(name.to_string());
+                for var in val.var_refs() {
+                    if check.seen.insert((name.to_string(), var.to_owned())) {
+                        check.build_refs.push((
+                            build.location.to_string(),
+                            name.to_string(),
+                            var.to_owned(),
+                        ));
+                    }
+                }
+            }
+        }
+
+        // Like ninja, paths are only quoted within the command.
         let implicit_vars = BuildImplicitVars {
             graph: &self.graph,
             build: &build,
+            quote: false,
+        };
+        let quoted_implicit_vars = BuildImplicitVars {
+            quote: true,
+            ..implicit_vars
         };
 
         // temp variable in order to not move all of b into the closure
         let build_vars = &b.vars;
-        let lookup = |key: &str| -> Option<String> {
-            // Look up `key = ...` binding in build and rule block.
-            // See "Variable scope" in the design notes.
-            Some(match build_vars.get(key) {
-                Some(val) => val.evaluate(&[env]),
-                None => rule.get(key)?.evaluate(&[&implicit_vars, build_vars, env]),
-            })
-        };
+        let lookup_in =
+            |key: &str, implicit_vars: &BuildImplicitVars| -> anyhow::Result<Option<String>> {
+                // Look up `key = ...` binding in build and rule block.
+                // See "Variable scope" in the design notes.
+                if let Some(val) = build_vars.get(key) {
+                    return Ok(Some(val.evaluate(&[env])));
+                }
+                let rule_vars = RuleVars {
+                    vars: &rule.vars,
+                    envs: [implicit_vars, build_vars, env],
+                    stack: RefCell::default(),
+                    cycle: RefCell::default(),
+                };
+                rule_vars.evaluate(key).map_err(|(cycle, index)| {
+                    // Only the built-in phony rule has no location, and it
+                    // has no bindings.
+                    let rule_location = rule.location.as_ref().unwrap();
+                    anyhow!(
+                        "{}:{}: cycle in variable expansion: {}, in rule {:?}",
+                        rule_location.filename.display(),
+                        rule.var_lines[index],
+                        cycle,
+                        b.rule
+                    )
+                })
+            };
+        let lookup = |key: &str| lookup_in(key, &implicit_vars);
 
-        let cmdline = lookup("command");
-        let desc = lookup("description");
-        let depfile = lookup("depfile");
-        let parse_showincludes = match lookup("deps").as_deref() {
-            None => false,
-            Some("gcc") => false,
-            Some("msvc") => true,
+        // Phony builds never run a command, even if one is bound.
+        let cmdline = match b.rule {
+            "phony" => None,
+            _ => lookup_in("command", &quoted_implicit_vars)?,
+        };
+        let desc = lookup("description")?;
+        let depfile = lookup("depfile")?;
+        let showincludes_prefix = match lookup("deps")?.as_deref() {
+            None => None,
+            Some("gcc") => None,
+            // The prefix varies with the language MSVC is installed in.  An
+            // empty one, as generators write when they have none, is unset.
+            Some("msvc") => Some(
+                lookup("msvc_deps_prefix")?
+                    .filter(|prefix| !prefix.is_empty())
+                    .unwrap_or_else(|| "Note: including file:".to_owned()),
+            ),
             Some(other) => bail!("invalid deps attribute {:?}", other),
         };
-        let pool = lookup("pool");
+        let pool = lookup("pool")?;
+        if let Some(name) = &pool {
+            if self.pools.get(name).is_none() {
+                let err = self.unknown_name("pool", name);
+                bail!("{}: {}", build.location, err);
+            }
+        }
+        let dyndep = lookup("dyndep")?;
+        // Like ninja, any nonempty value sets the flag.
+        let generator = lookup("generator")?.is_some_and(|val| !val.is_empty());
+        let precious = lookup("precious")?.is_some_and(|val| !val.is_empty());
 
-        let rspfile_path = lookup("rspfile");
-        let rspfile_content = lookup("rspfile_content");
+        let rspfile_path = lookup("rspfile")?;
+        let rspfile_content = lookup("rspfile_content")?;
         let rspfile = match (rspfile_path, rspfile_content) {
             (None, None) => None,
             (Some(path), Some(content)) => Some(RspFile {
@@ -159,127 +452,1203 @@ impl Loader {
             _ => bail!("rspfile and rspfile_content need to be both specified"),
         };
 
+        build.rule = b.rule.to_owned();
         build.cmdline = cmdline;
         build.desc = desc;
         build.depfile = depfile;
-        build.parse_showincludes = parse_showincludes;
+        build.showincludes_prefix = showincludes_prefix;
         build.rspfile = rspfile;
         build.pool = pool;
+        build.generator = generator;
+        build.precious = precious;
+        if let Some(dyndep) = dyndep {
+            let id = self.path(dyndep);
+            if !build.ins.ids.contains(&id) {
+                bail!(
+                    "{}: dyndep {:?} is not an input",
+                    build.location,
+                    self.graph.file(id).name
+                );
+            }
+            build.dyndep = Some(id);
+        }
+
+        self.graph.add_build(build, self.options.duplicate_out_warn)
+    }
 
-        self.graph.add_build(build)
+    /// Read and parse a file, with `scope` as its initial file-scope
+    /// bindings, returning the bindings as of the end of the file.  `via` is
+    /// the kind and line of the statement that named the file, if any.
+    fn read_file(
+        &mut self,
+        id: FileId,
+        via: Option<(&'static str, usize)>,
+        scope: eval::Vars<'static>,
+    ) -> anyhow::Result<eval::Vars<'static>> {
+        if let Some(start) = self.reading.iter().position(|&(file, _)| file == id) {
+            let mut chain = self.graph.file(id).name.clone();
+            let reached = self.reading[start + 1..].iter().copied();
+            for (i, (file, via)) in reached.chain([(id, via)]).enumerate() {
+                let (kind, line) = via.unwrap();
+                let which = if i == 0 { "" } else { " which" };
+                let verb = match kind {
+                    "subninja" => "includes subninja",
+                    _ => "includes",
+                };
+                chain.push_str(&format!(
+                    "{} {} {} (line {})",
+                    which,
+                    verb,
+                    self.graph.file(file).name,
+                    line
+                ));
+            }
+            bail!("cycle in includes: {}", chain);
+        }
+        if self.reading.len() >= MAX_INCLUDE_DEPTH {
+            bail!(
+                "{}: includes nested more than {} deep",
+                self.graph.file(id).name,
+                MAX_INCLUDE_DEPTH
+            );
+        }
+        self.reading.push((id, via));
+        let result = self.read_file_contents(id, scope);
+        self.reading.pop();
+        result
+    }
+
+    /// Describe how the file being read was reached, innermost first, to
+    /// follow a parse error in it.
+    fn inclusion_chain(&self) -> String {
+        let mut chain = String::new();
+        for k in (1..self.reading.len()).rev() {
+            let Some((kind, line)) = self.reading[k].1 else {
+                continue;
+            };
+            let how = match kind {
+                "subninja" => "read as subninja from",
+                _ => "included from",
+            };
+            let includer = &self.graph.file(self.reading[k - 1].0).name;
+            chain.push_str(&format!("{} {}:{}\n", how, includer, line));
+        }
+        chain
     }
 
-    fn read_file(&mut self, id: FileId) -> anyhow::Result<()> {
+    fn read_file_contents(
+        &mut self,
+        id: FileId,
+        scope: eval::Vars<'static>,
+    ) -> anyhow::Result<eval::Vars<'static>> {
         let path = self.graph.file(id).path().to_path_buf();
-        let bytes = match trace::scope("read file", || scanner::read_file_with_nul(&path)) {
-            Ok(b) => b,
-            Err(e) => bail!("read {}: {}", path.display(), e),
-        };
-        self.parse(path, &bytes)
+        let mut attempts = 0;
+        loop {
+            let mut file = match std::fs::File::open(&path) {
+                Ok(f) => f,
+                Err(e) => bail!("read {}: {}", path.display(), e),
+            };
+            let len = file.metadata().map(|m| m.len() as usize).unwrap_or(0);
+            // Large files aren't memory-mapped: a mapping faults if the file
+            // is truncated while being parsed, as a generator rewriting it
+            // in place would do.  Parsing in blocks keeps memory bounded.
+            if len > CHUNK_SIZE {
+                return self.parse_chunked_scoped(path, file, CHUNK_SIZE, scope);
+            }
+            let bytes = match trace::scope("read file", || read_sized(&mut file, len)) {
+                Ok(b) => b,
+                // The file is being rewritten; read it again from the start.
+                Err(ReadError::Shrank { .. }) if attempts < MAX_READ_ATTEMPTS => {
+                    attempts += 1;
+                    continue;
+                }
+                Err(e) => bail!("read {}: {}", path.display(), e),
+            };
+            return self.parse_scoped(path, &bytes, scope);
+        }
     }
 
-    fn evaluate_and_read_file(
+    /// Find the file named by an include or subninja statement in `includer`.
+    fn evaluate_include_path(
         &mut self,
+        kind: &'static str,
+        includer: &Path,
         file: EvalString<&str>,
         envs: &[&dyn eval::Env],
-    ) -> anyhow::Result<()> {
-        let evaluated = self.evaluate_path(file, envs);
-        self.read_file(evaluated)
+    ) -> anyhow::Result<FileId> {
+        let literal = file.evaluate(envs);
+        let dir = includer.parent().and_then(Path::to_str).unwrap_or("");
+        if self.options.include_resolution == IncludeResolution::RelativeToIncluder
+            && !dir.is_empty()
+            && !Path::new(&literal).is_absolute()
+        {
+            let resolved = self.path(format!("{}/{}", dir, literal));
+            let path = self.graph.file(resolved).path();
+            if let Err(err) = std::fs::metadata(path) {
+                bail!(
+                    "{}: {} {:?}: read {}: {}",
+                    includer.display(),
+                    kind,
+                    literal,
+                    path.display(),
+                    err
+                );
+            }
+            return Ok(resolved);
+        }
+        Ok(self.path(literal))
+    }
+
+    /// Read a deferred subninja as if from its subninja statement.
+    fn read_deferred(&mut self, deferred: DeferredSubninja) -> anyhow::Result<()> {
+        let reading = std::mem::replace(&mut self.reading, deferred.reading);
+        let in_subninja = std::mem::replace(&mut self.in_subninja, true);
+        let result = trace::scope("subninja", || {
+            self.read_file(
+                deferred.id,
+                Some(("subninja", deferred.line)),
+                deferred.scope,
+            )
+        });
+        self.in_subninja = in_subninja;
+        self.reading = reading;
+        result.map(|_| ())
+    }
+
+    /// Read deferred subninjas, including those they defer in turn, until
+    /// `targets` and everything they depend on can be built from what is
+    /// loaded; see Options::lazy_subninjas.
+    fn read_deferred_for(&mut self, targets: &[String]) -> anyhow::Result<()> {
+        let mut exists: HashMap<FileId, bool> = HashMap::new();
+        while !self.deferred.is_empty() && !self.resolves(targets, &mut exists) {
+            let deferred = self.deferred.pop_front().unwrap();
+            self.read_deferred(deferred)?;
+        }
+        Ok(())
+    }
+
+    /// Whether each of `targets` is generated by a loaded build, and all of
+    /// their inputs are either generated or exist on disk.
+    fn resolves(&self, targets: &[String], exists: &mut HashMap<FileId, bool>) -> bool {
+        let mut stack = Vec::new();
+        for name in targets {
+            let mut name = name.clone();
+            self.graph.files.canonicalize(&mut name);
+            match self.graph.files.lookup(&name) {
+                Some(id) if self.graph.file(id).input.is_some() => stack.push(id),
+                _ => return false,
+            }
+        }
+        if stack.is_empty() {
+            return false;
+        }
+        let mut visited: HashSet<FileId> = stack.iter().copied().collect();
+        while let Some(id) = stack.pop() {
+            let file = self.graph.file(id);
+            match file.input {
+                Some(bid) => {
+                    for &input in &self.graph.builds[bid].ins.ids {
+                        if visited.insert(input) {
+                            stack.push(input);
+                        }
+                    }
+                }
+                None => {
+                    let found = *exists
+                        .entry(id)
+                        .or_insert_with(|| std::fs::metadata(file.path()).is_ok());
+                    if !found {
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Check that each default target is generated by some build or exists
+    /// on disk, which can only be known once the whole manifest is loaded.
+    /// With subninjas still deferred it isn't, but then the targets were
+    /// named and the defaults go unused.
+    fn check_defaults(&self) -> anyhow::Result<()> {
+        if !self.deferred.is_empty() {
+            return Ok(());
+        }
+        for (id, location) in &self.default {
+            let file = self.graph.file(*id);
+            if file.input.is_none() && std::fs::metadata(file.path()).is_err() {
+                bail!(
+                    "{}: default target {:?} is not generated by any build and doesn't exist",
+                    location,
+                    file.name
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Report a rule or pool used by a build but unknown so far.  With lazy
+    /// subninjas, this reads the rest of them to give a better message if
+    /// one defines it.
+    fn unknown_name(&mut self, kind: &str, name: &str) -> anyhow::Error {
+        while let Some(deferred) = self.deferred.pop_front() {
+            if let Err(err) = self.read_deferred(deferred) {
+                return err;
+            }
+        }
+        let deferred = match kind {
+            "rule" => self.rules.contains_key(name),
+            _ => self.pools.get(name).is_some(),
+        };
+        if deferred {
+            return anyhow!(
+                "unknown {} {:?}; it is defined in a subninja deferred by lazy subninja loading",
+                kind,
+                name
+            );
+        }
+        let suggestions = match kind {
+            "rule" => similar_names(name, self.rules.keys().map(String::as_str)),
+            _ => similar_names(name, self.pools.iter().map(|(k, _)| k.as_str())),
+        };
+        match suggestions.as_slice() {
+            [] => anyhow!("unknown {} {:?}", kind, name),
+            [one] => anyhow!("unknown {} {:?}; did you mean {:?}?", kind, name, one),
+            [one, two, ..] => anyhow!(
+                "unknown {} {:?}; did you mean {:?} or {:?}?",
+                kind,
+                name,
+                one,
+                two
+            ),
+        }
     }
 
     pub fn parse(&mut self, path: PathBuf, bytes: &[u8]) -> anyhow::Result<()> {
+        self.parse_scoped(path, bytes, eval::Vars::default())?;
+        Ok(())
+    }
+
+    fn parse_scoped(
+        &mut self,
+        path: PathBuf,
+        bytes: &[u8],
+        scope: eval::Vars<'static>,
+    ) -> anyhow::Result<eval::Vars<'static>> {
         let filename = std::rc::Rc::new(path);
+        let mut state = BlockState {
+            vars: scope,
+            ..BlockState::default()
+        };
+        self.parse_block(&filename, bytes, &mut state)?;
+        Ok(state.vars)
+    }
+
+    /// Parse a file read from `reader` in blocks of about `chunk_size` bytes,
+    /// so that very large manifests need not be held in memory at once.
+    /// Blocks are split before a line that starts a new statement, and the
+    /// rest is carried over into the next block.
+    pub fn parse_chunked(
+        &mut self,
+        path: PathBuf,
+        reader: impl Read,
+        chunk_size: usize,
+    ) -> anyhow::Result<()> {
+        self.parse_chunked_scoped(path, reader, chunk_size, eval::Vars::default())?;
+        Ok(())
+    }
+
+    fn parse_chunked_scoped(
+        &mut self,
+        path: PathBuf,
+        mut reader: impl Read,
+        chunk_size: usize,
+        scope: eval::Vars<'static>,
+    ) -> anyhow::Result<eval::Vars<'static>> {
+        let filename = std::rc::Rc::new(path);
+        let mut state = BlockState {
+            vars: scope,
+            ..BlockState::default()
+        };
+        let mut buf: Vec<u8> = Vec::with_capacity(chunk_size);
+        let mut limit = chunk_size;
+        let mut eof = false;
+        while !eof {
+            while buf.len() < limit {
+                let len = buf.len();
+                buf.resize(limit, 0);
+                let n = match trace::scope("read file", || reader.read(&mut buf[len..])) {
+                    Ok(0) => {
+                        buf.truncate(len);
+                        eof = true;
+                        break;
+                    }
+                    Ok(n) => n,
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => 0,
+                    Err(e) => bail!("read {}: {}", filename.display(), e),
+                };
+                buf.truncate(len + n);
+            }
+            let end = if eof {
+                buf.len()
+            } else {
+                match statement_boundary(&buf) {
+                    Some(end) => end,
+                    None => {
+                        // A single statement larger than a block; read more.
+                        limit += chunk_size;
+                        continue;
+                    }
+                }
+            };
+            self.parse_block(&filename, &buf[..end], &mut state)?;
+            buf.drain(..end);
+            limit = buf.len() + chunk_size;
+        }
+        Ok(state.vars)
+    }
 
-        let mut parser = parse::Parser::new(&bytes);
+    /// Parse one block of a file, continuing from `state` left by the previous
+    /// block.
+    fn parse_block(
+        &mut self,
+        filename: &std::rc::Rc<PathBuf>,
+        bytes: &[u8],
+        state: &mut BlockState,
+    ) -> anyhow::Result<()> {
+        let mut parser = parse::Parser::with_options(
+            bytes,
+            parse::ParserOptions {
+                strict: self.options.strict,
+                ..parse::ParserOptions::default()
+            },
+        );
+        parser.vars = std::mem::take(&mut state.vars);
+        parser.vars.fallback = self.env_fallback.clone();
+        for (name, value) in &self.options.var_overrides {
+            parser.override_var(name, value);
+        }
+        parser.start_at_line(state.line);
+        let builddir = &mut state.builddir;
 
         loop {
-            let stmt = match parser
-                .read()
-                .map_err(|err| anyhow!(parser.format_parse_error(&filename, err)))?
-            {
+            // Share whether ninja_required_version was seen across includes.
+            parser.required_version_seen |= self.required_version_seen;
+            let stmt = match parser.read().map_err(|err| {
+                let mut msg =
+                    parser.format_parse_error_with_color(filename, err, self.options.color);
+                msg.push_str(&self.inclusion_chain());
+                anyhow!(msg)
+            })? {
                 None => break,
                 Some(s) => s,
             };
+            self.required_version_seen = parser.required_version_seen;
+            for shadowed in parser.take_shadowed_vars() {
+                println!(
+                    "n2: warn: {}:{}: {:?} is bound again, ignoring the binding at line {}",
+                    filename.display(),
+                    shadowed.line,
+                    shadowed.name,
+                    shadowed.prev_line
+                );
+            }
             match stmt {
-                Statement::Include(id) => trace::scope("include", || {
-                    self.evaluate_and_read_file(id, &[&parser.vars])
-                })?,
-                // TODO: implement scoping for subninja
-                Statement::Subninja(id) => trace::scope("subninja", || {
-                    self.evaluate_and_read_file(id, &[&parser.vars])
-                })?,
+                // An included file shares this file's scope: it sees the
+                // bindings so far, and its own bindings apply here after it.
+                Statement::Include(id) => {
+                    self.update_builddir(&parser.vars, builddir);
+                    let scope = parser.vars.clone().into_owned();
+                    let line = parser.statement_line();
+                    let id =
+                        self.evaluate_include_path("include", filename, id, &[&parser.vars])?;
+                    parser.vars = trace::scope("include", || {
+                        self.read_file(id, Some(("include", line)), scope)
+                    })?;
+                }
+                // A subninja gets a child scope: it sees the bindings so far,
+                // but its own bindings are discarded after it.
+                Statement::Subninja(id) => {
+                    let scope = parser.vars.clone().into_owned();
+                    let line = parser.statement_line();
+                    let id =
+                        self.evaluate_include_path("subninja", filename, id, &[&parser.vars])?;
+                    let deferred = DeferredSubninja {
+                        id,
+                        scope,
+                        reading: self.reading.clone(),
+                        line,
+                    };
+                    if self.options.lazy_subninjas.is_some() {
+                        self.deferred.push_back(deferred);
+                    } else {
+                        self.read_deferred(deferred)?;
+                    }
+                }
                 Statement::Default(defaults) => {
-                    let evaluated = self.evaluate_paths(defaults, &[&parser.vars]);
-                    self.default.extend(evaluated);
+                    let location = graph::FileLoc {
+                        filename: filename.clone(),
+                        line: parser.statement_line(),
+                    };
+                    for id in self.evaluate_paths(defaults, &[&parser.vars]) {
+                        if self.default_seen.insert(id) {
+                            self.default.push((id, location.clone()));
+                        }
+                    }
                 }
                 Statement::Rule(rule) => {
-                    let mut vars: SmallMap<String, eval::EvalString<String>> = SmallMap::default();
+                    let mut vars: SmallMap<String, eval::OwnedEvalString> = SmallMap::default();
                     for (name, val) in rule.vars.into_iter() {
                         // TODO: We should not need to call .into_owned() here
                         // if we keep the contents of all included files in
                         // memory.
                         vars.insert(name.to_owned(), val.into_owned());
                     }
-                    self.rules.insert(rule.name.to_owned(), vars);
+                    let location = graph::FileLoc {
+                        filename: filename.clone(),
+                        line: rule.line,
+                    };
+                    if vars.get("command").is_none() {
+                        let mut msg = format!(
+                            "{}: rule {:?} has no 'command' binding; \
+                             rules must bind 'command' in their own variable block",
+                            location, rule.name
+                        );
+                        if parser.vars.get("command").is_some() {
+                            msg.push_str(" (a file-scope 'command' doesn't apply to rules)");
+                        }
+                        bail!(msg);
+                    }
+                    for (name, _) in vars.iter() {
+                        if parse::is_reserved_rule_var(name)
+                            || vars.iter().any(|(other, val)| {
+                                other != name && val.var_refs().any(|r| r == name)
+                            })
+                        {
+                            continue;
+                        }
+                        let msg = format!(
+                            "{}: rule {:?} binds unknown variable {:?}",
+                            location, rule.name, name
+                        );
+                        if self.options.unknown_rule_var_err {
+                            bail!(msg);
+                        }
+                        println!("n2: warn: {}", msg);
+                    }
+                    if let Some(prev) = self.rules.get(rule.name) {
+                        let msg = format!(
+                            "{}: duplicate rule {:?}, previously defined at {}",
+                            location,
+                            rule.name,
+                            prev.location.as_ref().unwrap()
+                        );
+                        if !self.options.duplicate_rule_warn {
+                            bail!(msg);
+                        }
+                        println!("n2: warn: {}", msg);
+                    }
+                    if self.options.undefined_var_warn {
+                        let defined = &mut self.undefined_var_check.defined;
+                        defined.extend(vars.iter().map(|(name, _)| name.clone()));
+                    }
+                    self.rules.insert(
+                        rule.name.to_owned(),
+                        Rule {
+                            vars,
+                            var_lines: rule.var_lines,
+                            location: Some(location),
+                        },
+                    );
                 }
                 Statement::Build(build) => self.add_build(filename.clone(), &parser.vars, build)?,
                 Statement::Pool(pool) => {
                     self.pools.insert(pool.name.to_string(), pool.depth);
                 }
+                // Only produced by Parser::with_comments.
+                Statement::Binding(_) | Statement::Comment(_) => {}
             };
         }
-        self.builddir = parser.vars.get("builddir").cloned();
+        self.update_builddir(&parser.vars, builddir);
+        if self.options.undefined_var_warn {
+            let defined = &mut self.undefined_var_check.defined;
+            defined.extend(parser.vars.names().map(str::to_owned));
+        }
+        state.line = parser.line();
+        state.vars = std::mem::take(&mut parser.vars).into_owned();
         Ok(())
     }
+
+    /// Warn about references from used rules and from builds to variables
+    /// bound nowhere in the manifest.  Variables that are bound, even if
+    /// only in some other scope, are assumed to be intentionally optional.
+    fn warn_undefined_vars(&self) {
+        let check = &self.undefined_var_check;
+        let is_undefined = |var: &str| {
+            !check.defined.contains(var)
+                && !parse::is_reserved_rule_var(var)
+                && !matches!(var, "in" | "out" | "in_newline" | "out_newline")
+                && !self
+                    .env_fallback
+                    .as_ref()
+                    .is_some_and(|env| env.get(var).is_some())
+        };
+        // Report rules by location, for output that doesn't vary between runs.
+        let mut used_rules: Vec<(&String, &Rule)> = check
+            .used_rules
+            .iter()
+            .map(|name| (name, &self.rules[name]))
+            .collect();
+        used_rules.sort_by_key(|(_, rule)| {
+            let location = rule.location.as_ref();
+            location.map(|location| (location.filename.clone(), location.line))
+        });
+        for (name, rule) in used_rules {
+            let mut reported: Vec<(&str, &str)> = Vec::new();
+            for (binding, val) in rule.vars.iter() {
+                for var in val.var_refs() {
+                    if is_undefined(var) && !reported.contains(&(binding, var)) {
+                        reported.push((binding, var));
+                    }
+                }
+            }
+            for (binding, var) in reported {
+                println!(
+                    "n2: warn: {}: rule {:?} binding {:?} references undefined variable {:?}",
+                    rule.location.as_ref().unwrap(),
+                    name,
+                    binding,
+                    var
+                );
+            }
+        }
+        for (location, binding, var) in &check.build_refs {
+            if is_undefined(var) {
+                println!(
+                    "n2: warn: {}: build binding {:?} references undefined variable {:?}",
+                    location, binding, var
+                );
+            }
+        }
+    }
+
+    /// Take the file's builddir binding if it changed since the last call.
+    fn update_builddir(&mut self, vars: &eval::Vars, last: &mut Option<String>) {
+        if self.in_subninja {
+            return;
+        }
+        let builddir = vars.get("builddir");
+        if builddir != last.as_ref() {
+            *last = builddir.cloned();
+            self.builddir = builddir.cloned();
+        }
+    }
+}
+
+/// Manifests larger than this are parsed in blocks of about this size.
+const CHUNK_SIZE: usize = 8 << 20;
+
+/// How deeply includes and subninjas may nest, as a backstop for generators
+/// that produce very deep but acyclic chains.
+const MAX_INCLUDE_DEPTH: usize = 64;
+
+/// How many times to read a file that shrinks while being read.
+const MAX_READ_ATTEMPTS: usize = 3;
+
+/// Failure from read_sized.
+#[derive(Debug)]
+pub enum ReadError {
+    Io(std::io::Error),
+    /// The input ended before its expected size, e.g. because the file was
+    /// truncated while being rewritten.  Reading it again may succeed.
+    Shrank {
+        expected: usize,
+        actual: usize,
+    },
+}
+
+impl std::fmt::Display for ReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadError::Io(err) => err.fmt(f),
+            ReadError::Shrank { expected, actual } => write!(
+                f,
+                "file shrank while reading, from {} to {} bytes",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+/// Read all of `reader`, which is expected to hold `len` bytes as of its
+/// metadata.  Input beyond `len` is read too, as the file may have grown.
+pub fn read_sized(reader: &mut impl Read, len: usize) -> Result<Vec<u8>, ReadError> {
+    let mut buf = Vec::with_capacity(len);
+    reader
+        .take(len as u64)
+        .read_to_end(&mut buf)
+        .map_err(ReadError::Io)?;
+    if buf.len() < len {
+        return Err(ReadError::Shrank {
+            expected: len,
+            actual: buf.len(),
+        });
+    }
+    reader.read_to_end(&mut buf).map_err(ReadError::Io)?;
+    Ok(buf)
+}
+
+/// Parser state carried from one block of a file to the next.
+struct BlockState {
+    vars: eval::Vars<'static>,
+    /// The line number of the start of the next block.
+    line: usize,
+    /// The file's builddir binding as of the last include, so that a binding
+    /// in an included file is only overridden by a later one here.
+    builddir: Option<String>,
+}
+
+impl Default for BlockState {
+    fn default() -> Self {
+        BlockState {
+            vars: eval::Vars::default(),
+            line: 1,
+            builddir: None,
+        }
+    }
+}
+
+/// Find the end of the last complete line in `buf` that is followed by the
+/// start of a new statement, i.e. a line beginning with an identifier that
+/// isn't continued from the previous line by a `$` escape.
+fn statement_boundary(buf: &[u8]) -> Option<usize> {
+    let mut end = buf.len();
+    while let Some(nl) = buf[..end].iter().rposition(|&c| c == b'\n') {
+        end = nl;
+        let starts_statement = matches!(
+            buf.get(nl + 1),
+            Some(b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'.' | b'-')
+        );
+        if !starts_statement {
+            continue;
+        }
+        let line = buf[..nl].strip_suffix(b"\r").unwrap_or(&buf[..nl]);
+        let dollars = line.iter().rev().take_while(|&&c| c == b'$').count();
+        if dollars % 2 == 0 {
+            return Some(nl + 1);
+        }
+    }
+    None
 }
 
 /// State loaded by read().
 pub struct State {
     pub graph: graph::Graph,
     pub db: db::Writer,
+    pub db_path: PathBuf,
     pub hashes: graph::Hashes,
     pub default: Vec<FileId>,
     pub pools: SmallMap<String, usize>,
 }
 
+impl State {
+    /// The targets named by `default` statements across the manifest and the
+    /// files it includes, in the order first named, without duplicates.
+    pub fn default_targets(&self) -> impl Iterator<Item = &str> {
+        self.default
+            .iter()
+            .map(|&id| self.graph.file(id).name.as_str())
+    }
+}
+
+/// The (at most two) names closest to `name`, for "did you mean" hints.
+/// Only names within an edit distance of about a third of the name's length
+/// are suggested.  Candidates may come in any order: ties are broken by name,
+/// and names differing too much in length are rejected without comparing them.
+pub fn similar_names<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+    let max = std::cmp::max(1, name.chars().count() / 3);
+    let mut found: Vec<(usize, &str)> = candidates
+        .filter_map(|candidate| Some((edit_distance(name, candidate, max)?, candidate)))
+        .collect();
+    found.sort();
+    found.into_iter().take(2).map(|(_, name)| name).collect()
+}
+
+/// The edit distance between `a` and `b`, counting insertions, deletions,
+/// substitutions and transpositions of adjacent characters, or None if it
+/// exceeds `max`.
+fn edit_distance(a: &str, b: &str, max: usize) -> Option<usize> {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    if a.len().abs_diff(b.len()) > max {
+        return None;
+    }
+    let mut prev2 = vec![0; b.len() + 1];
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut cur = vec![0; b.len() + 1];
+    for i in 0..a.len() {
+        cur[0] = i + 1;
+        for j in 0..b.len() {
+            let mut d = (prev[j] + usize::from(a[i] != b[j]))
+                .min(prev[j + 1] + 1)
+                .min(cur[j] + 1);
+            if i > 0 && j > 0 && a[i] == b[j - 1] && a[i - 1] == b[j] {
+                d = d.min(prev2[j - 1] + 1);
+            }
+            cur[j + 1] = d;
+        }
+        // A transposition looks back two rows, so only give up once both
+        // rows are past the limit.
+        if cur.iter().chain(&prev).min().unwrap() > &max {
+            return None;
+        }
+        std::mem::swap(&mut prev2, &mut prev);
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    Some(prev[b.len()]).filter(|&d| d <= max)
+}
+
+/// Warn if the database is about to be started afresh while one left behind
+/// by a previous builddir still exists; otherwise the change of builddir
+/// silently rebuilds everything.  Nothing is recorded to notice this: the old
+/// database is looked for next to the new one, which covers dropping or
+/// adding a builddir and renaming it in place (such as out/a to out/b).
+fn check_builddir_moved(db_path: &Path) -> std::io::Result<()> {
+    if db_path.exists() {
+        return Ok(());
+    }
+    let builddir = db_path.parent().unwrap_or(Path::new(""));
+    // Without a builddir, look for a database one level down; with one, at
+    // its siblings and at its parent.
+    let parent = if builddir.as_os_str().is_empty() {
+        builddir
+    } else {
+        builddir.parent().unwrap_or(Path::new(""))
+    };
+    let entries = match std::fs::read_dir(if parent.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        parent
+    }) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    let mut candidates = Vec::new();
+    if !builddir.as_os_str().is_empty() {
+        candidates.push(parent.join(".n2_db"));
+    }
+    for entry in entries {
+        let dir = parent.join(entry?.file_name());
+        if dir != builddir {
+            candidates.push(dir.join(".n2_db"));
+        }
+    }
+    let mut found = Vec::new();
+    for prev in candidates {
+        if let Ok(meta) = std::fs::metadata(&prev) {
+            if meta.is_file() {
+                found.push((meta.modified()?, prev));
+            }
+        }
+    }
+    // Of several, the most recently used is the one being abandoned.
+    if let Some((_, prev_db)) = found.into_iter().max() {
+        println!(
+            "n2: warn: builddir changed; ignoring {} and starting a new database at {}",
+            prev_db.display(),
+            db_path.display()
+        );
+    }
+    Ok(())
+}
+
 /// Load build.ninja/.n2_db and return the loaded build graph and state.
-pub fn read(build_filename: &str) -> anyhow::Result<State> {
+/// A build_filename of "-" reads the manifest from stdin.
+pub fn read(build_filename: &str, options: &Options) -> anyhow::Result<State> {
     let mut loader = Loader::new();
+    loader.options = options.clone();
+    if options.windows_paths {
+        loader.graph.files.set_windows_paths();
+    }
+    if let Some(fold_case) = options.fold_case {
+        loader.graph.files.set_fold_case(fold_case);
+    }
+    if options.env_fallback {
+        loader.env_fallback = Some(std::rc::Rc::new(eval::EnvFallback::new(
+            options.log_env_fallback,
+        )));
+    }
     trace::scope("loader.read_file", || {
-        let id = loader
-            .graph
-            .files
-            .id_from_canonical(to_owned_canon_path(build_filename));
-        loader.read_file(id)
+        if build_filename == "-" {
+            // Paths within the manifest, including includes, are relative to
+            // the working directory as usual.
+            let stdin = std::io::stdin().lock();
+            return loader.parse_chunked(PathBuf::from("<stdin>"), stdin, CHUNK_SIZE);
+        }
+        let mut build_filename = build_filename.to_owned();
+        loader.graph.files.canonicalize(&mut build_filename);
+        let id = loader.graph.files.id_from_canonical(build_filename);
+        loader.read_file(id, None, eval::Vars::default())?;
+        anyhow::Ok(())
     })?;
+    if let Some(targets) = &options.lazy_subninjas {
+        trace::scope("read deferred subninjas", || {
+            loader.read_deferred_for(targets)
+        })?;
+    }
+    loader.check_defaults()?;
+    if options.undefined_var_warn {
+        loader.warn_undefined_vars();
+    }
     let mut hashes = graph::Hashes::default();
-    let db = trace::scope("db::open", || {
+    let (db, db_path) = trace::scope("db::open", || {
         let mut db_path = PathBuf::from(".n2_db");
         if let Some(builddir) = &loader.builddir {
             db_path = Path::new(&builddir).join(db_path);
+        }
+        if options.no_db {
+            return anyhow::Ok((db::Writer::unopened(), db_path));
+        }
+        if loader.builddir.is_some() && !options.read_only_db {
             if let Some(parent) = db_path.parent() {
                 std::fs::create_dir_all(parent)?;
             }
+        }
+        check_builddir_moved(&db_path)?;
+        // With lazy subninjas, builds not loaded would lose their state.
+        let may_compact = options.lazy_subninjas.is_none();
+        let db = if options.read_only_db {
+            db::open_read_only(&db_path, &mut loader.graph, &mut hashes)?
+        } else {
+            db::open(&db_path, &mut loader.graph, &mut hashes, may_compact)?
         };
-        db::open(&db_path, &mut loader.graph, &mut hashes)
+        anyhow::Ok((db, db_path))
     })
     .map_err(|err| anyhow!("load .n2_db: {}", err))?;
     Ok(State {
         graph: loader.graph,
         db,
+        db_path,
         hashes,
-        default: loader.default,
+        default: loader.default.into_iter().map(|(id, _)| id).collect(),
         pools: loader.pools,
     })
 }
 
 /// Parse a single file's content.
 #[cfg(test)]
-pub fn parse(name: &str, mut content: Vec<u8>) -> anyhow::Result<graph::Graph> {
-    content.push(0);
+pub fn parse(name: &str, content: Vec<u8>) -> anyhow::Result<graph::Graph> {
     let mut loader = Loader::new();
     trace::scope("loader.read_file", || {
         loader.parse(PathBuf::from(name), &content)
     })?;
     Ok(loader.graph)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::densemap::Index;
+
+    const MANIFEST: &str = "flags = -O2
+rule cc
+  command = cc $flags $
+      -c $in -o $out
+build a.o: cc a.c
+flags = -O0
+build b.o: cc b.c
+  flags = $flags -g
+
+build c.o: cc c.c
+";
+
+    fn build_summary(graph: &graph::Graph) -> Vec<(String, Option<String>)> {
+        (0..graph.builds.next_id().index())
+            .map(|i| {
+                let b = &graph.builds[graph::BuildId::from(i)];
+                (b.location.to_string(), b.cmdline.clone())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parse_chunked() {
+        let whole = parse("build.ninja", MANIFEST.as_bytes().to_vec()).unwrap();
+        let want = build_summary(&whole);
+        assert_eq!(want.len(), 3);
+        for chunk_size in 1..MANIFEST.len() + 1 {
+            let mut loader = Loader::new();
+            loader
+                .parse_chunked(
+                    PathBuf::from("build.ninja"),
+                    MANIFEST.as_bytes(),
+                    chunk_size,
+                )
+                .unwrap();
+            assert_eq!(
+                build_summary(&loader.graph),
+                want,
+                "chunk size {}",
+                chunk_size
+            );
+        }
+    }
+
+    #[test]
+    fn parse_chunked_error_line() {
+        let manifest = format!("{}rule\n", MANIFEST);
+        let mut loader = Loader::new();
+        let err = loader
+            .parse_chunked(PathBuf::from("build.ninja"), manifest.as_bytes(), 16)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("statement starting at line 11\n"), "{}", err);
+        assert!(err.contains("build.ninja:11: rule\n"), "{}", err);
+    }
+
+    #[test]
+    fn in_newline() {
+        let graph = parse(
+            "build.ninja",
+            b"rule link
+  command = link @$out.rsp
+  rspfile = $out.rsp
+  rspfile_content = $in_newline
+build none: link
+build one: link a.o
+build spaces: link a$ b.o c.o d$ e.o
+"
+            .to_vec(),
+        )
+        .unwrap();
+        let contents: Vec<_> = (0..graph.builds.next_id().index())
+            .map(|i| {
+                let build = &graph.builds[graph::BuildId::from(i)];
+                build.rspfile.as_ref().unwrap().content.clone()
+            })
+            .collect();
+        // Paths with spaces are not quoted, as each line is read literally.
+        assert_eq!(contents, ["", "a.o", "a b.o\nc.o\nd e.o"]);
+    }
+
+    #[test]
+    fn implicit_outs() {
+        let graph = parse(
+            "build.ninja",
+            b"rule gen
+  command = gen [$out]
+build a | a.h: gen
+build | b.h: gen
+"
+            .to_vec(),
+        )
+        .unwrap();
+        let outs: Vec<_> = (0..graph.builds.next_id().index())
+            .map(|i| {
+                let build = &graph.builds[graph::BuildId::from(i)];
+                let names = |ids: &[FileId]| -> Vec<String> {
+                    ids.iter().map(|&id| graph.file(id).name.clone()).collect()
+                };
+                (
+                    build.cmdline.clone().unwrap(),
+                    names(build.explicit_outs()),
+                    names(build.implicit_outs()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            outs,
+            [
+                (
+                    "gen [a]".to_owned(),
+                    vec!["a".to_owned()],
+                    vec!["a.h".to_owned()]
+                ),
+                ("gen []".to_owned(), vec![], vec!["b.h".to_owned()]),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn quote_command_paths() {
+        let graph = parse(
+            "build.ninja",
+            b"rule cp
+  command = cp $in $out
+  description = CP $in $out
+  depfile = $out.d
+build out$ dir/b: cp a$ b
+"
+            .to_vec(),
+        )
+        .unwrap();
+        let build = &graph.builds[graph::BuildId::from(0)];
+        assert_eq!(build.cmdline.as_deref(), Some("cp 'a b' 'out dir/b'"));
+        assert_eq!(build.desc.as_deref(), Some("CP a b out dir/b"));
+        assert_eq!(build.depfile.as_deref(), Some("out dir/b.d"));
+    }
+
+    #[test]
+    fn rule_var_cycle() {
+        let err = |manifest: &str| {
+            parse("build.ninja", manifest.as_bytes().to_vec())
+                .err()
+                .map(|err| err.to_string())
+        };
+        assert_eq!(
+            err("rule r\n  command = cc $a\n  a = $b\n  b = -x $a\nbuild out: r\n").as_deref(),
+            Some("build.ninja:4: cycle in variable expansion: a -> b -> a, in rule \"r\"")
+        );
+        assert_eq!(
+            err("rule r\n  command = cc $command\nbuild out: r\n").as_deref(),
+            Some("build.ninja:2: cycle in variable expansion: command -> command, in rule \"r\"")
+        );
+
+        // Rule bindings may refer to each other, as long as they don't cycle.
+        let graph = parse(
+            "build.ninja",
+            b"rule r\n  command = cc $a $b\n  a = $b\n  b = -x\nbuild out: r\n".to_vec(),
+        )
+        .unwrap();
+        let build = &graph.builds[graph::BuildId::from(0)];
+        assert_eq!(build.cmdline.as_deref(), Some("cc -x -x"));
+    }
+
+    /// A manifest larger than CHUNK_SIZE is read from disk in blocks, with
+    /// statements and file-scope bindings carried across block boundaries.
+    #[test]
+    fn read_file_chunked() {
+        let mut manifest = "flags = -O2\nrule cc\n  command = cc $flags $in\n".to_owned();
+        // Long paths keep the statement count, and so the test's run time,
+        // down.
+        let dir_name = "d".repeat(1000);
+        let mut builds = 0;
+        while manifest.len() <= CHUNK_SIZE + CHUNK_SIZE / 2 {
+            manifest.push_str(&format!(
+                "build out/obj{}.o: cc $\n    {}/file{}.c\n",
+                builds, dir_name, builds
+            ));
+            builds += 1;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("build.ninja");
+        std::fs::write(&path, &manifest).unwrap();
+
+        let mut loader = Loader::new();
+        let id = loader
+            .graph
+            .files
+            .id_from_canonical(path.to_str().unwrap().to_owned());
+        loader.read_file(id, None, eval::Vars::default()).unwrap();
+        assert_eq!(loader.graph.builds.next_id().index(), builds);
+        let last = &loader.graph.builds[graph::BuildId::from(builds - 1)];
+        assert_eq!(
+            last.cmdline.as_deref(),
+            Some(format!("cc -O2 {}/file{}.c", dir_name, builds - 1).as_str())
+        );
+        assert_eq!(last.location.line, 4 + 2 * (builds - 1));
+    }
+
+    #[test]
+    fn read_sized_changed_size() {
+        let mut file = tempfile::tempfile().unwrap();
+        std::io::Write::write_all(&mut file, b"build out: phony\n").unwrap();
+        let len = file.metadata().unwrap().len() as usize;
+
+        // Grown since the metadata was read.
+        std::io::Seek::rewind(&mut file).unwrap();
+        assert_eq!(read_sized(&mut file, len - 4).unwrap().len(), len);
+
+        // Truncated since the metadata was read.
+        file.set_len(5).unwrap();
+        std::io::Seek::rewind(&mut file).unwrap();
+        match read_sized(&mut file, len) {
+            Err(ReadError::Shrank { expected, actual }) => {
+                assert_eq!((expected, actual), (len, 5));
+            }
+            other => panic!("expected Shrank, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn defaults_accumulate() {
+        let mut loader = Loader::new();
+        let manifest = "build a b c: phony\ndefault b a\ndefault a c\n";
+        loader
+            .parse(PathBuf::from("build.ninja"), manifest.as_bytes())
+            .unwrap();
+        let defaults: Vec<(&str, String)> = loader
+            .default
+            .iter()
+            .map(|(id, loc)| (loader.graph.file(*id).name.as_str(), loc.to_string()))
+            .collect();
+        assert_eq!(
+            defaults,
+            [
+                ("b", "build.ninja:2".to_owned()),
+                ("a", "build.ninja:2".to_owned()),
+                ("c", "build.ninja:3".to_owned()),
+            ]
+        );
+        loader.check_defaults().unwrap();
+
+        loader
+            .parse(PathBuf::from("more.ninja"), b"default missing\n")
+            .unwrap();
+        assert_eq!(
+            loader.check_defaults().unwrap_err().to_string(),
+            "more.ninja:1: default target \"missing\" is not generated by any build and doesn't exist"
+        );
+    }
+
+    fn parse_strict(content: &str) -> anyhow::Result<()> {
+        let mut loader = Loader::new();
+        loader.options.strict = true;
+        loader.parse(PathBuf::from("build.ninja"), content.as_bytes())
+    }
+
+    #[test]
+    fn strict_duplicate_outputs() {
+        let rule = "rule r\n  command = c\n";
+        parse_strict(&format!("{rule}build a: r\nbuild b: r\n")).unwrap();
+        for (builds, line) in [
+            ("build a a: r\n", 3),
+            ("build $x a: r\n  x = a\n", 3),
+            ("build a ./a: r\n", 3),
+            ("build a: r\nbuild b | sub/../a: r\n", 4),
+        ] {
+            let err = parse_strict(&format!("{rule}{builds}")).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("build.ninja:{line}: multiple rules generate a")
+            );
+        }
+    }
+
+    #[test]
+    fn suggest_similar_names() {
+        assert_eq!(edit_distance("ccx", "cxx", 1), Some(1));
+        assert_eq!(edit_distance("cc", "link", 1), None);
+        assert_eq!(edit_distance("", "ab", 2), Some(2));
+        assert_eq!(edit_distance("tuoch", "touch", 1), Some(1));
+        let rules = ["cxx", "cc", "link", "phony", "stamp"];
+        assert_eq!(similar_names("ccx", rules.into_iter()), ["cc", "cxx"]);
+        assert_eq!(similar_names("lnik", rules.into_iter()), ["link"]);
+        assert!(similar_names("archive", rules.into_iter()).is_empty());
+        // The candidates' order doesn't affect the suggestions.
+        assert_eq!(similar_names("ccx", rules.into_iter().rev()), ["cc", "cxx"]);
+    }
+
+    #[test]
+    fn statement_boundary_skips_continuations() {
+        assert_eq!(statement_boundary(b"a = 1\nb = 2"), Some(6));
+        assert_eq!(statement_boundary(b"a = $\nb"), None);
+        assert_eq!(statement_boundary(b"a = $$\nb"), Some(7));
+        assert_eq!(statement_boundary(b"a = $\r\nb"), None);
+        assert_eq!(statement_boundary(b"build x: y\n  z = 1\n"), None);
+    }
+}
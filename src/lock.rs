@@ -0,0 +1,132 @@
+//! An advisory lock keeping concurrent n2 processes out of one build directory,
+//! where they would race writing the database and run the same builds.
+//!
+//! The lock is held by the OS on behalf of an open file (flock on Unix,
+//! LockFileEx on Windows), so it is released whenever the process exits, even
+//! by crashing, and a lock file left behind never wedges the directory.
+
+use anyhow::bail;
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+
+pub const LOCK_FILENAME: &str = ".n2_lock";
+
+/// A held lock, released when dropped.
+pub struct BuildLock {
+    _file: File,
+}
+
+#[cfg(unix)]
+fn try_lock(file: &File, wait: bool) -> std::io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+    let mut op = libc::LOCK_EX;
+    if !wait {
+        op |= libc::LOCK_NB;
+    }
+    loop {
+        // Safety: flock on a file descriptor we own.
+        if unsafe { libc::flock(file.as_raw_fd(), op) } == 0 {
+            return Ok(true);
+        }
+        let err = std::io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EWOULDBLOCK) => return Ok(false),
+            Some(libc::EINTR) => continue,
+            _ => return Err(err),
+        }
+    }
+}
+
+#[cfg(windows)]
+fn try_lock(file: &File, wait: bool) -> std::io::Result<bool> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Foundation::ERROR_LOCK_VIOLATION;
+    use windows_sys::Win32::Storage::FileSystem::{
+        LockFileEx, LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY,
+    };
+    use windows_sys::Win32::System::IO::OVERLAPPED;
+
+    let mut flags = LOCKFILE_EXCLUSIVE_LOCK;
+    if !wait {
+        flags |= LOCKFILE_FAIL_IMMEDIATELY;
+    }
+    // Windows locks keep others from reading the locked range, so lock a
+    // byte far past the pid written at the start of the file.
+    // Safety: an all-zero OVERLAPPED is valid, and LockFileEx is called on a
+    // handle we own.
+    unsafe {
+        let mut overlapped: OVERLAPPED = std::mem::zeroed();
+        overlapped.Anonymous.Anonymous.OffsetHigh = 1;
+        if LockFileEx(file.as_raw_handle() as _, flags, 0, 1, 0, &mut overlapped) != 0 {
+            return Ok(true);
+        }
+    }
+    let err = std::io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(code) if code as u32 == ERROR_LOCK_VIOLATION => Ok(false),
+        _ => Err(err),
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn try_lock(_file: &File, _wait: bool) -> std::io::Result<bool> {
+    Ok(true)
+}
+
+/// The pid the lock holder wrote into the lock file, if it got to it yet.
+fn holder(file: &mut File) -> Option<u32> {
+    let mut text = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut text).ok()?;
+    text.trim().parse().ok()
+}
+
+/// Lock the build directory `dir`.  If another process holds the lock, wait
+/// for it if `wait`, or else fail.
+pub fn lock(dir: &Path, wait: bool) -> anyhow::Result<BuildLock> {
+    let path = dir.join(LOCK_FILENAME);
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .map_err(|err| anyhow::anyhow!("open {}: {}", path.display(), err))?;
+    if !try_lock(&file, false)? {
+        let other = match holder(&mut file) {
+            Some(pid) => format!("other n2 process (pid {})", pid),
+            None => "other n2 process".to_owned(),
+        };
+        if !wait {
+            bail!("{} is building in this directory", other);
+        }
+        println!("n2: waiting for {}", other);
+        try_lock(&file, true)?;
+    }
+    file.set_len(0)?;
+    file.rewind()?;
+    write!(file, "{}", std::process::id())?;
+    Ok(BuildLock { _file: file })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exclusive() {
+        let dir = tempfile::tempdir().unwrap();
+        let held = lock(dir.path(), false).unwrap();
+        let err = lock(dir.path(), false).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "other n2 process (pid {}) is building in this directory",
+                std::process::id()
+            )
+        );
+        drop(held);
+        lock(dir.path(), false).unwrap();
+    }
+}
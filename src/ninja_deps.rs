@@ -0,0 +1,291 @@
+//! Reads ninja's `.ninja_deps` log, so that a build directory last built by
+//! ninja can keep its discovered dependencies when switching to n2.
+//!
+//! The format, as written by ninja's deps_log.cc, is a signature and version
+//! followed by records, each prefixed by a little-endian u32 size whose high
+//! bit distinguishes deps records from path records:
+//! - a path record is the path, NUL-padded to a multiple of four bytes, then
+//!   the one's complement of the id it is being assigned;
+//! - a deps record is the output's id, its mtime when the deps were recorded
+//!   (a u32 in seconds in version 3, a u64 in nanoseconds in version 4), and
+//!   the ids of its inputs.
+//!
+//! Later deps records for an output replace earlier ones.  Like ninja, a
+//! truncated final record, as left when ninja was killed while writing it, is
+//! ignored along with anything after it.
+
+use anyhow::bail;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SIGNATURE: &[u8] = b"# ninjadeps\n";
+const DEPS_MASK: u32 = 0x8000_0000;
+const MAX_RECORD_SIZE: usize = (1 << 19) - 1;
+
+/// The dependencies ninja recorded for one output.
+#[derive(Debug, PartialEq)]
+pub struct Deps {
+    pub output: String,
+    /// The output's mtime when the deps were recorded, in the units of
+    /// the log's version: seconds for version 3, nanoseconds for 4.
+    pub mtime: u64,
+    pub deps: Vec<String>,
+}
+
+/// The parsed contents of a deps log.
+#[derive(Debug, PartialEq)]
+pub struct DepsLog {
+    pub version: u32,
+    pub deps: Vec<Deps>,
+}
+
+impl DepsLog {
+    /// Convert a file's mtime to the units of this log's recorded mtimes.
+    pub fn timestamp(&self, mtime: SystemTime) -> u64 {
+        let since_epoch = mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+        match self.version {
+            3 => since_epoch.as_secs(),
+            _ => since_epoch.as_nanos() as u64,
+        }
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    ofs: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if self.buf.len() - self.ofs < len {
+            bail!("truncated at offset {}", self.ofs);
+        }
+        let bytes = &self.buf[self.ofs..self.ofs + len];
+        self.ofs += len;
+        Ok(bytes)
+    }
+
+    fn read_u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
+
+/// Parse the contents of a `.ninja_deps` file.
+pub fn parse(buf: &[u8]) -> anyhow::Result<DepsLog> {
+    let mut r = Reader { buf, ofs: 0 };
+    if r.take(SIGNATURE.len()).ok() != Some(SIGNATURE) {
+        bail!("not a ninja deps log");
+    }
+    let version = r.read_u32()?;
+    if version != 3 && version != 4 {
+        bail!("unsupported ninja deps log version {}", version);
+    }
+    let mtime_len = if version == 3 { 4 } else { 8 };
+
+    let mut paths: Vec<String> = Vec::new();
+    let mut by_output: HashMap<u32, usize> = HashMap::new();
+    let mut deps: Vec<Deps> = Vec::new();
+    while r.ofs < buf.len() {
+        let Ok(header) = r.read_u32() else {
+            break;
+        };
+        let size = (header & !DEPS_MASK) as usize;
+        if size > MAX_RECORD_SIZE || size % 4 != 0 {
+            bail!("bad record size {} at offset {}", size, r.ofs - 4);
+        }
+        let Ok(buf) = r.take(size) else {
+            break;
+        };
+        let mut record = Reader { buf, ofs: 0 };
+        if header & DEPS_MASK == 0 {
+            if size < 4 {
+                bail!("bad path record at offset {}", r.ofs - size);
+            }
+            let name = record.take(size - 4)?;
+            let checksum = record.read_u32()?;
+            if checksum != !(paths.len() as u32) {
+                bail!("bad path record checksum at offset {}", r.ofs - size);
+            }
+            let end = name.iter().rposition(|&c| c != 0).map_or(0, |i| i + 1);
+            if name.len() - end > 3 {
+                bail!("bad path record padding at offset {}", r.ofs - size);
+            }
+            paths.push(String::from_utf8(name[..end].to_vec())?);
+        } else {
+            if size < 4 + mtime_len {
+                bail!("bad deps record at offset {}", r.ofs - size);
+            }
+            let lookup = |id: u32| -> anyhow::Result<String> {
+                match paths.get(id as usize) {
+                    Some(path) => Ok(path.clone()),
+                    None => bail!("deps record refers to unknown path id {}", id),
+                }
+            };
+            let out = record.read_u32()?;
+            let mtime = match version {
+                3 => record.read_u32()? as u64,
+                _ => record.read_u32()? as u64 | (record.read_u32()? as u64) << 32,
+            };
+            let ins = (0..(size - 4 - mtime_len) / 4)
+                .map(|_| lookup(record.read_u32()?))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let entry = Deps {
+                output: lookup(out)?,
+                mtime,
+                deps: ins,
+            };
+            match by_output.get(&out) {
+                Some(&i) => deps[i] = entry,
+                None => {
+                    by_output.insert(out, deps.len());
+                    deps.push(entry);
+                }
+            }
+        }
+    }
+    Ok(DepsLog { version, deps })
+}
+
+/// Read and parse a `.ninja_deps` file.
+pub fn read(path: &std::path::Path) -> anyhow::Result<DepsLog> {
+    parse(&std::fs::read(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a log the way ninja's DepsLog::RecordDeps does.
+    struct Writer {
+        version: u32,
+        buf: Vec<u8>,
+        ids: Vec<String>,
+    }
+
+    impl Writer {
+        fn new(version: u32) -> Self {
+            let mut buf = SIGNATURE.to_vec();
+            buf.extend(version.to_le_bytes());
+            Writer {
+                version,
+                buf,
+                ids: Vec::new(),
+            }
+        }
+
+        fn id(&mut self, path: &str) -> u32 {
+            if let Some(id) = self.ids.iter().position(|p| p == path) {
+                return id as u32;
+            }
+            let padding = (4 - path.len() % 4) % 4;
+            let size = path.len() + padding + 4;
+            self.buf.extend((size as u32).to_le_bytes());
+            self.buf.extend(path.as_bytes());
+            self.buf.extend(std::iter::repeat(0).take(padding));
+            let id = self.ids.len() as u32;
+            self.buf.extend((!id).to_le_bytes());
+            self.ids.push(path.to_owned());
+            id
+        }
+
+        fn record(&mut self, out: &str, mtime: u64, deps: &[&str]) {
+            let out = self.id(out);
+            let ids = deps.iter().map(|d| self.id(d)).collect::<Vec<_>>();
+            let mtime_len = if self.version == 3 { 4 } else { 8 };
+            let size = 4 + mtime_len + 4 * ids.len();
+            self.buf.extend((size as u32 | DEPS_MASK).to_le_bytes());
+            self.buf.extend(out.to_le_bytes());
+            self.buf.extend(&mtime.to_le_bytes()[..mtime_len]);
+            for id in ids {
+                self.buf.extend(id.to_le_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for version in [3, 4] {
+            let mut w = Writer::new(version);
+            w.record("foo.o", 1234, &["foo.c", "foo.h", "common.h"]);
+            w.record("bar.o", 5678, &["bar.c", "common.h"]);
+            // A later record for the same output replaces the earlier one.
+            w.record("foo.o", 4321, &["foo.c", "common.h"]);
+            let log = parse(&w.buf).unwrap();
+            assert_eq!(log.version, version);
+            assert_eq!(
+                log.deps,
+                vec![
+                    Deps {
+                        output: "foo.o".into(),
+                        mtime: 4321,
+                        deps: vec!["foo.c".into(), "common.h".into()],
+                    },
+                    Deps {
+                        output: "bar.o".into(),
+                        mtime: 5678,
+                        deps: vec!["bar.c".into(), "common.h".into()],
+                    },
+                ]
+            );
+        }
+    }
+
+    /// A log laid out byte for byte as ninja 1.11 writes it after building
+    /// `out.o` from in.c, which includes in.h.
+    #[test]
+    fn ninja_written() {
+        let buf = b"# ninjadeps\n\x04\x00\x00\x00\
+            \x0c\x00\x00\x00out.o\x00\x00\x00\xff\xff\xff\xff\
+            \x08\x00\x00\x00in.c\xfe\xff\xff\xff\
+            \x08\x00\x00\x00in.h\xfd\xff\xff\xff\
+            \x14\x00\x00\x80\x00\x00\x00\x00\
+            \x00\x94\x35\x77\x0a\x3e\x38\x18\
+            \x01\x00\x00\x00\x02\x00\x00\x00";
+        let log = parse(buf).unwrap();
+        assert_eq!(log.version, 4);
+        assert_eq!(
+            log.deps,
+            vec![Deps {
+                output: "out.o".into(),
+                mtime: 0x1838_3e0a_7735_9400,
+                deps: vec!["in.c".into(), "in.h".into()],
+            }]
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let mut w = Writer::new(4);
+        w.record("foo.o", 1, &["foo.c"]);
+        let good = w.buf.len();
+        w.record("bar.o", 2, &["bar.c"]);
+
+        // Cut within the next record's header, and within its body.
+        for len in [good + 2, w.buf.len() - 2] {
+            let log = parse(&w.buf[..len]).unwrap();
+            assert_eq!(
+                log.deps,
+                vec![Deps {
+                    output: "foo.o".into(),
+                    mtime: 1,
+                    deps: vec!["foo.c".into()],
+                }]
+            );
+        }
+    }
+
+    #[test]
+    fn corrupt() {
+        let mut w = Writer::new(4);
+        w.record("foo.o", 1, &["foo.c"]);
+        let good = w.buf.clone();
+
+        assert!(parse(b"# ninjadeps\n\x05\x00\x00\x00").is_err());
+        assert!(parse(b"not a deps log at all").is_err());
+
+        // Flip a byte of the first path's checksum.
+        let mut bad = good.clone();
+        bad[SIGNATURE.len() + 4 + 4 + 8] ^= 1;
+        assert!(parse(&bad).is_err());
+    }
+}
@@ -0,0 +1,384 @@
+//! Reads ninja's `.ninja_log`, which records for each output the command that
+//! last built it, so that a build directory last built by ninja can tell which
+//! of its outputs are up to date when switching to n2.
+//!
+//! After a `# ninja log vN` header line, each line holds tab-separated
+//! fields: the task's start and end times in milliseconds, the output's mtime
+//! after the task ran, the output's path, and a hex MurmurHash64A of the
+//! command.  Later lines for an output replace earlier ones.
+//!
+//! n2 also appends to the log as builds finish, for tools that read it, and
+//! like the database, rewrites it without superseded lines once they dominate.
+
+use crate::graph::Build;
+use anyhow::bail;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::Instant;
+
+const HEADER: &str = "# ninja log v5\n";
+
+/// A log is compacted when opening it once it holds at least this many
+/// lines...
+const COMPACT_MIN_LINES: usize = 1000;
+/// ...and this many times as many lines as outputs they apply to.
+const COMPACT_RATIO: usize = 3;
+
+/// One output's entry in the log.
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+    pub start_ms: u32,
+    pub end_ms: u32,
+    pub mtime: u64,
+    pub output: String,
+    pub command_hash: u64,
+}
+
+/// The parsed contents of a build log.
+#[derive(Debug, PartialEq)]
+pub struct NinjaLog {
+    pub version: u32,
+    pub entries: Vec<Entry>,
+    /// Lines that couldn't be parsed, which ninja also skips.
+    pub malformed: usize,
+}
+
+fn parse_entry(line: &str) -> Option<Entry> {
+    let mut fields = line.split('\t');
+    let entry = Entry {
+        start_ms: fields.next()?.parse().ok()?,
+        end_ms: fields.next()?.parse().ok()?,
+        mtime: fields.next()?.parse().ok()?,
+        output: fields.next()?.to_owned(),
+        command_hash: u64::from_str_radix(fields.next()?, 16).ok()?,
+    };
+    if entry.output.is_empty() || fields.next().is_some() {
+        return None;
+    }
+    Some(entry)
+}
+
+/// Parse the contents of a `.ninja_log` file.
+pub fn parse(text: &str) -> anyhow::Result<NinjaLog> {
+    let mut lines = text.lines();
+    let version = match lines.next().and_then(|l| l.strip_prefix("# ninja log v")) {
+        Some(v) => v.parse::<u32>().ok(),
+        None => bail!("not a ninja log"),
+    };
+    let version = match version {
+        Some(v @ (5 | 6)) => v,
+        _ => bail!("unsupported ninja log version"),
+    };
+
+    let mut by_output: HashMap<String, usize> = HashMap::new();
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut malformed = 0;
+    for line in lines {
+        let Some(entry) = parse_entry(line) else {
+            malformed += 1;
+            continue;
+        };
+        match by_output.get(&entry.output) {
+            Some(&i) => entries[i] = entry,
+            None => {
+                by_output.insert(entry.output.clone(), entries.len());
+                entries.push(entry);
+            }
+        }
+    }
+    Ok(NinjaLog {
+        version,
+        entries,
+        malformed,
+    })
+}
+
+/// Read and parse a `.ninja_log` file.
+pub fn read(path: &std::path::Path) -> anyhow::Result<NinjaLog> {
+    parse(&std::fs::read_to_string(path)?)
+}
+
+/// The hash ninja logs for a command, MurmurHash64A with ninja's seed.
+/// For a build with an rspfile, ninja hashes the command followed by
+/// `;rspfile=` and the rspfile's content.
+pub fn command_hash(command: &str) -> u64 {
+    const SEED: u64 = 0xDECA_FBAD_DECA_FBAD;
+    const M: u64 = 0xc6a4_a793_5bd1_e995;
+    const R: u32 = 47;
+
+    let data = command.as_bytes();
+    let mut h = SEED ^ (data.len() as u64).wrapping_mul(M);
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, &b) in tail.iter().enumerate() {
+            h ^= (b as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+/// The hash ninja logs for a build's command, if it has one.
+pub fn build_command_hash(build: &Build) -> Option<u64> {
+    let cmdline = build.cmdline.as_ref()?;
+    Some(match &build.rspfile {
+        Some(rspfile) => command_hash(&format!("{};rspfile={}", cmdline, rspfile.content)),
+        None => command_hash(cmdline),
+    })
+}
+
+/// Append a log line for an output to `out`.
+fn push_line(
+    out: &mut String,
+    (start_ms, end_ms): (u128, u128),
+    mtime: u64,
+    output: &str,
+    command_hash: u64,
+) {
+    out.push_str(&format!(
+        "{}\t{}\t{}\t{}\t{:x}\n",
+        start_ms, end_ms, mtime, output, command_hash
+    ));
+}
+
+/// Rewrite the log at `path` with just the latest entry for each output.
+/// The new log is written beside the old one and renamed over it, so an
+/// interrupted compaction leaves the old one intact.
+fn compact(path: &Path, log: &NinjaLog) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = Path::new(&tmp);
+    let mut text = HEADER.to_owned();
+    for entry in &log.entries {
+        push_line(
+            &mut text,
+            (entry.start_ms.into(), entry.end_ms.into()),
+            entry.mtime,
+            &entry.output,
+            entry.command_hash,
+        );
+    }
+    let mut f = File::create(tmp)?;
+    f.write_all(text.as_bytes())?;
+    f.sync_all()?;
+    drop(f);
+    std::fs::rename(tmp, path)
+}
+
+/// Appends entries to a `.ninja_log` as builds finish.
+pub struct Writer {
+    f: File,
+    /// When the build started, which entries' times are relative to.
+    start: Instant,
+}
+
+impl Writer {
+    /// Open the log at `path` for appending, creating it if needed.  A log
+    /// in another version, such as the v6 newer ninjas write, is left alone
+    /// and None is returned: it belongs to ninja, and isn't n2's to discard.
+    pub fn open(path: &Path, start: Instant) -> std::io::Result<Option<Self>> {
+        let open = || {
+            File::options()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(path)
+        };
+        let mut f = open()?;
+        let mut content = Vec::new();
+        f.read_to_end(&mut content)?;
+        if content.is_empty() {
+            f.write_all(HEADER.as_bytes())?;
+            return Ok(Some(Writer { f, start }));
+        }
+        if !content.starts_with(HEADER.as_bytes()) {
+            println!(
+                "n2: warn: {}: not a v5 ninja log; not writing to it",
+                path.display()
+            );
+            return Ok(None);
+        }
+        let lines = content[HEADER.len()..]
+            .iter()
+            .filter(|&&c| c == b'\n')
+            .count();
+        if lines >= COMPACT_MIN_LINES {
+            if let Ok(log) = parse(&String::from_utf8_lossy(&content)) {
+                if lines > log.entries.len() * COMPACT_RATIO {
+                    drop(f);
+                    compact(path, &log)?;
+                    f = open()?;
+                }
+            }
+        }
+        Ok(Some(Writer { f, start }))
+    }
+
+    /// Record a finished task, with an entry for each of its outputs.
+    /// `mtime` is the newest of the outputs' mtimes after it ran.
+    pub fn record<'a>(
+        &mut self,
+        span: (Instant, Instant),
+        mtime: u64,
+        outputs: impl Iterator<Item = &'a str>,
+        command_hash: u64,
+    ) -> std::io::Result<()> {
+        let start_ms = span.0.saturating_duration_since(self.start).as_millis();
+        let end_ms = span.1.saturating_duration_since(self.start).as_millis();
+        let mut entries = String::new();
+        for output in outputs {
+            push_line(
+                &mut entries,
+                (start_ms, end_ms),
+                mtime,
+                output,
+                command_hash,
+            );
+        }
+        self.f.write_all(entries.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_log() {
+        let log = parse(
+            "# ninja log v5\n\
+             10\t20\t1700000000000000000\tfoo.o\t1f2e3d4c5b6a7988\n\
+             garbage\n\
+             30\t40\t1700000000000000001\tbar.o\tabc\n\
+             12\t\t0\tbaz.o\t1\n\
+             50\t60\t1700000000000000002\tfoo.o\t2\n",
+        )
+        .unwrap();
+        assert_eq!(log.version, 5);
+        assert_eq!(log.malformed, 2);
+        assert_eq!(
+            log.entries,
+            vec![
+                Entry {
+                    start_ms: 50,
+                    end_ms: 60,
+                    mtime: 1700000000000000002,
+                    output: "foo.o".into(),
+                    command_hash: 2,
+                },
+                Entry {
+                    start_ms: 30,
+                    end_ms: 40,
+                    mtime: 1700000000000000001,
+                    output: "bar.o".into(),
+                    command_hash: 0xabc,
+                },
+            ]
+        );
+
+        assert!(parse("# ninja log v6\n").is_ok());
+        assert!(parse("# ninja log v4\n").is_err());
+        assert!(parse("# ninja log v7\n").is_err());
+        assert!(parse("").is_err());
+    }
+
+    #[test]
+    fn write_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".ninja_log");
+
+        // A log in another version is ninja's, and left as it is.
+        let v6 = "# ninja log v6\n10\t20\t1\tfoo.o\tabc\n";
+        std::fs::write(&path, v6).unwrap();
+        let start = Instant::now();
+        assert!(Writer::open(&path, start).unwrap().is_none());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), v6);
+        std::fs::remove_file(&path).unwrap();
+
+        let span = (start, start + std::time::Duration::from_millis(20));
+        let mut w = Writer::open(&path, start).unwrap().unwrap();
+        w.record(span, 123, ["a.o", "a.d"].into_iter(), 0xabc)
+            .unwrap();
+        drop(w);
+        let mut w = Writer::open(&path, start).unwrap().unwrap();
+        w.record(span, 456, ["a.o"].into_iter(), 0xdef).unwrap();
+        drop(w);
+
+        let log = parse(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(log.malformed, 0);
+        assert_eq!(
+            log.entries,
+            vec![
+                Entry {
+                    start_ms: 0,
+                    end_ms: 20,
+                    mtime: 456,
+                    output: "a.o".into(),
+                    command_hash: 0xdef,
+                },
+                Entry {
+                    start_ms: 0,
+                    end_ms: 20,
+                    mtime: 123,
+                    output: "a.d".into(),
+                    command_hash: 0xabc,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn compact_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".ninja_log");
+        let mut text = HEADER.to_owned();
+        for i in 0..COMPACT_MIN_LINES {
+            push_line(&mut text, (0, 1), i as u64, &format!("{}.o", i % 2), 0xabc);
+        }
+        std::fs::write(&path, &text).unwrap();
+
+        let start = Instant::now();
+        let mut w = Writer::open(&path, start).unwrap().unwrap();
+        w.record((start, start), 5, ["c.o"].into_iter(), 0xdef)
+            .unwrap();
+        drop(w);
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(text.lines().count(), 4);
+        let log = parse(&text).unwrap();
+        let mtimes: Vec<_> = log.entries.iter().map(|e| (&*e.output, e.mtime)).collect();
+        let last = COMPACT_MIN_LINES as u64 - 1;
+        assert_eq!(mtimes, [("0.o", last - 1), ("1.o", last), ("c.o", 5)]);
+
+        // A log with few enough superseded lines is left as it is.
+        let mut text = HEADER.to_owned();
+        for i in 0..COMPACT_MIN_LINES {
+            push_line(&mut text, (0, 1), 0, &format!("{}.o", i % 400), 0xabc);
+        }
+        std::fs::write(&path, &text).unwrap();
+        drop(Writer::open(&path, start).unwrap());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), text);
+    }
+
+    #[test]
+    fn hash() {
+        // Values from an independent implementation of MurmurHash64A,
+        // covering empty, partial and whole-plus-partial blocks.
+        assert_eq!(command_hash(""), 0x87c2_bc0b_eaf1_d91d);
+        assert_eq!(command_hash("touch out"), 0x8ea3_cc54_bdcc_ad2c);
+        assert_eq!(command_hash("cc -c foo.c -o foo.o"), 0xc1cf_c096_7c85_181b);
+    }
+}
@@ -7,18 +7,41 @@
 
 use crate::{
     eval::{EvalPart, EvalString, Vars},
-    scanner::{ParseError, ParseResult, Scanner},
+    scanner::{stop_table, ColorMode, ParseError, ParseErrorKind, ParseResult, Scanner, StopTable},
     smallmap::SmallMap,
+    version,
 };
-use std::path::Path;
+use std::{collections::VecDeque, path::Path};
 
 /// A list of variable bindings, as expressed with syntax like:
 ///   key = $val
 pub type VarList<'text> = SmallMap<&'text str, EvalString<&'text str>>;
 
+/// Whether a variable name is one that ninja, or n2, consults on rules.
+pub fn is_reserved_rule_var(var: &str) -> bool {
+    matches!(
+        var,
+        "command"
+            | "depfile"
+            | "dyndep"
+            | "description"
+            | "deps"
+            | "generator"
+            | "pool"
+            | "precious"
+            | "restat"
+            | "rspfile"
+            | "rspfile_content"
+            | "msvc_deps_prefix"
+    )
+}
+
 pub struct Rule<'text> {
     pub name: &'text str,
+    pub line: usize,
     pub vars: VarList<'text>,
+    /// The line of each of vars' bindings, in the same order.
+    pub var_lines: Vec<usize>,
 }
 
 pub struct Build<'text> {
@@ -40,66 +63,320 @@ pub struct Pool<'text> {
     pub depth: usize,
 }
 
+/// A comment, only produced by parsers created with `ParserOptions::comments`.
+#[derive(Debug, PartialEq)]
+pub struct Comment<'text> {
+    /// The comment text verbatim, starting with the '#' and excluding the
+    /// line ending.
+    pub text: &'text str,
+    pub line: usize,
+    /// Whether the comment was indented, i.e. within a rule/build/pool body.
+    pub indented: bool,
+    /// Whether the comment is attached to what follows it, i.e. it isn't
+    /// followed by a blank line or the end of the file.
+    pub attached: bool,
+}
+
+/// A variable bound more than once within one rule or build body.  Like
+/// ninja, the later binding wins.
+#[derive(Debug, PartialEq)]
+pub struct ShadowedVar<'text> {
+    pub name: &'text str,
+    pub line: usize,
+    /// Line of the earlier, ignored binding.
+    pub prev_line: usize,
+}
+
+/// A file-scope variable binding, only produced by parsers created with
+/// `ParserOptions::bindings`.  The parser still applies it to `vars`.
+#[derive(Debug, PartialEq)]
+pub struct Binding<'text> {
+    pub name: &'text str,
+    pub line: usize,
+    pub value: EvalString<&'text str>,
+}
+
 pub enum Statement<'text> {
+    Binding(Binding<'text>),
     Rule(Rule<'text>),
     Build(Build<'text>),
     Default(Vec<EvalString<&'text str>>),
     Include(EvalString<&'text str>),
     Subninja(EvalString<&'text str>),
     Pool(Pool<'text>),
+    Comment(Comment<'text>),
+}
+
+/// Options controlling what the parser accepts and produces.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ParserOptions {
+    /// Reject constructs that n2 accepts but ninja 1.11 rejects, to keep
+    /// manifests buildable by both.
+    pub strict: bool,
+    /// Produce comments as statements rather than skipping them.
+    pub comments: bool,
+    /// Produce file-scope bindings as statements, unevaluated, as well as
+    /// applying them.
+    pub bindings: bool,
 }
 
+/// Bytes that may end a literal run in a value or path, so that read_eval can
+/// skip over the rest in bulk.  A \r only matters as part of a \r\n.
+const VALUE_STOPS: StopTable = stop_table(b"\0\n\r$");
+const PATH_STOPS: StopTable = stop_table(b"\0 :|\n\r$");
+
+/// In error recovery mode, the maximum number of errors collected before
+/// parsing stops.
+pub const MAX_RECOVERED_ERRORS: usize = 20;
+
 pub struct Parser<'text> {
     scanner: Scanner<'text>,
     pub vars: Vars<'text>,
     /// Reading EvalStrings is very hot when parsing, so we always read into
     /// this buffer and then clone it afterwards.
     eval_buf: Vec<EvalPart<&'text str>>,
+    /// Whether a `ninja_required_version` has already been checked.  Only the
+    /// first one seen, possibly in an earlier file, is checked.
+    pub required_version_seen: bool,
+    options: ParserOptions,
+    /// Comments found within statement bodies, produced after the statement.
+    pending_comments: VecDeque<Comment<'text>>,
+    /// Errors collected by read_recovering.
+    errors: Vec<ParseError>,
+    /// Lines of the bindings in the scoped variable block being read, to
+    /// report shadowed bindings.
+    var_lines: Vec<(&'text str, usize)>,
+    shadowed_vars: Vec<ShadowedVar<'text>>,
+    /// Names bound by override_var, whose file-scope assignments are skipped.
+    overridden: Vec<String>,
+    /// Line of the statement last returned by read.
+    statement_line: usize,
 }
 
 impl<'text> Parser<'text> {
     pub fn new(buf: &'text [u8]) -> Parser<'text> {
+        Parser::with_options(buf, ParserOptions::default())
+    }
+
+    pub fn with_options(buf: &'text [u8], options: ParserOptions) -> Parser<'text> {
         Parser {
             scanner: Scanner::new(buf),
             vars: Vars::default(),
             eval_buf: Vec::with_capacity(16),
+            required_version_seen: false,
+            options,
+            pending_comments: VecDeque::new(),
+            errors: Vec::new(),
+            var_lines: Vec::new(),
+            shadowed_vars: Vec::new(),
+            overridden: Vec::new(),
+            statement_line: 0,
         }
     }
 
+    /// Create a parser that produces `Statement::Comment` for comments
+    /// rather than skipping them, for tools that rewrite manifests.
+    /// Comments within a statement's body are produced after the statement.
+    pub fn with_comments(buf: &'text [u8]) -> Parser<'text> {
+        Parser::with_options(
+            buf,
+            ParserOptions {
+                comments: true,
+                ..ParserOptions::default()
+            },
+        )
+    }
+
+    /// Number lines from `line` rather than 1, for input that is one block of
+    /// a larger file.
+    pub fn start_at_line(&mut self, line: usize) {
+        self.scanner.start_at_line(line);
+    }
+
+    /// The line number of the current position.
+    pub fn line(&self) -> usize {
+        self.scanner.line()
+    }
+
+    /// The line of the statement last returned by read, for statements such
+    /// as includes that don't record their own line.
+    pub fn statement_line(&self) -> usize {
+        self.statement_line
+    }
+
     pub fn format_parse_error(&self, filename: &Path, err: ParseError) -> String {
         self.scanner.format_parse_error(filename, err)
     }
 
+    pub fn format_parse_error_with_color(
+        &self,
+        filename: &Path,
+        err: ParseError,
+        color: ColorMode,
+    ) -> String {
+        self.scanner
+            .format_parse_error_with_color(filename, err, color)
+    }
+
+    /// Read all remaining statements.
+    pub fn read_all(&mut self) -> ParseResult<Vec<Statement<'text>>> {
+        self.statements().collect()
+    }
+
+    /// Iterate over the remaining statements.
+    pub fn statements<'p>(&'p mut self) -> Statements<'p, 'text> {
+        Statements {
+            parser: self,
+            done: false,
+        }
+    }
+
+    /// Like read, but rather than failing on the first parse error, records
+    /// the error, skips to the next non-indented line and continues.
+    /// Returns None at the end of the file or once MAX_RECOVERED_ERRORS
+    /// errors have been collected; retrieve them with take_errors.
+    pub fn read_recovering(&mut self) -> Option<Statement<'text>> {
+        while self.errors.len() < MAX_RECOVERED_ERRORS {
+            let start = self.scanner.ofs;
+            match self.read() {
+                Ok(stmt) => return stmt,
+                Err(err) => {
+                    self.errors.push(err);
+                    self.skip_to_next_statement(start);
+                }
+            }
+        }
+        None
+    }
+
+    /// Take the errors collected by read_recovering.
+    pub fn take_errors(&mut self) -> Vec<ParseError> {
+        std::mem::take(&mut self.errors)
+    }
+
+    /// Bind `name` to `value` at file scope, as from the command line.  Any
+    /// file-scope assignment to `name` is then skipped, so the override wins
+    /// over defaults in the manifest; rule and build bindings still shadow
+    /// it as usual.  Call before the first `read`.
+    pub fn override_var(&mut self, name: &str, value: &str) {
+        self.vars.insert_owned(name.to_owned(), value.to_owned());
+        if !self.overridden.iter().any(|n| n == name) {
+            self.overridden.push(name.to_owned());
+        }
+    }
+
+    /// Take the shadowed variable bindings found so far.
+    pub fn take_shadowed_vars(&mut self) -> Vec<ShadowedVar<'text>> {
+        std::mem::take(&mut self.shadowed_vars)
+    }
+
+    /// Skip past the current line and any indented lines following it,
+    /// after a failed attempt to read a statement from `start`.  An error
+    /// that left the scanner at the start of a line already consumed the
+    /// line, so only the indented lines are skipped, lest the next statement
+    /// be dropped.  Always makes progress unless already at the end of the
+    /// file.
+    fn skip_to_next_statement(&mut self, start: usize) {
+        if self.scanner.ofs > start
+            && self.scanner.at_line_start()
+            && !matches!(self.scanner.peek(), ' ' | '\t')
+        {
+            return;
+        }
+        loop {
+            match self.scanner.read() {
+                '\0' => {
+                    self.scanner.back();
+                    return;
+                }
+                '\n' if !matches!(self.scanner.peek(), ' ' | '\t') => return,
+                _ => {}
+            }
+        }
+    }
+
     pub fn read(&mut self) -> ParseResult<Option<Statement<'text>>> {
+        if self.options.comments {
+            if let Some(comment) = self.pending_comments.pop_front() {
+                return Ok(Some(Statement::Comment(comment)));
+            }
+        }
+        if self.scanner.ofs == 0 && self.scanner.is_utf16() {
+            return self.scanner.parse_error(
+                ParseErrorKind::Encoding,
+                "build file appears to be UTF-16; n2 requires UTF-8",
+            );
+        }
+        if self.scanner.ofs == 0 && self.options.strict {
+            if let Some(ofs) = self.scanner.find_lone_cr() {
+                return self.scanner.parse_error_at(
+                    ofs,
+                    ParseErrorKind::Encoding,
+                    "carriage return without a following newline",
+                );
+            }
+        }
         loop {
             match self.scanner.peek() {
                 '\0' => return Ok(None),
                 '\n' => self.scanner.next(),
+                '#' if self.options.comments => {
+                    return Ok(Some(Statement::Comment(self.read_comment(false)?)));
+                }
                 '#' => self.skip_comment()?,
-                ' ' | '\t' => return self.scanner.parse_error("unexpected whitespace"),
+                ' ' | '\t' => {
+                    return self.scanner.parse_error(
+                        ParseErrorKind::UnexpectedWhitespace,
+                        "unexpected whitespace",
+                    )
+                }
                 _ => {
+                    let line = self.scanner.line();
+                    self.statement_line = line;
                     let ident = self.read_ident()?;
                     self.skip_spaces();
-                    match ident {
-                        "rule" => return Ok(Some(Statement::Rule(self.read_rule()?))),
-                        "build" => return Ok(Some(Statement::Build(self.read_build()?))),
-                        "default" => return Ok(Some(Statement::Default(self.read_default()?))),
-                        "include" => {
-                            return Ok(Some(Statement::Include(self.read_eval(false)?)));
-                        }
-                        "subninja" => {
-                            return Ok(Some(Statement::Subninja(self.read_eval(false)?)));
-                        }
-                        "pool" => return Ok(Some(Statement::Pool(self.read_pool()?))),
-                        ident => {
+                    // Like ninja, an identifier followed by '=' is a variable
+                    // assignment even if it's also a keyword.
+                    let keyword = match self.scanner.peek() {
+                        '=' => "",
+                        _ => ident,
+                    };
+                    let (kind, stmt) = match keyword {
+                        "rule" => ("rule", self.read_rule().map(Statement::Rule)),
+                        "build" => ("build", self.read_build().map(Statement::Build)),
+                        "default" => ("default", self.read_default().map(Statement::Default)),
+                        "include" => ("include", self.read_eval(false).map(Statement::Include)),
+                        "subninja" => ("subninja", self.read_eval(false).map(Statement::Subninja)),
+                        "pool" => ("pool", self.read_pool().map(Statement::Pool)),
+                        _ => {
+                            let ofs = self.scanner.ofs;
                             // TODO: The evaluation of global variables should
                             // be moved out of the parser, so that we can run
                             // multiple parsers in parallel and then evaluate
                             // all the variables in series at the end.
-                            let val = self.read_vardef()?.evaluate(&[&self.vars]);
-                            self.vars.insert(ident, val);
+                            let value = self.read_vardef()?;
+                            let val = value.evaluate(&[&self.vars]);
+                            if ident == "ninja_required_version" && !self.required_version_seen {
+                                self.required_version_seen = true;
+                                if let Err(msg) = version::check_required_version(&val) {
+                                    return self.scanner.parse_error_at(
+                                        ofs,
+                                        ParseErrorKind::RequiredVersion,
+                                        msg,
+                                    );
+                                }
+                            }
+                            if !self.overridden.iter().any(|n| n == ident) {
+                                self.vars.insert(ident, val);
+                            }
+                            if self.options.bindings {
+                                let name = ident;
+                                return Ok(Some(Statement::Binding(Binding { name, line, value })));
+                            }
+                            continue;
                         }
-                    }
+                    };
+                    return stmt.map(Some).map_err(|err| err.in_statement(kind, line));
                 }
             }
         }
@@ -110,14 +387,22 @@ impl<'text> Parser<'text> {
         self.skip_spaces();
         self.scanner.expect('=')?;
         self.skip_spaces();
-        // read_eval will error out if there's nothing to read
-        if self.scanner.peek() == '\n' {
-            self.scanner.expect('\n')?;
-            return Ok(EvalString::new(Vec::new()));
+        // read_eval will error out if there's nothing to read.  An empty value
+        // evaluates to the empty string.
+        let result = match self.scanner.peek() {
+            '\n' | '\0' => EvalString::new(Vec::new()),
+            _ => self.read_eval(false)?,
+        };
+        self.expect_line_end()?;
+        Ok(result)
+    }
+
+    /// Expect the end of a line, which may also be the end of the file.
+    fn expect_line_end(&mut self) -> ParseResult<()> {
+        if self.scanner.peek() == '\0' {
+            return Ok(());
         }
-        let result = self.read_eval(false);
-        self.scanner.expect('\n')?;
-        result
+        self.scanner.expect('\n')
     }
 
     /// Read a collection of `  foo = bar` variables, with leading indent.
@@ -126,54 +411,137 @@ impl<'text> Parser<'text> {
         variable_name_validator: fn(var: &str) -> bool,
     ) -> ParseResult<VarList<'text>> {
         let mut vars = VarList::default();
-        while self.scanner.peek() == ' ' {
-            self.scanner.skip_spaces();
+        self.var_lines.clear();
+        loop {
+            match self.scanner.peek() {
+                ' ' => self.scanner.skip_spaces(),
+                // Like ninja, comments are allowed within the block at any
+                // indentation.
+                '#' => {
+                    self.body_comment(false)?;
+                    continue;
+                }
+                _ => break,
+            }
+            match self.scanner.peek() {
+                '#' => {
+                    self.body_comment(true)?;
+                    continue;
+                }
+                // A whitespace-only line ends the block, as in ninja.
+                '\n' => {
+                    self.scanner.next();
+                    break;
+                }
+                '\0' => break,
+                _ => {}
+            }
+            let line = self.scanner.line();
+            let start = self.scanner.ofs;
             let name = self.read_ident()?;
             if !variable_name_validator(name) {
-                self.scanner
-                    .parse_error(format!("unexpected variable {:?}", name))?;
+                self.scanner.parse_error_span(
+                    start,
+                    self.scanner.ofs,
+                    ParseErrorKind::UnexpectedVariable,
+                    format!("unexpected variable {:?}", name),
+                )?;
             }
             self.skip_spaces();
             let val = self.read_vardef()?;
+            // SmallMap::insert replaces in place, so the later binding wins
+            // while keeping the definition order of the names.
+            match self.var_lines.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, prev_line)) => {
+                    self.shadowed_vars.push(ShadowedVar {
+                        name,
+                        line,
+                        prev_line: *prev_line,
+                    });
+                    *prev_line = line;
+                }
+                None => self.var_lines.push((name, line)),
+            }
             vars.insert(name, val);
         }
         Ok(vars)
     }
 
     fn read_rule(&mut self) -> ParseResult<Rule<'text>> {
+        let line = self.scanner.line();
+        let start = self.scanner.ofs;
         let name = self.read_ident()?;
-        self.scanner.expect('\n')?;
-        let vars = self.read_scoped_vars(|var| {
-            matches!(
-                var,
-                "command"
-                    | "depfile"
-                    | "dyndep"
-                    | "description"
-                    | "deps"
-                    | "generator"
-                    | "pool"
-                    | "restat"
-                    | "rspfile"
-                    | "rspfile_content"
-                    | "msvc_deps_prefix"
-            )
-        })?;
-        Ok(Rule { name, vars })
+        if name == "phony" {
+            // phony is built in and can't be redefined.
+            return self.scanner.parse_error_span(
+                start,
+                self.scanner.ofs,
+                ParseErrorKind::DuplicateRule,
+                "duplicate rule 'phony'",
+            );
+        }
+        self.expect_line_end()?;
+        // Like ninja, rules may bind arbitrary variables; the loader warns
+        // about ones that look unused.  Ninja itself only accepts the ones it
+        // consults.
+        let validator: fn(&str) -> bool = if self.options.strict {
+            is_reserved_rule_var
+        } else {
+            |_| true
+        };
+        let vars = self.read_scoped_vars(validator)?;
+        let var_lines = self.var_lines.iter().map(|&(_, line)| line).collect();
+        Ok(Rule {
+            name,
+            line,
+            vars,
+            var_lines,
+        })
     }
 
     fn read_pool(&mut self) -> ParseResult<Pool<'text>> {
+        let start = self.scanner.ofs;
         let name = self.read_ident()?;
-        self.scanner.expect('\n')?;
+        if name == "console" {
+            // console is built in and can't be redefined.
+            return self.scanner.parse_error_span(
+                start,
+                self.scanner.ofs,
+                ParseErrorKind::DuplicatePool,
+                "duplicate pool 'console'",
+            );
+        }
+        self.expect_line_end()?;
         let vars = self.read_scoped_vars(|var| matches!(var, "depth"))?;
-        let mut depth = 0;
-        if let Some((_, val)) = vars.into_iter().next() {
-            let val = val.evaluate(&[]);
-            depth = match val.parse::<usize>() {
-                Ok(d) => d,
-                Err(err) => return self.scanner.parse_error(format!("pool depth: {}", err)),
+        let val = match vars.into_iter().next() {
+            Some((_, val)) => val.evaluate(&[&self.vars]),
+            None => {
+                return self.scanner.parse_error_at(
+                    start,
+                    ParseErrorKind::BadPoolDepth,
+                    "expected 'depth =' line",
+                )
             }
-        }
+        };
+        // Like ninja, we require a positive depth; internally depth 0 means
+        // unlimited, which is only used for the implied default pool.
+        let depth = match val.parse::<usize>() {
+            Ok(0) => {
+                return self.scanner.parse_error_at(
+                    start,
+                    ParseErrorKind::BadPoolDepth,
+                    "pool depth must be positive",
+                )
+            }
+            Ok(d) => d,
+            Err(err) => {
+                return self.scanner.parse_error_at(
+                    start,
+                    ParseErrorKind::BadPoolDepth,
+                    format!("pool depth {:?}: {}", val, err),
+                )
+            }
+        };
         Ok(Pool { name, depth })
     }
 
@@ -182,7 +550,7 @@ impl<'text> Parser<'text> {
         v: &mut Vec<EvalString<&'text str>>,
     ) -> ParseResult<()> {
         self.skip_spaces();
-        while !matches!(self.scanner.peek(), ':' | '|' | '\n') {
+        while !matches!(self.scanner.peek(), ':' | '|' | '\n' | '\0') {
             v.push(self.read_eval(true)?);
             self.skip_spaces();
         }
@@ -190,7 +558,7 @@ impl<'text> Parser<'text> {
     }
 
     fn read_build(&mut self) -> ParseResult<Build<'text>> {
-        let line = self.scanner.line;
+        let line = self.scanner.line();
         let mut outs = Vec::new();
         self.read_unevaluated_paths_to(&mut outs)?;
         let explicit_outs = outs.len();
@@ -208,22 +576,17 @@ impl<'text> Parser<'text> {
         self.read_unevaluated_paths_to(&mut ins)?;
         let explicit_ins = ins.len();
 
-        if self.scanner.peek() == '|' {
-            self.scanner.next();
-            let peek = self.scanner.peek();
-            if peek == '|' || peek == '@' {
-                self.scanner.back();
-            } else {
+        if let ('|', next) = self.scanner.peek2() {
+            if !matches!(next, '|' | '@') {
+                self.scanner.next();
                 self.read_unevaluated_paths_to(&mut ins)?;
             }
         }
         let implicit_ins = ins.len() - explicit_ins;
 
-        if self.scanner.peek() == '|' {
-            self.scanner.next();
-            if self.scanner.peek() == '@' {
-                self.scanner.back();
-            } else {
+        if let ('|', next) = self.scanner.peek2() {
+            if next != '@' {
+                self.scanner.next();
                 self.scanner.expect('|')?;
                 self.read_unevaluated_paths_to(&mut ins)?;
             }
@@ -233,11 +596,18 @@ impl<'text> Parser<'text> {
         if self.scanner.peek() == '|' {
             self.scanner.next();
             self.scanner.expect('@')?;
+            let start = ins.len();
             self.read_unevaluated_paths_to(&mut ins)?;
+            if ins.len() == start {
+                return self.scanner.parse_error(
+                    ParseErrorKind::ExpectedPath,
+                    "expected validation path after |@",
+                );
+            }
         }
         let validation_ins = ins.len() - order_only_ins - implicit_ins - explicit_ins;
 
-        self.scanner.expect('\n')?;
+        self.expect_line_end()?;
         let vars = self.read_scoped_vars(|_| true)?;
         Ok(Build {
             rule,
@@ -257,9 +627,11 @@ impl<'text> Parser<'text> {
         let mut defaults = Vec::new();
         self.read_unevaluated_paths_to(&mut defaults)?;
         if defaults.is_empty() {
-            return self.scanner.parse_error("expected path");
+            return self
+                .scanner
+                .parse_error(ParseErrorKind::ExpectedPath, "expected path");
         }
-        self.scanner.expect('\n')?;
+        self.expect_line_end()?;
         Ok(defaults)
     }
 
@@ -276,6 +648,43 @@ impl<'text> Parser<'text> {
         }
     }
 
+    /// Read a comment, including its line ending.
+    fn read_comment(&mut self, indented: bool) -> ParseResult<Comment<'text>> {
+        let line = self.scanner.line();
+        let start = self.scanner.ofs;
+        while !matches!(self.scanner.peek(), '\n' | '\0') {
+            self.scanner.next();
+        }
+        let text = self.scanner.slice(start, self.scanner.ofs)?;
+        if self.scanner.peek() == '\n' {
+            self.scanner.next();
+        }
+
+        // Peek at the next line to see whether it's blank.
+        let checkpoint = self.scanner.checkpoint();
+        self.scanner.skip_spaces();
+        let attached = !matches!(self.scanner.peek(), '\n' | '\0');
+        self.scanner.rewind(checkpoint);
+
+        Ok(Comment {
+            text,
+            line,
+            indented,
+            attached,
+        })
+    }
+
+    /// Handle a comment within a statement body.
+    fn body_comment(&mut self, indented: bool) -> ParseResult<()> {
+        if self.options.comments {
+            let comment = self.read_comment(indented)?;
+            self.pending_comments.push_back(comment);
+            Ok(())
+        } else {
+            self.skip_comment()
+        }
+    }
+
     /// Read an identifier -- rule name, pool name, variable name, etc.
     fn read_ident(&mut self) -> ParseResult<&'text str> {
         let start = self.scanner.ofs;
@@ -286,9 +695,11 @@ impl<'text> Parser<'text> {
         self.scanner.back();
         let end = self.scanner.ofs;
         if end == start {
-            return self.scanner.parse_error("failed to scan ident");
+            return self
+                .scanner
+                .parse_error(ParseErrorKind::ExpectedIdent, "failed to scan ident");
         }
-        Ok(self.scanner.slice(start, end))
+        self.scanner.slice(start, end)
     }
 
     /// Reads an EvalString. Stops at either a newline, or ' ', ':', '|' if
@@ -305,9 +716,9 @@ impl<'text> Parser<'text> {
         // ninja files.
         let end = if stop_at_path_separators {
             loop {
+                self.scanner.skip_to(&PATH_STOPS);
                 match self.scanner.read() {
-                    '\0' => return self.scanner.parse_error("unexpected EOF"),
-                    ' ' | ':' | '|' | '\n' => {
+                    '\0' | ' ' | ':' | '|' | '\n' => {
                         self.scanner.back();
                         break self.scanner.ofs;
                     }
@@ -315,7 +726,7 @@ impl<'text> Parser<'text> {
                         let end = self.scanner.ofs - 1;
                         if end > ofs {
                             self.eval_buf
-                                .push(EvalPart::Literal(self.scanner.slice(ofs, end)));
+                                .push(EvalPart::Literal(self.scanner.slice(ofs, end)?));
                         }
                         let escape = self.read_escape()?;
                         self.eval_buf.push(escape);
@@ -326,9 +737,10 @@ impl<'text> Parser<'text> {
             }
         } else {
             loop {
+                self.scanner.skip_to(&VALUE_STOPS);
                 match self.scanner.read() {
-                    '\0' => return self.scanner.parse_error("unexpected EOF"),
-                    '\n' => {
+                    // A value may run up to the end of the file.
+                    '\0' | '\n' => {
                         self.scanner.back();
                         break self.scanner.ofs;
                     }
@@ -336,7 +748,7 @@ impl<'text> Parser<'text> {
                         let end = self.scanner.ofs - 1;
                         if end > ofs {
                             self.eval_buf
-                                .push(EvalPart::Literal(self.scanner.slice(ofs, end)));
+                                .push(EvalPart::Literal(self.scanner.slice(ofs, end)?));
                         }
                         let escape = self.read_escape()?;
                         self.eval_buf.push(escape);
@@ -348,10 +760,12 @@ impl<'text> Parser<'text> {
         };
         if end > ofs {
             self.eval_buf
-                .push(EvalPart::Literal(self.scanner.slice(ofs, end)));
+                .push(EvalPart::Literal(self.scanner.slice(ofs, end)?));
         }
         if self.eval_buf.is_empty() {
-            return self.scanner.parse_error(format!("Expected a string"));
+            return self
+                .scanner
+                .parse_error(ParseErrorKind::ExpectedValue, "Expected a string");
         }
         Ok(EvalString::new(self.eval_buf.clone()))
     }
@@ -367,32 +781,46 @@ impl<'text> Parser<'text> {
         self.scanner.back();
         let end = self.scanner.ofs;
         if end == start {
-            return self.scanner.parse_error("failed to scan variable name");
+            return self.scanner.parse_error(
+                ParseErrorKind::ExpectedVarName,
+                "failed to scan variable name",
+            );
         }
-        Ok(self.scanner.slice(start, end))
+        self.scanner.slice(start, end)
     }
 
     /// Read and interpret the text following a '$' escape character.
     fn read_escape(&mut self) -> ParseResult<EvalPart<&'text str>> {
         Ok(match self.scanner.read() {
+            '\0' => {
+                self.scanner.back();
+                return self
+                    .scanner
+                    .parse_error(ParseErrorKind::UnexpectedEof, "unexpected EOF");
+            }
             '\n' => {
                 self.scanner.skip_spaces();
-                EvalPart::Literal(self.scanner.slice(0, 0))
+                EvalPart::Literal("")
             }
             ' ' | '$' | ':' => {
-                EvalPart::Literal(self.scanner.slice(self.scanner.ofs - 1, self.scanner.ofs))
+                EvalPart::Literal(self.scanner.slice(self.scanner.ofs - 1, self.scanner.ofs)?)
             }
             '{' => {
                 let start = self.scanner.ofs;
                 loop {
                     match self.scanner.read() {
-                        '\0' => return self.scanner.parse_error("unexpected EOF"),
+                        '\0' => {
+                            self.scanner.back();
+                            return self
+                                .scanner
+                                .parse_error(ParseErrorKind::UnexpectedEof, "unexpected EOF");
+                        }
                         '}' => break,
                         _ => {}
                     }
                 }
                 let end = self.scanner.ofs - 1;
-                EvalPart::VarRef(self.scanner.slice(start, end))
+                EvalPart::VarRef(self.scanner.slice(start, end)?)
             }
             _ => {
                 // '$' followed by some other text.
@@ -405,48 +833,57 @@ impl<'text> Parser<'text> {
 
     fn skip_spaces(&mut self) {
         loop {
-            match self.scanner.read() {
-                ' ' => {}
-                '$' => {
-                    if self.scanner.peek() != '\n' {
-                        self.scanner.back();
-                        return;
-                    }
-                    self.scanner.skip('\n');
-                }
-                _ => {
-                    self.scanner.back();
-                    return;
+            match self.scanner.peek() {
+                ' ' => self.scanner.next(),
+                '$' if self.scanner.peek2().1 == '\n' => {
+                    self.scanner.next();
+                    self.scanner.next();
                 }
+                _ => return,
             }
         }
     }
 }
 
+/// Iterator over a parser's statements, from Parser::statements.
+/// Produces nothing further after the end of the file or the first error.
+pub struct Statements<'p, 'text> {
+    parser: &'p mut Parser<'text>,
+    done: bool,
+}
+
+impl<'p, 'text> Iterator for Statements<'p, 'text> {
+    type Item = ParseResult<Statement<'text>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.parser.read().transpose();
+        if !matches!(result, Some(Ok(_))) {
+            self.done = true;
+        }
+        result
+    }
+}
+
+impl<'p, 'text> std::iter::FusedIterator for Statements<'p, 'text> {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    fn test_case_buffer(test_case: &str) -> Vec<u8> {
-        let mut buf = test_case.as_bytes().to_vec();
-        buf.push(0);
-        buf
-    }
-
     fn test_for_line_endings(input: &[&str], test: fn(&str)) {
         let test_case_lf = input.join("\n");
         test(&test_case_lf);
-        if cfg!(feature = "crlf") {
-            let test_case_crlf = input.join("\r\n");
-            test(&test_case_crlf);
-        }
+        let test_case_crlf = input.join("\r\n");
+        test(&test_case_crlf);
     }
 
     #[test]
     fn parse_defaults() {
         test_for_line_endings(&["var = 3", "default a b$var c", ""], |test_case| {
-            let mut buf = test_case_buffer(test_case);
-            let mut parser = Parser::new(&mut buf);
+            let mut parser = Parser::new(test_case.as_bytes());
             let default = match parser.read().unwrap().unwrap() {
                 Statement::Default(d) => d,
                 _ => panic!("expected default"),
@@ -464,34 +901,827 @@ mod tests {
 
     #[test]
     fn parse_dot_in_eval() {
-        let mut buf = test_case_buffer("x = $y.z\n");
-        let mut parser = Parser::new(&mut buf);
+        let mut parser = Parser::new("x = $y.z\n".as_bytes());
         parser.read().unwrap();
         let x = parser.vars.get("x").unwrap();
         assert_eq!(x, ".z");
     }
 
+    #[test]
+    fn parse_nul_ends_value() {
+        let mut parser = Parser::new(b"x = a\0b\n");
+        parser.read().unwrap();
+        let x = parser.vars.get("x").unwrap();
+        assert_eq!(x, "a");
+    }
+
     #[test]
     fn parse_dot_in_rule() {
-        let mut buf = test_case_buffer("rule x.y\n  command = x\n");
-        let mut parser = Parser::new(&mut buf);
+        let mut parser = Parser::new("rule x.y\n  command = x\n".as_bytes());
         let stmt = parser.read().unwrap().unwrap();
         assert!(matches!(
             stmt,
             Statement::Rule(Rule {
                 name: "x.y",
-                vars: _
+                line: 1,
+                ..
             })
         ));
     }
 
+    #[test]
+    fn parse_validations() {
+        test_for_line_endings(
+            &[
+                "build a: r b | c || d |@ e f",
+                "build a: r b |@ e",
+                "build a: r || d |@ e",
+                "build a: r | c |@ e",
+                "",
+            ],
+            |test_case| {
+                let mut parser = Parser::new(test_case.as_bytes());
+                let mut counts = Vec::new();
+                for stmt in parser.statements() {
+                    let b = match stmt.unwrap() {
+                        Statement::Build(b) => b,
+                        _ => panic!("expected build"),
+                    };
+                    assert_eq!(
+                        b.explicit_ins + b.implicit_ins + b.order_only_ins + b.validation_ins,
+                        b.ins.len()
+                    );
+                    counts.push((
+                        b.explicit_ins,
+                        b.implicit_ins,
+                        b.order_only_ins,
+                        b.validation_ins,
+                    ));
+                }
+                assert_eq!(
+                    counts,
+                    vec![(1, 1, 1, 2), (1, 0, 0, 1), (0, 0, 1, 1), (0, 1, 0, 1)]
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn parse_empty_validations() {
+        test_for_line_endings(&["build a: r b |@", ""], |test_case| {
+            let mut parser = Parser::new(test_case.as_bytes());
+            let err = match parser.read() {
+                Err(err) => parser.format_parse_error(Path::new("build.ninja"), err),
+                Ok(_) => panic!("expected parse error"),
+            };
+            assert!(
+                err.starts_with(
+                    "parse error: expected validation path after |@\n\
+                     while parsing build statement starting at line 1\n\
+                     build.ninja:1: "
+                ),
+                "{}",
+                err
+            );
+        });
+    }
+
+    fn required_version_error(input: &[&str]) -> Option<String> {
+        let buf = input.join("\n");
+        let mut parser = Parser::new(buf.as_bytes());
+        match parser.read_all() {
+            Ok(_) => None,
+            Err(err) => Some(parser.format_parse_error(Path::new("build.ninja"), err)),
+        }
+    }
+
+    #[test]
+    fn parse_required_version() {
+        assert_eq!(
+            required_version_error(&["ninja_required_version = 1.8.2.git", ""]),
+            None
+        );
+        let err = required_version_error(&["ninja_required_version = 1.99", ""]).unwrap();
+        assert!(
+            err.starts_with("parse error: manifest requires ninja 1.99 features: "),
+            "{}",
+            err
+        );
+        assert!(err.contains("\nbuild.ninja:1: "), "{}", err);
+    }
+
+    #[test]
+    fn parse_required_version_malformed() {
+        let err = required_version_error(&["ninja_required_version = latest", ""]).unwrap();
+        assert!(
+            err.starts_with("parse error: invalid ninja_required_version \"latest\"\n"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn parse_required_version_after_statements() {
+        let err = required_version_error(&[
+            "rule r",
+            "  command = c",
+            "build a: r",
+            "ninja_required_version = 2.0",
+            "",
+        ])
+        .unwrap();
+        assert!(err.contains("\nbuild.ninja:4: "), "{}", err);
+    }
+
+    #[test]
+    fn parse_required_version_only_first() {
+        assert_eq!(
+            required_version_error(&[
+                "ninja_required_version = 1.3",
+                "ninja_required_version = 2.0",
+                ""
+            ]),
+            None
+        );
+    }
+
+    #[test]
+    fn parse_comments_in_var_blocks() {
+        test_for_line_endings(
+            &[
+                "rule r",
+                "  # before",
+                "  command = c",
+                "    # between",
+                "# unindented",
+                "  description = d",
+                "  # after",
+                "build a: r",
+                "  # before",
+                "  x = 1",
+                "  # between",
+                "  y = 2",
+                "  # after",
+                "",
+            ],
+            |test_case| {
+                let mut parser = Parser::new(test_case.as_bytes());
+                let rule = match parser.read().unwrap().unwrap() {
+                    Statement::Rule(r) => r,
+                    _ => panic!("expected rule"),
+                };
+                let names: Vec<_> = rule.vars.iter().map(|(name, _)| *name).collect();
+                assert_eq!(names, vec!["command", "description"]);
+                let build = match parser.read().unwrap().unwrap() {
+                    Statement::Build(b) => b,
+                    _ => panic!("expected build"),
+                };
+                let names: Vec<_> = build.vars.iter().map(|(name, _)| *name).collect();
+                assert_eq!(names, vec!["x", "y"]);
+                assert!(parser.read().unwrap().is_none());
+            },
+        );
+    }
+
+    #[test]
+    fn parse_blank_line_ends_var_block() {
+        test_for_line_endings(
+            &["rule r", "  command = c", "  ", "build a: r", ""],
+            |test_case| {
+                let mut parser = Parser::new(test_case.as_bytes());
+                assert!(matches!(parser.read(), Ok(Some(Statement::Rule(_)))));
+                assert!(matches!(parser.read(), Ok(Some(Statement::Build(_)))));
+            },
+        );
+    }
+
+    #[test]
+    fn parse_console_pool_reserved() {
+        test_for_line_endings(&["pool console", "  depth = 2", ""], |test_case| {
+            let mut parser = Parser::new(test_case.as_bytes());
+            let err = match parser.read() {
+                Err(err) => parser.format_parse_error(Path::new("build.ninja"), err),
+                Ok(_) => panic!("expected parse error"),
+            };
+            assert!(
+                err.starts_with(
+                    "parse error: duplicate pool 'console'\n\
+                     while parsing pool statement starting at line 1\n\
+                     build.ninja:1: pool console"
+                ),
+                "{}",
+                err
+            );
+        });
+    }
+
+    fn pool_result(input: &[&str]) -> Result<usize, String> {
+        let buf = input.join("\n");
+        let mut parser = Parser::new(buf.as_bytes());
+        let stmts = parser
+            .read_all()
+            .map_err(|err| parser.format_parse_error(Path::new("build.ninja"), err))?;
+        Ok(stmts
+            .into_iter()
+            .find_map(|stmt| match stmt {
+                Statement::Pool(pool) => Some(pool.depth),
+                _ => None,
+            })
+            .expect("expected pool"))
+    }
+
+    #[test]
+    fn parse_pool_depth_var() {
+        assert_eq!(
+            pool_result(&["jobs = 4", "pool link", "  depth = $jobs", ""]),
+            Ok(4)
+        );
+    }
+
+    #[test]
+    fn parse_pool_missing_depth() {
+        let err = pool_result(&["pool link", "build a: r", ""]).unwrap_err();
+        assert!(
+            err.starts_with(
+                "parse error: expected 'depth =' line\n\
+                 while parsing pool statement starting at line 1\n\
+                 build.ninja:1: pool link"
+            ),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn parse_pool_bad_depth() {
+        let err = pool_result(&["jobs = four", "pool link", "  depth = $jobs", ""]).unwrap_err();
+        assert!(
+            err.starts_with("parse error: pool depth \"four\": "),
+            "{}",
+            err
+        );
+        let err = pool_result(&["pool link", "  depth = 0", ""]).unwrap_err();
+        assert!(
+            err.starts_with("parse error: pool depth must be positive\n"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn parse_keywords_as_vars() {
+        test_for_line_endings(
+            &[
+                "pool = fast",
+                "default = x",
+                "include = y",
+                "build =z",
+                "rule r",
+                "  command = $pool $default $include $build",
+                "build foo: r",
+                "",
+            ],
+            |test_case| {
+                let mut parser = Parser::new(test_case.as_bytes());
+                assert!(matches!(parser.read(), Ok(Some(Statement::Rule(_)))));
+                assert_eq!(parser.vars.get("pool").unwrap(), "fast");
+                assert_eq!(parser.vars.get("default").unwrap(), "x");
+                assert_eq!(parser.vars.get("include").unwrap(), "y");
+                assert_eq!(parser.vars.get("build").unwrap(), "z");
+                let build = match parser.read().unwrap().unwrap() {
+                    Statement::Build(b) => b,
+                    _ => panic!("expected build"),
+                };
+                assert_eq!(build.rule, "r");
+            },
+        );
+    }
+
+    #[test]
+    fn parse_empty_values() {
+        test_for_line_endings(&["a =", "b = $", "", "c =  "], |test_case| {
+            let mut parser = Parser::new(test_case.as_bytes());
+            assert!(parser.read().unwrap().is_none());
+            assert_eq!(parser.vars.get("a").unwrap(), "");
+            assert_eq!(parser.vars.get("b").unwrap(), "");
+            assert_eq!(parser.vars.get("c").unwrap(), "");
+        });
+    }
+
+    #[test]
+    fn parse_override_var() {
+        let mut parser = Parser::new(b"mode = release\nflags = -D$mode\nother = 1\n");
+        parser.override_var("mode", "debug");
+        assert!(parser.read().unwrap().is_none());
+        assert_eq!(parser.vars.get("mode").unwrap(), "debug");
+        assert_eq!(parser.vars.get("flags").unwrap(), "-Ddebug");
+        assert_eq!(parser.vars.get("other").unwrap(), "1");
+    }
+
+    #[test]
+    fn parse_value_at_eof() {
+        let mut parser = Parser::new("a = 1".as_bytes());
+        assert!(parser.read().unwrap().is_none());
+        assert_eq!(parser.vars.get("a").unwrap(), "1");
+        // A line continuation right before the end of the file.
+        test_for_line_endings(&["b = x$", "  y$", ""], |test_case| {
+            let mut parser = Parser::new(test_case.as_bytes());
+            assert!(parser.read().unwrap().is_none());
+            assert_eq!(parser.vars.get("b").unwrap(), "xy");
+        });
+        test_for_line_endings(&["rule r", "  command = c"], |test_case| {
+            let mut parser = Parser::new(test_case.as_bytes());
+            let rule = match parser.read().unwrap().unwrap() {
+                Statement::Rule(r) => r,
+                _ => panic!("expected rule"),
+            };
+            assert_eq!(
+                rule.vars.get("command"),
+                Some(&EvalString::new(vec![EvalPart::Literal("c")]))
+            );
+        });
+    }
+
+    #[test]
+    fn parse_escape_at_eof() {
+        let mut parser = Parser::new("a = x $".as_bytes());
+        let err = match parser.read() {
+            Err(err) => parser.format_parse_error(Path::new("build.ninja"), err),
+            Ok(_) => panic!("expected parse error"),
+        };
+        assert!(err.starts_with("parse error: unexpected EOF\n"), "{}", err);
+    }
+
+    #[test]
+    fn parse_no_final_newline() {
+        let cases: &[&[&str]] = &[
+            &["rule r", "  command = c"],
+            &["rule r"],
+            &["build a: r b | c || d |@ e"],
+            &["build a: r", "  x = 1"],
+            &["default a b"],
+            &["include a.ninja"],
+            &["subninja a.ninja"],
+            &["pool p", "  depth = 1"],
+            &["x = 1"],
+            &["# comment"],
+        ];
+        for case in cases {
+            test_for_line_endings(case, |test_case| {
+                let mut parser = Parser::new(test_case.as_bytes());
+                parser.read_all().unwrap();
+            });
+        }
+    }
+
+    #[test]
+    fn parse_comments() {
+        test_for_line_endings(
+            &[
+                "# header $not_a_var",
+                "",
+                "# about r",
+                "rule r",
+                "  command = c",
+                "  # in body",
+                "build a: r",
+                "# last",
+            ],
+            |test_case| {
+                let mut parser = Parser::with_comments(test_case.as_bytes());
+                let mut comments = Vec::new();
+                let mut statements = Vec::new();
+                for stmt in parser.statements() {
+                    match stmt.unwrap() {
+                        Statement::Comment(c) => {
+                            statements.push("comment");
+                            comments.push(c);
+                        }
+                        Statement::Rule(_) => statements.push("rule"),
+                        Statement::Build(_) => statements.push("build"),
+                        _ => panic!("unexpected statement"),
+                    }
+                }
+                assert_eq!(
+                    statements,
+                    vec!["comment", "comment", "rule", "comment", "build", "comment"]
+                );
+                let comment = |text, line, indented, attached| Comment {
+                    text,
+                    line,
+                    indented,
+                    attached,
+                };
+                assert_eq!(
+                    comments,
+                    vec![
+                        comment("# header $not_a_var", 1, false, false),
+                        comment("# about r", 3, false, true),
+                        comment("# in body", 6, true, true),
+                        comment("# last", 8, false, false),
+                    ]
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn parse_skips_comments_by_default() {
+        let mut parser = Parser::new("# a\nrule r\n  # b\n  command = c\n".as_bytes());
+        assert!(matches!(parser.read(), Ok(Some(Statement::Rule(_)))));
+        assert!(parser.read().unwrap().is_none());
+    }
+
+    #[test]
+    fn parse_bindings() {
+        let options = ParserOptions {
+            bindings: true,
+            ..ParserOptions::default()
+        };
+        let mut parser = Parser::with_options("a = x\nb = $a y\n".as_bytes(), options);
+        let mut bindings = Vec::new();
+        for stmt in parser.statements() {
+            match stmt.unwrap() {
+                Statement::Binding(b) => bindings.push(b),
+                _ => panic!("unexpected statement"),
+            }
+        }
+        assert_eq!(
+            bindings,
+            vec![
+                Binding {
+                    name: "a",
+                    line: 1,
+                    value: EvalString::new(vec![EvalPart::Literal("x")]),
+                },
+                Binding {
+                    name: "b",
+                    line: 2,
+                    value: EvalString::new(vec![EvalPart::VarRef("a"), EvalPart::Literal(" y")]),
+                },
+            ]
+        );
+        assert_eq!(parser.vars.get("b").unwrap(), "x y");
+    }
+
+    #[test]
+    fn parse_recovering() {
+        test_for_line_endings(
+            &[
+                "build a:b: r",
+                "  x = 1",
+                "rule r",
+                "  command = c",
+                "build c: r d",
+                "pool",
+                "  depth = 1",
+                "default",
+                "build e: r",
+                "",
+            ],
+            |test_case| {
+                let mut parser = Parser::new(test_case.as_bytes());
+                let mut statements = Vec::new();
+                while let Some(stmt) = parser.read_recovering() {
+                    statements.push(match stmt {
+                        Statement::Rule(_) => "rule",
+                        Statement::Build(_) => "build",
+                        _ => panic!("unexpected statement"),
+                    });
+                }
+                assert_eq!(statements, vec!["rule", "build", "build"]);
+                let errors: Vec<_> = parser
+                    .take_errors()
+                    .into_iter()
+                    .map(|err| parser.format_parse_error(Path::new("build.ninja"), err))
+                    .collect();
+                assert_eq!(errors.len(), 3);
+                assert!(errors[0].contains("\nbuild.ninja:1: "), "{}", errors[0]);
+                assert!(errors[1].contains("\nbuild.ninja:6: "), "{}", errors[1]);
+                assert!(errors[2].contains("\nbuild.ninja:8: "), "{}", errors[2]);
+            },
+        );
+    }
+
+    #[test]
+    fn parse_recovering_consecutive() {
+        // Each bad line is followed directly by a good one, which is kept
+        // even when the error is only found at the start of the next line.
+        test_for_line_endings(
+            &[
+                "rule r",
+                "  command = c",
+                "pool p",
+                "build a: r",
+                "pool q",
+                "  bogus = 1",
+                "build b: r",
+                "build",
+                "build c: r",
+                "",
+            ],
+            |test_case| {
+                let mut parser = Parser::new(test_case.as_bytes());
+                let mut builds = Vec::new();
+                while let Some(stmt) = parser.read_recovering() {
+                    if let Statement::Build(build) = stmt {
+                        builds.push(build.line);
+                    }
+                }
+                assert_eq!(builds, vec![4, 7, 9]);
+                assert_eq!(parser.take_errors().len(), 3);
+            },
+        );
+    }
+
+    #[test]
+    fn parse_recovering_capped() {
+        let text = "build\n".repeat(MAX_RECOVERED_ERRORS * 2) + "  $";
+        let mut parser = Parser::new(text.as_bytes());
+        assert!(parser.read_recovering().is_none());
+        assert_eq!(parser.take_errors().len(), MAX_RECOVERED_ERRORS);
+
+        // Errors right at the end of the file.
+        for text in ["build", "a = $", "a = ${", " ", "x"] {
+            let mut parser = Parser::new(text.as_bytes());
+            assert!(parser.read_recovering().is_none());
+            assert_eq!(parser.take_errors().len(), 1, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn parse_statements_fused() {
+        let mut parser = Parser::new("rule r\n  command = c\nbuild\nbuild a: r\n".as_bytes());
+        let mut stmts = parser.statements();
+        assert!(matches!(stmts.next(), Some(Ok(Statement::Rule(_)))));
+        assert!(matches!(stmts.next(), Some(Err(_))));
+        assert!(stmts.next().is_none());
+        assert!(stmts.next().is_none());
+
+        let mut parser = Parser::new("build a: r\nbuild b: r\n".as_bytes());
+        let outs: Vec<_> = parser
+            .statements()
+            .map(|stmt| match stmt {
+                Ok(Statement::Build(b)) => b.outs,
+                _ => panic!("expected build"),
+            })
+            .collect();
+        assert_eq!(outs.len(), 2);
+        assert!(parser.statements().next().is_none());
+        assert!(parser.read_all().unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_static_buffer() {
+        // The parser only borrows its input, so it can parse directly from
+        // static data without copying.
+        const MANIFEST: &[u8] = b"rule r\n  command = c\nbuild a: r\n";
+        let mut parser = Parser::new(MANIFEST);
+        let stmts: Vec<Statement<'static>> = parser.read_all().unwrap();
+        assert_eq!(stmts.len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn parse_mapped_buffer() {
+        use std::io::Write;
+        use std::os::unix::io::AsRawFd;
+
+        // Nor does it need a buffer it owns: a read-only mapping of a file,
+        // with no terminator after the last byte, works too.
+        const MANIFEST: &[u8] = b"rule r\n  command = c\nbuild a: r";
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(MANIFEST).unwrap();
+        // Safety: mapping a file we hold open, unmapped below once the
+        // statements borrowing it are dropped.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                MANIFEST.len(),
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        assert_ne!(ptr, libc::MAP_FAILED);
+        {
+            // Safety: the mapping is MANIFEST.len() bytes long.
+            let buf = unsafe { std::slice::from_raw_parts(ptr as *const u8, MANIFEST.len()) };
+            let mut parser = Parser::new(buf);
+            let stmts = parser.read_all().unwrap();
+            assert_eq!(stmts.len(), 2);
+        }
+        // Safety: ptr and len came from the mmap above.
+        unsafe { libc::munmap(ptr, MANIFEST.len()) };
+    }
+
+    #[test]
+    fn parse_bom() {
+        let mut parser = Parser::new(b"\xEF\xBB\xBFx = 1\nx = $!\n");
+        let err = match parser.read() {
+            Err(err) => parser.format_parse_error(Path::new("build.ninja"), err),
+            Ok(_) => panic!("expected parse error"),
+        };
+        assert_eq!(parser.vars.get("x").unwrap(), "1");
+        // The error column is as an editor would show it, ignoring the BOM.
+        assert_eq!(
+            err,
+            "parse error: failed to scan variable name\nbuild.ninja:2: x = $!\n                    ^\n"
+        );
+
+        let mut parser = Parser::new(b"\xFF\xFEx\x00 \x00=\x00 \x001\x00");
+        let err = match parser.read() {
+            Err(err) => parser.format_parse_error(Path::new("build.ninja"), err),
+            Ok(_) => panic!("expected parse error"),
+        };
+        assert!(
+            err.starts_with("parse error: build file appears to be UTF-16; n2 requires UTF-8\n"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn parse_invalid_utf8() {
+        // Latin-1 is rejected where it occurs rather than mangled.
+        let mut parser = Parser::new(b"rule r\n  command = c\nbuild caf\xE9.o: r\n");
+        let err = match parser.read_all() {
+            Err(err) => parser.format_parse_error(Path::new("build.ninja"), err),
+            Ok(_) => panic!("expected parse error"),
+        };
+        assert!(
+            err.starts_with(
+                "parse error: invalid UTF-8; n2 requires UTF-8 paths and text\n\
+                 while parsing build statement starting at line 3\n\
+                 build.ninja:3: build caf"
+            ),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn parse_lone_cr() {
+        // A \r that isn't part of a \r\n pair is ordinary content.
+        let mut parser = Parser::new(b"a = x\ry\nb = 1\r\r\nc = 2\r\nd = 3\r");
+        assert!(parser.read_all().unwrap().is_empty());
+        assert_eq!(parser.vars.get("a").unwrap(), "x\ry");
+        assert_eq!(parser.vars.get("b").unwrap(), "1\r");
+        assert_eq!(parser.vars.get("c").unwrap(), "2");
+        assert_eq!(parser.vars.get("d").unwrap(), "3\r");
+
+        let mut parser = Parser::new(b"rule r\r\n  command = c\r\nbuild o\rx: r\r\n");
+        let stmts = parser.read_all().unwrap();
+        match &stmts[1] {
+            Statement::Build(b) => {
+                assert_eq!(b.rule, "r");
+                assert_eq!(b.outs.len(), 1);
+                assert_eq!(b.outs[0].evaluate(&[]), "o\rx");
+            }
+            _ => panic!("expected build"),
+        }
+    }
+
+    #[test]
+    fn parse_shadowed_vars() {
+        let mut parser = Parser::new(
+            b"rule r\n  command = a\n  description = d\n  command = b\nbuild o: r\n  x = 1\n  x = 2\n  x = 3\n",
+        );
+        let stmts = parser.read_all().unwrap();
+        match &stmts[0] {
+            Statement::Rule(r) => {
+                let names: Vec<_> = r.vars.iter().map(|(n, _)| *n).collect();
+                assert_eq!(names, ["command", "description"]);
+                assert_eq!(r.vars.get("command").unwrap().evaluate(&[]), "b");
+            }
+            _ => panic!("expected rule"),
+        }
+        match &stmts[1] {
+            Statement::Build(b) => assert_eq!(b.vars.get("x").unwrap().evaluate(&[]), "3"),
+            _ => panic!("expected build"),
+        }
+        assert_eq!(
+            parser.take_shadowed_vars(),
+            [
+                ShadowedVar {
+                    name: "command",
+                    line: 4,
+                    prev_line: 2
+                },
+                ShadowedVar {
+                    name: "x",
+                    line: 7,
+                    prev_line: 6
+                },
+                ShadowedVar {
+                    name: "x",
+                    line: 8,
+                    prev_line: 7
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_error_statement_context() {
+        let mut parser = Parser::new(b"x = 1\nbuild out: r in1 $\n  in2 $\n  | :\n");
+        let err = match parser.read() {
+            Err(err) => parser.format_parse_error(Path::new("build.ninja"), err),
+            Ok(_) => panic!("expected parse error"),
+        };
+        assert_eq!(
+            err,
+            "parse error: expected '\\n', got ':'\n\
+             while parsing build statement starting at line 2\n\
+             build.ninja:4:   | :\n                   ^\n"
+        );
+
+        // Errors outside of a statement have no context.
+        let mut parser = Parser::new(b"x = $!\n");
+        let err = match parser.read() {
+            Err(err) => parser.format_parse_error(Path::new("build.ninja"), err),
+            Ok(_) => panic!("expected parse error"),
+        };
+        assert!(!err.contains("while parsing"), "{}", err);
+    }
+
+    #[test]
+    fn parse_error_kinds() {
+        let cases = [
+            ("x = $!\n", ParseErrorKind::ExpectedVarName),
+            ("build a: r |@\n", ParseErrorKind::ExpectedPath),
+            ("build a b\n", ParseErrorKind::UnexpectedChar),
+            (" x = 1\n", ParseErrorKind::UnexpectedWhitespace),
+            ("pool p\n  depth = x\n", ParseErrorKind::BadPoolDepth),
+            ("x = ${y", ParseErrorKind::UnexpectedEof),
+        ];
+        for (input, kind) in cases {
+            let mut parser = Parser::new(input.as_bytes());
+            match parser.read_all() {
+                Err(err) => assert_eq!(err.kind(), kind, "{:?}", input),
+                Ok(_) => panic!("expected parse error for {:?}", input),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_error_span() {
+        let mut parser = Parser::new(b"x = 1\npool link\n  depths = 3\n");
+        let err = match parser.read() {
+            Err(err) => parser.format_parse_error(Path::new("build.ninja"), err),
+            Ok(_) => panic!("expected parse error"),
+        };
+        assert_eq!(
+            err,
+            "parse error: unexpected variable \"depths\"\n\
+             while parsing pool statement starting at line 2\n\
+             build.ninja:2: pool link\n\
+             build.ninja:3:   depths = 3\n                 ^~~~~~\n"
+        );
+    }
+
+    fn strict_error(input: &str) -> Option<String> {
+        let mut parser = Parser::with_options(
+            input.as_bytes(),
+            ParserOptions {
+                strict: true,
+                ..ParserOptions::default()
+            },
+        );
+        match parser.read_all() {
+            Ok(_) => None,
+            Err(err) => Some(parser.format_parse_error(Path::new("build.ninja"), err)),
+        }
+    }
+
+    #[test]
+    fn parse_strict() {
+        let cases = ["rule r\n  command = c\n  extra = 1\n", "x = 1\ry\n"];
+        for input in cases {
+            // Accepted by default.
+            let mut parser = Parser::new(input.as_bytes());
+            parser.read_all().unwrap();
+        }
+        assert!(strict_error(cases[0])
+            .unwrap()
+            .starts_with("parse error: unexpected variable \"extra\"\n"));
+        assert_eq!(
+            strict_error(cases[1]).unwrap(),
+            "parse error: carriage return without a following newline\nbuild.ninja:1: x = 1\ry\n                    ^\n"
+        );
+        assert_eq!(
+            strict_error("rule r\r\n  command = c\r\n  description = d\r\nbuild a: r\r\n"),
+            None
+        );
+    }
+
     #[test]
     fn parse_trailing_newline() {
         test_for_line_endings(
             &["build$", " foo$", " : $", "  touch $", "", ""],
             |test_case| {
-                let mut buf = test_case_buffer(test_case);
-                let mut parser = Parser::new(&mut buf);
+                let mut parser = Parser::new(test_case.as_bytes());
                 let stmt = parser.read().unwrap().unwrap();
                 assert!(matches!(
                     stmt,
@@ -500,4 +1730,58 @@ mod tests {
             },
         );
     }
+
+    /// Parse `buf` in each mode and format any error, which must not panic.
+    fn parse_without_panic(buf: &[u8]) {
+        let mut parser = Parser::new(buf);
+        if let Err(err) = parser.read_all() {
+            parser.format_parse_error(Path::new("build.ninja"), err);
+        }
+        let mut parser = Parser::new(buf);
+        while parser.read_recovering().is_some() {}
+        for err in parser.take_errors() {
+            parser.format_parse_error(Path::new("build.ninja"), err);
+        }
+    }
+
+    #[test]
+    fn parse_no_panic_regressions() {
+        for buf in [
+            &b"$"[..],
+            b"build \0",
+            b"x = ${",
+            b"x = ${a",
+            b"build a: r ${",
+            b"build $",
+            b"x = $\r",
+            b"build a$\r\n",
+            b"rule r\n  command = $",
+            b"pool p\n  depth = ",
+            b"default $\r\n",
+            b"include $\n",
+            b"\r\n\r\n  ",
+        ] {
+            parse_without_panic(buf);
+        }
+    }
+
+    #[test]
+    fn parse_no_panic_random() {
+        // A small deterministic fuzz over bytes the parser treats specially.
+        let alphabet = b"$:|@{}\r\n \0\t#=abrule build default pool include";
+        let mut seed: u32 = 1;
+        let mut rand = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as usize
+        };
+        for _ in 0..20000 {
+            let len = rand() % 16;
+            let buf: Vec<u8> = (0..len)
+                .map(|_| alphabet[rand() % alphabet.len()])
+                .collect();
+            parse_without_panic(&buf);
+        }
+    }
 }
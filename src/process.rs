@@ -1,21 +1,29 @@
 //! Exposes process::run_command, a wrapper around platform-native process execution.
 
 #[cfg(unix)]
-pub use crate::process_posix::run_command;
+pub use crate::process_posix::{interrupt_running, kill_running, run_command};
 #[cfg(windows)]
-pub use crate::process_win::run_command;
+pub use crate::process_win::{interrupt_running, kill_running, run_command};
 
 #[cfg(target_arch = "wasm32")]
 fn run_command(
     cmdline: &str,
+    console: bool,
     mut output_cb: impl FnMut(&[u8]),
 ) -> anyhow::Result<(Termination, Vec<u8>)> {
     anyhow::bail!("wasm cannot run commands");
 }
 
+#[cfg(target_arch = "wasm32")]
+pub fn interrupt_running() {}
+
+#[cfg(target_arch = "wasm32")]
+pub fn kill_running() {}
+
 #[derive(Debug, PartialEq)]
 pub enum Termination {
     Success,
     Interrupted,
-    Failure,
+    /// Failed, with a description of how, e.g. "exit status 1".
+    Failure(String),
 }
@@ -5,6 +5,45 @@ use crate::process::Termination;
 use std::io::{Error, Read};
 use std::os::fd::FromRawFd;
 use std::os::unix::process::ExitStatusExt;
+use std::sync::Mutex;
+
+/// The running tasks, apart from console ones, each the leader of its own
+/// process group, so that an interrupted build can pass signals on to them.
+static RUNNING: Mutex<Vec<libc::pid_t>> = Mutex::new(Vec::new());
+
+fn signal_running(sig: libc::c_int) {
+    for &pid in RUNNING.lock().unwrap().iter() {
+        // Safety: the pid isn't reaped until it's removed from RUNNING, so
+        // the group can't have been reused.
+        unsafe { libc::kill(-pid, sig) };
+    }
+}
+
+/// Keeps a task in RUNNING while alive.
+struct Running(libc::pid_t);
+
+impl Running {
+    fn new(pid: libc::pid_t) -> Self {
+        RUNNING.lock().unwrap().push(pid);
+        Running(pid)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.lock().unwrap().retain(|&pid| pid != self.0);
+    }
+}
+
+/// Pass the signal that interrupted the build on to the running tasks.
+pub fn interrupt_running() {
+    signal_running(crate::signal::interrupt_signal());
+}
+
+/// Kill running tasks that didn't exit when interrupted.
+pub fn kill_running() {
+    signal_running(libc::SIGKILL);
+}
 
 // https://github.com/rust-lang/libc/issues/2520
 // libc crate doesn't expose the 'environ' pointer.
@@ -56,6 +95,15 @@ impl PosixSpawnAttr {
             )
         }
     }
+
+    fn setpgroup(&mut self, pgroup: libc::pid_t) -> anyhow::Result<()> {
+        unsafe {
+            check_posix_spawn(
+                "posix_spawnattr_setpgroup",
+                libc::posix_spawnattr_setpgroup(self.as_ptr(), pgroup),
+            )
+        }
+    }
 }
 
 impl Drop for PosixSpawnAttr {
@@ -150,29 +198,55 @@ fn pipe2() -> anyhow::Result<[libc::c_int; 2]> {
     }
 }
 
-pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::Result<Termination> {
+/// Runs a command, passing its combined stdout and stderr to output_cb.
+/// A console command instead shares n2's own stdin, stdout and stderr.
+pub fn run_command(
+    cmdline: &str,
+    console: bool,
+    mut output_cb: impl FnMut(&[u8]),
+) -> anyhow::Result<Termination> {
     // Spawn the subprocess using posix_spawn with output redirected to the pipe.
     // We don't use Rust's process spawning because of issue #14 and because
     // we want to feed both stdout and stderr into the same pipe, which cannot
     // be done with the existing std::process API.
-    let (pid, mut pipe) = unsafe {
-        let pipe = pipe2()?;
-
+    let (pid, pipe) = unsafe {
         let mut attr = PosixSpawnAttr::new()?;
 
+        let mut flags = 0;
         // Apple-specific extension: close any open fds.
         #[cfg(target_os = "macos")]
-        attr.setflags(libc::POSIX_SPAWN_CLOEXEC_DEFAULT as _)?;
+        {
+            flags |= libc::POSIX_SPAWN_CLOEXEC_DEFAULT;
+        }
+        // Give tasks their own process group, so that Ctrl-C reaches n2 and
+        // not them, but leave console tasks in the terminal's.
+        if !console {
+            flags |= libc::POSIX_SPAWN_SETPGROUP;
+            attr.setpgroup(0)?;
+        }
+        attr.setflags(flags as _)?;
 
         let mut actions = PosixSpawnFileActions::new()?;
-        // open /dev/null over stdin
-        actions.addopen(0, c"/dev/null", libc::O_RDONLY, 0)?;
-        // stdout/stderr => pipe
-        actions.adddup2(pipe[1], 1)?;
-        actions.adddup2(pipe[1], 2)?;
-        // close pipe in child
-        actions.addclose(pipe[0])?;
-        actions.addclose(pipe[1])?;
+        let pipe = if console {
+            // Under CLOEXEC_DEFAULT, stdio is only inherited if dup2'd onto
+            // itself.
+            #[cfg(target_os = "macos")]
+            for fd in 0..3 {
+                actions.adddup2(fd, fd)?;
+            }
+            None
+        } else {
+            let pipe = pipe2()?;
+            // open /dev/null over stdin
+            actions.addopen(0, c"/dev/null", libc::O_RDONLY, 0)?;
+            // stdout/stderr => pipe
+            actions.adddup2(pipe[1], 1)?;
+            actions.adddup2(pipe[1], 2)?;
+            // close pipe in child
+            actions.addclose(pipe[0])?;
+            actions.addclose(pipe[1])?;
+            Some(pipe)
+        };
 
         let mut pid: libc::pid_t = 0;
         let path = c"/bin/sh";
@@ -198,20 +272,49 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
             ),
         )?;
 
-        check_ret_errno("close", libc::close(pipe[1]))?;
-
-        (pid, std::fs::File::from_raw_fd(pipe[0]))
+        let pipe = match pipe {
+            Some(pipe) => {
+                check_ret_errno("close", libc::close(pipe[1]))?;
+                Some(std::fs::File::from_raw_fd(pipe[0]))
+            }
+            None => None,
+        };
+        (pid, pipe)
+    };
+    let running = if console {
+        None
+    } else {
+        Some(Running::new(pid))
     };
 
-    let mut buf: [u8; 4 << 10] = [0; 4 << 10];
-    loop {
-        let n = pipe.read(&mut buf)?;
-        if n == 0 {
-            break;
+    if let Some(mut pipe) = pipe {
+        let mut buf: [u8; 4 << 10] = [0; 4 << 10];
+        loop {
+            let n = pipe.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            output_cb(&buf[0..n]);
         }
-        output_cb(&buf[0..n]);
     }
-    drop(pipe);
+
+    if let Some(running) = running {
+        // Wait for the task to exit but leave it unreaped until it's no
+        // longer in RUNNING, so its pid isn't signalled after reuse.
+        unsafe {
+            let mut info: libc::siginfo_t = std::mem::zeroed();
+            check_ret_errno(
+                "waitid",
+                libc::waitid(
+                    libc::P_PID,
+                    pid as libc::id_t,
+                    &mut info,
+                    libc::WEXITED | libc::WNOWAIT,
+                ),
+            )?;
+        }
+        drop(running);
+    }
 
     let status = unsafe {
         let mut status: i32 = 0;
@@ -223,17 +326,18 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
         Termination::Success
     } else if let Some(sig) = status.signal() {
         match sig {
-            libc::SIGINT => {
-                output_cb("interrupted".as_bytes());
-                Termination::Interrupted
-            }
+            // Progress reports the interruption itself.
+            libc::SIGINT => Termination::Interrupted,
             _ => {
-                output_cb(format!("signal {}", sig).as_bytes());
-                Termination::Failure
+                output_cb(format!("signal {}\n", sig).as_bytes());
+                Termination::Failure(format!("signal {}", sig))
             }
         }
     } else {
-        Termination::Failure
+        Termination::Failure(match status.code() {
+            Some(code) => format!("exit status {}", code),
+            None => status.to_string(),
+        })
     };
 
     Ok(termination)
@@ -6,13 +6,52 @@ use std::ffi::c_void;
 use std::io::Read;
 use std::os::windows::io::{FromRawHandle, OwnedHandle};
 use std::os::windows::prelude::AsRawHandle;
-use std::pin::{pin, Pin};
+use std::pin::Pin;
+use std::sync::Mutex;
 use windows_sys::Win32::{
     Foundation::*,
     Security::SECURITY_ATTRIBUTES,
     System::{Console::*, Diagnostics::Debug::*, Pipes::CreatePipe, Threading::*},
 };
 
+/// The running tasks, apart from console ones, each in its own process
+/// group, so that an interrupted build can pass Ctrl-Break on to them.
+static RUNNING: Mutex<Vec<(u32, HANDLE)>> = Mutex::new(Vec::new());
+
+/// Keeps a task in RUNNING while alive.
+struct Running(u32);
+
+impl Running {
+    fn new(process_info: &PROCESS_INFORMATION) -> Self {
+        RUNNING
+            .lock()
+            .unwrap()
+            .push((process_info.dwProcessId, process_info.hProcess));
+        Running(process_info.dwProcessId)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.lock().unwrap().retain(|&(pid, _)| pid != self.0);
+    }
+}
+
+/// Pass Ctrl-Break on to the running tasks, which don't see Ctrl-C.
+pub fn interrupt_running() {
+    for &(pid, _) in RUNNING.lock().unwrap().iter() {
+        unsafe { GenerateConsoleCtrlEvent(CTRL_BREAK_EVENT, pid) };
+    }
+}
+
+/// Kill running tasks that didn't exit when interrupted.
+pub fn kill_running() {
+    for &(_, process) in RUNNING.lock().unwrap().iter() {
+        // The handle stays open until the task is removed from RUNNING.
+        unsafe { TerminateProcess(process, 0xC000013A) };
+    }
+}
+
 fn get_error_string(err: u32) -> String {
     let mut buf: [u8; 1024] = [0; 1024];
     let len = unsafe {
@@ -153,48 +192,78 @@ impl<'a> Drop for ProcThreadAttributeList<'a> {
     }
 }
 
-pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::Result<Termination> {
+/// Runs a command, passing its combined stdout and stderr to output_cb.
+/// A console command instead shares n2's own stdin, stdout and stderr.
+pub fn run_command(
+    cmdline: &str,
+    console: bool,
+    mut output_cb: impl FnMut(&[u8]),
+) -> anyhow::Result<Termination> {
     // Don't want to run `cmd /c` since that limits cmd line length to 8192 bytes.
     // std::process::Command can't take a string and pass it through to CreateProcess unchanged,
     // so call that ourselves.
     // https://github.com/rust-lang/rust/issues/38227
 
-    let (pipe_read, pipe_write) = unsafe {
-        let mut pipe_read: HANDLE = 0;
-        let mut pipe_write: HANDLE = 0;
-        let mut attrs = std::mem::zeroed::<SECURITY_ATTRIBUTES>();
-        attrs.nLength = std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32;
-        attrs.bInheritHandle = TRUE;
-        if CreatePipe(
-            &mut pipe_read,
-            &mut pipe_write,
-            &mut attrs,
-            /* use default buffer size */ 0,
-        ) == 0
-        {
-            win_bail!(CreatePipe);
-        }
-        (
-            OwnedHandle::from_raw_handle(pipe_read as *mut c_void),
-            OwnedHandle::from_raw_handle(pipe_write as *mut c_void),
-        )
+    let pipe = if console {
+        None
+    } else {
+        Some(unsafe {
+            let mut pipe_read: HANDLE = 0;
+            let mut pipe_write: HANDLE = 0;
+            let mut attrs = std::mem::zeroed::<SECURITY_ATTRIBUTES>();
+            attrs.nLength = std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32;
+            attrs.bInheritHandle = TRUE;
+            if CreatePipe(
+                &mut pipe_read,
+                &mut pipe_write,
+                &mut attrs,
+                /* use default buffer size */ 0,
+            ) == 0
+            {
+                win_bail!(CreatePipe);
+            }
+            (
+                OwnedHandle::from_raw_handle(pipe_read as *mut c_void),
+                OwnedHandle::from_raw_handle(pipe_write as *mut c_void),
+            )
+        })
     };
 
     let process_info = unsafe {
-        // TODO: Set this to just 0 for console pool jobs.
-        let process_flags = CREATE_NEW_PROCESS_GROUP | EXTENDED_STARTUPINFO_PRESENT;
+        // Console jobs stay in our process group so they see Ctrl-C directly.
+        let process_flags = if console {
+            EXTENDED_STARTUPINFO_PRESENT
+        } else {
+            CREATE_NEW_PROCESS_GROUP | EXTENDED_STARTUPINFO_PRESENT
+        };
 
         let mut startup_info = std::mem::zeroed::<STARTUPINFOEXA>();
         startup_info.StartupInfo.cb = std::mem::size_of::<STARTUPINFOEXA>() as u32;
         startup_info.StartupInfo.dwFlags = STARTF_USESTDHANDLES;
         startup_info.StartupInfo.hStdInput = GetStdHandle(STD_INPUT_HANDLE);
-        let raw_pipe_write = pipe_write.as_raw_handle() as isize;
-        startup_info.StartupInfo.hStdOutput = raw_pipe_write;
-        startup_info.StartupInfo.hStdError = raw_pipe_write;
+        let mut handles = vec![startup_info.StartupInfo.hStdInput];
+        match &pipe {
+            Some((_, pipe_write)) => {
+                let raw_pipe_write = pipe_write.as_raw_handle() as isize;
+                startup_info.StartupInfo.hStdOutput = raw_pipe_write;
+                startup_info.StartupInfo.hStdError = raw_pipe_write;
+                handles.push(raw_pipe_write);
+            }
+            None => {
+                startup_info.StartupInfo.hStdOutput = GetStdHandle(STD_OUTPUT_HANDLE);
+                startup_info.StartupInfo.hStdError = GetStdHandle(STD_ERROR_HANDLE);
+                handles.push(startup_info.StartupInfo.hStdOutput);
+                handles.push(startup_info.StartupInfo.hStdError);
+            }
+        }
+        // The handle list may not name a handle twice, as when stdout and
+        // stderr are the same console.
+        handles.sort();
+        handles.dedup();
 
         // Safely inherit in/out handles.
         // https://devblogs.microsoft.com/oldnewthing/20111216-00/?p=8873
-        let handles = pin!([startup_info.StartupInfo.hStdInput, raw_pipe_write]);
+        let handles = Pin::new(&handles[..]);
         let mut attrs = ProcThreadAttributeList::new(1)?;
         attrs.inherit_handles(handles)?;
         startup_info.lpAttributeList = attrs.as_mut_ptr();
@@ -230,19 +299,25 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
             }
             win_bail!(CreateProcessA);
         }
-        drop(pipe_write);
-
         process_info
     };
+    let running = if console {
+        None
+    } else {
+        Some(Running::new(&process_info))
+    };
 
-    let mut pipe = std::fs::File::from(pipe_read);
-    let mut buf: [u8; 4 << 10] = [0; 4 << 10];
-    loop {
-        let n = pipe.read(&mut buf)?;
-        if n == 0 {
-            break;
+    if let Some((pipe_read, pipe_write)) = pipe {
+        drop(pipe_write);
+        let mut pipe = std::fs::File::from(pipe_read);
+        let mut buf: [u8; 4 << 10] = [0; 4 << 10];
+        loop {
+            let n = pipe.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            output_cb(&buf[0..n]);
         }
-        output_cb(&buf[0..n]);
     }
 
     let exit_code = unsafe {
@@ -257,11 +332,14 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
 
         exit_code
     };
+    drop(running);
 
     let termination = match exit_code {
         0 => Termination::Success,
         0xC000013A => Termination::Interrupted,
-        _ => Termination::Failure,
+        // NTSTATUS codes like access violations read better in hex.
+        _ if exit_code > 0xffff => Termination::Failure(format!("exit status {:#x}", exit_code)),
+        _ => Termination::Failure(format!("exit status {}", exit_code)),
     };
 
     Ok(termination)
@@ -275,7 +353,9 @@ mod tests {
     #[test]
     fn run_echo() -> anyhow::Result<()> {
         let mut output = Vec::new();
-        run_command("cmd /c echo hello", |buf| output.extend_from_slice(buf))?;
+        run_command("cmd /c echo hello", false, |buf| {
+            output.extend_from_slice(buf)
+        })?;
         assert_eq!(output, b"hello\r\n");
         Ok(())
     }
@@ -284,8 +364,8 @@ mod tests {
     #[test]
     fn empty_command() -> anyhow::Result<()> {
         let mut output = Vec::new();
-        let err =
-            run_command("", |buf| output.extend_from_slice(buf)).expect_err("expected failure");
+        let err = run_command("", false, |buf| output.extend_from_slice(buf))
+            .expect_err("expected failure");
         assert!(err.to_string().contains("command is empty"));
         Ok(())
     }
@@ -294,8 +374,10 @@ mod tests {
     #[test]
     fn initial_space() -> anyhow::Result<()> {
         let mut output = Vec::new();
-        let err = run_command(" cmd /c echo hello", |buf| output.extend_from_slice(buf))
-            .expect_err("expected failure");
+        let err = run_command(" cmd /c echo hello", false, |buf| {
+            output.extend_from_slice(buf)
+        })
+        .expect_err("expected failure");
         assert!(err.to_string().contains("command has leading whitespace"));
         Ok(())
     }
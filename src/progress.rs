@@ -12,6 +12,24 @@ pub fn build_message(build: &Build) -> &str {
         .unwrap_or_else(|| build.cmdline.as_ref().unwrap())
 }
 
+/// Find the span of the last line of text in buf, ignoring trailing empty
+/// lines.
+pub fn find_last_line(buf: &[u8]) -> &[u8] {
+    fn is_nl(c: u8) -> bool {
+        c == b'\r' || c == b'\n'
+    }
+
+    let end = match buf.iter().rposition(|&c| !is_nl(c)) {
+        Some(pos) => pos + 1,
+        None => buf.len(),
+    };
+    let start = match buf[..end].iter().rposition(|&c| is_nl(c)) {
+        Some(pos) => pos + 1,
+        None => 0,
+    };
+    &buf[start..end]
+}
+
 /// Trait for build progress notifications.
 pub trait Progress {
     /// Called as individual build tasks progress through build states.
@@ -20,8 +38,8 @@ pub trait Progress {
     /// Called when a task starts.
     fn task_started(&self, id: BuildId, build: &Build);
 
-    /// Called when a task's last line of output changes.
-    fn task_output(&self, id: BuildId, line: Vec<u8>);
+    /// Called with each piece of output a task prints, as it prints it.
+    fn task_output(&self, id: BuildId, build: &Build, output: &[u8]);
 
     /// Called when a task completes.
     fn task_finished(&self, id: BuildId, build: &Build, result: &TaskResult);
@@ -32,3 +50,23 @@ pub trait Progress {
     /// task's output even if we do more work after it fails.
     fn log(&self, msg: &str);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_last() {
+        assert_eq!(find_last_line(b""), b"");
+        assert_eq!(find_last_line(b"\n"), b"");
+
+        assert_eq!(find_last_line(b"hello"), b"hello");
+        assert_eq!(find_last_line(b"hello\n"), b"hello");
+
+        assert_eq!(find_last_line(b"hello\nt"), b"t");
+        assert_eq!(find_last_line(b"hello\nt\n"), b"t");
+
+        assert_eq!(find_last_line(b"hello\n\n"), b"hello");
+        assert_eq!(find_last_line(b"hello\nt\n\n"), b"t");
+    }
+}
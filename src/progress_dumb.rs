@@ -4,9 +4,24 @@ use crate::progress::{build_message, Progress};
 use crate::{
     graph::Build, graph::BuildId, process::Termination, task::TaskResult, work::StateCounts,
 };
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
 use std::io::Write;
 
+/// A task whose output is printed as it runs, because it was running alone.
+struct Streamed {
+    id: BuildId,
+    /// The output printed so far.  Kept rather than counted, because a task
+    /// that fails after running reports an error in place of its output.
+    printed: Vec<u8>,
+    /// Output after the last complete line, held back so that lines aren't
+    /// broken up by other tasks' messages.
+    partial: Vec<u8>,
+    /// Cleared once another task starts, after which the rest of the output
+    /// waits for the task to finish like any other.
+    live: bool,
+}
+
 /// Progress implementation for "dumb" console, without any overprinting.
 #[derive(Default)]
 pub struct DumbConsoleProgress {
@@ -16,6 +31,22 @@ pub struct DumbConsoleProgress {
     /// The id of the last command printed, used to avoid printing it twice
     /// when we have two updates from the same command in a row.
     last_started: Cell<Option<BuildId>>,
+
+    /// The running console pool build, if any, which owns the terminal.
+    console: Cell<Option<BuildId>>,
+
+    /// Output held back while a console build runs.
+    held: RefCell<Vec<u8>>,
+
+    /// Number of tasks running.
+    running: Cell<usize>,
+
+    /// The task whose output is being printed as it runs, if any.
+    streamed: RefCell<Option<Streamed>>,
+
+    /// Running tasks that printed output while not streamed, which can't
+    /// start streaming later without printing it out of order.
+    buffered: RefCell<HashSet<BuildId>>,
 }
 
 impl DumbConsoleProgress {
@@ -23,6 +54,20 @@ impl DumbConsoleProgress {
         Self {
             verbose,
             last_started: Default::default(),
+            console: Default::default(),
+            held: Default::default(),
+            running: Default::default(),
+            streamed: Default::default(),
+            buffered: Default::default(),
+        }
+    }
+
+    /// Print some output, or hold it if a console build has the terminal.
+    fn write(&self, buf: &[u8]) {
+        if self.console.get().is_some() {
+            self.held.borrow_mut().extend_from_slice(buf);
+        } else {
+            std::io::stdout().write_all(buf).unwrap();
         }
     }
 }
@@ -39,30 +84,92 @@ impl Progress for DumbConsoleProgress {
             build_message(build)
         });
         self.last_started.set(Some(id));
+        self.running.set(self.running.get() + 1);
+        if let Some(streamed) = self.streamed.borrow_mut().as_mut() {
+            streamed.live = false;
+        }
+        if build.is_console() {
+            std::io::stdout().flush().unwrap();
+            self.console.set(Some(id));
+        }
     }
 
-    fn task_output(&self, _id: BuildId, _line: Vec<u8>) {
-        // ignore
+    fn task_output(&self, id: BuildId, build: &Build, output: &[u8]) {
+        // Output of a task running alone is printed as it comes, so long
+        // tasks aren't silent.  Tasks whose output is filtered once they
+        // finish can't be.
+        let mut streamed = self.streamed.borrow_mut();
+        let streamed = match streamed.as_mut() {
+            Some(streamed) if streamed.id == id && streamed.live => streamed,
+            Some(_) => {
+                self.buffered.borrow_mut().insert(id);
+                return;
+            }
+            None if self.running.get() == 1
+                && build.showincludes_prefix.is_none()
+                && !self.buffered.borrow().contains(&id) =>
+            {
+                if self.last_started.get() != Some(id) {
+                    self.log(build_message(build));
+                    self.last_started.set(Some(id));
+                }
+                streamed.insert(Streamed {
+                    id,
+                    printed: Vec::new(),
+                    partial: Vec::new(),
+                    live: true,
+                })
+            }
+            None => {
+                self.buffered.borrow_mut().insert(id);
+                return;
+            }
+        };
+        streamed.partial.extend_from_slice(output);
+        if let Some(nl) = streamed.partial.iter().rposition(|&c| c == b'\n') {
+            let lines: Vec<u8> = streamed.partial.drain(..=nl).collect();
+            self.write(&lines);
+            streamed.printed.extend_from_slice(&lines);
+        }
     }
 
     fn task_finished(&self, id: BuildId, build: &Build, result: &TaskResult) {
+        let console_done = self.console.get() == Some(id);
+        if console_done {
+            self.console.set(None);
+        }
+        self.running.set(self.running.get() - 1);
+        self.buffered.borrow_mut().remove(&id);
+        let mut output = &result.output[..];
+        if self.streamed.borrow().as_ref().is_some_and(|s| s.id == id) {
+            let streamed = self.streamed.take().unwrap();
+            // Only skip what was printed if the result still holds it; an
+            // error replacing the output is printed in full.
+            if let Some(rest) = output.strip_prefix(&streamed.printed[..]) {
+                output = rest;
+            }
+        }
         match result.termination {
             Termination::Success => {
-                if result.output.is_empty() || self.last_started.get() == Some(id) {
+                if output.is_empty() || self.last_started.get() == Some(id) {
                     // Output is empty, or we just printed the command, don't print it again.
                 } else {
                     self.log(build_message(build))
                 }
             }
             Termination::Interrupted => self.log(&format!("interrupted: {}", build_message(build))),
-            Termination::Failure => self.log(&format!("failed: {}", build_message(build))),
+            Termination::Failure(_) => self.log(&format!("failed: {}", build_message(build))),
         };
-        if !result.output.is_empty() {
-            std::io::stdout().write_all(&result.output).unwrap();
+        if !output.is_empty() {
+            self.write(output);
+            self.last_started.set(Some(id));
+        }
+        if console_done {
+            self.write(&self.held.take());
         }
     }
 
     fn log(&self, msg: &str) {
-        println!("{}", msg);
+        self.write(format!("{}\n", msg).as_bytes());
     }
 }
@@ -1,6 +1,6 @@
 //! Build progress reporting for a "fancy" console, with progress bar etc.
 
-use crate::progress::{build_message, Progress};
+use crate::progress::{build_message, find_last_line, Progress};
 use crate::{
     graph::Build, graph::BuildId, process::Termination, task::TaskResult, terminal,
     work::BuildState, work::StateCounts,
@@ -22,6 +22,8 @@ struct Task {
     message: String,
     /// Last line of output from the task.
     last_line: Option<String>,
+    /// Output since the last line break, which may continue the last line.
+    tail: Vec<u8>,
 }
 
 /// Progress implementation for "fancy" console, with progress bar etc.
@@ -53,6 +55,7 @@ impl FancyConsoleProgress {
             dirty_cond: dirty_cond.clone(),
             counts: StateCounts::default(),
             tasks: VecDeque::new(),
+            console: None,
             verbose,
         }));
 
@@ -101,8 +104,8 @@ impl Progress for FancyConsoleProgress {
         self.state.lock().unwrap().task_started(id, build);
     }
 
-    fn task_output(&self, id: BuildId, line: Vec<u8>) {
-        self.state.lock().unwrap().task_output(id, line);
+    fn task_output(&self, id: BuildId, _build: &Build, output: &[u8]) {
+        self.state.lock().unwrap().task_output(id, output);
     }
 
     fn task_finished(&self, id: BuildId, build: &Build, result: &TaskResult) {
@@ -138,6 +141,9 @@ struct FancyState {
     /// Build tasks that are currently executing.
     /// Pushed to as tasks are started, so it's always in order of age.
     tasks: VecDeque<Task>,
+    /// The running console pool build, if any.  While it runs it owns the
+    /// terminal, so progress isn't drawn and other output stays in pending.
+    console: Option<BuildId>,
     /// Whether to print command lines of started programs.
     verbose: bool,
 }
@@ -155,27 +161,51 @@ impl FancyState {
 
     fn task_started(&mut self, id: BuildId, build: &Build) {
         if self.verbose {
-            write!(&mut self.pending, "{}\n", build.cmdline.as_ref().unwrap()).ok();
+            writeln!(&mut self.pending, "{}", build.cmdline.as_ref().unwrap()).ok();
         }
         let message = build_message(build);
+        if build.is_console() {
+            // Clear the progress and flush everything before the build
+            // starts writing to the terminal itself.
+            if !self.verbose {
+                writeln!(&mut self.pending, "{}", message).ok();
+            }
+            let mut stdout = std::io::stdout();
+            stdout.write_all(&self.pending).unwrap();
+            stdout.flush().unwrap();
+            self.pending.clear();
+            self.console = Some(id);
+        }
         self.tasks.push_back(Task {
             id,
             start: Instant::now(),
             message: message.to_string(),
             last_line: None,
+            tail: Vec::new(),
         });
         self.dirty();
     }
 
-    fn task_output(&mut self, id: BuildId, line: Vec<u8>) {
+    fn task_output(&mut self, id: BuildId, output: &[u8]) {
         let task = self.tasks.iter_mut().find(|t| t.id == id).unwrap();
-        task.last_line = Some(String::from_utf8_lossy(&line).into_owned());
+        task.tail.extend_from_slice(output);
+        let line = find_last_line(&task.tail);
+        if !line.is_empty() {
+            task.last_line = Some(String::from_utf8_lossy(line).into_owned());
+        }
+        if let Some(nl) = task.tail.iter().rposition(|&c| c == b'\n' || c == b'\r') {
+            task.tail.drain(..=nl);
+        }
         self.dirty();
     }
 
     fn task_finished(&mut self, id: BuildId, build: &Build, result: &TaskResult) {
         self.tasks
             .remove(self.tasks.iter().position(|t| t.id == id).unwrap());
+        if self.console == Some(id) {
+            self.console = None;
+            self.dirty();
+        }
 
         // Show task name, status, and output.
         let buf = &mut self.pending;
@@ -184,9 +214,9 @@ impl FancyState {
                 // Common case: don't show anything.
                 return;
             }
-            Termination::Success => write!(buf, "{}\n", build_message(build)).ok(),
-            Termination::Interrupted => write!(buf, "interrupted: {}\n", build_message(build)).ok(),
-            Termination::Failure => write!(buf, "failed: {}\n", build_message(build)).ok(),
+            Termination::Success => writeln!(buf, "{}", build_message(build)).ok(),
+            Termination::Interrupted => writeln!(buf, "interrupted: {}", build_message(build)).ok(),
+            Termination::Failure(_) => writeln!(buf, "failed: {}", build_message(build)).ok(),
         };
         buf.extend_from_slice(&result.output);
         if !result.output.ends_with(b"\n") {
@@ -208,6 +238,10 @@ impl FancyState {
     }
 
     fn print_progress(&mut self) {
+        if self.console.is_some() {
+            self.dirty = false;
+            return;
+        }
         let failed = self.counts.get(BuildState::Failed);
         let mut buf: &mut Vec<u8> = &mut self.pending;
         write!(
@@ -221,9 +255,9 @@ impl FancyState {
         if failed > 0 {
             write!(&mut buf, "{} failed, ", failed).ok();
         }
-        write!(
+        writeln!(
             &mut buf,
-            "{}/{} running\n",
+            "{}/{} running",
             self.tasks.len(),
             self.counts.get(BuildState::Queued)
                 + self.counts.get(BuildState::Running)
@@ -237,29 +271,24 @@ impl FancyState {
         let now = Instant::now();
         for task in self.tasks.iter().take(max_tasks) {
             let delta = now.duration_since(task.start).as_secs() as usize;
-            write!(
-                &mut buf,
-                "{}\n",
-                task_message(&task.message, delta, max_cols)
-            )
-            .ok();
+            writeln!(&mut buf, "{}", task_message(&task.message, delta, max_cols)).ok();
             lines += 1;
             if let Some(line) = &task.last_line {
                 let max_len = max_cols - 2;
-                write!(&mut buf, "  {}\n", truncate(line, max_len)).ok();
+                writeln!(&mut buf, "  {}", truncate(line, max_len)).ok();
                 lines += 1;
             }
         }
 
         if self.tasks.len() > max_tasks {
             let remaining = self.tasks.len() - max_tasks;
-            write!(&mut buf, "...and {} more\n", remaining).ok();
+            writeln!(&mut buf, "...and {} more", remaining).ok();
             lines += 1;
         }
 
         // Move cursor up to the first printed line, for overprinting.
         write!(&mut buf, "\x1b[{}A", lines).ok();
-        std::io::stdout().write_all(&buf).unwrap();
+        std::io::stdout().write_all(buf).unwrap();
 
         // Set up buf for next print.
         // If the user hit ctl-c, it may have printed something on the line.
@@ -0,0 +1,138 @@
+//! Describes a file's place in the graph, as `ninja -t query` does: the build
+//! producing it, with its inputs by kind and its command, and the builds that
+//! use it.
+
+use crate::graph::{BuildId, FileId, Graph};
+use std::fmt::Write;
+
+/// Format what produces and what uses the file.
+pub fn format(graph: &Graph, id: FileId) -> String {
+    let file = graph.file(id);
+    let mut out = format!("{}:\n", file.name);
+    if let Some(bid) = file.input {
+        let build = &graph.builds[bid];
+        writeln!(out, "  input: {}", build.rule).unwrap();
+        let kinds = [
+            ("", build.explicit_ins().len()),
+            ("| ", build.ins.implicit),
+            ("|| ", build.ins.order_only),
+        ];
+        let mut ins = build.ordering_ins().iter();
+        for (prefix, count) in kinds {
+            for &input in ins.by_ref().take(count) {
+                writeln!(out, "    {}{}", prefix, graph.file(input).name).unwrap();
+            }
+        }
+        if !build.validation_ins().is_empty() {
+            out.push_str("  validations:\n");
+            for &input in build.validation_ins() {
+                writeln!(out, "    {}", graph.file(input).name).unwrap();
+            }
+        }
+        if let Some(cmdline) = &build.cmdline {
+            writeln!(out, "  command: {}", cmdline).unwrap();
+        }
+        if let Some(depfile) = &build.depfile {
+            writeln!(out, "  depfile: {}", depfile).unwrap();
+        }
+        if let Some(pool) = &build.pool {
+            writeln!(out, "  pool: {}", pool).unwrap();
+        }
+    }
+
+    // A build lists the file once per time it names it, so skip repeats.
+    let mut users: Vec<BuildId> = Vec::new();
+    for &bid in &file.dependents {
+        if !users.contains(&bid) {
+            users.push(bid);
+        }
+    }
+    let (validated, used): (Vec<BuildId>, Vec<BuildId>) = users
+        .into_iter()
+        .partition(|&bid| !graph.builds[bid].ordering_ins().contains(&id));
+    for (header, builds) in [("outputs", used), ("validation for", validated)] {
+        if builds.is_empty() {
+            continue;
+        }
+        writeln!(out, "  {}:", header).unwrap();
+        for bid in builds {
+            for &output in graph.builds[bid].outs() {
+                writeln!(out, "    {}", graph.file(output).name).unwrap();
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = "
+pool link_pool
+  depth = 1
+rule cc
+  command = cc -c $in -o $out
+  depfile = $out.d
+rule link
+  command = ld $in -o $out
+  pool = link_pool
+build a.o: cc a.c | a.h || gen |@ lint
+build b.o: cc b.c a.h
+build out: link a.o b.o
+build lint: phony a.h
+";
+
+    fn query(name: &str) -> anyhow::Result<String> {
+        let graph = crate::load::parse("build.ninja", MANIFEST.as_bytes().to_vec())?;
+        Ok(format(&graph, graph.files.lookup(name).unwrap()))
+    }
+
+    #[test]
+    fn output() -> anyhow::Result<()> {
+        assert_eq!(
+            query("a.o")?,
+            "a.o:
+  input: cc
+    a.c
+    | a.h
+    || gen
+  validations:
+    lint
+  command: cc -c a.c -o a.o
+  depfile: a.o.d
+  outputs:
+    out
+"
+        );
+        assert_eq!(
+            query("out")?,
+            "out:
+  input: link
+    a.o
+    b.o
+  command: ld a.o b.o -o out
+  pool: link_pool
+"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn source() -> anyhow::Result<()> {
+        assert_eq!(
+            query("a.h")?,
+            "a.h:
+  outputs:
+    a.o
+    b.o
+    lint
+"
+        );
+        assert_eq!(
+            query("lint")?,
+            "lint:\n  input: phony\n    a.h\n  validation for:\n    a.o\n"
+        );
+        Ok(())
+    }
+}
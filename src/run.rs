@@ -1,8 +1,10 @@
 //! Command line argument parsing and initial build invocation.
 
 use crate::{
-    load, progress::Progress, progress_dumb::DumbConsoleProgress,
-    progress_fancy::FancyConsoleProgress, terminal, trace, work,
+    clean, commands, compdb, db, densemap::Index, deps, dump, graph, graph::BuildId, graphviz,
+    inputs, load, lock, ninja_log, progress::Progress, progress_dumb::DumbConsoleProgress,
+    progress_fancy::FancyConsoleProgress, query, scanner::ColorMode, targets, terminal, trace,
+    work,
 };
 use anyhow::anyhow;
 
@@ -11,15 +13,60 @@ use anyhow::anyhow;
 struct BuildArgs {
     fake_ninja_compat: bool,
     options: work::Options,
+    load_options: load::Options,
     build_filename: Option<String>,
     targets: Vec<String>,
     verbose: bool,
+    /// Compact the database rather than building.
+    recompact: bool,
+    /// Remove built files rather than building.
+    clean: bool,
+    /// With clean, also remove the outputs of generator builds.
+    clean_generator: bool,
+    /// With clean, take the arguments given as targets as rule names.
+    clean_rules: bool,
+    /// Print the commands needed to build the targets rather than building.
+    commands: bool,
+    /// With commands, print only the targets' own commands.
+    commands_single: bool,
+    /// Print a compilation database for the rules named as targets rather
+    /// than building.
+    compdb: bool,
+    /// With compdb, replace @rspfile arguments with the rspfiles' content.
+    compdb_expand_rsp: bool,
+    /// Print the dependencies recorded for the targets, or every recorded
+    /// build, rather than building.
+    deps: bool,
+    /// Print the graph for dot rather than building.
+    graph: bool,
+    /// With graph, what to print.
+    graph_options: graphviz::Options,
+    /// List the files the targets are built from rather than building.
+    inputs: bool,
+    /// List the outputs built from the files given as targets rather than
+    /// building.
+    outputs: bool,
+    /// With inputs or outputs, which files to list.
+    inputs_options: inputs::Options,
+    /// Print the parsed manifest as JSON rather than building.
+    manifest: bool,
+    /// List targets as the arguments given as targets ask rather than
+    /// building.
+    list_targets: bool,
+    /// Describe the files given as targets rather than building.
+    query: bool,
+    /// Fail rather than wait if another n2 holds the build directory lock.
+    no_lock_wait: bool,
+    /// Don't append finished tasks to .ninja_log.
+    no_ninja_log: bool,
 }
 
 /// Returns the number of completed tasks on a successful build.
 fn build(args: BuildArgs) -> anyhow::Result<Option<usize>> {
+    let start = std::time::Instant::now();
     let (dumb_console, fancy_console);
-    let progress: &dyn Progress = if terminal::use_fancy() {
+    // A dry run lists every task, which the fancy progress would overprint.
+    let progress: &dyn Progress = if terminal::use_fancy() && !args.options.dry_run {
         fancy_console = FancyConsoleProgress::new(args.verbose);
         &fancy_console
     } else {
@@ -28,7 +75,9 @@ fn build(args: BuildArgs) -> anyhow::Result<Option<usize>> {
     };
 
     let build_filename = args.build_filename.as_deref().unwrap_or("build.ninja");
-    let mut state = trace::scope("load::read", || load::read(build_filename))?;
+    let mut state = trace::scope("load::read", || {
+        load::read(build_filename, &args.load_options)
+    })?;
     let mut work = work::Work::new(
         state.graph,
         state.hashes,
@@ -36,26 +85,33 @@ fn build(args: BuildArgs) -> anyhow::Result<Option<usize>> {
         &args.options,
         progress,
         state.pools,
+        open_ninja_log(&args, &state.db_path, start)?,
     );
 
     let mut tasks_run = 0;
 
-    // Attempt to rebuild build.ninja.
-    let build_file_target = work.lookup(&build_filename);
+    // Attempt to rebuild build.ninja, unless it came from stdin.
+    let build_file_target = match build_filename {
+        "-" => None,
+        _ => work.lookup(build_filename),
+    };
     if let Some(target) = build_file_target {
         work.want_file(target)?;
         if !trace::scope("work.run", || work.run())? {
             return Ok(None);
         }
-        if work.tasks_run == 0 {
-            // build.ninja already up to date.
+        if work.tasks_run == 0 || args.options.dry_run {
+            // build.ninja already up to date, or a dry run, where it isn't
+            // really regenerated and the same work carries on.
             // TODO: this logic is not right in the case where a build has
             // a step that doesn't touch build.ninja.  We should instead
             // verify the specific FileId was updated.
         } else {
             // Regenerated build.ninja; start over.
             tasks_run = work.tasks_run;
-            state = trace::scope("load::read", || load::read(&build_filename))?;
+            state = trace::scope("load::read", || {
+                load::read(build_filename, &args.load_options)
+            })?;
             work = work::Work::new(
                 state.graph,
                 state.hashes,
@@ -63,6 +119,7 @@ fn build(args: BuildArgs) -> anyhow::Result<Option<usize>> {
                 &args.options,
                 progress,
                 state.pools,
+                open_ninja_log(&args, &state.db_path, start)?,
             );
         }
     }
@@ -93,11 +150,267 @@ fn build(args: BuildArgs) -> anyhow::Result<Option<usize>> {
     Ok(Some(tasks_run + work.tasks_run))
 }
 
+/// Open the .ninja_log beside the database, unless disabled or a dry run.
+fn open_ninja_log(
+    args: &BuildArgs,
+    db_path: &std::path::Path,
+    start: std::time::Instant,
+) -> anyhow::Result<Option<ninja_log::Writer>> {
+    if args.no_ninja_log || args.options.dry_run {
+        return Ok(None);
+    }
+    let path = db_path.with_file_name(".ninja_log");
+    ninja_log::Writer::open(&path, start).map_err(|err| anyhow!("open {}: {}", path.display(), err))
+}
+
+/// Look up the files named by the targets, failing on any not in the graph.
+fn lookup_targets(graph: &graph::Graph, names: &[String]) -> anyhow::Result<Vec<graph::FileId>> {
+    names
+        .iter()
+        .map(|name| {
+            let mut name = name.clone();
+            graph.files.canonicalize(&mut name);
+            graph
+                .files
+                .lookup(&name)
+                .ok_or_else(|| anyhow!("unknown path requested: {:?}", name))
+        })
+        .collect()
+}
+
+/// Load every build of the manifest, without the database, for a tool that
+/// only inspects the graph.
+fn load_graph(args: &mut BuildArgs) -> anyhow::Result<load::State> {
+    args.load_options.lazy_subninjas = None;
+    args.load_options.no_db = true;
+    let build_filename = args.build_filename.as_deref().unwrap_or("build.ninja");
+    load::read(build_filename, &args.load_options)
+}
+
+/// Look up the files named by the targets; without any, use the defaults, or
+/// lacking those, the outputs no build uses.
+fn targets_or_defaults(
+    state: &mut load::State,
+    names: &[String],
+) -> anyhow::Result<Vec<graph::FileId>> {
+    let mut targets = lookup_targets(&state.graph, names)?;
+    if targets.is_empty() {
+        targets = std::mem::take(&mut state.default);
+    }
+    if targets.is_empty() {
+        targets = state.graph.roots();
+    }
+    Ok(targets)
+}
+
+/// Rewrite the database without superseded records, reporting its size.
+fn recompact(mut args: BuildArgs) -> anyhow::Result<()> {
+    // Every build must be loaded for its state to be kept.
+    args.load_options.lazy_subninjas = None;
+    // Opening the database normally may compact it already, which would
+    // leave nothing to report; compact it here instead.
+    args.load_options.read_only_db = true;
+    let build_filename = args.build_filename.as_deref().unwrap_or("build.ninja");
+    let state = load::read(build_filename, &args.load_options)?;
+    drop(state.db);
+    let size = |path: &std::path::Path| std::fs::metadata(path).map(|m| m.len());
+    let before = size(&state.db_path)?;
+    db::compact(&state.db_path, &state.graph, &state.hashes)?;
+    let after = size(&state.db_path)?;
+    println!(
+        "n2: compacted {} from {} to {} bytes",
+        state.db_path.display(),
+        before,
+        after
+    );
+    Ok(())
+}
+
+/// Remove built files as the -t clean arguments ask, and forget the builds
+/// that made them so the database doesn't keep their stale state.
+fn clean(mut args: BuildArgs) -> anyhow::Result<()> {
+    // Every build must be loaded for its state to be kept.
+    args.load_options.lazy_subninjas = None;
+    let build_filename = args.build_filename.as_deref().unwrap_or("build.ninja");
+    let mut state = load::read(build_filename, &args.load_options)?;
+    let generator = args.clean_generator;
+    // `rule NAME` is another way to write `-r NAME`; `./rule` names a file.
+    let rules = args.clean_rules || args.targets.first().is_some_and(|t| t == "rule");
+    if rules && !args.clean_rules {
+        args.targets.remove(0);
+    }
+    let filter = if rules {
+        clean::Filter::Rules {
+            rules: args.targets,
+            generator,
+        }
+    } else if args.targets.is_empty() {
+        clean::Filter::All { generator }
+    } else {
+        clean::Filter::Targets(lookup_targets(&state.graph, &args.targets)?)
+    };
+
+    let dry_run = args.options.dry_run;
+    let builds = clean::select(&state.graph, &filter);
+    let mut seen = std::collections::HashSet::new();
+    let mut removed = 0;
+    for &id in &builds {
+        for path in clean::files(&state.graph, id) {
+            if !seen.insert(path.clone()) {
+                continue;
+            }
+            if dry_run {
+                if std::path::Path::new(&path).exists() {
+                    println!("n2: would remove {}", path);
+                    removed += 1;
+                }
+                continue;
+            }
+            match std::fs::remove_file(&path) {
+                Ok(()) => removed += 1,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => println!("n2: warn: remove {}: {}", path, err),
+            }
+        }
+        state.hashes.forget(id);
+    }
+
+    let plural = if removed == 1 { "" } else { "s" };
+    if dry_run {
+        println!("n2: dry run: would remove {} file{}", removed, plural);
+        return Ok(());
+    }
+    if !builds.is_empty() {
+        drop(state.db);
+        db::compact(&state.db_path, &state.graph, &state.hashes)?;
+    }
+    println!("n2: removed {} file{}", removed, plural);
+    Ok(())
+}
+
+/// Print the commands needed to build the targets, or the defaults.
+fn print_commands(mut args: BuildArgs) -> anyhow::Result<()> {
+    let mut state = load_graph(&mut args)?;
+    let targets = targets_or_defaults(&mut state, &args.targets)?;
+    let text = commands::format(&state.graph, &targets, args.commands_single);
+    std::io::Write::write_all(&mut std::io::stdout().lock(), text.as_bytes())?;
+    Ok(())
+}
+
+/// List the files the targets, or the defaults, are built from, or with
+/// outputs, the outputs built from the files given as targets.
+fn print_inputs(mut args: BuildArgs) -> anyhow::Result<()> {
+    let mut state = load_graph(&mut args)?;
+    let text = if args.outputs {
+        let targets = lookup_targets(&state.graph, &args.targets)?;
+        if targets.is_empty() {
+            anyhow::bail!("-t outputs: expected a file to list the outputs of");
+        }
+        inputs::outputs(&state.graph, &targets, &args.inputs_options)
+    } else {
+        let targets = targets_or_defaults(&mut state, &args.targets)?;
+        inputs::inputs(&state.graph, &targets, &args.inputs_options)
+    };
+    std::io::Write::write_all(&mut std::io::stdout().lock(), text.as_bytes())?;
+    Ok(())
+}
+
+/// Print a compilation database for the rules named as targets.
+fn print_compdb(mut args: BuildArgs) -> anyhow::Result<()> {
+    let state = load_graph(&mut args)?;
+    let dir = std::env::current_dir()?;
+    let json = compdb::format(
+        &state.graph,
+        &dir.to_string_lossy(),
+        &args.targets,
+        args.compdb_expand_rsp,
+    );
+    std::io::Write::write_all(&mut std::io::stdout().lock(), json.as_bytes())?;
+    Ok(())
+}
+
+/// Print the part of the graph needed for the targets, or the defaults, in
+/// Graphviz's dot language.
+fn print_graph(mut args: BuildArgs) -> anyhow::Result<()> {
+    let mut state = load_graph(&mut args)?;
+    let targets = targets_or_defaults(&mut state, &args.targets)?;
+    let dot = graphviz::format(&state.graph, &targets, &args.graph_options);
+    std::io::Write::write_all(&mut std::io::stdout().lock(), dot.as_bytes())?;
+    Ok(())
+}
+
+/// Print the dependencies the database recorded for the targets, or with no
+/// targets, for every build it has a record of.
+fn print_deps(mut args: BuildArgs) -> anyhow::Result<()> {
+    args.load_options.lazy_subninjas = None;
+    args.load_options.read_only_db = true;
+    let build_filename = args.build_filename.as_deref().unwrap_or("build.ninja");
+    let state = load::read(build_filename, &args.load_options)?;
+    let mut outs = lookup_targets(&state.graph, &args.targets)?;
+    if outs.is_empty() {
+        outs = (0..state.graph.builds.next_id().index())
+            .map(BuildId::from)
+            .filter(|&id| state.hashes.get(id).is_some())
+            .map(|id| state.graph.builds[id].outs()[0])
+            .collect();
+    }
+    let mut file_state = graph::FileState::new(&state.graph);
+    let mut w = std::io::BufWriter::new(std::io::stdout().lock());
+    for out in outs {
+        deps::write(&mut w, &state.graph, &state.hashes, &mut file_state, out)?;
+    }
+    std::io::Write::flush(&mut w)?;
+    Ok(())
+}
+
+/// Describe the producers and users of each file given as a target.
+fn print_query(mut args: BuildArgs) -> anyhow::Result<()> {
+    if args.targets.is_empty() {
+        anyhow::bail!("-t query: expected a target to query");
+    }
+    let state = load_graph(&mut args)?;
+    let mut text = String::new();
+    for name in &args.targets {
+        let mut name = name.clone();
+        state.graph.files.canonicalize(&mut name);
+        let Some(id) = state.graph.files.lookup(&name) else {
+            let ids = state.graph.files.all_ids();
+            let names = ids.map(|id| state.graph.file(id).name.as_str());
+            match load::similar_names(&name, names).as_slice() {
+                [] => anyhow::bail!("unknown path {:?}", name),
+                [one] => anyhow::bail!("unknown path {:?}; did you mean {:?}?", name, one),
+                [one, two, ..] => anyhow::bail!(
+                    "unknown path {:?}; did you mean {:?} or {:?}?",
+                    name,
+                    one,
+                    two
+                ),
+            }
+        };
+        text.push_str(&query::format(&state.graph, id));
+    }
+    std::io::Write::write_all(&mut std::io::stdout().lock(), text.as_bytes())?;
+    Ok(())
+}
+
+/// List targets as asked by the arguments given as targets.
+fn print_targets(mut args: BuildArgs) -> anyhow::Result<()> {
+    let mode = targets::Mode::parse(&args.targets)?;
+    let state = load_graph(&mut args)?;
+    let text = targets::format(&state.graph, &state.default, &mode);
+    std::io::Write::write_all(&mut std::io::stdout().lock(), text.as_bytes())?;
+    Ok(())
+}
+
+/// The default -j, chosen as ninja does: a couple more tasks than processors,
+/// to keep them busy while some tasks wait on IO.
 fn default_parallelism() -> anyhow::Result<usize> {
-    // Ninja uses available processors + a constant, but I don't think the
-    // difference matters too much.
-    let par = std::thread::available_parallelism()?;
-    Ok(usize::from(par))
+    let par = usize::from(std::thread::available_parallelism()?);
+    Ok(match par {
+        0 | 1 => 2,
+        2 => 3,
+        _ => par + 2,
+    })
 }
 
 /// Run a tool as specified by the `-t` flag`.
@@ -105,15 +418,32 @@ fn subtool(args: &mut BuildArgs, tool: &str) -> anyhow::Result<Option<i32>> {
     match tool {
         "list" => {
             println!("subcommands:");
+            println!("  clean      remove built files: [-g] [rule NAME... | targets...]");
+            println!("  commands   print the commands to build targets [-s for only their own]");
+            println!("  compdb     print a compilation database for the named rules [-x to expand rspfiles]");
             println!(
-                "  (none yet, but see README if you're looking here trying to get CMake to work)"
+                "  deps       print the dependencies recorded for targets, or for every build"
             );
+            println!("  graph      print the graph for dot: [--depth N] [--collapse] [targets...]");
+            println!("  inputs     list the files targets are built from [--direct] [--sources] [--no-order-only]");
+            println!("  manifest   print the parsed manifest as JSON");
+            println!("  outputs    list the outputs built from files [--no-order-only]");
+            println!("  query      show the inputs, command and users of the named targets");
+            println!("  recompact  drop superseded records from the build database");
+            println!("  targets    list targets: depth [N], rule [name] or all");
             return Ok(Some(1));
         }
-        "recompact" if args.fake_ninja_compat => {
-            // CMake unconditionally invokes this tool, yuck.
-            return Ok(Some(0)); // do nothing
-        }
+        "clean" => args.clean = true,
+        "commands" => args.commands = true,
+        "compdb" => args.compdb = true,
+        "deps" => args.deps = true,
+        "graph" => args.graph = true,
+        "inputs" => args.inputs = true,
+        "manifest" => args.manifest = true,
+        "outputs" => args.outputs = true,
+        "query" => args.query = true,
+        "recompact" => args.recompact = true,
+        "targets" => args.list_targets = true,
         "restat" if args.fake_ninja_compat => {
             // CMake invokes this after generating build files; mark build
             // targets as up to date by running the build with "adopt" flag
@@ -134,12 +464,16 @@ fn debugtool(args: &mut BuildArgs, tool: &str) -> anyhow::Result<Option<i32>> {
             println!("debug tools:");
             println!("  ninja_compat  enable ninja quirks compatibility mode");
             println!("  explain       print why each target is considered out of date");
+            println!("  env           print environment variables consulted by --env-vars");
+            println!("  keeprsp       don't delete rspfiles after their commands succeed");
             println!("  trace         generate json performance trace");
             return Ok(Some(1));
         }
 
         "ninja_compat" => args.fake_ninja_compat = true,
         "explain" => args.options.explain = true,
+        "env" => args.load_options.log_env_fallback = true,
+        "keeprsp" => args.options.keep_rsp = true,
         "trace" => trace::open("trace.json")?,
 
         _ => anyhow::bail!("unknown -d {:?}, use -d list to list", tool),
@@ -147,8 +481,42 @@ fn debugtool(args: &mut BuildArgs, tool: &str) -> anyhow::Result<Option<i32>> {
     Ok(None)
 }
 
+/// Adjust a warning as specified by the `-w` flag.
+fn warningflag(args: &mut BuildArgs, flag: &str) -> anyhow::Result<Option<i32>> {
+    match flag {
+        "list" => {
+            println!("warning flags:");
+            println!("  duprule={{err,warn}}         redefining a rule is an error/warning");
+            println!("  dupbuild={{err,warn}}        multiple builds generating an output are an error/warning");
+            println!("  unknownrulevar={{err,warn}}  unused non-reserved rule binding is an error/warning");
+            println!(
+                "  undefvar={{warn,off}}        warn about references to variables bound nowhere"
+            );
+            println!(
+                "  depfiletarget={{err,warn}}   a depfile naming non-outputs is an error/warning"
+            );
+            return Ok(Some(1));
+        }
+
+        "duprule=err" => args.load_options.duplicate_rule_warn = false,
+        "duprule=warn" => args.load_options.duplicate_rule_warn = true,
+        "dupbuild=err" => args.load_options.duplicate_out_warn = false,
+        "dupbuild=warn" => args.load_options.duplicate_out_warn = true,
+        "unknownrulevar=err" => args.load_options.unknown_rule_var_err = true,
+        "unknownrulevar=warn" => args.load_options.unknown_rule_var_err = false,
+        "undefvar=warn" => args.load_options.undefined_var_warn = true,
+        "undefvar=off" => args.load_options.undefined_var_warn = false,
+        "depfiletarget=err" => args.options.depfile_target_warn = false,
+        "depfiletarget=warn" => args.options.depfile_target_warn = true,
+
+        _ => anyhow::bail!("unknown -w {:?}, use -w list to list", flag),
+    }
+    Ok(None)
+}
+
 fn parse_args() -> anyhow::Result<Result<BuildArgs, i32>> {
     let mut args = BuildArgs::default();
+    args.options.failures_left = Some(1);
     args.fake_ninja_compat = std::path::Path::new(&std::env::args().next().unwrap())
         .file_name()
         .unwrap()
@@ -165,13 +533,28 @@ usage: n2 [options] [targets...]
 
 options:
 -C dir   chdir before running
--f file  input build file [default: build.ninja]
--j N     parallelism [default: use system thread count]
--k N     keep going until at least N failures [default: 1]
+-D k=v   bind top-level variable k to v, overriding the manifest
+-f file  input build file, or - for stdin [default: build.ninja]
+-j N     run N tasks in parallel, 0 for no limit [default: processors + 2]
+-k N     keep going until N tasks fail, 0 for no limit [default: 1]
+-l N     don't start new tasks while the load average is at least N
+-n       dry run: list the tasks that would run, without running them
 -v       print executed command lines
 
 -t tool  tools (`-t list` to list)
 -d tool  debugging tools (use `-d list` to list)
+-w flag  adjust warnings (use `-w list` to list)
+--color when         color parse errors: auto, always or never [default: auto]
+--env-vars           take variables bound nowhere in the manifest from the environment
+--windows-paths      treat backslashes in paths as separators (always on Windows)
+--path-case c        sensitive or insensitive [default: insensitive on Windows]
+--relative-includes  resolve include/subninja paths against the including file
+--strict             reject manifest constructs that ninja would reject
+--lazy-subninjas     only read the subninjas needed for the requested targets
+--content-hash       decide whether inputs changed by their content, not mtime
+--no-lock-wait       fail rather than wait when another n2 is building here
+--no-ninja-log       don't write .ninja_log for tools that read ninja's
+--failure-summary f  write a JSON summary of failed tasks to f
 "
                 );
                 return Ok(Err(0));
@@ -183,20 +566,111 @@ options:
                     .map_err(|err| anyhow!("chdir {:?}: {}", dir, err))?;
             }
 
+            Short('D') => {
+                let arg = parser.value()?.to_string_lossy().into_owned();
+                let Some((name, value)) = arg.split_once('=') else {
+                    anyhow::bail!("-D {:?}: expected var=value", arg);
+                };
+                args.load_options
+                    .var_overrides
+                    .push((name.to_owned(), value.to_owned()));
+            }
             Short('f') => args.build_filename = Some(parser.value()?.to_string_lossy().into()),
             Short('t') => {
-                if let Some(exit) = subtool(&mut args, &*parser.value()?.to_string_lossy())? {
+                if let Some(exit) = subtool(&mut args, &parser.value()?.to_string_lossy())? {
                     return Ok(Err(exit));
                 }
             }
             Short('d') => {
-                if let Some(exit) = debugtool(&mut args, &*parser.value()?.to_string_lossy())? {
+                if let Some(exit) = debugtool(&mut args, &parser.value()?.to_string_lossy())? {
                     return Ok(Err(exit));
                 }
             }
-            Short('j') => args.options.parallelism = parser.value()?.parse()?,
-            Short('k') => args.options.failures_left = Some(parser.value()?.parse()?),
+            Short('w') => {
+                if let Some(exit) = warningflag(&mut args, &parser.value()?.to_string_lossy())? {
+                    return Ok(Err(exit));
+                }
+            }
+            Short('j') => {
+                args.options.parallelism = match parser.value()?.parse()? {
+                    0 => usize::MAX,
+                    n => n,
+                }
+            }
+            Short('l') => {
+                let max_load: f64 = parser.value()?.parse()?;
+                if cfg!(unix) {
+                    // As in ninja, a limit of 0 or less is no limit.
+                    args.options.max_load = Some(max_load).filter(|&l| l > 0.0);
+                } else {
+                    println!("n2: warn: -l is not supported on this platform; ignoring it");
+                }
+            }
+            Short('k') => {
+                // As in ninja, -k 0 means keep going whatever fails.
+                args.options.failures_left = match parser.value()?.parse()? {
+                    0 => None,
+                    n => Some(n),
+                }
+            }
+            Short('n') => {
+                args.options.dry_run = true;
+                args.load_options.read_only_db = true;
+            }
             Short('v') => args.verbose = true,
+            Short('s') if args.commands => args.commands_single = true,
+            Short('x') if args.compdb => args.compdb_expand_rsp = true,
+            Short('g') if args.clean => args.clean_generator = true,
+            Short('r') if args.clean => args.clean_rules = true,
+
+            Long("color") => {
+                args.load_options.color = match parser.value()?.to_string_lossy().as_ref() {
+                    "auto" => ColorMode::Auto,
+                    "always" => ColorMode::Always,
+                    "never" => ColorMode::Never,
+                    when => {
+                        anyhow::bail!("unknown --color {:?}, expected auto, always or never", when)
+                    }
+                }
+            }
+
+            Long("depth") if args.graph => {
+                let depth = parser.value()?.to_string_lossy().into_owned();
+                let depth: usize = depth
+                    .parse()
+                    .map_err(|_| anyhow!("--depth: invalid depth {:?}", depth))?;
+                // As with -t targets, 0 means no limit.
+                args.graph_options.depth = Some(depth).filter(|&d| d > 0);
+            }
+            Long("collapse") if args.graph => args.graph_options.collapse = true,
+            Long("direct") if args.inputs => args.inputs_options.direct = true,
+            Long("sources") if args.inputs => args.inputs_options.sources = true,
+            Long("no-order-only") if args.inputs || args.outputs => {
+                args.inputs_options.skip_order_only = true
+            }
+            Long("env-vars") => args.load_options.env_fallback = true,
+            Long("content-hash") => args.options.content_hash = true,
+            Long("no-lock-wait") => args.no_lock_wait = true,
+            Long("no-ninja-log") => args.no_ninja_log = true,
+            Long("failure-summary") => {
+                args.options.failure_summary = Some(parser.value()?.into());
+            }
+            Long("windows-paths") => args.load_options.windows_paths = true,
+            Long("strict") => args.load_options.strict = true,
+            Long("lazy-subninjas") => args.load_options.lazy_subninjas = Some(Vec::new()),
+            Long("relative-includes") => {
+                args.load_options.include_resolution = load::IncludeResolution::RelativeToIncluder
+            }
+            Long("path-case") => {
+                args.load_options.fold_case = match parser.value()?.to_string_lossy().as_ref() {
+                    "sensitive" => Some(false),
+                    "insensitive" => Some(true),
+                    case => anyhow::bail!(
+                        "unknown --path-case {:?}, expected sensitive or insensitive",
+                        case
+                    ),
+                }
+            }
 
             Long("version") => {
                 if args.fake_ninja_compat {
@@ -217,6 +691,9 @@ options:
     if args.options.parallelism == 0 {
         args.options.parallelism = default_parallelism()?;
     }
+    if let Some(targets) = &mut args.load_options.lazy_subninjas {
+        targets.clone_from(&args.targets);
+    }
 
     Ok(Ok(args))
 }
@@ -227,6 +704,55 @@ fn run_impl() -> anyhow::Result<i32> {
         Err(exit) => return Ok(exit),
     };
 
+    // These only read the manifest, so needn't wait for a running build.
+    if args.commands {
+        print_commands(args)?;
+        return Ok(0);
+    }
+    if args.compdb {
+        print_compdb(args)?;
+        return Ok(0);
+    }
+    if args.list_targets {
+        print_targets(args)?;
+        return Ok(0);
+    }
+    if args.graph {
+        print_graph(args)?;
+        return Ok(0);
+    }
+    if args.inputs || args.outputs {
+        print_inputs(args)?;
+        return Ok(0);
+    }
+    if args.manifest {
+        let build_filename = args.build_filename.as_deref().unwrap_or("build.ninja");
+        let stdout = std::io::BufWriter::new(std::io::stdout().lock());
+        dump::write(stdout, build_filename, &args.load_options)?;
+        return Ok(0);
+    }
+    if args.query {
+        print_query(args)?;
+        return Ok(0);
+    }
+    // The database is only read, and a record a running build is still
+    // appending is ignored.
+    if args.deps {
+        print_deps(args)?;
+        return Ok(0);
+    }
+
+    let _lock = lock::lock(std::path::Path::new("."), !args.no_lock_wait)?;
+    if args.recompact {
+        recompact(args)?;
+        return Ok(0);
+    }
+    if args.clean {
+        clean(args)?;
+        return Ok(0);
+    }
+
+    let dry_run = args.options.dry_run;
     match build(args)? {
         None => {
             // Don't print any summary, the failing task is enough info.
@@ -236,6 +762,15 @@ fn run_impl() -> anyhow::Result<i32> {
             // Special case: don't print numbers when no work done.
             println!("n2: no work to do");
         }
+        Some(n) if dry_run => {
+            // Tasks that restat their outputs or discover new deps might
+            // not really make their dependents run.
+            println!(
+                "n2: dry run: would run {} task{}, assuming each changes its outputs",
+                n,
+                if n == 1 { "" } else { "s" }
+            );
+        }
         Some(n) => {
             println!(
                 "n2: ran {} task{}, now up to date",
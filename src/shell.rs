@@ -0,0 +1,88 @@
+//! Quoting of paths substituted into command lines, as ninja does for `$in`
+//! and `$out` in a rule's `command`.
+
+use std::borrow::Cow;
+
+fn is_posix_safe(c: char) -> bool {
+    matches!(c, 'A'..='Z' | 'a'..='z' | '0'..='9' | '_' | '+' | '-' | '.' | '/')
+}
+
+/// Quote a string for a POSIX shell, wrapping it in single quotes if it
+/// contains any character that might be significant to the shell.  An empty
+/// string is quoted so it remains an argument.
+pub fn quote_posix(s: &str) -> Cow<'_, str> {
+    if !s.is_empty() && s.chars().all(is_posix_safe) {
+        return Cow::Borrowed(s);
+    }
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('\'');
+    for c in s.chars() {
+        if c == '\'' {
+            out.push_str("'\\''");
+        } else {
+            out.push(c);
+        }
+    }
+    out.push('\'');
+    Cow::Owned(out)
+}
+
+/// Quote a string for Windows command lines, following the rules of
+/// CommandLineToArgvW: quoted if it is empty or contains whitespace or a
+/// quote, with the backslashes preceding a quote doubled.
+pub fn quote_windows(s: &str) -> Cow<'_, str> {
+    if !s.is_empty() && !s.contains(|c: char| c.is_whitespace() || c == '"') {
+        return Cow::Borrowed(s);
+    }
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    let mut backslashes = 0;
+    for c in s.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                out.extend(std::iter::repeat('\\').take(backslashes + 1));
+                backslashes = 0;
+            }
+            _ => backslashes = 0,
+        }
+        out.push(c);
+    }
+    out.extend(std::iter::repeat('\\').take(backslashes));
+    out.push('"');
+    Cow::Owned(out)
+}
+
+/// Quote a string for the platform's command lines.
+pub fn quote(s: &str) -> Cow<'_, str> {
+    if cfg!(windows) {
+        quote_windows(s)
+    } else {
+        quote_posix(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn posix() {
+        assert_eq!(quote_posix("foo/bar-1.0_x+y.o"), "foo/bar-1.0_x+y.o");
+        assert_eq!(quote_posix(""), "''");
+        assert_eq!(quote_posix("a b"), "'a b'");
+        assert_eq!(quote_posix("$HOME;rm"), "'$HOME;rm'");
+        assert_eq!(quote_posix("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn windows() {
+        assert_eq!(quote_windows("c:\\foo\\bar.o"), "c:\\foo\\bar.o");
+        assert_eq!(quote_windows(""), "\"\"");
+        assert_eq!(quote_windows("a b"), "\"a b\"");
+        assert_eq!(quote_windows("a\tb"), "\"a\tb\"");
+        assert_eq!(quote_windows("a\\ b\\"), "\"a\\ b\\\\\"");
+        assert_eq!(quote_windows("say \"hi\""), "\"say \\\"hi\\\"\"");
+        assert_eq!(quote_windows("a\\\"b"), "\"a\\\\\\\"b\"");
+    }
+}
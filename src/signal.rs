@@ -1,17 +1,33 @@
-//! Unix signal handling (SIGINT).
+//! Signal handling (SIGINT, SIGTERM and SIGHUP, or Ctrl-C and Ctrl-Break on
+//! Windows).
 //!
-//! We let the first SIGINT reach child processes, which ought to build-fail
-//! and let the parent properly print that progress.  This also lets us still
-//! write out pending debug traces, too.
+//! Tasks run in their own process groups, apart from console ones, so the
+//! terminal's Ctrl-C reaches only n2.  The build loop notices the signal,
+//! passes it on to the running tasks and waits for them to exit, so it can
+//! clean up after them and still write out pending debug traces.  A second
+//! signal kills the running tasks rather than waiting for them.
 
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
 
-static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// The first signal received, or 0 if none.
+static INTERRUPTED: AtomicI32 = AtomicI32::new(0);
+
+/// Set once a second signal is received.
+static REPEATED: AtomicBool = AtomicBool::new(false);
+
+/// Record a signal.  Only atomics are touched, as befits a signal handler.
+fn record(sig: i32) {
+    if INTERRUPTED
+        .compare_exchange(0, sig, Ordering::Relaxed, Ordering::Relaxed)
+        .is_err()
+    {
+        REPEATED.store(true, Ordering::Relaxed);
+    }
+}
 
 #[cfg(unix)]
-extern "C" fn sigint_handler(_sig: libc::c_int) {
-    INTERRUPTED.store(true, std::sync::atomic::Ordering::Relaxed);
-    // SA_RESETHAND should clear the handler.
+extern "C" fn interrupt_handler(sig: libc::c_int) {
+    record(sig);
 }
 
 #[cfg(unix)]
@@ -19,13 +35,52 @@ pub fn register_sigint() {
     // Safety: registering a signal handler is libc unsafe code.
     unsafe {
         let mut sa: libc::sigaction = std::mem::zeroed();
-        sa.sa_sigaction = sigint_handler as libc::sighandler_t;
-        sa.sa_flags = libc::SA_RESETHAND;
+        sa.sa_sigaction = interrupt_handler as *const () as libc::sighandler_t;
+        // SA_RESTART keeps the tasks' threads' reads and waits going.  The
+        // handler stays installed, so that a second signal is seen too.
+        sa.sa_flags = libc::SA_RESTART;
         #[cfg(not(miri))]
-        libc::sigaction(libc::SIGINT, &sa, std::ptr::null_mut());
+        for sig in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            libc::sigaction(sig, &sa, std::ptr::null_mut());
+        }
+    }
+}
+
+#[cfg(windows)]
+unsafe extern "system" fn ctrl_handler(ctrl_type: u32) -> windows_sys::Win32::Foundation::BOOL {
+    use windows_sys::Win32::System::Console::{CTRL_BREAK_EVENT, CTRL_C_EVENT};
+    match ctrl_type {
+        CTRL_C_EVENT | CTRL_BREAK_EVENT => {
+            record(ctrl_type as i32 + 1);
+            1
+        }
+        _ => 0,
+    }
+}
+
+#[cfg(windows)]
+pub fn register_sigint() {
+    // Safety: registering a handler has no preconditions.
+    unsafe {
+        windows_sys::Win32::System::Console::SetConsoleCtrlHandler(Some(ctrl_handler), 1);
     }
 }
 
 pub fn was_interrupted() -> bool {
-    INTERRUPTED.load(std::sync::atomic::Ordering::Relaxed)
+    INTERRUPTED.load(Ordering::Relaxed) != 0
+}
+
+/// Whether a second signal was received, after which running tasks are
+/// killed rather than given time to exit.
+pub fn was_repeated() -> bool {
+    REPEATED.load(Ordering::Relaxed)
+}
+
+/// The signal that interrupted the build, to pass on to running tasks.
+#[cfg(unix)]
+pub fn interrupt_signal() -> libc::c_int {
+    match INTERRUPTED.load(Ordering::Relaxed) {
+        0 => libc::SIGINT,
+        sig => sig,
+    }
 }
@@ -2,60 +2,132 @@
 //! TODO: this may not be needed at all, but the code used this pattern in a
 //! few places so I figured I may as well name it.
 
-use std::{borrow::Borrow, fmt::Debug};
+use rustc_hash::FxHasher;
+use std::{
+    borrow::Borrow,
+    fmt::Debug,
+    hash::{Hash, Hasher},
+};
+
+/// Maps with more entries than this are indexed by hash rather than scanned.
+const LINEAR_MAX: usize = 8;
 
 /// A map-like object implemented as a list of pairs, for cases where the
-/// number of entries in the map is small.
-pub struct SmallMap<K, V>(Vec<(K, V)>);
+/// number of entries in the map is usually small.  Iteration is in insertion
+/// order.  Larger maps get a hash index over the list, so that lookups stay
+/// fast for e.g. rules with many bindings.
+pub struct SmallMap<K, V> {
+    entries: Vec<(K, V)>,
+    /// Open-addressed table of entry index + 1, with 0 marking an empty slot.
+    /// Empty while the map has at most LINEAR_MAX entries.
+    index: Vec<u32>,
+}
 
 impl<K, V> Default for SmallMap<K, V> {
     fn default() -> Self {
-        SmallMap(Vec::default())
+        SmallMap {
+            entries: Vec::default(),
+            index: Vec::default(),
+        }
     }
 }
 
-impl<K: PartialEq, V> SmallMap<K, V> {
+fn hash<Q: Hash + ?Sized>(q: &Q) -> usize {
+    let mut hasher = FxHasher::default();
+    q.hash(&mut hasher);
+    hasher.finish() as usize
+}
+
+impl<K: Hash + Eq, V> SmallMap<K, V> {
+    /// Find the position of `q` in entries, or in the index the empty slot
+    /// where it would go.
+    fn find<Q>(&self, q: &Q) -> Result<usize, usize>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if self.index.is_empty() {
+            return match self.entries.iter().position(|(k, _)| k.borrow() == q) {
+                Some(i) => Ok(i),
+                None => Err(0),
+            };
+        }
+        let mask = self.index.len() - 1;
+        let mut slot = hash(q) & mask;
+        loop {
+            match self.index[slot] {
+                0 => return Err(slot),
+                i if self.entries[i as usize - 1].0.borrow() == q => return Ok(i as usize - 1),
+                _ => slot = (slot + 1) & mask,
+            }
+        }
+    }
+
+    /// Rebuild the index with room for twice the current entries.
+    fn reindex(&mut self) {
+        self.index = vec![0; (self.entries.len() * 2).next_power_of_two()];
+        let mask = self.index.len() - 1;
+        for (i, (k, _)) in self.entries.iter().enumerate() {
+            let mut slot = hash(k) & mask;
+            while self.index[slot] != 0 {
+                slot = (slot + 1) & mask;
+            }
+            self.index[slot] = i as u32 + 1;
+        }
+    }
+
     pub fn insert(&mut self, k: K, v: V) {
-        for (ik, iv) in self.0.iter_mut() {
-            if *ik == k {
-                *iv = v;
-                return;
+        match self.find(&k) {
+            Ok(i) => self.entries[i].1 = v,
+            Err(slot) => {
+                self.entries.push((k, v));
+                if self.entries.len() * 2 > self.index.len() {
+                    if self.entries.len() > LINEAR_MAX {
+                        self.reindex();
+                    }
+                } else {
+                    self.index[slot] = self.entries.len() as u32;
+                }
             }
         }
-        self.0.push((k, v));
     }
 
     pub fn get<Q>(&self, q: &Q) -> Option<&V>
     where
         K: Borrow<Q>,
-        Q: PartialEq + ?Sized,
+        Q: Hash + Eq + ?Sized,
     {
-        for (k, v) in self.0.iter() {
-            if k.borrow() == q {
-                return Some(v);
-            }
-        }
-        None
+        self.find(q).ok().map(|i| &self.entries[i].1)
+    }
+
+    pub fn get_mut<Q>(&mut self, q: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.find(q).ok().map(|i| &mut self.entries[i].1)
     }
+}
 
-    pub fn iter(&self) -> std::slice::Iter<(K, V)> {
-        self.0.iter()
+impl<K, V> SmallMap<K, V> {
+    pub fn iter(&self) -> std::slice::Iter<'_, (K, V)> {
+        self.entries.iter()
     }
 
-    pub fn iter_mut(&mut self) -> std::slice::IterMut<(K, V)> {
-        self.0.iter_mut()
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut V)> {
+        self.entries.iter_mut().map(|(k, v)| (&*k, v))
     }
 
     pub fn into_iter(self) -> std::vec::IntoIter<(K, V)> {
-        self.0.into_iter()
+        self.entries.into_iter()
     }
 
     pub fn values(&self) -> impl Iterator<Item = &V> + '_ {
-        self.0.iter().map(|x| &x.1)
+        self.entries.iter().map(|x| &x.1)
     }
 }
 
-impl<K: PartialEq, V, const N: usize> std::convert::From<[(K, V); N]> for SmallMap<K, V> {
+impl<K: Hash + Eq, V, const N: usize> std::convert::From<[(K, V); N]> for SmallMap<K, V> {
     fn from(value: [(K, V); N]) -> Self {
         let mut result = SmallMap::default();
         for (k, v) in value {
@@ -67,14 +139,44 @@ impl<K: PartialEq, V, const N: usize> std::convert::From<[(K, V); N]> for SmallM
 
 impl<K: Debug, V: Debug> Debug for SmallMap<K, V> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.0.fmt(f)
+        self.entries.fmt(f)
     }
 }
 
 // Only for tests because it is order-sensitive
 #[cfg(test)]
-impl<K: PartialEq, V: PartialEq> PartialEq for SmallMap<K, V> {
-    fn eq(&self, other: &Self) -> bool {
-        return self.0 == other.0;
+impl<K: PartialEq<K2>, V: PartialEq<V2>, K2, V2> PartialEq<SmallMap<K2, V2>> for SmallMap<K, V> {
+    fn eq(&self, other: &SmallMap<K2, V2>) -> bool {
+        self.entries.len() == other.entries.len()
+            && self
+                .entries
+                .iter()
+                .zip(&other.entries)
+                .all(|((k, v), (k2, v2))| k == k2 && v == v2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_get_order() {
+        for n in [3, LINEAR_MAX, LINEAR_MAX + 1, 100] {
+            let mut map = SmallMap::default();
+            for i in 0..n {
+                map.insert(format!("k{}", i), i);
+            }
+            // Replacing keeps the original position.
+            map.insert("k1".to_owned(), 1000);
+            for i in 0..n {
+                let want = if i == 1 { 1000 } else { i };
+                assert_eq!(map.get(format!("k{}", i).as_str()), Some(&want));
+            }
+            assert_eq!(map.get("missing"), None);
+            let keys: Vec<_> = map.iter().map(|(k, _)| k.clone()).collect();
+            let want: Vec<_> = (0..n).map(|i| format!("k{}", i)).collect();
+            assert_eq!(keys, want);
+        }
     }
 }
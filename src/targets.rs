@@ -0,0 +1,204 @@
+//! Lists targets, as `ninja -t targets` does.
+
+use crate::densemap::Index;
+use crate::graph::{BuildId, FileId, Graph};
+use anyhow::bail;
+use std::collections::HashSet;
+use std::fmt::Write;
+
+pub enum Mode {
+    /// The dependency tree from the default targets, to a depth; 0 for no
+    /// limit.
+    Depth(usize),
+    /// The outputs of builds using a rule, or with no rule, the source files.
+    Rule(Option<String>),
+    /// Every output, with its rule.
+    All,
+}
+
+impl Mode {
+    /// Parse the arguments following `-t targets`.
+    pub fn parse(args: &[String]) -> anyhow::Result<Mode> {
+        let args: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+        Ok(match args[..] {
+            [] => Mode::Depth(1),
+            ["depth"] => Mode::Depth(1),
+            ["depth", depth] => match depth.parse() {
+                Ok(depth) => Mode::Depth(depth),
+                Err(_) => bail!("-t targets depth: invalid depth {:?}", depth),
+            },
+            ["rule"] => Mode::Rule(None),
+            ["rule", rule] => Mode::Rule(Some(rule.to_owned())),
+            ["all"] => Mode::All,
+            _ => bail!(
+                "-t targets {:?}: expected depth [N], rule [name] or all",
+                args.join(" ")
+            ),
+        })
+    }
+}
+
+fn builds(graph: &Graph) -> impl Iterator<Item = BuildId> {
+    (0..graph.builds.next_id().index()).map(BuildId::from)
+}
+
+/// A step of the walk in print_tree.
+enum Step {
+    /// Print a file, at a level of the tree.
+    Print(FileId, usize),
+    /// Done printing the inputs of a file.
+    Leave(FileId),
+}
+
+/// Print `roots` and, to `depth` levels, the inputs they're built from.  A
+/// file already being printed further up the tree is marked rather than
+/// printed again, so a cyclic graph still prints.  Walks with an explicit
+/// stack, as with no depth limit the tree can be deep.
+fn print_tree(out: &mut String, graph: &Graph, roots: &[FileId], depth: usize) {
+    let mut stack: Vec<Step> = roots.iter().rev().map(|&id| Step::Print(id, 0)).collect();
+    let mut path: HashSet<FileId> = HashSet::new();
+    while let Some(step) = stack.pop() {
+        let (id, level) = match step {
+            Step::Print(id, level) => (id, level),
+            Step::Leave(id) => {
+                path.remove(&id);
+                continue;
+            }
+        };
+        let file = graph.file(id);
+        let indent = "  ".repeat(level);
+        let Some(bid) = file.input else {
+            writeln!(out, "{}{}", indent, file.name).unwrap();
+            continue;
+        };
+        let build = &graph.builds[bid];
+        if path.contains(&id) {
+            writeln!(out, "{}{}: {} (cycle)", indent, file.name, build.rule).unwrap();
+            continue;
+        }
+        writeln!(out, "{}{}: {}", indent, file.name, build.rule).unwrap();
+        if depth == 0 || level + 1 < depth {
+            path.insert(id);
+            stack.push(Step::Leave(id));
+            stack.extend(
+                build
+                    .ins
+                    .ids
+                    .iter()
+                    .rev()
+                    .map(|&id| Step::Print(id, level + 1)),
+            );
+        }
+    }
+}
+
+/// Format the listing, in manifest order.  The tree starts from the default
+/// targets, or if there are none, the outputs nothing else uses.
+pub fn format(graph: &Graph, default: &[FileId], mode: &Mode) -> String {
+    let mut out = String::new();
+    match mode {
+        Mode::Depth(depth) => {
+            let roots = if default.is_empty() {
+                graph.roots()
+            } else {
+                default.to_vec()
+            };
+            print_tree(&mut out, graph, &roots, *depth);
+        }
+        Mode::Rule(rule) => {
+            let mut seen = HashSet::new();
+            for bid in builds(graph) {
+                let build = &graph.builds[bid];
+                let ids = match rule {
+                    Some(rule) if &build.rule == rule => build.outs(),
+                    Some(_) => continue,
+                    None => &build.ins.ids,
+                };
+                for &id in ids {
+                    let file = graph.file(id);
+                    if (rule.is_some() || file.input.is_none()) && seen.insert(id) {
+                        writeln!(out, "{}", file.name).unwrap();
+                    }
+                }
+            }
+        }
+        Mode::All => {
+            for bid in builds(graph) {
+                let build = &graph.builds[bid];
+                for &id in build.outs() {
+                    writeln!(out, "{}: {}", graph.file(id).name, build.rule).unwrap();
+                }
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn targets(manifest: &str, args: &[&str]) -> anyhow::Result<String> {
+        let graph = crate::load::parse("build.ninja", manifest.as_bytes().to_vec())?;
+        let args: Vec<String> = args.iter().map(|&s| s.to_owned()).collect();
+        Ok(format(&graph, &[], &Mode::parse(&args)?))
+    }
+
+    const MANIFEST: &str = "
+rule cc
+  command = cc $in
+rule link
+  command = ld $in
+build a.o: cc a.c | a.h
+build b.o: cc b.c
+build out: link a.o b.o
+build all: phony out
+";
+
+    #[test]
+    fn depth() -> anyhow::Result<()> {
+        assert_eq!(targets(MANIFEST, &[])?, "all: phony\n");
+        assert_eq!(
+            targets(MANIFEST, &["depth", "0"])?,
+            "all: phony
+  out: link
+    a.o: cc
+      a.c
+      a.h
+    b.o: cc
+      b.c
+"
+        );
+        assert_eq!(
+            targets(MANIFEST, &["depth", "2"])?,
+            "all: phony\n  out: link\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rule_and_all() -> anyhow::Result<()> {
+        assert_eq!(targets(MANIFEST, &["rule", "cc"])?, "a.o\nb.o\n");
+        assert_eq!(targets(MANIFEST, &["rule"])?, "a.c\na.h\nb.c\n");
+        assert_eq!(
+            targets(MANIFEST, &["all"])?,
+            "a.o: cc\nb.o: cc\nout: link\nall: phony\n"
+        );
+        assert!(targets(MANIFEST, &["bogus"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cycle() -> anyhow::Result<()> {
+        let manifest = "
+build a: phony b
+build b: phony a
+build c: phony a
+";
+        assert_eq!(
+            targets(manifest, &["depth", "0"])?,
+            "c: phony\n  a: phony\n    b: phony\n      a: phony (cycle)\n"
+        );
+        Ok(())
+    }
+}
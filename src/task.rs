@@ -12,12 +12,12 @@ use crate::{
     depfile,
     graph::{Build, BuildId, RspFile},
     process,
-    scanner::{self, Scanner},
+    scanner::Scanner,
 };
 use anyhow::{anyhow, bail};
 use std::path::{Path, PathBuf};
 use std::sync::mpsc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 pub struct FinishedTask {
     /// A (faked) "thread id", used to put different finished builds in different
@@ -34,53 +34,69 @@ pub struct TaskResult {
     /// Console output.
     pub output: Vec<u8>,
     pub discovered_deps: Option<Vec<String>>,
+    /// The targets named by the depfile, if any, which should all be outputs
+    /// of the build.
+    pub depfile_targets: Vec<String>,
 }
 
-/// Reads dependencies from a .d file path.
-fn read_depfile(path: &Path) -> anyhow::Result<Vec<String>> {
-    let bytes = match scanner::read_file_with_nul(path) {
+/// Reads the targets and dependencies from a .d file path.
+fn read_depfile(path: &Path) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+    let bytes = match std::fs::read(path) {
         Ok(b) => b,
         // See discussion of missing depfiles in #80.
         // TODO(#99): warn or error in this circumstance?
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Default::default()),
         Err(e) => bail!("read {}: {}", path.display(), e),
     };
 
     let mut scanner = Scanner::new(&bytes);
     let parsed_deps = depfile::parse(&mut scanner)
         .map_err(|err| anyhow!(scanner.format_parse_error(path, err)))?;
-    // TODO verify deps refers to correct output
+    let targets: Vec<String> = parsed_deps.iter().map(|(t, _)| t.to_string()).collect();
     let deps: Vec<String> = parsed_deps
         .values()
         .flat_map(|x| x.iter())
-        .map(|&dep| dep.to_owned())
+        .map(|dep| dep.to_string())
         .collect();
-    Ok(deps)
+    Ok((targets, deps))
 }
 
+/// Writes an rspfile beside its path and renames it into place, so that a
+/// command never sees one half-written by an interrupted build.
 fn write_rspfile(rspfile: &RspFile) -> anyhow::Result<()> {
     if let Some(parent) = rspfile.path.parent() {
         std::fs::create_dir_all(parent)?;
     }
-    std::fs::write(&rspfile.path, &rspfile.content)?;
+    let mut tmp = rspfile.path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, &rspfile.content)?;
+    std::fs::rename(&tmp, &rspfile.path)?;
     Ok(())
 }
 
 /// Parse some subcommand output to extract "Note: including file:" lines as
-/// emitted by MSVC/clang-cl.
-fn extract_showincludes(output: Vec<u8>) -> (Vec<String>, Vec<u8>) {
+/// emitted by MSVC/clang-cl, or lines with another prefix as emitted by a
+/// localized MSVC.  The compiler prints paths in the console code page, which
+/// may not be UTF-8; such a path is an error rather than being mangled.
+fn extract_showincludes(output: Vec<u8>, prefix: &str) -> anyhow::Result<(Vec<String>, Vec<u8>)> {
     let mut filtered_output = Vec::new();
     let mut includes = Vec::new();
     for line in output.split(|&c| c == b'\n') {
-        if let Some(include) = line.strip_prefix(b"Note: including file: ") {
+        if let Some(include) = line.strip_prefix(prefix.as_bytes()) {
             let start = include.iter().position(|&c| c != b' ').unwrap_or(0);
-            let end = if include.ends_with(&[b'\r']) {
+            let end = if include.ends_with(b"\r") {
                 include.len() - 1
             } else {
                 include.len()
             };
             let include = &include[start..end];
-            includes.push(unsafe { String::from_utf8_unchecked(include.to_vec()) });
+            match String::from_utf8(include.to_vec()) {
+                Ok(include) => includes.push(include),
+                Err(_) => anyhow::bail!(
+                    "included file {:?} is not UTF-8",
+                    String::from_utf8_lossy(include)
+                ),
+            }
         } else {
             if !filtered_output.is_empty() {
                 filtered_output.push(b'\n');
@@ -88,25 +104,7 @@ fn extract_showincludes(output: Vec<u8>) -> (Vec<String>, Vec<u8>) {
             filtered_output.extend_from_slice(line);
         }
     }
-    (includes, filtered_output)
-}
-
-/// Find the span of the last line of text in buf, ignoring trailing empty
-/// lines.
-fn find_last_line(buf: &[u8]) -> &[u8] {
-    fn is_nl(c: u8) -> bool {
-        c == b'\r' || c == b'\n'
-    }
-
-    let end = match buf.iter().rposition(|&c| !is_nl(c)) {
-        Some(pos) => pos + 1,
-        None => buf.len(),
-    };
-    let start = match buf[..end].iter().rposition(|&c| is_nl(c)) {
-        Some(pos) => pos + 1,
-        None => 0,
-    };
-    &buf[start..end]
+    Ok((includes, filtered_output))
 }
 
 /// Executes a build task as a subprocess.
@@ -117,39 +115,60 @@ fn find_last_line(buf: &[u8]) -> &[u8] {
 fn run_task(
     cmdline: &str,
     depfile: Option<&Path>,
-    parse_showincludes: bool,
+    showincludes_prefix: Option<&str>,
     rspfile: Option<&RspFile>,
-    mut last_line_cb: impl FnMut(&[u8]),
+    console: bool,
+    mut output_cb: impl FnMut(&[u8]),
 ) -> anyhow::Result<TaskResult> {
     if let Some(rspfile) = rspfile {
         write_rspfile(rspfile)?;
     }
 
     let mut output = Vec::new();
-    let termination = process::run_command(cmdline, |buf| {
+    let termination = process::run_command(cmdline, console, |buf| {
         output.extend_from_slice(buf);
-        last_line_cb(find_last_line(&output));
+        output_cb(buf);
     })?;
 
     let mut discovered_deps = None;
-    if parse_showincludes {
+    let mut depfile_targets = Vec::new();
+    if let Some(prefix) = showincludes_prefix {
         // Remove /showIncludes lines from output, regardless of success/fail.
-        let (includes, filtered) = extract_showincludes(output);
+        let (includes, filtered) = extract_showincludes(output, prefix)?;
         output = filtered;
         discovered_deps = Some(includes);
     }
     if termination == process::Termination::Success {
         if let Some(depfile) = depfile {
-            discovered_deps = Some(read_depfile(depfile)?);
+            let (targets, deps) = read_depfile(depfile)?;
+            depfile_targets = targets;
+            discovered_deps = Some(deps);
         }
     }
     Ok(TaskResult {
         termination,
         output,
         discovered_deps,
+        depfile_targets,
     })
 }
 
+/// The one-minute load average, if the platform provides one.
+#[cfg(unix)]
+pub fn load_average() -> Option<f64> {
+    let mut load: f64 = 0.0;
+    // Safety: getloadavg writes at most the one sample we ask for.
+    if unsafe { libc::getloadavg(&mut load, 1) } != 1 {
+        return None;
+    }
+    Some(load)
+}
+
+#[cfg(not(unix))]
+pub fn load_average() -> Option<f64> {
+    None
+}
+
 /// Tracks faked "thread ids" -- integers assigned to build tasks to track
 /// parallelism in perf trace output.
 #[derive(Default)]
@@ -188,10 +207,11 @@ pub struct Runner {
     pub running: usize,
     tids: ThreadIds,
     parallelism: usize,
+    max_load: Option<f64>,
 }
 
 impl Runner {
-    pub fn new(parallelism: usize) -> Self {
+    pub fn new(parallelism: usize, max_load: Option<f64>) -> Self {
         let (tx, rx) = mpsc::channel();
         Runner {
             tx,
@@ -199,11 +219,22 @@ impl Runner {
             running: 0,
             tids: ThreadIds::default(),
             parallelism,
+            max_load,
         }
     }
 
     pub fn can_start_more(&self) -> bool {
-        self.running < self.parallelism
+        if self.running >= self.parallelism {
+            return false;
+        }
+        // Always run something, so that a busy machine still makes progress.
+        if self.running == 0 {
+            return true;
+        }
+        match self.max_load {
+            Some(max_load) => load_average().map_or(true, |load| load < max_load),
+            None => true,
+        }
     }
 
     pub fn is_running(&self) -> bool {
@@ -214,7 +245,8 @@ impl Runner {
         let cmdline = build.cmdline.clone().unwrap();
         let depfile = build.depfile.clone().map(PathBuf::from);
         let rspfile = build.rspfile.clone();
-        let parse_showincludes = build.parse_showincludes;
+        let showincludes_prefix = build.showincludes_prefix.clone();
+        let console = build.is_console();
 
         let tid = self.tids.claim();
         let tx = self.tx.clone();
@@ -223,16 +255,18 @@ impl Runner {
             let result = run_task(
                 &cmdline,
                 depfile.as_deref(),
-                parse_showincludes,
+                showincludes_prefix.as_deref(),
                 rspfile.as_ref(),
-                |line| {
-                    let _ = tx.send(Message::Output((id, line.to_owned())));
+                console,
+                |buf| {
+                    let _ = tx.send(Message::Output((id, buf.to_owned())));
                 },
             )
             .unwrap_or_else(|err| TaskResult {
-                termination: process::Termination::Failure,
+                termination: process::Termination::Failure("error".to_owned()),
                 output: format!("{}\n", err).into_bytes(),
                 discovered_deps: None,
+                depfile_targets: Vec::new(),
             });
             let finish = Instant::now();
 
@@ -248,16 +282,24 @@ impl Runner {
         self.running += 1;
     }
 
-    /// Wait for a build to complete.  May block for a long time.
-    pub fn wait(&mut self, mut output: impl FnMut(BuildId, Vec<u8>)) -> FinishedTask {
+    /// Wait for a build to complete, or for `timeout` to pass.
+    pub fn wait(
+        &mut self,
+        timeout: Duration,
+        mut output: impl FnMut(BuildId, Vec<u8>),
+    ) -> Option<FinishedTask> {
+        let deadline = Instant::now() + timeout;
         loop {
-            match self.rx.recv().unwrap() {
-                Message::Output((bid, line)) => output(bid, line),
-                Message::Done(task) => {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.rx.recv_timeout(timeout) {
+                Ok(Message::Output((bid, buf))) => output(bid, buf),
+                Ok(Message::Done(task)) => {
                     self.tids.release(task.tid);
                     self.running -= 1;
-                    return task;
+                    return Some(task);
                 }
+                Err(mpsc::RecvTimeoutError::Timeout) => return None,
+                Err(mpsc::RecvTimeoutError::Disconnected) => unreachable!(),
             }
         }
     }
@@ -277,7 +319,9 @@ Note: including file: b\r
 more text
 "
             .to_vec(),
-        );
+            "Note: including file:",
+        )
+        .unwrap();
         assert_eq!(includes, &["a", "b"]);
         assert_eq!(
             output,
@@ -289,23 +333,52 @@ more text
     }
 
     #[test]
-    fn find_last() {
-        assert_eq!(find_last_line(b""), b"");
-        assert_eq!(find_last_line(b"\n"), b"");
+    fn show_includes_prefix() {
+        let (includes, output) = extract_showincludes(
+            b"Remarque : inclusion du fichier :  C:\\inc\\a.h\r
+Note: including file: b.h\r
+a.c(1): error\r"
+                .to_vec(),
+            "Remarque : inclusion du fichier :",
+        )
+        .unwrap();
+        assert_eq!(includes, &["C:\\inc\\a.h"]);
+        assert_eq!(output, b"Note: including file: b.h\r\na.c(1): error\r");
+    }
 
-        assert_eq!(find_last_line(b"hello"), b"hello");
-        assert_eq!(find_last_line(b"hello\n"), b"hello");
+    #[test]
+    fn show_includes_not_utf8() {
+        let err = extract_showincludes(
+            b"Note: including file: caf\xE9.h\r\n".to_vec(),
+            "Note: including file:",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "included file \"caf\u{FFFD}.h\" is not UTF-8"
+        );
+    }
 
-        assert_eq!(find_last_line(b"hello\nt"), b"t");
-        assert_eq!(find_last_line(b"hello\nt\n"), b"t");
+    #[test]
+    fn load_limit() {
+        // With nothing running, a task may start whatever the load.
+        let mut runner = Runner::new(2, Some(f64::MIN_POSITIVE));
+        assert!(runner.can_start_more());
+        runner.running = 1;
+        if let Some(load) = load_average() {
+            assert_eq!(runner.can_start_more(), load < f64::MIN_POSITIVE);
+        }
+        runner.running = 2;
+        assert!(!runner.can_start_more());
 
-        assert_eq!(find_last_line(b"hello\n\n"), b"hello");
-        assert_eq!(find_last_line(b"hello\nt\n\n"), b"t");
+        runner.max_load = None;
+        runner.running = 1;
+        assert!(runner.can_start_more());
     }
 
     #[test]
     fn missing_depfile_allowed() {
-        let deps = read_depfile(Path::new("/missing/dep/file")).unwrap();
-        assert_eq!(deps.len(), 0);
+        let (targets, deps) = read_depfile(Path::new("/missing/dep/file")).unwrap();
+        assert_eq!((targets.len(), deps.len()), (0, 0));
     }
 }
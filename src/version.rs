@@ -0,0 +1,95 @@
+//! Support for `ninja_required_version`, which manifests use to declare the
+//! oldest Ninja release that understands them.
+
+/// A Ninja release, as (major, minor).  Like Ninja, we ignore the patch
+/// component when comparing versions.
+pub type Version = (u32, u32);
+
+/// Manifest features n2 implements, along with the Ninja release that
+/// introduced them.  Ordered by version.
+pub const NINJA_FEATURES: &[(Version, &str)] = &[
+    ((1, 1), "pools"),
+    ((1, 3), "deps"),
+    ((1, 5), "console pool"),
+    ((1, 7), "implicit outputs"),
+    ((1, 10), "dyndep"),
+    ((1, 11), "validations"),
+];
+
+/// The newest Ninja release whose manifest features n2 implements.
+pub fn supported_version() -> Version {
+    NINJA_FEATURES.last().unwrap().0
+}
+
+/// Parse a version string like "1.8.2".  Trailing components and prerelease
+/// suffixes like "1.8.2.git" or "1.10.0-rc1" are ignored.
+pub fn parse_version(text: &str) -> Option<Version> {
+    let mut parts = text.split('.');
+    let mut component = |required: bool| -> Option<u32> {
+        let part = match parts.next() {
+            Some(part) => part,
+            None if required => return None,
+            None => return Some(0),
+        };
+        let digits = part.len() - part.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        if digits == 0 {
+            return if required { None } else { Some(0) };
+        }
+        part[..digits].parse().ok()
+    };
+    let major = component(true)?;
+    let minor = component(false)?;
+    Some((major, minor))
+}
+
+/// Check a `ninja_required_version` value against the features n2 implements.
+pub fn check_required_version(text: &str) -> Result<(), String> {
+    let required = match parse_version(text) {
+        Some(v) => v,
+        None => return Err(format!("invalid ninja_required_version {:?}", text)),
+    };
+    let supported = supported_version();
+    if required > supported {
+        let features = NINJA_FEATURES
+            .iter()
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>()
+            .join(", ");
+        return Err(format!(
+            "manifest requires ninja {}.{} features: n2 implements up to ninja {}.{} ({})",
+            required.0, required.1, supported.0, supported.1, features
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_version("1"), Some((1, 0)));
+        assert_eq!(parse_version("1.8.2"), Some((1, 8)));
+        assert_eq!(parse_version("1.8.2.git"), Some((1, 8)));
+        assert_eq!(parse_version("1.10.0-rc1"), Some((1, 10)));
+        assert_eq!(parse_version("1.git"), Some((1, 0)));
+        assert_eq!(parse_version(""), None);
+        assert_eq!(parse_version("git"), None);
+        assert_eq!(parse_version("99999999999.1"), None);
+    }
+
+    #[test]
+    fn check() {
+        assert!(check_required_version("1.8.2").is_ok());
+        assert!(check_required_version("1.11").is_ok());
+        assert!(check_required_version("1.12").unwrap_err().starts_with(
+            "manifest requires ninja 1.12 features: n2 implements up to ninja 1.11 ("
+        ));
+        assert!(check_required_version("2.0").is_err());
+        assert_eq!(
+            check_required_version("x.y").unwrap_err(),
+            "invalid ninja_required_version \"x.y\""
+        );
+    }
+}
@@ -1,11 +1,12 @@
 //! Build runner, choosing and executing tasks as determined by out of date inputs.
 
 use crate::{
-    canon::{canonicalize_path, to_owned_canon_path},
     db,
-    densemap::DenseMap,
+    densemap::{DenseMap, Index},
+    dyndep,
+    failures::{self, Failure},
     graph::*,
-    hash, process,
+    hash, ninja_log, process,
     progress::{self, Progress},
     signal,
     smallmap::SmallMap,
@@ -13,6 +14,15 @@ use crate::{
 };
 use std::collections::HashSet;
 use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+/// How often a build waiting on tasks checks whether it was interrupted.
+const INTERRUPT_POLL: Duration = Duration::from_millis(100);
+
+/// How long interrupted tasks get to exit before they're killed, unless n2
+/// gets a second signal first.
+const INTERRUPT_GRACE: Duration = Duration::from_secs(2);
 
 /// Build steps go through this sequence of states.
 /// See "Build states" in the design notes.
@@ -97,6 +107,58 @@ impl PoolState {
     }
 }
 
+/// A build being visited by BuildStates::want_file, reached through one of
+/// its outputs.
+struct Visit {
+    file: FileId,
+    build: BuildId,
+    /// The next of the build's ordering inputs to visit.
+    next_in: usize,
+    /// Whether all the inputs visited so far are done.
+    ready: bool,
+}
+
+impl Visit {
+    fn new(file: FileId, build: BuildId) -> Self {
+        Visit {
+            file,
+            build,
+            next_in: 0,
+            ready: true,
+        }
+    }
+}
+
+/// Describe the cycle found when `file`, generated by a build on `stack`, is
+/// an input of the build on top of it.
+fn cycle_error(graph: &Graph, stack: &[Visit], file: FileId) -> anyhow::Error {
+    let bid = graph.file(file).input.unwrap();
+    let start = stack.iter().position(|v| v.build == bid).unwrap();
+    let cycle = &stack[start..];
+    if cycle.len() == 1 {
+        return anyhow::anyhow!(
+            "{}: dependency cycle: {:?} is an input of the build generating it",
+            graph.builds[bid].location,
+            graph.file(file).name
+        );
+    }
+    let mut err = "dependency cycle: ".to_string();
+    for v in cycle {
+        err.push_str(&format!("{} -> ", graph.file(v.file).name));
+    }
+    err.push_str(&graph.file(file).name);
+    for (i, v) in cycle.iter().enumerate() {
+        let next = cycle.get(i + 1).map_or(file, |next| next.file);
+        err.push_str(&format!(
+            "\n  {}: {} depends on {}",
+            graph.builds[v.build].location,
+            graph.file(v.file).name,
+            graph.file(next).name
+        ));
+    }
+    anyhow::anyhow!(err)
+}
+
 /// BuildStates tracks progress of each Build step through the build.
 /// See "Tracking build state" in the design notes.
 struct BuildStates {
@@ -122,8 +184,6 @@ impl BuildStates {
         let mut pools = SmallMap::default();
         // The implied default pool.
         pools.insert(String::from(""), PoolState::new(0));
-        // TODO: the console pool is just a depth-1 pool for now.
-        pools.insert(String::from("console"), PoolState::new(1));
         for (name, depth) in depths.into_iter() {
             pools.insert(name, PoolState::new(depth));
         }
@@ -202,83 +262,95 @@ impl BuildStates {
         self.total_pending > 0
     }
 
-    /// Visits a BuildId that is an input to the desired output.
-    /// Will recursively visit its own inputs.
-    /// Returns the state of the build after visiting it.
-    fn want_build(
-        &mut self,
-        graph: &Graph,
-        stack: &mut Vec<FileId>,
-        id: BuildId,
-    ) -> anyhow::Result<BuildState> {
-        let state = self.get(id);
-        if state != BuildState::Unknown {
-            return Ok(state); // Already visited.
-        }
-
-        let build = &graph.builds[id];
-        let mut state = BuildState::Want;
-
-        // Any Build whose inputs are already in place is ready.
-        let mut ready = true;
-        for &id in build.ordering_ins() {
-            if !self.want_file(graph, stack, id)? {
-                ready = false;
-            }
-        }
-        if ready {
-            state = BuildState::Ready;
-        }
-
-        self.set(id, build, state);
-        // Warning: validations somehow allow cycles and rely on the build state
-        // being set here to avoid infinite loops.
-
-        for &id in build.validation_ins() {
-            // This build doesn't technically depend on the validation inputs, so
-            // allocate a new stack. Validation inputs could in theory depend on this build's
-            // outputs.
-            let mut stack = Vec::new();
-            self.want_file(graph, &mut stack, id)?;
-        }
-
-        Ok(state)
+    /// Builds that were wanted but never ran, e.g. because one of their
+    /// inputs failed to build.
+    fn unbuilt(&self) -> impl Iterator<Item = BuildId> + '_ {
+        (0..self.states.next_id().index())
+            .map(BuildId::from)
+            .filter(|&id| {
+                matches!(
+                    self.states[id],
+                    BuildState::Want | BuildState::Ready | BuildState::Queued
+                )
+            })
     }
 
-    /// Visits a FileId that is an input to the desired output.
-    /// Will recursively visit its own inputs.
+    /// Visits a FileId that is an input to the desired output, and the builds
+    /// generating its inputs in turn.  The traversal keeps its own stack
+    /// rather than recursing, so deep graphs don't overflow the thread's.
     /// Returns true if the file is ready to be used in a dependent build
     /// (i.e. its inputs are already done).
-    pub fn want_file(
+    pub fn want_file(&mut self, graph: &Graph, id: FileId) -> anyhow::Result<bool> {
+        let mut validations = Vec::new();
+        let ready = self.visit(graph, id, &mut validations)?;
+        // This build doesn't technically depend on the validation inputs, so
+        // they are visited separately.  Validation inputs could in theory
+        // depend on this build's outputs; they rely on the build state
+        // already being set to avoid revisiting it.
+        while let Some(id) = validations.pop() {
+            self.visit(graph, id, &mut validations)?;
+        }
+        Ok(ready)
+    }
+
+    /// Visit one file and its not yet visited inputs, collecting validation
+    /// inputs of the visited builds to visit afterwards.
+    fn visit(
         &mut self,
         graph: &Graph,
-        stack: &mut Vec<FileId>,
         id: FileId,
+        validations: &mut Vec<FileId>,
     ) -> anyhow::Result<bool> {
-        // Check for a dependency cycle.
-        if let Some(cycle) = stack.iter().position(|&sid| sid == id) {
-            let mut err = "dependency cycle: ".to_string();
-            for &id in stack[cycle..].iter() {
-                err.push_str(&format!("{} -> ", graph.file(id).name));
-            }
-            err.push_str(&graph.file(id).name);
-            anyhow::bail!(err);
+        let Some(bid) = graph.file(id).input else {
+            return Ok(true);
+        };
+        // state can already be Done in the case where we executed a prior
+        // build (to generate build.ninja), brought the dependent
+        // up to date, and are reusing that state.
+        // In all other cases we expect it to not be Done.
+        let state = self.get(bid);
+        if state != BuildState::Unknown {
+            return Ok(state == BuildState::Done); // Already visited.
         }
 
-        let mut ready = true;
-        if let Some(bid) = graph.file(id).input {
-            stack.push(id);
-            let state = self.want_build(graph, stack, bid)?;
-            // state can already be Done in the case where we executed a prior
-            // build (to generate build.ninja), brought the dependent
-            // up to date, and are reusing that state.
-            // In all other cases we expect it to not be Done.
-            if state != BuildState::Done {
-                ready = false;
+        let mut stack = vec![Visit::new(id, bid)];
+        let mut on_stack = HashSet::from([bid]);
+        while let Some(top) = stack.last_mut() {
+            let build = &graph.builds[top.build];
+            if let Some(&input) = build.ordering_ins().get(top.next_in) {
+                top.next_in += 1;
+                let Some(input_bid) = graph.file(input).input else {
+                    continue;
+                };
+                if on_stack.contains(&input_bid) {
+                    return Err(cycle_error(graph, &stack, input));
+                }
+                match self.get(input_bid) {
+                    BuildState::Unknown => {
+                        on_stack.insert(input_bid);
+                        stack.push(Visit::new(input, input_bid));
+                    }
+                    BuildState::Done => {}
+                    _ => top.ready = false,
+                }
+                continue;
+            }
+
+            // Any Build whose inputs are already in place is ready.
+            let state = if top.ready {
+                BuildState::Ready
+            } else {
+                BuildState::Want
+            };
+            let visit = stack.pop().unwrap();
+            on_stack.remove(&visit.build);
+            self.set(visit.build, build, state);
+            validations.extend(build.validation_ins().iter().rev());
+            if let Some(parent) = stack.last_mut() {
+                parent.ready = false;
             }
-            stack.pop();
         }
-        Ok(ready)
+        Ok(false)
     }
 
     pub fn pop_ready(&mut self) -> Option<BuildId> {
@@ -330,12 +402,30 @@ impl BuildStates {
 
 #[derive(Clone, Default)]
 pub struct Options {
+    /// Stop starting builds after this many have failed; None for no limit.
     pub failures_left: Option<usize>,
     pub parallelism: usize,
+    /// If set, new tasks aren't started while the load average is at least
+    /// this, unless nothing is running.
+    pub max_load: Option<f64>,
     /// When true, verbosely explain why targets are considered dirty.
     pub explain: bool,
     /// When true, just mark targets up to date without running anything.
     pub adopt: bool,
+    /// When true, a depfile naming targets that aren't outputs of its build is
+    /// a warning rather than an error, and its dependencies are still used.
+    pub depfile_target_warn: bool,
+    /// When true, inputs are compared by content digests recorded in the
+    /// database rather than by mtime.
+    pub content_hash: bool,
+    /// When true, rspfiles are kept after their commands succeed.
+    pub keep_rsp: bool,
+    /// When true, list the tasks that need to run without running them,
+    /// assuming each one changes all its outputs.
+    pub dry_run: bool,
+    /// If set, a JSON summary of the build's failures is written here once
+    /// it finishes, for tools like CI to read.
+    pub failure_summary: Option<PathBuf>,
 }
 
 pub struct Work<'a> {
@@ -346,6 +436,12 @@ pub struct Work<'a> {
     file_state: FileState,
     last_hashes: Hashes,
     build_states: BuildStates,
+    /// Dyndep files that have been loaded into the graph.
+    dyndeps_loaded: HashSet<FileId>,
+    /// In a dry run, outputs of the builds that would have run.
+    dry_run_outs: HashSet<FileId>,
+    /// Where finished tasks are logged for tools reading ninja's log.
+    ninja_log: Option<ninja_log::Writer>,
     pub tasks_run: usize,
 }
 
@@ -357,6 +453,7 @@ impl<'a> Work<'a> {
         options: &Options,
         progress: &'a dyn Progress,
         pools: SmallMap<String, usize>,
+        ninja_log: Option<ninja_log::Writer>,
     ) -> Self {
         let file_state = FileState::new(&graph);
         let build_count = graph.builds.next_id();
@@ -368,17 +465,21 @@ impl<'a> Work<'a> {
             file_state,
             last_hashes,
             build_states: BuildStates::new(build_count, pools),
+            dyndeps_loaded: HashSet::new(),
+            dry_run_outs: HashSet::new(),
+            ninja_log,
             tasks_run: 0,
         }
     }
 
     pub fn lookup(&self, name: &str) -> Option<FileId> {
-        self.graph.files.lookup(&to_owned_canon_path(name))
+        let mut name = name.to_owned();
+        self.graph.files.canonicalize(&mut name);
+        self.graph.files.lookup(&name)
     }
 
     pub fn want_file(&mut self, id: FileId) -> anyhow::Result<()> {
-        let mut stack = Vec::new();
-        self.build_states.want_file(&self.graph, &mut stack, id)?;
+        self.build_states.want_file(&self.graph, id)?;
         Ok(())
     }
 
@@ -465,11 +566,35 @@ impl<'a> Work<'a> {
     fn record_finished(&mut self, id: BuildId, result: task::TaskResult) -> anyhow::Result<()> {
         let build = &self.graph.builds[id];
 
+        // Compilers write the output paths as they were given them, so a
+        // depfile naming anything else is likely stale or for another build.
+        for target in &result.depfile_targets {
+            let mut name = target.clone();
+            self.graph.files.canonicalize(&mut name);
+            let is_out = self
+                .graph
+                .files
+                .lookup(&name)
+                .is_some_and(|id| build.outs().contains(&id));
+            if !is_out {
+                let msg = format!(
+                    "{}: depfile {:?} mentions {:?} as an output, but no such output was declared",
+                    build.location,
+                    build.depfile.as_deref().unwrap_or_default(),
+                    target
+                );
+                if !self.options.depfile_target_warn {
+                    anyhow::bail!(msg);
+                }
+                self.progress.log(&format!("n2: warn: {}", msg));
+            }
+        }
+
         // Update the deps discovered from the task.
         let mut deps = Vec::new();
         if let Some(names) = result.discovered_deps {
             for mut name in names {
-                canonicalize_path(&mut name);
+                self.graph.files.canonicalize(&mut name);
                 let fileid = self.graph.files.id_from_canonical(name);
                 // Filter duplicates from the file list.
                 if deps.contains(&fileid) {
@@ -481,6 +606,12 @@ impl<'a> Work<'a> {
                 if build.dirtying_ins().contains(&fileid) {
                     continue;
                 }
+                // A depfile may name the build's own outputs, e.g. a generated
+                // header that is an implicit output, which would otherwise make
+                // the build depend on itself.
+                if build.outs().contains(&fileid) {
+                    continue;
+                }
                 deps.push(fileid);
             }
         }
@@ -494,7 +625,15 @@ impl<'a> Work<'a> {
         // everything.
         let mut input_was_missing = false;
         for &id in build.dirtying_ins().iter().chain(build.discovered_ins()) {
-            if self.file_state.stat(id, self.graph.file(id).path())? == MTime::Missing {
+            let file = self.graph.file(id);
+            let mtime = match file.input {
+                // Phony outputs got their mtime when the phony build was checked.
+                Some(bid) if self.graph.builds[bid].cmdline.is_none() => {
+                    self.file_state.get(id).unwrap_or(MTime::Missing)
+                }
+                _ => self.file_state.stat(id, file.path())?,
+            };
+            if mtime == MTime::Missing {
                 input_was_missing = true;
             }
         }
@@ -507,9 +646,20 @@ impl<'a> Work<'a> {
             return Ok(());
         }
 
-        let hash = hash::hash_build(&self.graph.files, &mut self.file_state, build);
+        let hash = hash::hash_build(&self.graph.files, &self.file_state, build);
         self.db.write_build(&self.graph, id, hash)?;
 
+        if self.options.content_hash {
+            self.ensure_digests(id)?;
+            let content = hash::hash_build_content(
+                &self.graph.files,
+                &self.file_state,
+                &self.last_hashes.digests,
+                &self.graph.builds[id],
+            );
+            self.db.write_content_hash(&self.graph, id, content)?;
+        }
+
         Ok(())
     }
 
@@ -568,7 +718,7 @@ impl<'a> Work<'a> {
     ) -> anyhow::Result<Option<FileId>> {
         // Ensure we have state for all input files.
         if let Some(missing) =
-            Self::ensure_input_files(&graph, file_state, build, build.dirtying_ins())?
+            Self::ensure_input_files(graph, file_state, build, build.dirtying_ins())?
         {
             let file = graph.file(missing);
             if file.input.is_none() {
@@ -577,7 +727,7 @@ impl<'a> Work<'a> {
             return Ok(Some(missing));
         }
         if let Some(missing) =
-            Self::ensure_input_files(&graph, file_state, build, build.discovered_ins())?
+            Self::ensure_input_files(graph, file_state, build, build.discovered_ins())?
         {
             return Ok(Some(missing));
         }
@@ -587,7 +737,7 @@ impl<'a> Work<'a> {
         // and if we're checking if it's dirty we are visiting it the first
         // time, so we stat unconditionally.
         // This is looking at if the outputs are already present.
-        if let Some(missing) = Self::stat_all_outputs(&graph, &mut *file_state, build)? {
+        if let Some(missing) = Self::stat_all_outputs(graph, &mut *file_state, build)? {
             return Ok(Some(missing));
         }
 
@@ -612,10 +762,30 @@ impl<'a> Work<'a> {
 
         // Maintain the invariant that we have stat info for all outputs, but
         // we generally don't expect them to have been created.
-        // TODO: what should happen if a rule uses a phony output as its own input?
-        // The Ninja manual suggests you can use phony rules to aggregate outputs
-        // together, so we might need to create some sort of fake mtime here?
         Self::stat_all_outputs(graph, file_state, build)?;
+
+        // The Ninja manual suggests you can use phony rules to aggregate outputs
+        // together.  As in Ninja, a missing phony output takes the newest mtime
+        // of its inputs so that it doesn't dirty its dependents.  A phony build
+        // with no inputs leaves its output missing, which makes dependents
+        // always rebuild rather than failing on the missing file.
+        let mut newest = None;
+        for &id in build.dirtying_ins() {
+            let mtime = match file_state.get(id) {
+                Some(mtime) => mtime,
+                None => file_state.stat(id, graph.file(id).path())?,
+            };
+            if let MTime::Stamp(mtime) = mtime {
+                newest = newest.max(Some(mtime));
+            }
+        }
+        if let Some(newest) = newest {
+            for &id in build.outs() {
+                if file_state.get(id) == Some(MTime::Missing) {
+                    file_state.set(id, MTime::Stamp(newest));
+                }
+            }
+        }
         Ok(())
     }
 
@@ -647,10 +817,31 @@ impl<'a> Work<'a> {
         // If we get here, all the relevant files are present and stat()ed,
         // so compare the hash against the last hash.
 
+        if self.options.content_hash {
+            return self.check_build_dirty_content(id);
+        }
+
         // TODO: skip this whole function if no previous hash is present.
         // More complex than just moving this block up, because we currently
         // assume that we've always checked inputs after we've run a build.
         let prev_hash = match self.last_hashes.get(id) {
+            None if self.last_hashes.is_imported(id) => {
+                // Deps came from ninja's log; adopt the build if ninja would
+                // have considered it up to date.
+                if let Some(newer) = self.newer_input(build) {
+                    if self.options.explain {
+                        self.progress.log(&format!(
+                            "explain: {}: input {} newer than output",
+                            build.location,
+                            self.graph.file(newer).name
+                        ));
+                    }
+                    return Ok(true);
+                }
+                let hash = hash::hash_build(&self.graph.files, &self.file_state, build);
+                self.db.write_build(&self.graph, id, hash)?;
+                return Ok(false);
+            }
             None => {
                 if self.options.explain {
                     self.progress.log(&format!(
@@ -680,6 +871,267 @@ impl<'a> Work<'a> {
         Ok(false)
     }
 
+    /// Like the hash comparison of check_build_dirty, but comparing file
+    /// contents.  A build last run without content hashing is trusted if its
+    /// mtime hash is current.  Both hashes are kept current, so that switching
+    /// between modes doesn't rerun builds.
+    fn check_build_dirty_content(&mut self, id: BuildId) -> anyhow::Result<bool> {
+        self.ensure_digests(id)?;
+        let build = &self.graph.builds[id];
+        let hash = hash::hash_build(&self.graph.files, &self.file_state, build);
+        let content = hash::hash_build_content(
+            &self.graph.files,
+            &self.file_state,
+            &self.last_hashes.digests,
+            build,
+        );
+        let clean = match self.last_hashes.get_content(id) {
+            Some(prev) => prev == content,
+            None => self.last_hashes.get(id) == Some(hash),
+        };
+        if !clean {
+            if self.options.explain {
+                self.progress
+                    .log(&format!("explain: {}: content changed", build.location));
+            }
+            return Ok(true);
+        }
+        if self.last_hashes.get(id) != Some(hash) {
+            self.db.write_build(&self.graph, id, hash)?;
+        }
+        if self.last_hashes.get_content(id) != Some(content) {
+            self.db.write_content_hash(&self.graph, id, content)?;
+        }
+        Ok(false)
+    }
+
+    /// Bring the content digests of a build's files up to date, reading those
+    /// files that look changed since they were last digested.
+    /// Generated files are only read once the builds generating them are done.
+    fn ensure_digests(&mut self, id: BuildId) -> anyhow::Result<()> {
+        let build = &self.graph.builds[id];
+        let files = build
+            .dirtying_ins()
+            .iter()
+            .chain(build.discovered_ins())
+            .chain(build.outs());
+        for &fid in files {
+            let file = self.graph.file(fid);
+            let phony = file
+                .input
+                .is_some_and(|bid| self.graph.builds[bid].cmdline.is_none());
+            if phony {
+                // Phony outputs have no content of their own; their mtime,
+                // as derived from their inputs, stands in for it.
+                if let Some(MTime::Stamp(mtime)) = self.file_state.get(fid) {
+                    let digest = hash::Digest {
+                        mtime,
+                        len: 0,
+                        ctime: 0,
+                        digest: hash::digest_mtime(mtime),
+                    };
+                    self.last_hashes.digests.set(fid, digest);
+                }
+                continue;
+            }
+            let path = file.path();
+            let meta = std::fs::metadata(path)
+                .map_err(|err| anyhow::anyhow!("stat {:?}: {}", path, err))?;
+            if let Some(prev) = self.last_hashes.digests.get(fid) {
+                if prev.matches(&meta)? {
+                    continue;
+                }
+            }
+            let digest = hash::Digest {
+                mtime: meta.modified()?,
+                len: meta.len(),
+                ctime: hash::ctime(&meta),
+                digest: hash::digest_file(path, &meta)
+                    .map_err(|err| anyhow::anyhow!("read {:?}: {}", path, err))?,
+            };
+            self.db.write_digest(&self.graph, fid, &digest)?;
+            self.last_hashes.digests.set(fid, digest);
+        }
+        Ok(())
+    }
+
+    /// Find an input of a build that is newer than its oldest output, as ninja
+    /// checks dirtiness.  Expects all its files to have been stat()ed.
+    fn newer_input(&self, build: &Build) -> Option<FileId> {
+        let oldest_out = build
+            .outs()
+            .iter()
+            .filter_map(|&id| match self.file_state.get(id) {
+                Some(MTime::Stamp(mtime)) => Some(mtime),
+                _ => None,
+            })
+            .min()?;
+        build
+            .dirtying_ins()
+            .iter()
+            .chain(build.discovered_ins())
+            .copied()
+            .find(|&id| match self.file_state.get(id) {
+                Some(MTime::Stamp(mtime)) => mtime > oldest_out,
+                _ => true,
+            })
+    }
+
+    /// If a ready build has a dyndep file that hasn't been loaded yet, load it
+    /// now that it has been generated and attach the extra inputs/outputs it
+    /// lists.  Returns false if the build is no longer ready as a result.
+    fn load_dyndep(&mut self, id: BuildId) -> anyhow::Result<bool> {
+        let file = match self.graph.builds[id].dyndep {
+            // In a dry run, a dyndep file that would be regenerated may not
+            // exist yet, so it is left unread.
+            Some(file)
+                if !self.dyndeps_loaded.contains(&file) && !self.dry_run_outs.contains(&file) =>
+            {
+                file
+            }
+            _ => return Ok(true),
+        };
+        self.dyndeps_loaded.insert(file);
+        let updated = dyndep::load(&mut self.graph, file)?;
+
+        // Builds we've already visited need to visit their new inputs too.
+        for bid in updated {
+            match self.build_states.get(bid) {
+                BuildState::Want | BuildState::Ready => {}
+                _ => continue,
+            }
+            let ins = self.graph.builds[bid].ordering_ins().to_vec();
+            for fid in ins {
+                self.want_file(fid)?;
+            }
+            let build = &self.graph.builds[bid];
+            if self.build_states.get(bid) == BuildState::Ready && !self.recheck_ready(build) {
+                self.build_states.set(bid, build, BuildState::Want);
+            }
+        }
+        Ok(self.build_states.get(id) == BuildState::Ready)
+    }
+
+    /// Append a finished task to the ninja log, if any.  Its outputs were
+    /// stat()ed when recording it.
+    fn log_ninja(&mut self, id: BuildId, span: (Instant, Instant)) -> anyhow::Result<()> {
+        let Some(log) = &mut self.ninja_log else {
+            return Ok(());
+        };
+        let build = &self.graph.builds[id];
+        let mtime = build
+            .outs()
+            .iter()
+            .filter_map(|&out| match self.file_state.get(out) {
+                Some(MTime::Stamp(mtime)) => Some(mtime),
+                _ => None,
+            })
+            .max()
+            .map_or(0, |mtime| {
+                mtime
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_nanos() as u64
+            });
+        let outs = build
+            .outs()
+            .iter()
+            .map(|&out| self.graph.file(out).name.as_str());
+        log.record(
+            span,
+            mtime,
+            outs,
+            ninja_log::build_command_hash(build).unwrap(),
+        )
+        .map_err(|err| anyhow::anyhow!("write .ninja_log: {}", err))
+    }
+
+    /// Remove a succeeded build's rspfile, as ninja does, unless asked to
+    /// keep it.  A failed build's is kept for debugging.
+    fn remove_rspfile(&self, id: BuildId) {
+        let Some(rspfile) = &self.graph.builds[id].rspfile else {
+            return;
+        };
+        if self.options.keep_rsp {
+            return;
+        }
+        match std::fs::remove_file(&rspfile.path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                self.progress.log(&format!(
+                    "n2: warn: remove {}: {}",
+                    rspfile.path.display(),
+                    err
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    /// Delete the outputs an interrupted build changed, which may be half
+    /// written, so that the next build doesn't take them as up to date.
+    fn remove_interrupted_outputs(&self, id: BuildId) {
+        let build = &self.graph.builds[id];
+        if build.precious {
+            return;
+        }
+        for &out in build.outs() {
+            let file = self.graph.file(out);
+            let changed = match stat(file.path()) {
+                Ok(MTime::Stamp(mtime)) => self.file_state.get(out) != Some(MTime::Stamp(mtime)),
+                _ => false,
+            };
+            if !changed {
+                continue;
+            }
+            if let Err(err) = std::fs::remove_file(file.path()) {
+                self.progress
+                    .log(&format!("n2: warn: remove {}: {}", file.name, err));
+            }
+        }
+    }
+
+    /// In a dry run, whether the build has an input that would have been
+    /// rebuilt, which makes the build dirty whatever is on disk.
+    fn dry_run_rebuilt_input(&self, id: BuildId) -> bool {
+        let build = &self.graph.builds[id];
+        let Some(&input) = build
+            .dirtying_ins()
+            .iter()
+            .chain(build.discovered_ins())
+            .find(|id| self.dry_run_outs.contains(id))
+        else {
+            return false;
+        };
+        if self.options.explain && build.cmdline.is_some() {
+            self.progress.log(&format!(
+                "explain: {}: input {} would be rebuilt",
+                build.location,
+                self.graph.file(input).name
+            ));
+        }
+        true
+    }
+
+    /// "Run" a dirty build in a dry run: list it, and consider its outputs
+    /// rebuilt so that its dependents run too.  Phony builds just pass on
+    /// that their inputs were rebuilt.
+    fn dry_run(&mut self, id: BuildId) {
+        let build = &self.graph.builds[id];
+        self.dry_run_outs.extend(build.outs());
+        if build.cmdline.is_none() {
+            return;
+        }
+        self.progress.task_started(id, build);
+        let result = task::TaskResult {
+            termination: process::Termination::Success,
+            output: vec![],
+            discovered_deps: None,
+            depfile_targets: Vec::new(),
+        };
+        self.progress.task_finished(id, build, &result);
+        self.tasks_run += 1;
+    }
+
     /// Create the parent directories of a given list of fileids.
     /// Used to create directories used for outputs.
     /// TODO: do this within the thread executing the subtask?
@@ -687,7 +1139,7 @@ impl<'a> Work<'a> {
         let mut dirs: Vec<&std::path::Path> = Vec::new();
         for &out in ids {
             if let Some(parent) = self.graph.file(out).path().parent() {
-                if dirs.iter().any(|&p| p == parent) {
+                if dirs.contains(&parent) {
                     continue;
                 }
                 std::fs::create_dir_all(parent)?;
@@ -700,16 +1152,38 @@ impl<'a> Work<'a> {
     /// Runs the build.
     /// Returns true on successful builds.
     pub fn run(&mut self) -> anyhow::Result<bool> {
-        #[cfg(unix)]
         signal::register_sigint();
-        let mut tasks_failed = 0;
-        let mut runner = task::Runner::new(self.options.parallelism);
+        let mut failed = Vec::new();
+        // Set once too many builds have failed, after which we only wait for
+        // the running ones to finish.
+        let mut stopping = false;
+        // When the build was interrupted, by a signal to n2 or to a task, and
+        // whether the tasks still running after the grace period were killed.
+        let mut interrupted: Option<Instant> = None;
+        let mut task_interrupted = false;
+        let mut killed = false;
+        let mut runner = task::Runner::new(self.options.parallelism, self.options.max_load);
         while self.build_states.unfinished() {
+            match interrupted {
+                None if task_interrupted || signal::was_interrupted() => {
+                    interrupted = Some(Instant::now());
+                    stopping = true;
+                    process::interrupt_running();
+                }
+                Some(at)
+                    if !killed && (at.elapsed() >= INTERRUPT_GRACE || signal::was_repeated()) =>
+                {
+                    process::kill_running();
+                    killed = true;
+                }
+                _ => {}
+            }
             self.progress.update(&self.build_states.counts);
 
             // Approach:
             // - First make sure we're running as many queued tasks as the runner
-            //   allows.
+            //   allows.  A task is only queued once its pool has room, and
+            //   then the runner applies -j and then -l.
             // - Next make sure we've finished or enqueued any tasks that are
             //   ready.
             // - If either one of those made progress, loop, to ensure the other
@@ -718,7 +1192,7 @@ impl<'a> Work<'a> {
             //   loop.
 
             let mut made_progress = false;
-            while runner.can_start_more() {
+            while !stopping && runner.can_start_more() {
                 let id = match self.build_states.pop_queued() {
                     Some(id) => id,
                     None => break,
@@ -726,30 +1200,47 @@ impl<'a> Work<'a> {
                 let build = &self.graph.builds[id];
                 self.build_states.set(id, build, BuildState::Running);
                 self.create_parent_dirs(build.outs())?;
-                runner.start(id, build);
+                // Tell progress first, so it can get off the terminal before
+                // a console build takes it over.
                 self.progress.task_started(id, build);
+                runner.start(id, build);
                 made_progress = true;
             }
 
-            while let Some(id) = self.build_states.pop_ready() {
-                if !self.check_build_dirty(id)? {
-                    // Not dirty; go directly to the Done state.
-                    self.ready_dependents(id);
-                } else if self.options.adopt {
-                    // Act as if the target already finished.
-                    self.record_finished(
-                        id,
-                        task::TaskResult {
-                            termination: process::Termination::Success,
-                            output: vec![],
-                            discovered_deps: None,
-                        },
-                    )?;
-                    self.ready_dependents(id);
-                } else {
-                    self.build_states.enqueue(id, &self.graph.builds[id])?;
+            // Once stopping, leave ready builds be rather than queue them.
+            if !stopping {
+                while let Some(id) = self.build_states.pop_ready() {
+                    made_progress = true;
+                    if self.build_states.get(id) != BuildState::Ready {
+                        // Sent back to Want by a dyndep file loaded after it
+                        // became ready.
+                        continue;
+                    }
+                    if !self.load_dyndep(id)? {
+                        continue;
+                    }
+                    if !self.dry_run_rebuilt_input(id) && !self.check_build_dirty(id)? {
+                        // Not dirty; go directly to the Done state.
+                        self.ready_dependents(id);
+                    } else if self.options.dry_run {
+                        self.dry_run(id);
+                        self.ready_dependents(id);
+                    } else if self.options.adopt {
+                        // Act as if the target already finished.
+                        self.record_finished(
+                            id,
+                            task::TaskResult {
+                                termination: process::Termination::Success,
+                                output: vec![],
+                                discovered_deps: None,
+                                depfile_targets: Vec::new(),
+                            },
+                        )?;
+                        self.ready_dependents(id);
+                    } else {
+                        self.build_states.enqueue(id, &self.graph.builds[id])?;
+                    }
                 }
-                made_progress = true;
             }
 
             if made_progress {
@@ -757,16 +1248,20 @@ impl<'a> Work<'a> {
             }
 
             if !runner.is_running() {
-                if tasks_failed > 0 {
-                    // No more progress can be made, hopefully due to tasks that failed.
+                if !failed.is_empty() || interrupted.is_some() {
+                    // No more progress can be made, hopefully due to tasks that
+                    // failed or were interrupted.
                     break;
                 }
                 panic!("BUG: no work to do and runner not running");
             }
 
-            let task = runner.wait(|id, line| {
-                self.progress.task_output(id, line);
-            });
+            let Some(task) = runner.wait(INTERRUPT_POLL, |id, output| {
+                self.progress
+                    .task_output(id, &self.graph.builds[id], &output);
+            }) else {
+                continue;
+            };
             let build = &self.graph.builds[task.buildid];
             if trace::enabled() {
                 let desc = progress::build_message(build);
@@ -776,34 +1271,57 @@ impl<'a> Work<'a> {
             self.progress
                 .task_finished(task.buildid, build, &task.result);
             match task.result.termination {
-                process::Termination::Failure => {
-                    if let Some(failures_left) = &mut self.options.failures_left {
-                        *failures_left -= 1;
-                        if *failures_left == 0 {
-                            return Ok(false);
-                        }
-                    }
-                    tasks_failed += 1;
+                process::Termination::Interrupted => {
+                    task_interrupted = true;
+                    self.remove_interrupted_outputs(task.buildid);
+                }
+                process::Termination::Failure(_) if interrupted.is_some() => {
+                    // Likely failed because it was interrupted.
+                    self.remove_interrupted_outputs(task.buildid);
+                }
+                process::Termination::Failure(status) => {
+                    failed.push(Failure {
+                        id: task.buildid,
+                        status,
+                        output: task.result.output,
+                    });
                     self.build_states
                         .set(task.buildid, build, BuildState::Failed);
-                }
-                process::Termination::Interrupted => {
-                    // If the task was interrupted bail immediately.
-                    return Ok(false);
+                    if let Some(limit) = self.options.failures_left {
+                        stopping |= failed.len() >= limit;
+                    }
                 }
                 process::Termination::Success => {
                     self.tasks_run += 1;
                     self.record_finished(task.buildid, task.result)?;
+                    self.log_ninja(task.buildid, task.span)?;
+                    self.remove_rspfile(task.buildid);
                     self.ready_dependents(task.buildid);
                 }
             };
         }
 
+        let unbuilt = if failed.is_empty() || interrupted.is_some() {
+            Vec::new()
+        } else {
+            self.build_states
+                .unbuilt()
+                .filter(|&id| self.graph.builds[id].cmdline.is_some())
+                .collect()
+        };
+        if !failed.is_empty() {
+            for line in failures::summarize(&self.graph, &failed, &unbuilt) {
+                self.progress.log(&line);
+            }
+        }
+        if let Some(path) = &self.options.failure_summary {
+            failures::write_json(path, &self.graph, &failed, &unbuilt)?;
+        }
         // If the user ctl-c's, it likely caused a subtask to fail.
         // But at least for the LLVM test suite it can catch sigint and print
         // "interrupted by user" and exit with success, and in that case we
         // don't want n2 to print a "succeeded" message afterwards.
-        let success = tasks_failed == 0 && !signal::was_interrupted();
+        let success = failed.is_empty() && interrupted.is_none() && !signal::was_interrupted();
         Ok(success)
     }
 }
@@ -822,11 +1340,56 @@ build c: phony a
         let mut graph = crate::load::parse("build.ninja", file.as_bytes().to_vec())?;
         let a_id = graph.files.id_from_canonical("a".to_owned());
         let mut states = BuildStates::new(graph.builds.next_id(), SmallMap::default());
-        let mut stack = Vec::new();
-        match states.want_file(&graph, &mut stack, a_id) {
+        match states.want_file(&graph, a_id) {
+            Ok(_) => panic!("expected build cycle error"),
+            Err(err) => assert_eq!(
+                err.to_string(),
+                "dependency cycle: a -> b -> c -> a
+  build.ninja:2: a depends on b
+  build.ninja:3: b depends on c
+  build.ninja:4: c depends on a"
+            ),
+        }
+        Ok(())
+    }
+
+    /// The loader rejects builds using their own outputs, but a dyndep file
+    /// can still add one as an input.
+    #[test]
+    fn build_self_cycle() -> Result<(), anyhow::Error> {
+        let file = "
+build a: phony b
+build b c: phony
+";
+        let mut graph = crate::load::parse("build.ninja", file.as_bytes().to_vec())?;
+        let a_id = graph.files.id_from_canonical("a".to_owned());
+        let c_id = graph.files.id_from_canonical("c".to_owned());
+        let bid = graph.file(c_id).input.unwrap();
+        graph.builds[bid].ins.ids.push(c_id);
+        graph.builds[bid].ins.implicit += 1;
+        let mut states = BuildStates::new(graph.builds.next_id(), SmallMap::default());
+        match states.want_file(&graph, a_id) {
             Ok(_) => panic!("expected build cycle error"),
-            Err(err) => assert_eq!(err.to_string(), "dependency cycle: a -> b -> c -> a"),
+            Err(err) => assert_eq!(
+                err.to_string(),
+                "build.ninja:3: dependency cycle: \"c\" is an input of the build generating it"
+            ),
         }
         Ok(())
     }
+
+    /// Long dependency chains don't overflow the stack.
+    #[test]
+    fn deep_chain() -> Result<(), anyhow::Error> {
+        let mut file = String::new();
+        for i in 0..100_000 {
+            file.push_str(&format!("build f{}: phony f{}\n", i, i + 1));
+        }
+        let mut graph = crate::load::parse("build.ninja", file.into_bytes())?;
+        let root = graph.files.id_from_canonical("f0".to_owned());
+        let mut states = BuildStates::new(graph.builds.next_id(), SmallMap::default());
+        assert!(!states.want_file(&graph, root)?);
+        assert_eq!(states.ready.len(), 1);
+        Ok(())
+    }
 }
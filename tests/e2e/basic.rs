@@ -38,6 +38,49 @@ fn create_subdir() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// With --windows-paths, a file referenced with either separator is one
+/// file, while commands are left alone: paths in them keep the separators
+/// the file was first named with.
+#[cfg(unix)]
+#[test]
+fn windows_paths() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule gen
+  command = printf '%s\\n' '/DFOO a\\b' > $out
+rule cat
+  command = cat $in > $out
+rule list
+  command = printf '%s\\n' $in > $out
+build gen/out.h: gen
+build out: cat gen\\out.h
+build out2: cat .\\gen\\..\\gen/out.h
+build list: list src\\a.c
+",
+    )?;
+    space.write("src\\a.c", "")?;
+
+    // Without the flag, gen\out.h is a different file.
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "input gen\\out.h missing");
+
+    let out = space.run_expect(&mut n2_command(vec![
+        "--windows-paths",
+        "out",
+        "out2",
+        "list",
+    ]))?;
+    assert_output_contains(&out, "ran 4 tasks");
+    assert_eq!(space.read("gen/out.h")?, b"/DFOO a\\b\n");
+    assert_eq!(space.read("out")?, b"/DFOO a\\b\n");
+    assert_eq!(space.read("out2")?, b"/DFOO a\\b\n");
+    assert_eq!(space.read("list")?, b"src\\a.c\n");
+
+    Ok(())
+}
+
 #[cfg(unix)]
 #[test]
 fn generate_rsp_file() -> anyhow::Result<()> {
@@ -76,9 +119,12 @@ build baz: touch in
     assert_eq!(foo_rsp, b"random stuff");
 
     // The 'make/me' directory was created when writing an rsp file.
-    // It should still be there.
+    // It should still be there, though the rsp files were deleted after
+    // their commands succeeded.
     let meta = space.metadata("make/me").unwrap();
     assert!(meta.is_dir());
+    assert!(space.read("make/me/foo.rsp").is_err());
+    assert!(space.read("main.rsp").is_err());
 
     // Run again: everything should be up to date.
     let out = space.run_expect(&mut n2_command(vec!["main"]))?;
@@ -87,6 +133,177 @@ build baz: touch in
     Ok(())
 }
 
+/// Output of tasks running in parallel is printed a task at a time, each
+/// after its description.
+#[cfg(unix)]
+#[test]
+fn parallel_output_grouped() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule a
+  command = echo a1 && sleep 0.2 && echo a2 && touch $out
+  description = build $out
+rule b
+  command = sleep 0.1 && echo b1 && sleep 0.2 && echo b2 && touch $out
+  description = build $out
+build a: a
+build b: b
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["-j2", "a", "b"]))?;
+    assert_output_contains(&out, "build a\na1\na2\n");
+    assert_output_contains(&out, "build b\nb1\nb2\n");
+    Ok(())
+}
+
+/// Output of a task running alone is printed while it runs.
+#[cfg(unix)]
+#[test]
+fn lone_task_output_streams() -> anyhow::Result<()> {
+    use std::io::BufRead;
+    let space = TestSpace::new()?;
+    // The task waits, up to about five seconds, for the test to see its
+    // first line.
+    space.write(
+        "build.ninja",
+        "
+rule wait
+  command = echo started && for i in $$(seq 100); do test -e go && break; sleep 0.05; done && echo done && test -e go && touch $out
+  description = wait $out
+build out: wait
+",
+    )?;
+    let mut child = n2_command(vec!["out"])
+        .current_dir(space.path("."))
+        .stdout(std::process::Stdio::piped())
+        .spawn()?;
+    let mut stdout = std::io::BufReader::new(child.stdout.take().unwrap());
+    let mut seen = String::new();
+    while !seen.ends_with("started\n") {
+        if stdout.read_line(&mut seen)? == 0 {
+            break;
+        }
+    }
+    space.write("go", "")?;
+    std::io::Read::read_to_string(&mut stdout, &mut seen)?;
+    assert!(child.wait()?.success(), "{}", seen);
+    assert_eq!(
+        seen,
+        "wait out\nstarted\ndone\nn2: ran 1 task, now up to date\n"
+    );
+    Ok(())
+}
+
+/// Interrupting n2 passes the signal on to running tasks and deletes the
+/// outputs they had started writing, unless their rule marks them precious.
+#[cfg(unix)]
+#[test]
+fn interrupt_removes_outputs() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule slow
+  command = echo partial > $out && sleep 10 && echo done >> $out
+rule slow_precious
+  command = echo partial > $out && sleep 10 && echo done >> $out
+  precious = 1
+build out: slow
+build kept: slow_precious
+build all: phony out kept
+",
+    )?;
+    for sig in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+        let _ = std::fs::remove_file(space.path("out"));
+        let _ = std::fs::remove_file(space.path("kept"));
+        let start = std::time::Instant::now();
+        let child = n2_command(vec!["-j2", "all"])
+            .current_dir(space.path("."))
+            .stdout(std::process::Stdio::piped())
+            .spawn()?;
+        while !(space.path("out").exists() && space.path("kept").exists()) {
+            assert!(start.elapsed().as_secs() < 5, "tasks didn't start");
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        unsafe { libc::kill(child.id() as libc::pid_t, sig) };
+        let out = child.wait_with_output()?;
+        assert!(!out.status.success());
+        assert!(start.elapsed().as_secs() < 5, "tasks weren't interrupted");
+        assert!(space.read("out").is_err());
+        assert_eq!(space.read("kept")?, b"partial\n");
+    }
+    Ok(())
+}
+
+/// A second signal kills tasks that ignore the first, without waiting out
+/// the grace period.
+#[cfg(unix)]
+#[test]
+fn second_interrupt_kills_tasks() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule stubborn
+  command = trap '' INT && touch $out && sleep 10
+build out: stubborn
+",
+    )?;
+    let start = std::time::Instant::now();
+    let child = n2_command(vec!["out"])
+        .current_dir(space.path("."))
+        .stdout(std::process::Stdio::piped())
+        .spawn()?;
+    while !space.path("out").exists() {
+        assert!(start.elapsed().as_secs() < 5, "task didn't start");
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+    let interrupted = std::time::Instant::now();
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+    let out = child.wait_with_output()?;
+    // n2 exits by itself, having cleaned up after the task.
+    assert_eq!(out.status.code(), Some(1));
+    assert!(
+        interrupted.elapsed() < std::time::Duration::from_millis(1500),
+        "task wasn't killed before the grace period ended"
+    );
+    Ok(())
+}
+
+/// A failed command's rspfile is kept for debugging, as is every rspfile
+/// with -d keeprsp.  Neither makes the build dirty.
+#[cfg(unix)]
+#[test]
+fn keep_rsp_file() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cat
+  command = cat $out.rsp > $out && test -e ok
+  rspfile = $out.rsp
+  rspfile_content = $in
+build out: cat in
+",
+    )?;
+    space.write("in", "")?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert!(!out.status.success());
+    assert_eq!(space.read("out.rsp")?, b"in");
+
+    space.write("ok", "")?;
+    space.run_expect(&mut n2_command(vec!["-d", "keeprsp", "out"]))?;
+    assert_eq!(space.read("out.rsp")?, b"in");
+    space.write("out.rsp", "changed")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
 /// Run a task that prints something, and verify it shows up.
 #[cfg(unix)]
 #[test]
@@ -156,6 +373,127 @@ fn repeated_out() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Conflicting outputs are reported with the locations of both builds, even
+/// across included files.
+#[test]
+fn duplicate_out_location() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch in", "include inc.ninja", ""].join("\n"),
+    )?;
+    space.write("inc.ninja", "\nbuild other out: touch in\n")?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(
+        &out,
+        "inc.ninja:2: \"out\" is already an output at build.ninja:6",
+    );
+
+    Ok(())
+}
+
+/// Duplicate phony outputs are errors too, unless downgraded with
+/// -w dupbuild=warn, where the first build generating the output wins.
+#[cfg(unix)]
+#[test]
+fn duplicate_out_warn() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build a: touch",
+            "build b: touch",
+            "build all: phony a",
+            "build all: phony b",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run(&mut n2_command(vec!["all"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(
+        &out,
+        "build.ninja:9: \"all\" is already an output at build.ninja:8",
+    );
+
+    let out = space.run_expect(&mut n2_command(vec!["-w", "dupbuild=warn", "all"]))?;
+    assert_output_contains(
+        &out,
+        "n2: warn: build.ninja:9: \"all\" is already an output at build.ninja:8, ignoring it here",
+    );
+    space.read("a")?;
+    assert!(space.read("b").is_err());
+    Ok(())
+}
+
+/// A build listing one of its outputs as an input is an error, except for
+/// phony builds, where the self-reference is dropped with a warning.
+#[cfg(unix)]
+#[test]
+fn own_output_as_input() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build gen.h gen.c: touch || gen.h", ""].join("\n"),
+    )?;
+    let out = space.run(&mut n2_command(vec!["gen.c"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(
+        &out,
+        "build.ninja:6: \"gen.h\" is both an input and an output of this build",
+    );
+
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build a: touch", "build all: phony all a", ""].join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["all"]))?;
+    assert_output_contains(
+        &out,
+        "n2: warn: build.ninja:7: phony \"all\" lists itself as an input, ignoring it",
+    );
+    space.read("a")?;
+    Ok(())
+}
+
+/// Order-only inputs are built first, but changes to them don't make the
+/// build dirty.
+#[test]
+fn order_only_not_dirtying() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build gen.h: touch gen.in",
+            "build out: touch in || gen.h src.h",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("gen.in", "")?;
+    space.write("in", "")?;
+    space.write("src.h", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+
+    space.write("src.h", "x")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+
+    // A regenerated order-only input reruns only its own build.
+    space.write("gen.in", "x")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    assert_output_not_contains(&out, "touch out");
+
+    space.write("in", "x")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "touch out");
+    Ok(())
+}
+
 /// Regression test for https://github.com/evmar/n2/issues/55
 /// UTF-8 filename.
 #[cfg(unix)]
@@ -305,6 +643,109 @@ fn builddir() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// builddir may be set in an included file, but not in a subninja.
+#[test]
+fn builddir_scope() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write("inc.ninja", "builddir = inc\n")?;
+    space.write("sub.ninja", "builddir = sub\n")?;
+    space.write(
+        "build.ninja",
+        &[
+            "builddir = top",
+            "include inc.ninja",
+            "subninja sub.ninja",
+            TOUCH_RULE,
+            "build out: touch",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    space.read("inc/.n2_db")?;
+    assert!(space.read("top/.n2_db").is_err());
+    assert!(space.read("sub/.n2_db").is_err());
+    Ok(())
+}
+
+/// Setting builddir after a build warns about the now-unused database.
+#[test]
+fn builddir_changed() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch", ""].join("\n"),
+    )?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    space.read(".n2_db")?;
+
+    space.write(
+        "build.ninja",
+        &["builddir = b", TOUCH_RULE, "build out: touch", ""].join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(
+        &out,
+        "n2: warn: builddir changed; ignoring .n2_db and starting a new database at b/.n2_db",
+    );
+    Ok(())
+}
+
+/// Moving builddir from one directory to another also warns.
+#[test]
+fn builddir_moved() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &["builddir = a", TOUCH_RULE, "build out: touch", ""].join("\n"),
+    )?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    space.read("a/.n2_db")?;
+
+    space.write(
+        "build.ninja",
+        &["builddir = b", TOUCH_RULE, "build out: touch", ""].join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(
+        &out,
+        "n2: warn: builddir changed; ignoring a/.n2_db and starting a new database at b/.n2_db",
+    );
+
+    // Only the first build after the move warns.
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_not_contains(&out, "builddir changed");
+    // Beyond the lock, nothing of n2's own is left in the source root.
+    let mut names = std::fs::read_dir(space.path(""))?
+        .map(|entry| Ok(entry?.file_name().into_string().unwrap()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    names.sort();
+    assert_eq!(names, [".n2_lock", "a", "b", "build.ninja", "out"]);
+    Ok(())
+}
+
+/// Renaming a nested builddir in place warns too.
+#[test]
+fn builddir_renamed_nested() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &["builddir = out/debug", TOUCH_RULE, "build x: touch", ""].join("\n"),
+    )?;
+    space.run_expect(&mut n2_command(vec!["x"]))?;
+
+    space.write(
+        "build.ninja",
+        &["builddir = out/release", TOUCH_RULE, "build x: touch", ""].join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["x"]))?;
+    assert_output_contains(
+        &out,
+        "n2: warn: builddir changed; ignoring out/debug/.n2_db and starting a new database at out/release/.n2_db",
+    );
+    Ok(())
+}
+
 /// Verify the error message when a command doesn't exist.
 #[test]
 fn missing_command() -> anyhow::Result<()> {
@@ -330,3 +771,1020 @@ fn missing_command() -> anyhow::Result<()> {
     }
     Ok(())
 }
+
+/// ninja_required_version rejects manifests needing newer Ninja features, and
+/// only the first one seen, even across includes, is checked.
+#[test]
+fn required_version() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            "ninja_required_version = 1.8.2",
+            "include inc.ninja",
+            TOUCH_RULE,
+            "build out: touch",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("inc.ninja", "ninja_required_version = 99.0\n")?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+
+    space.write("build.ninja", "include inc.ninja\n")?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "manifest requires ninja 99.0 features");
+    Ok(())
+}
+
+/// A phony output that doesn't exist on disk doesn't dirty its dependents.
+#[test]
+fn phony_not_dirtying() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build out1: touch in",
+            "build all: phony out1",
+            "build out2: touch all",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["out2"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    let out = space.run_expect(&mut n2_command(vec!["out2"]))?;
+    assert_output_contains(&out, "no work");
+
+    space.write("in", "x")?;
+    let out = space.run_expect(&mut n2_command(vec!["out2"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    Ok(())
+}
+
+/// A phony build with no inputs makes a missing file acceptable as an input.
+#[test]
+fn phony_no_inputs() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build missing: phony",
+            "build out: touch missing",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+/// phony is built in and can't be redefined.
+#[test]
+fn phony_redefined() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule phony
+  command = touch $out
+build out: phony
+",
+    )?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "duplicate rule 'phony'");
+    Ok(())
+}
+
+/// The console pool is available without being declared.
+#[test]
+fn console_pool() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch", "  pool = console", ""].join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+/// A console build writes straight to n2's output as it runs, while output
+/// of builds finishing meanwhile is held until it is done.
+#[cfg(unix)]
+#[test]
+fn console_pool_output() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule interactive
+  command = echo console begin && sleep 0.5 && echo console end && touch $out
+  description = interactive $out
+  pool = console
+rule quick
+  command = sleep 0.1 && echo quick output && touch $out
+  description = quick $out
+build a: interactive
+build b: quick
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["-j2", "a", "b"]))?;
+    let stdout = std::str::from_utf8(&out.stdout)?;
+    assert_eq!(stdout.matches("console begin").count(), 1);
+    let begin = stdout.find("console begin").unwrap();
+    let end = stdout.find("console end").unwrap();
+    let quick = stdout.find("quick output").unwrap();
+    assert!(begin < end && end < quick, "{}", stdout);
+    Ok(())
+}
+
+/// Builds in a pool of depth 1 run one at a time whatever -j is, whether the
+/// pool comes from the build or its rule.
+#[cfg(unix)]
+#[test]
+fn pool_depth() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+pool serial
+  depth = 1
+rule slow
+  command = echo start >> log && sleep 0.2 && echo end >> log && touch $out
+rule slow_serial
+  command = echo start >> log && sleep 0.2 && echo end >> log && touch $out
+  pool = serial
+build a: slow
+  pool = serial
+build b: slow_serial
+build c: slow_serial
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["-j8", "a", "b", "c"]))?;
+    assert_output_contains(&out, "ran 3 tasks");
+    assert_eq!(
+        std::str::from_utf8(&space.read("log")?)?,
+        "start\nend\n".repeat(3)
+    );
+    Ok(())
+}
+
+/// -j 0 means no limit, and -l never stops the build from making progress,
+/// even with a limit no machine is under.
+#[cfg(unix)]
+#[test]
+fn parallelism_limits() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build a: touch",
+            "build b: touch",
+            "build c: touch",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["-j0", "a"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    let out = space.run_expect(&mut n2_command(vec!["-j4", "-l", "0.000001", "b", "c"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    let out = space.run(&mut n2_command(vec!["-l", "lots"]))?;
+    assert_output_contains(&out, "invalid float literal");
+    Ok(())
+}
+
+/// -k keeps building what doesn't depend on failed builds, and the summary
+/// lists both the failures and the builds they held back.  The failing
+/// builds share a depth-1 pool, which a failure must not leave occupied.
+#[cfg(unix)]
+#[test]
+fn keep_going() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "pool serial",
+            "  depth = 1",
+            "rule fail",
+            "  command = exit 1",
+            "  description = fail $out",
+            "  pool = serial",
+            "build bad1: fail",
+            "build bad2: fail",
+            "build dep: touch bad1",
+            "build good: touch",
+            "build all: phony dep good bad2",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run(&mut n2_command(vec!["-k", "0", "-j4", "all"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(
+        &out,
+        "n2: 2 tasks failed:
+  fail bad1 (exit status 1)
+    outputs: bad1
+  fail bad2 (exit status 1)
+    outputs: bad2
+n2: 1 task not built due to earlier failure:
+  touch dep
+",
+    );
+    assert!(space.read("good").is_ok());
+    assert!(space.read("dep").is_err());
+
+    // With the default of -k 1, the first failure stops the build.
+    let out = space.run(&mut n2_command(vec!["-j1", "bad1", "bad2"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "n2: 1 task failed:");
+    assert_output_contains(&out, "n2: 1 task not built due to earlier failure:");
+    Ok(())
+}
+
+/// A failed build's recap repeats the start of its output, and the summary
+/// file holds all of it; a successful build writes an empty summary.
+#[cfg(unix)]
+#[test]
+fn failure_summary() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "rule fail",
+            "  command = for i in 1 2 3 4 5 6 7; do echo \"line $$i\"; done; exit 3",
+            "build bad: fail",
+            "build dep: touch bad",
+            "build good: touch",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run(&mut n2_command(vec![
+        "--failure-summary",
+        "summary.json",
+        "dep",
+    ]))?;
+    assert!(!out.status.success());
+    assert_output_contains(
+        &out,
+        "n2: 1 task failed:
+  for i in 1 2 3 4 5 6 7; do echo \"line $i\"; done; exit 3 (exit status 3)
+    outputs: bad
+    | line 1
+    | line 2
+    | line 3
+    | line 4
+    | line 5
+    | ...2 more lines
+n2: 1 task not built due to earlier failure:
+  touch dep
+",
+    );
+    let summary = space.read("summary.json")?;
+    assert_eq!(
+        std::str::from_utf8(&summary)?,
+        r#"{
+  "failed": [
+    {"description": "for i in 1 2 3 4 5 6 7; do echo \"line $i\"; done; exit 3", "status": "exit status 3", "outputs": ["bad"], "output": "line 1\nline 2\nline 3\nline 4\nline 5\nline 6\nline 7\n"}
+  ],
+  "not_built": [
+    {"description": "touch dep", "outputs": ["dep"]}
+  ]
+}
+"#
+    );
+
+    let out = space.run_expect(&mut n2_command(vec![
+        "--failure-summary",
+        "summary.json",
+        "good",
+    ]))?;
+    assert_output_not_contains(&out, "failed");
+    let summary = space.read("summary.json")?;
+    assert_eq!(
+        std::str::from_utf8(&summary)?,
+        "{\n  \"failed\": [],\n  \"not_built\": []\n}\n"
+    );
+    Ok(())
+}
+
+/// -n lists what would run, in order and including the dependents of
+/// builds that would run, without running anything or touching the database.
+#[cfg(unix)]
+#[test]
+fn dry_run() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build mid: touch in",
+            "build out: touch mid",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+
+    let out = space.run_expect(&mut n2_command(vec!["-n", "out"]))?;
+    assert_output_contains(
+        &out,
+        "touch mid\ntouch out\nn2: dry run: would run 2 tasks, assuming each changes its outputs\n",
+    );
+    assert!(space.read("mid").is_err());
+    assert!(space.read(".n2_db").is_err());
+
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    let db = space.read(".n2_db")?;
+    space.sub_mtime("in", std::time::Duration::from_secs(1))?;
+
+    // out is up to date with mid on disk, but mid would be rebuilt.
+    let out = space.run_expect(&mut n2_command(vec!["-n", "-v", "-d", "explain", "out"]))?;
+    assert_output_contains(&out, "touch mid\n");
+    assert_output_contains(&out, "input mid would be rebuilt\ntouch out\n");
+    assert_output_contains(&out, "would run 2 tasks");
+    assert_eq!(space.read(".n2_db")?, db);
+
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    let out = space.run_expect(&mut n2_command(vec!["-n", "out"]))?;
+    assert_output_contains(&out, "n2: no work to do");
+    Ok(())
+}
+
+/// Referencing an undeclared pool points at the build that used it.
+#[test]
+fn unknown_pool() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch", "  pool = nope", ""].join("\n"),
+    )?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "build.ninja:6: unknown pool \"nope\"");
+    Ok(())
+}
+
+/// A misspelled rule gets a suggestion of the rule it probably meant.
+#[test]
+fn unknown_rule_suggestion() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: tuoch", ""].join("\n"),
+    )?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(
+        &out,
+        "build.ninja:6: unknown rule \"tuoch\"; did you mean \"touch\"?",
+    );
+
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out2: archive", ""].join("\n"),
+    )?;
+    let out = space.run(&mut n2_command(vec!["out2"]))?;
+    assert_output_contains(&out, "build.ninja:6: unknown rule \"archive\"\n");
+    Ok(())
+}
+
+/// Redefining a rule reports both definitions, unless downgraded to a warning.
+#[test]
+fn duplicate_rule() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "include inc.ninja", "build out: touch", ""].join("\n"),
+    )?;
+    space.write("inc.ninja", TOUCH_RULE)?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(
+        &out,
+        "inc.ninja:2: duplicate rule \"touch\", previously defined at build.ninja:2",
+    );
+
+    let out = space.run_expect(&mut n2_command(vec!["-w", "duprule=warn", "out"]))?;
+    assert_output_contains(&out, "n2: warn: inc.ninja:2: duplicate rule \"touch\"");
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+/// Rules must bind command themselves.
+#[test]
+fn rule_without_command() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write("build.ninja", "rule empty\nbuild out: empty\n")?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(
+        &out,
+        "build.ninja:1: rule \"empty\" has no 'command' binding",
+    );
+
+    space.write(
+        "build.ninja",
+        "
+command = touch out
+rule desc
+  description = oops
+build out: desc
+",
+    )?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(
+        &out,
+        "build.ninja:3: rule \"desc\" has no 'command' binding",
+    );
+    assert_output_contains(&out, "a file-scope 'command' doesn't apply to rules");
+    Ok(())
+}
+
+/// `-f -` reads the manifest from stdin, with includes resolved as usual.
+#[test]
+fn manifest_from_stdin() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write("rules.ninja", TOUCH_RULE)?;
+    space.write("in", "")?;
+    space.write("stdin.ninja", "include rules.ninja\nbuild out: touch in\n")?;
+    let stdin = std::fs::File::open(space.path("stdin.ninja"))?;
+    space.run_expect(n2_command(vec!["-f", "-", "out"]).stdin(stdin))?;
+    assert!(space.read("out").is_ok());
+
+    space.write("stdin.ninja", "rule\n")?;
+    let stdin = std::fs::File::open(space.path("stdin.ninja"))?;
+    let out = space.run(n2_command(vec!["-f", "-", "out"]).stdin(stdin))?;
+    assert_output_contains(&out, "<stdin>:1: ");
+    Ok(())
+}
+
+#[test]
+fn include_cycle() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write("build.ninja", "x = 1\n\ninclude a.ninja\n")?;
+    space.write("a.ninja", "subninja b.ninja\n")?;
+    space.write("b.ninja", "\ninclude a.ninja\n")?;
+    let out = space.run(&mut n2_command(vec![]))?;
+    assert_output_contains(
+        &out,
+        "cycle in includes: a.ninja includes subninja b.ninja (line 1) which includes a.ninja (line 2)",
+    );
+
+    // Reading a file twice without a cycle is fine.
+    space.write("build.ninja", "include a.ninja\ninclude a.ninja\n")?;
+    space.write("a.ninja", "subninja b.ninja\nsubninja b.ninja\n")?;
+    space.write("b.ninja", "x = 1\n")?;
+    let out = space.run_expect(&mut n2_command(vec![]))?;
+    assert_output_contains(&out, "no work to do");
+
+    // Very deep nesting is rejected even without a cycle.
+    space.write("build.ninja", "include d0.ninja\n")?;
+    for i in 0..100 {
+        space.write(
+            &format!("d{}.ninja", i),
+            &format!("include d{}.ninja\n", i + 1),
+        )?;
+    }
+    let out = space.run(&mut n2_command(vec![]))?;
+    assert_output_contains(&out, "includes nested more than 64 deep");
+    Ok(())
+}
+
+/// A parse error in an included file names that file and how it was reached.
+#[test]
+fn parse_error_in_include() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write("build.ninja", "x = 1\nsubninja a.ninja\n")?;
+    space.write("a.ninja", "\n\ninclude b.ninja\n")?;
+    space.write("b.ninja", "rule r\n  command = x\nbuild $: r\n")?;
+    let out = space.run(&mut n2_command(vec![]))?;
+    assert_output_contains(
+        &out,
+        "b.ninja:3: build $: r
+                     ^
+included from a.ninja:3
+read as subninja from build.ninja:2
+",
+    );
+    Ok(())
+}
+
+#[test]
+fn lazy_subninjas() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "subninja a.ninja",
+            "subninja c.ninja",
+            "subninja b.ninja",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("a.ninja", "build a_out: touch gen_c\n")?;
+    space.write("c.ninja", "build gen_c: touch\n")?;
+    space.write("b.ninja", "build b_out touch\n")?;
+
+    // Eagerly, the error in b.ninja is found.
+    let out = space.run(&mut n2_command(vec!["a_out"]))?;
+    assert_output_contains(&out, "b.ninja:1");
+
+    // a_out needs a.ninja, and its input gen_c needs c.ninja, but b.ninja
+    // is never read.
+    let out = space.run_expect(&mut n2_command(vec!["--lazy-subninjas", "a_out"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+
+    // The default targets need every subninja.
+    let out = space.run(&mut n2_command(vec!["--lazy-subninjas"]))?;
+    assert_output_contains(&out, "b.ninja:1");
+
+    // A default target from an unread subninja isn't checked.
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build y: touch",
+            "subninja d.ninja",
+            "default z",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("d.ninja", "build z: touch\n")?;
+    space.run_expect(&mut n2_command(vec!["--lazy-subninjas", "y"]))?;
+
+    // A rule used outside the deferred subninja defining it is an error.
+    space.write("build.ninja", "subninja rules.ninja\nbuild x: myrule\n")?;
+    space.write("rules.ninja", "rule myrule\n  command = touch $out\n")?;
+    let out = space.run(&mut n2_command(vec!["--lazy-subninjas", "x"]))?;
+    assert_output_contains(
+        &out,
+        "unknown rule \"myrule\"; it is defined in a subninja deferred by lazy subninja loading",
+    );
+    space.run_expect(&mut n2_command(vec!["x"]))?;
+    Ok(())
+}
+
+/// Like ninja's `restat = 1`, which n2 applies to every rule: a build that
+/// leaves its output untouched doesn't rerun the builds depending on it.
+#[cfg(unix)]
+#[test]
+fn unchanged_output_spares_dependents() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule gen
+  command = cmp -s $in $out || cp $in $out
+  description = gen $out
+  restat = 1
+rule link
+  command = cat $in > $out
+  description = link $out
+build gen.h: gen gen.in
+build app: link gen.h
+",
+    )?;
+    space.write("gen.in", "v1")?;
+    let out = space.run_expect(&mut n2_command(vec!["app"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+
+    // The generator reruns but leaves gen.h as it was.
+    space.sub_mtime("gen.in", std::time::Duration::from_secs(1))?;
+    let out = space.run_expect(&mut n2_command(vec!["app"]))?;
+    assert_output_contains(&out, "gen gen.h");
+    assert_output_not_contains(&out, "link app");
+    assert_output_contains(&out, "ran 1 task");
+    let out = space.run_expect(&mut n2_command(vec!["app"]))?;
+    assert_output_contains(&out, "no work to do");
+
+    // The generator rewrites gen.h, so app is relinked.
+    space.write("gen.in", "v2")?;
+    let out = space.run_expect(&mut n2_command(vec!["app"]))?;
+    assert_output_contains(&out, "link app");
+    assert_eq!(space.read("app")?, b"v2");
+    Ok(())
+}
+
+/// -t recompact drops the records of superseded runs and of builds no longer
+/// in the manifest, without forgetting the state of the others.
+#[test]
+fn recompact() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch in", "build gone: touch", ""].join("\n"),
+    )?;
+    for i in 0..3 {
+        space.write("in", &i.to_string())?;
+        space.run_expect(&mut n2_command(vec!["out", "gone"]))?;
+    }
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch in", ""].join("\n"),
+    )?;
+    let before = space.metadata(".n2_db")?.len();
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "recompact"]))?;
+    let after = space.metadata(".n2_db")?.len();
+    assert!(after < before);
+    assert_output_contains(
+        &out,
+        &format!("n2: compacted .n2_db from {} to {} bytes", before, after),
+    );
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
+/// -t recompact reports the size from before it ran, even when the database
+/// is one that opening it normally would already compact.
+#[test]
+fn recompact_large() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    let mut manifest = vec![TOUCH_RULE.to_owned()];
+    manifest.extend((0..1000).map(|i| format!("build out{}: touch", i)));
+    manifest.push(String::new());
+    space.write("build.ninja", &manifest.join("\n"))?;
+    space.run_expect(&mut n2_command(vec!["-j", "16"]))?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out0: touch", ""].join("\n"),
+    )?;
+    let before = space.metadata(".n2_db")?.len();
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "recompact"]))?;
+    let after = space.metadata(".n2_db")?.len();
+    assert!(after < before);
+    assert_output_contains(
+        &out,
+        &format!("n2: compacted .n2_db from {} to {} bytes", before, after),
+    );
+    Ok(())
+}
+
+/// A database with a damaged tail, as left by a crash, keeps the records
+/// before it, and one that can't be read at all is set aside.
+#[test]
+fn damaged_database() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build a: touch", "build b: touch", ""].join("\n"),
+    )?;
+    space.run_expect(&mut n2_command(vec!["a"]))?;
+    space.run_expect(&mut n2_command(vec!["b"]))?;
+    let db = space.read(".n2_db")?;
+    std::fs::write(space.path(".n2_db"), &db[..db.len() - 1])?;
+
+    let out = space.run_expect(&mut n2_command(vec!["a", "b"]))?;
+    assert_output_contains(&out, "n2: warn: .n2_db: truncated record at byte");
+    assert_output_contains(&out, "touch b");
+    assert_output_contains(&out, "ran 1 task");
+
+    space.write(".n2_db", "garbage")?;
+    let out = space.run_expect(&mut n2_command(vec!["a", "b"]))?;
+    assert_output_contains(
+        &out,
+        "n2: warn: .n2_db: invalid db signature; moved it to .n2_db.bad and starting a new database",
+    );
+    assert_output_contains(&out, "ran 2 tasks");
+    Ok(())
+}
+
+/// With --content-hash, inputs are compared by content: rewriting an input
+/// without changing it doesn't rerun anything, and changing it does even if
+/// its mtime is restored.  Either mode keeps the other's state current.
+#[cfg(unix)]
+#[test]
+fn content_hash() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cp
+  command = cp $in $out
+  description = cp $out
+build out: cp in
+",
+    )?;
+    space.write("in", "v1")?;
+    let out = space.run_expect(&mut n2_command(vec!["--content-hash", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    // Same content, new mtime.
+    space.write("in", "v1")?;
+    space.sub_mtime("in", std::time::Duration::ZERO)?;
+    let out = space.run_expect(&mut n2_command(vec!["--content-hash", "out"]))?;
+    assert_output_contains(&out, "no work to do");
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+
+    // New content, old mtime, as left by tools that restore timestamps.
+    let mtime = space.metadata("in")?.modified()?;
+    space.write("in", "v2")?;
+    std::fs::File::options()
+        .write(true)
+        .open(space.path("in"))?
+        .set_modified(mtime)?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+    let out = space.run_expect(&mut n2_command(vec!["--content-hash", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    assert_eq!(space.read("out")?, b"v2");
+    Ok(())
+}
+
+/// Content hashing sees changes that keep the length, and zero bytes
+/// anywhere in the file.
+#[cfg(unix)]
+#[test]
+fn content_hash_changes() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cp
+  command = cp $in $out
+  description = cp $out
+build out: cp in
+",
+    )?;
+    space.write("in", "abcdefgh")?;
+    let out = space.run_expect(&mut n2_command(vec!["--content-hash", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    // Leading zeros.
+    space.write("in", "\0\0\0\0\0\0\0\0abcdefgh")?;
+    space.sub_mtime("in", std::time::Duration::ZERO)?;
+    let out = space.run_expect(&mut n2_command(vec!["--content-hash", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    // Same length.
+    space.write("in", "abcdefgh\0\0\0\0\0\0\0\0")?;
+    space.sub_mtime("in", std::time::Duration::ZERO)?;
+    let out = space.run_expect(&mut n2_command(vec!["--content-hash", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    assert_eq!(space.read("out")?, b"abcdefgh\0\0\0\0\0\0\0\0");
+    Ok(())
+}
+
+/// A second n2 in the same directory waits for the first to finish, or with
+/// --no-lock-wait fails.
+#[cfg(unix)]
+#[test]
+fn concurrent_lock() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule slow
+  command = sleep 1 && touch $out
+build out: slow
+",
+    )?;
+    let mut first = n2_command(vec!["out"])
+        .current_dir(space.path("."))
+        .stdout(std::process::Stdio::null())
+        .spawn()?;
+    // The lock holder writes its pid once it has the lock.
+    while space.read(".n2_lock").map_or(true, |pid| pid.is_empty()) {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+
+    let out = space.run(&mut n2_command(vec!["--no-lock-wait", "out"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(
+        &out,
+        &format!(
+            "other n2 process (pid {}) is building in this directory",
+            first.id()
+        ),
+    );
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(
+        &out,
+        &format!("n2: waiting for other n2 process (pid {})", first.id()),
+    );
+    assert_output_contains(&out, "no work to do");
+    assert!(first.wait()?.success());
+    Ok(())
+}
+
+/// -t compdb prints a compilation database for the named rules, and nothing
+/// else, without touching the database.
+#[test]
+fn compdb() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cc
+  command = cc -c $in -o $out
+rule link
+  command = ld @$out.rsp
+  rspfile = $out.rsp
+  rspfile_content = $in
+build a.o: cc a.c
+build out: link a.o
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "compdb", "-x", "cc", "link"]))?;
+    let dir = space.path(".").canonicalize()?;
+    let dir = dir.to_str().unwrap().replace('\\', "\\\\");
+    assert_eq!(
+        std::str::from_utf8(&out.stdout)?,
+        format!(
+            r#"[
+  {{
+    "directory": "{dir}",
+    "command": "cc -c a.c -o a.o",
+    "file": "a.c",
+    "output": "a.o"
+  }},
+  {{
+    "directory": "{dir}",
+    "command": "ld a.o",
+    "file": "a.o",
+    "output": "out"
+  }}
+]
+"#
+        )
+    );
+    assert!(space.metadata(".n2_db").is_err());
+    Ok(())
+}
+
+/// -t clean removes built files and depfiles, leaving generator outputs and
+/// sources, and forgets the builds so they all run again.
+#[cfg(unix)]
+#[test]
+fn clean() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "rule touchd",
+            "  command = touch $out $out.d",
+            "  depfile = $out.d",
+            "rule gen",
+            "  command = touch $out",
+            "  generator = 1",
+            "build gen.out: gen",
+            "build mid: touchd in",
+            "build out: touch mid",
+            "build other: touch",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+    space.run_expect(&mut n2_command(vec!["gen.out", "out", "other"]))?;
+
+    let out = space.run_expect(&mut n2_command(vec!["-n", "-t", "clean", "out"]))?;
+    assert_output_contains(
+        &out,
+        "n2: would remove out
+n2: would remove mid
+n2: would remove mid.d
+n2: dry run: would remove 3 files
+",
+    );
+    assert!(space.read("mid").is_ok());
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "clean", "rule", "touchd"]))?;
+    assert_output_contains(&out, "n2: removed 2 files");
+    assert!(space.read("mid").is_err());
+    assert!(space.read("out").is_ok());
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "clean"]))?;
+    assert_output_contains(&out, "n2: removed 2 files");
+    assert!(space.read("gen.out").is_ok());
+    assert!(space.read("in").is_ok());
+
+    let out = space.run_expect(&mut n2_command(vec!["gen.out", "out", "other"]))?;
+    assert_output_contains(&out, "ran 3 tasks");
+    Ok(())
+}
+
+#[test]
+fn query() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build mid: touch in | dep",
+            "build out: touch mid",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "query", "mid"]))?;
+    assert_output_contains(&out, "mid:\n  input: touch\n    in\n    | dep\n");
+    assert_output_contains(&out, "  outputs:\n    out\n");
+
+    let out = space.run(&mut n2_command(vec!["-t", "query", "mdi"]))?;
+    assert_output_contains(&out, "unknown path \"mdi\"; did you mean \"mid\"?");
+    Ok(())
+}
+
+#[test]
+fn commands() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cc
+  command = cc -c $in -o $out
+rule link
+  command = ld $in -o $out
+build a.o: cc a.c
+build b.o: cc b.c
+build out: link a.o b.o
+default out
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "commands"]))?;
+    assert_eq!(
+        std::str::from_utf8(&out.stdout)?,
+        "cc -c a.c -o a.o\ncc -c b.c -o b.o\nld a.o b.o -o out\n"
+    );
+    let out = space.run_expect(&mut n2_command(vec!["-t", "commands", "-s", "out"]))?;
+    assert_eq!(std::str::from_utf8(&out.stdout)?, "ld a.o b.o -o out\n");
+    Ok(())
+}
+
+#[test]
+fn inputs_and_outputs() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cc
+  command = cc -c $in -o $out
+rule link
+  command = ld $in -o $out
+build gen.h: cc gen.in
+build a.o: cc a.c | gen.h
+build b.o: cc b.c || gen.h
+build out: link a.o b.o
+build all: phony out
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "inputs", "--sources", "all"]))?;
+    assert_eq!(std::str::from_utf8(&out.stdout)?, "a.c\ngen.in\nb.c\n");
+    let out = space.run_expect(&mut n2_command(vec!["-t", "inputs", "--direct", "out"]))?;
+    assert_eq!(std::str::from_utf8(&out.stdout)?, "a.o\nb.o\n");
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "outputs", "gen.h"]))?;
+    assert_eq!(std::str::from_utf8(&out.stdout)?, "a.o\nb.o\nout\n");
+    let out = space.run_expect(&mut n2_command(vec![
+        "-t",
+        "outputs",
+        "--no-order-only",
+        "gen.h",
+    ]))?;
+    assert_eq!(std::str::from_utf8(&out.stdout)?, "a.o\nout\n");
+    Ok(())
+}
+
+#[test]
+fn manifest_json() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+inc = rules
+include $inc.ninja
+build out: touch in$$
+",
+    )?;
+    space.write("rules.ninja", "rule touch\n  command = touch $out\n")?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "manifest"]))?;
+    assert_eq!(
+        std::str::from_utf8(&out.stdout)?,
+        r#"{"version":1,"file":{"path":"build.ninja","statements":[
+{"kind":"binding","line":2,"name":"inc","value":[{"literal":"rules"}]},
+{"kind":"include","line":3,"path":[{"var":"inc"},{"literal":".ninja"}],"file":{"path":"rules.ninja","statements":[
+{"kind":"rule","line":1,"name":"touch","bindings":[{"name":"command","value":[{"literal":"touch "},{"var":"out"}]}]}
+]}},
+{"kind":"build","line":4,"rule":"touch","outputs":{"explicit":[[{"literal":"out"}]],"implicit":[]},"inputs":{"explicit":[[{"literal":"in$"}]],"implicit":[],"order_only":[],"validations":[]},"bindings":[]}
+]}}
+"#
+    );
+    Ok(())
+}
@@ -41,8 +41,87 @@ build out: my_rule
 ",
     )?;
 
-    let out = space.run(&mut n2_command(vec!["out"]))?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(
+        &out,
+        "n2: warn: build.ninja:2: rule \"my_rule\" binds unknown variable \"my_var\"",
+    );
+
+    let out = space.run(&mut n2_command(vec!["-w", "unknownrulevar=err", "out"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "rule \"my_rule\" binds unknown variable \"my_var\"");
+    Ok(())
+}
+
+/// With --strict, rule bindings ninja doesn't consult are rejected, as ninja
+/// does.
+#[test]
+fn strict_rule_variable() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule my_rule
+    command = touch $out
+    my_var = foo
+
+build out: my_rule
+",
+    )?;
+
+    let out = space.run(&mut n2_command(vec!["--strict", "out"]))?;
+    assert!(!out.status.success());
     assert_output_contains(&out, "unexpected variable \"my_var\"");
+    assert!(space.read("out").is_err());
+    Ok(())
+}
+
+/// Rule variables referenced by the rule's other bindings aren't unknown.
+#[test]
+fn referenced_rule_variable() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            "rule my_rule",
+            "  command = touch $out $extra",
+            "  extra = $out.extra",
+            "build out: my_rule",
+            "",
+        ]
+        .join("\n"),
+    )?;
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_not_contains(&out, "warn");
+    space.read("out.extra")?;
+    Ok(())
+}
+
+/// A variable bound twice in one body takes the later value, with a warning.
+#[cfg(unix)]
+#[test]
+fn rebound_variable() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            "rule echo",
+            "  command = echo $msg",
+            "build out: echo",
+            "  msg = first",
+            "  msg = second",
+            "",
+        ]
+        .join("\n"),
+    )?;
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(
+        &out,
+        "n2: warn: build.ninja:5: \"msg\" is bound again, ignoring the binding at line 4",
+    );
+    assert_output_contains(&out, "echo second");
     Ok(())
 }
 
@@ -147,3 +226,182 @@ build foo: write_file
     assert_eq!(space.read("foo")?, b"Hello, world!\n");
     Ok(())
 }
+
+/// With -w undefvar=warn, references to variables bound nowhere are reported.
+#[cfg(unix)]
+#[test]
+fn undefined_variable_warning() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+cflags =
+rule cc
+    command = touch $out $cflgs $cflags $extra $pool
+    description = CC $out $cflgs
+
+build a: cc
+    flags = $defines
+build b: cc
+    extra = 1
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["-w", "undefvar=warn", "a", "b"]))?;
+    assert_output_contains(
+        &out,
+        "build.ninja:3: rule \"cc\" binding \"command\" references undefined variable \"cflgs\"",
+    );
+    assert_output_contains(
+        &out,
+        "build.ninja:3: rule \"cc\" binding \"description\" references undefined variable \"cflgs\"",
+    );
+    assert_output_contains(
+        &out,
+        "build.ninja:7: build binding \"flags\" references undefined variable \"defines\"",
+    );
+    assert_output_not_contains(&out, "\"cflags\"");
+    assert_output_not_contains(&out, "\"extra\"");
+    assert_output_not_contains(&out, "\"pool\"");
+
+    // Off by default.
+    let out = space.run_expect(&mut n2_command(vec!["a"]))?;
+    assert_output_not_contains(&out, "undefined variable");
+    Ok(())
+}
+
+/// An include shares the including file's scope, while a subninja gets a
+/// child scope whose bindings don't leak back.
+#[cfg(unix)]
+#[test]
+fn include_and_subninja_scope() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule echo
+    command = echo $cflags > $out
+cflags = -O2
+include inc.ninja
+build after_include: echo
+subninja sub.ninja
+build after_subninja: echo
+",
+    )?;
+    space.write(
+        "inc.ninja",
+        "
+build in_include: echo
+cflags = $cflags -g
+",
+    )?;
+    space.write(
+        "sub.ninja",
+        "
+cflags = $cflags -DSUB
+build in_subninja: echo
+",
+    )?;
+    space.run_expect(&mut n2_command(vec![
+        "in_include",
+        "after_include",
+        "in_subninja",
+        "after_subninja",
+    ]))?;
+    assert_eq!(space.read("in_include")?, b"-O2\n");
+    assert_eq!(space.read("after_include")?, b"-O2 -g\n");
+    assert_eq!(space.read("in_subninja")?, b"-O2 -g -DSUB\n");
+    assert_eq!(space.read("after_subninja")?, b"-O2 -g\n");
+    Ok(())
+}
+
+/// Variables bound with -D win over file-scope assignments, including those
+/// in included and subninja'd files, while build bindings still shadow them.
+#[cfg(unix)]
+#[test]
+fn command_line_var_override() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule echo
+    command = echo $mode $cflags > $out
+mode = release
+cflags = -O2
+include inc.ninja
+subninja sub.ninja
+build top: echo
+build shadowed: echo
+    mode = build
+",
+    )?;
+    space.write(
+        "inc.ninja",
+        "
+mode = inc
+cflags = $cflags -DMODE=$mode
+",
+    )?;
+    space.write(
+        "sub.ninja",
+        "
+mode = sub
+build in_subninja: echo
+",
+    )?;
+    space.run_expect(&mut n2_command(vec![
+        "-D",
+        "mode=debug",
+        "top",
+        "shadowed",
+        "in_subninja",
+    ]))?;
+    assert_eq!(space.read("top")?, b"debug -O2 -DMODE=debug\n");
+    assert_eq!(space.read("shadowed")?, b"build -O2 -DMODE=debug\n");
+    assert_eq!(space.read("in_subninja")?, b"debug -O2 -DMODE=debug\n");
+
+    let out = space.run(&mut n2_command(vec!["-D", "mode", "top"]))?;
+    assert_output_contains(&out, "expected var=value");
+    Ok(())
+}
+
+/// With --env-vars, variables bound nowhere come from the environment, but
+/// the implicit variables never do.
+#[cfg(unix)]
+#[test]
+fn env_var_fallback() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule echo
+    command = echo $N2_TEST_CC $N2_TEST_HOME $cflags > $out
+cflags = -O2
+N2_TEST_HOME = manifest
+build out: echo
+",
+    )?;
+    let mut cmd = n2_command(vec!["--env-vars", "-d", "env", "out"]);
+    cmd.env("N2_TEST_CC", "clang")
+        .env("N2_TEST_HOME", "env")
+        .env("out", "bad");
+    let out = space.run_expect(&mut cmd)?;
+    assert_eq!(space.read("out")?, b"clang manifest -O2\n");
+    assert_output_contains(&out, "n2: env: N2_TEST_CC=clang");
+    assert_output_not_contains(&out, "N2_TEST_HOME=");
+    assert_output_not_contains(&out, "bad");
+
+    // Off by default.
+    space.write(
+        "build.ninja",
+        "
+rule echo
+    command = echo $N2_TEST_CC > $out
+build out2: echo
+",
+    )?;
+    let mut cmd = n2_command(vec!["out2"]);
+    cmd.env("N2_TEST_CC", "clang");
+    space.run_expect(&mut cmd)?;
+    assert_eq!(space.read("out2")?, b"\n");
+    Ok(())
+}
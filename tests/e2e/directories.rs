@@ -30,3 +30,68 @@ build out: list_files .
 
     Ok(())
 }
+
+/// With --relative-includes, include and subninja paths are resolved against
+/// the including file's directory, composing with -C and nested subninjas.
+#[cfg(unix)]
+#[test]
+fn relative_includes() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    std::fs::create_dir_all(space.path("top/rules"))?;
+    std::fs::create_dir_all(space.path("top/lib/deep"))?;
+    space.write(
+        "top/build.ninja",
+        "
+include rules/echo.ninja
+subninja lib/build.ninja
+",
+    )?;
+    space.write(
+        "top/rules/echo.ninja",
+        "
+rule echo
+    command = echo $msg > $out
+",
+    )?;
+    space.write(
+        "top/lib/build.ninja",
+        "
+include local.ninja
+subninja deep/build.ninja
+",
+    )?;
+    space.write("top/lib/local.ninja", "msg = local\n")?;
+    space.write(
+        "top/lib/deep/build.ninja",
+        "
+include ../local.ninja
+build out: echo
+",
+    )?;
+
+    // By default, paths are relative to the working directory.
+    let out = space.run(&mut n2_command(vec!["-C", "top", "out"]))?;
+    assert_output_contains(&out, "read local.ninja: No such file");
+
+    space.run_expect(&mut n2_command(vec![
+        "-C",
+        "top",
+        "--relative-includes",
+        "out",
+    ]))?;
+    assert_eq!(space.read("top/out")?, b"local\n");
+
+    // Errors show both the path as written and the path tried.
+    space.write("top/lib/build.ninja", "include missing.ninja\n")?;
+    let out = space.run(&mut n2_command(vec![
+        "-C",
+        "top",
+        "--relative-includes",
+        "out",
+    ]))?;
+    assert_output_contains(
+        &out,
+        "lib/build.ninja: include \"missing.ninja\": read lib/missing.ninja: No such file",
+    );
+    Ok(())
+}
@@ -38,6 +38,28 @@ build out: gendep
     Ok(())
 }
 
+/// A task whose output was printed as it ran, then failed on its depfile,
+/// reports the depfile error in full.
+#[cfg(unix)]
+#[test]
+fn bad_depfile_after_streamed_output() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule gendep
+  description = gendep $out
+  command = seq 100 && echo garbage text > $out.d && touch $out
+  depfile = $out.d
+build out: gendep
+",
+    )?;
+
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "99\n100\nfailed: gendep out\nparse error: expected");
+    Ok(())
+}
+
 /// depfile contains reference to existing order-only dep.
 #[test]
 fn discover_existing_dep() -> anyhow::Result<()> {
@@ -72,6 +94,131 @@ build out: gendep || in
     Ok(())
 }
 
+/// depfile names several outputs of its build before the colon.
+#[cfg(unix)]
+#[test]
+fn discover_multiple_targets() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule gen2
+  description = gen2 $out
+  command = echo \"$out: in\" > gen.d && touch $out
+  depfile = gen.d
+build out out2: gen2
+",
+    )?;
+    space.write("in", "")?;
+    space.run_expect(&mut n2_command(vec!["out2"]))?;
+    let out = space.run_expect(&mut n2_command(vec!["out2"]))?;
+    assert_output_contains(&out, "no work");
+
+    space.write("in", "x")?;
+    let out = space.run_expect(&mut n2_command(vec!["out2"]))?;
+    assert_output_contains(&out, "gen2 out out2");
+    Ok(())
+}
+
+/// depfile names a target that isn't one of the build's outputs.
+#[test]
+fn depfile_target_mismatch() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            GENDEP_RULE,
+            "
+build out: gendep
+  dep_content = other: in
+",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(
+        &out,
+        "build.ninja:8: depfile \"out.d\" mentions \"other\" as an output, but no such output was declared",
+    );
+
+    let out = space.run_expect(&mut n2_command(vec!["-w", "depfiletarget=warn", "out"]))?;
+    assert_output_contains(&out, "n2: warn: build.ninja:8: depfile \"out.d\" mentions");
+    // The dependencies are used regardless.
+    space.write("in", "x")?;
+    let out = space.run_expect(&mut n2_command(vec!["-w", "depfiletarget=warn", "out"]))?;
+    assert_output_contains(&out, "gendep out");
+    Ok(())
+}
+
+/// depfile names an implicit output of its own build, which isn't a dep.
+#[test]
+fn discover_own_implicit_out() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            GENDEP_RULE,
+            "
+build out | out.d: gendep in
+  dep_content = out: in out.d
+",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work");
+
+    space.write("in", "x")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "gendep out");
+
+    Ok(())
+}
+
+/// With case-insensitive paths, a depfile spelling a file differently than
+/// the manifest refers to the same file.
+#[test]
+fn discover_dep_other_case() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            GENDEP_RULE,
+            TOUCH_RULE,
+            "build Gen.h: touch",
+            "
+build out: gendep || Gen.h
+  dep_content = out: gen.h
+",
+            "",
+        ]
+        .join("\n"),
+    )?;
+
+    let args = vec!["--path-case", "insensitive", "out"];
+    let out = space.run_expect(&mut n2_command(args.clone()))?;
+    assert_output_contains(&out, "ran 2 tasks");
+
+    let out = space.run_expect(&mut n2_command(args.clone()))?;
+    assert_output_contains(&out, "no work");
+
+    space.write("Gen.h", "x")?;
+    let out = space.run_expect(&mut n2_command(args))?;
+    assert_output_contains(&out, "gendep out");
+
+    Ok(())
+}
+
 #[cfg(unix)]
 #[test]
 fn multi_output_depfile() -> anyhow::Result<()> {
@@ -135,3 +282,230 @@ build out: myrule
     assert_output_contains(&out, "no work");
     Ok(())
 }
+
+/// deps = msvc takes dependencies from /showIncludes lines in the output,
+/// with the prefix given by msvc_deps_prefix, if not empty, and hides those
+/// lines.
+#[cfg(unix)]
+#[test]
+fn discover_showincludes() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cl
+  command = printf '$prefix  in.h\\nwarning: hello\\n' && touch $out
+  description = cl $out
+  deps = msvc
+build out: cl
+  prefix = Note: including file:
+build localized: cl
+  prefix = Remarque : inclusion du fichier :
+  msvc_deps_prefix = Remarque : inclusion du fichier :
+build empty: cl
+  prefix = Note: including file:
+  msvc_deps_prefix =
+",
+    )?;
+    space.write("in.h", "")?;
+    let targets = vec!["out", "localized", "empty"];
+    let out = space.run_expect(&mut n2_command(targets.clone()))?;
+    assert_output_contains(&out, "warning: hello");
+    assert_output_not_contains(&out, "in.h");
+
+    space.write("in.h", "x")?;
+    let out = space.run_expect(&mut n2_command(targets))?;
+    assert_output_contains(&out, "ran 3 tasks");
+    Ok(())
+}
+
+/// Write a version 4 ninja deps log recording `deps` for `out`.
+fn ninja_deps_log(out: &str, deps: &[&str]) -> Vec<u8> {
+    let mut buf = b"# ninjadeps\n".to_vec();
+    buf.extend(4u32.to_le_bytes());
+    for (id, path) in std::iter::once(&out).chain(deps).enumerate() {
+        let padding = (4 - path.len() % 4) % 4;
+        buf.extend(((path.len() + padding + 4) as u32).to_le_bytes());
+        buf.extend(path.as_bytes());
+        buf.extend(std::iter::repeat(0).take(padding));
+        buf.extend((!(id as u32)).to_le_bytes());
+    }
+    buf.extend(((12 + 4 * deps.len()) as u32 | 0x8000_0000).to_le_bytes());
+    buf.extend(0u32.to_le_bytes());
+    buf.extend(i64::MAX.to_le_bytes());
+    for id in 1..=deps.len() as u32 {
+        buf.extend(id.to_le_bytes());
+    }
+    buf
+}
+
+/// A build directory last built by ninja keeps the deps from its deps log,
+/// and builds that ninja considered up to date aren't rerun.
+#[cfg(unix)]
+#[test]
+fn import_ninja_deps() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            GENDEP_RULE,
+            "
+build out: gendep in
+  dep_content = out: in in.h
+build stale: gendep in
+  dep_content = stale: in
+",
+        ]
+        .join("\n"),
+    )?;
+    for file in ["in", "in.h", "out", "stale"] {
+        space.write(file, "")?;
+    }
+    space.sub_mtime("in", std::time::Duration::from_secs(10))?;
+    space.sub_mtime("in.h", std::time::Duration::from_secs(10))?;
+    space.sub_mtime("out", std::time::Duration::from_secs(5))?;
+    std::fs::write(space.path(".ninja_deps"), ninja_deps_log("out", &["in.h"]))?;
+
+    // "stale" has no deps recorded, so n2 can't know it's up to date.
+    let out = space.run_expect(&mut n2_command(vec!["out", "stale"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    assert_output_contains(&out, "gendep stale");
+
+    // The imported dep on in.h was kept in n2's database.
+    space.write("in.h", "x")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "gendep out");
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
+/// An unreadable ninja deps log is warned about and otherwise ignored, while
+/// one whose last record was cut short keeps the records before it.
+#[test]
+fn import_corrupt_ninja_deps() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write("build.ninja", TOUCH_RULE)?;
+    let mut log = ninja_deps_log("out", &["in.h"]);
+    std::fs::write(space.path(".ninja_deps"), &log[..log.len() - 2])?;
+    let out = space.run_expect(&mut n2_command(vec![]))?;
+    assert_output_not_contains(&out, ".ninja_deps");
+
+    // Flip a byte of the first path's checksum.
+    log[24] ^= 1;
+    std::fs::write(space.path(".ninja_deps"), &log)?;
+    std::fs::remove_file(space.path(".n2_db"))?;
+    let out = space.run_expect(&mut n2_command(vec![]))?;
+    assert_output_contains(
+        &out,
+        "n2: warn: .ninja_deps: bad path record checksum at offset 20; not importing its deps",
+    );
+    Ok(())
+}
+
+/// Builds whose commands match those in ninja's build log are adopted, and
+/// others run.
+#[cfg(unix)]
+#[test]
+fn import_ninja_log() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build out: touch in",
+            "build changed: touch in",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    for file in ["in", "out", "changed"] {
+        space.write(file, "")?;
+    }
+    space.sub_mtime("in", std::time::Duration::from_secs(10))?;
+    // The hash is MurmurHash64A of "touch out", as ninja logs it.
+    space.write(
+        ".ninja_log",
+        "# ninja log v5\n\
+         0\t10\t0\tout\t8ea3cc54bdccad2c\n\
+         0\t10\t0\tchanged\t1234\n\
+         not a log line\n",
+    )?;
+
+    let out = space.run_expect(&mut n2_command(vec!["out", "changed"]))?;
+    assert_output_contains(&out, "n2: warn: .ninja_log: skipped 1 malformed lines");
+    assert_output_contains(&out, "n2: imported 1 of 2 entries from .ninja_log");
+    assert_output_contains(&out, "touch changed");
+    assert_output_contains(&out, "ran 1 task");
+
+    let out = space.run_expect(&mut n2_command(vec!["out", "changed"]))?;
+    assert_output_contains(&out, "no work to do");
+    assert_output_not_contains(&out, ".ninja_log");
+    Ok(())
+}
+
+/// Finished tasks are appended to .ninja_log under builddir, in ninja's
+/// format, which n2 can import from again if its own database is lost.
+#[cfg(unix)]
+#[test]
+fn write_ninja_log() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &["builddir = b", TOUCH_RULE, "build out: touch in", ""].join("\n"),
+    )?;
+    space.write("in", "")?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+
+    let log = String::from_utf8(space.read("b/.ninja_log")?)?;
+    let mut lines = log.lines();
+    assert_eq!(lines.next(), Some("# ninja log v5"));
+    let fields: Vec<&str> = lines.next().unwrap().split('\t').collect();
+    assert_eq!(fields[3..], ["out", "8ea3cc54bdccad2c"]);
+    let mtime = space
+        .metadata("out")?
+        .modified()?
+        .duration_since(std::time::UNIX_EPOCH)?;
+    assert_eq!(fields[2], mtime.as_nanos().to_string());
+    assert_eq!(lines.next(), None);
+
+    std::fs::remove_file(space.path("b/.n2_db"))?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "n2: imported 1 of 1 entries from b/.ninja_log");
+    assert_output_contains(&out, "no work to do");
+
+    std::fs::remove_file(space.path("b/.ninja_log"))?;
+    space.sub_mtime("in", std::time::Duration::ZERO)?;
+    space.run_expect(&mut n2_command(vec!["--no-ninja-log", "out"]))?;
+    assert!(space.read("b/.ninja_log").is_err());
+    Ok(())
+}
+
+/// -t deps shows the discovered deps, and whether they're still up to date.
+#[test]
+fn deps_tool() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            GENDEP_RULE,
+            "
+build out: gendep
+  dep_content = out: header
+",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("header", "")?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "deps"]))?;
+    assert_output_contains(&out, "out: #deps 1, hash ");
+    assert_output_contains(&out, " (VALID)\n    header\n\n");
+
+    space.write("header", "changed")?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "deps", "out", "header"]))?;
+    assert_output_contains(&out, " (STALE)\n    header\n\nheader: deps not found\n");
+    Ok(())
+}
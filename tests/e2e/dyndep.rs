@@ -0,0 +1,148 @@
+//! Tests for builds that use dyndep files.
+
+use crate::e2e::*;
+
+/// A dyndep file checked in to the source tree adds an input to a build.
+#[test]
+fn dyndep_implicit_input() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch || dd", "  dyndep = dd", ""].join("\n"),
+    )?;
+    space.write(
+        "dd",
+        "ninja_dyndep_version = 1
+build out: dyndep | in
+",
+    )?;
+    space.write("in", "")?;
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work");
+
+    // The input is only known via the dyndep file, but still dirties out.
+    space.write("in", "x")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work");
+    Ok(())
+}
+
+/// A generated dyndep file adds a dependency on another build step, which
+/// must run before the build that uses the dyndep file.
+#[cfg(unix)]
+#[test]
+fn dyndep_generated() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "
+rule gendd
+  command = printf 'ninja_dyndep_version = 1\\nbuild out: dyndep | gen\\n' > $out
+  description = gendd $out
+",
+            "build gen: touch",
+            "build dd: gendd",
+            "build out: touch || dd",
+            "  dyndep = dd",
+            "",
+        ]
+        .join("\n"),
+    )?;
+
+    // Build with parallelism 1 so that, if the dependency were missing,
+    // out would be built before gen.
+    let out = space.run_expect(&mut n2_command(vec!["-j", "1", "out"]))?;
+    assert_output_contains(&out, "ran 3 tasks");
+    let gen = space.metadata("gen")?.modified()?;
+    let out_mtime = space.metadata("out")?.modified()?;
+    assert!(gen <= out_mtime);
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work");
+    Ok(())
+}
+
+/// A dyndep file's `restat = 1` is accepted; like every build in n2, one
+/// that leaves its output untouched doesn't rerun the builds using it.
+#[cfg(unix)]
+#[test]
+fn dyndep_restat() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule gen
+  command = cmp -s $in $out || cp $in $out
+  description = gen $out
+rule link
+  command = cat $in > $out
+  description = link $out
+build gen.h: gen gen.in || dd
+  dyndep = dd
+build app: link gen.h
+",
+    )?;
+    space.write(
+        "dd",
+        "ninja_dyndep_version = 1
+build gen.h: dyndep
+  restat = 1
+",
+    )?;
+    space.write("gen.in", "v1")?;
+    let out = space.run_expect(&mut n2_command(vec!["app"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+
+    space.sub_mtime("gen.in", std::time::Duration::from_secs(1))?;
+    let out = space.run_expect(&mut n2_command(vec!["app"]))?;
+    assert_output_contains(&out, "gen gen.h");
+    assert_output_not_contains(&out, "link app");
+    Ok(())
+}
+
+/// The build referencing a dyndep file must also list it as an input.
+#[test]
+fn dyndep_not_input() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch", "  dyndep = dd", ""].join("\n"),
+    )?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "dyndep \"dd\" is not an input");
+    Ok(())
+}
+
+/// A dyndep file may only describe builds that name it.
+#[test]
+fn dyndep_unknown_build() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build out: touch || dd",
+            "  dyndep = dd",
+            "build other: touch",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write(
+        "dd",
+        "ninja_dyndep_version = 1
+build out: dyndep
+build other: dyndep
+",
+    )?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "dd:3: build statement for \"other\" has no binding");
+    Ok(())
+}
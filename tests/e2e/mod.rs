@@ -4,6 +4,7 @@ mod basic;
 mod bindings;
 mod directories;
 mod discovered;
+mod dyndep;
 mod missing;
 mod regen;
 mod validations;
@@ -68,6 +69,11 @@ impl TestSpace {
         std::fs::write(self.dir.path().join(path), content)
     }
 
+    /// The path of a file in the working space.
+    pub fn path(&self, path: &str) -> std::path::PathBuf {
+        self.dir.path().join(path)
+    }
+
     /// Read a file from the working space.
     pub fn read(&self, path: &str) -> anyhow::Result<Vec<u8>> {
         let path = self.dir.path().join(path);
@@ -174,3 +174,35 @@ fn restat() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Changing the command of a generator build doesn't rerun it, though
+/// changing its inputs does.
+#[cfg(unix)]
+#[test]
+fn generator_ignores_command() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    let manifest = |flags: &str| {
+        format!(
+            "
+rule gen
+  command = touch $out {}
+  generator = 1
+build out: gen in
+",
+            flags
+        )
+    };
+    space.write("build.ninja", &manifest(""))?;
+    space.write("in", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    space.write("build.ninja", &manifest("extra"))?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+
+    space.write("in", "x")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "touch out extra");
+    Ok(())
+}
@@ -46,7 +46,9 @@ build validation_input: build_slow
   wait_for = out
 ",
     )?;
-    space.run_expect(&mut n2_command(vec!["out"]))?;
+    // The validation blocks until "out" exists, so this needs at least two
+    // tasks running at once regardless of the machine's CPU count.
+    space.run_expect(&mut n2_command(vec!["-j", "2", "out"]))?;
     Ok(())
 }
 
@@ -88,3 +90,60 @@ fn validation_inputs_break_cycles() -> anyhow::Result<()> {
     space.run_expect(&mut n2_command(vec!["out"]))?;
     Ok(())
 }
+
+/// A failed validation fails the build, but not the build that referenced
+/// it, which stays up to date once the validation is fixed.
+#[cfg(unix)]
+#[test]
+fn validation_failure_spares_referrer() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    let manifest = |validation_rule: &str| {
+        [
+            TOUCH_RULE,
+            "rule fail\n  command = exit 1",
+            "build out: touch |@ validation_input",
+            &format!("build validation_input: {}", validation_rule),
+            "",
+        ]
+        .join("\n")
+    };
+    space.write("build.ninja", &manifest("fail"))?;
+    let out = space.run(&mut n2_command(vec!["-k", "2", "out"]))?;
+    assert!(!out.status.success());
+    assert!(space.read("out").is_ok());
+
+    space.write("build.ninja", &manifest("touch"))?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "touch validation_input");
+    assert_output_not_contains(&out, "touch out");
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+/// Changes to a validation's inputs rerun only the validation, and a
+/// validation shared by several builds runs once.
+#[test]
+fn validation_doesnt_dirty_referrers() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build a: touch |@ check",
+            "build b: touch |@ check",
+            "build check: touch check.in",
+            "build all: phony a b",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("check.in", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["all"]))?;
+    assert_output_contains(&out, "ran 3 tasks");
+
+    space.write("check.in", "x")?;
+    let out = space.run_expect(&mut n2_command(vec!["all"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    assert_output_contains(&out, "touch check");
+    Ok(())
+}